@@ -0,0 +1,457 @@
+//! A Client for `RepositoryWs`.
+//!
+//! With:
+//! - [`Client`] to handle `single repository` transactions and;
+//! - [`Clients`] to manipulate `multi repository` transactions.
+use futures_util::future::join_all;
+use std::{
+    net::Ipv4Addr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use awc::http::Uri;
+use cereal_core::{
+    application::TransactionKind,
+    messages::{CommitVote, TransactionError},
+    operations::{Arguments, Operation, Table},
+    retry::RetryPolicy,
+    runtime::Runtime,
+};
+use tracing::Instrument as _;
+use uuid::Uuid;
+
+use cereal_protocol::{MessageWs, Participant, ResponseBody};
+
+use crate::{connection::ConnectionHandle, credentials::Credentials};
+
+/// ClientBuilder.
+///
+/// A auxiliary Type to build `RepositoryWs` connections.
+#[derive(Debug)]
+pub struct ClientBuilder {
+    uri: Uri,
+    runtime: Runtime,
+    credentials: Credentials,
+    timeout: Option<Duration>,
+}
+
+impl ClientBuilder {
+    /// ClientBuilder::new.
+    ///
+    /// Creates a [ClientBuilder] from a `ip` and `port`.
+    pub fn new(ip: Ipv4Addr, port: u16) -> Self {
+        let uri = Uri::builder()
+            .authority(format!("{ip}:{port}"))
+            .scheme("http")
+            .path_and_query("/ws/")
+            .build()
+            .unwrap();
+
+        let runtime = Runtime::new();
+
+        ClientBuilder {
+            uri,
+            runtime,
+            credentials: Credentials::default(),
+            timeout: None,
+        }
+    }
+
+    /// Creates a [ClientBuilder] from the `uri` of a `RepositoryWs` (or of a
+    /// `ws gateway`), e.g. `http://127.0.0.1:8080/ws/`.
+    pub fn from_uri(uri: &str) -> anyhow::Result<Self> {
+        let uri: Uri = uri
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid uri {uri}: {e}"))?;
+        Ok(ClientBuilder {
+            uri,
+            runtime: Runtime::new(),
+            credentials: Credentials::default(),
+            timeout: None,
+        })
+    }
+
+    /// Authenticates the connection with `credentials`.
+    pub fn credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Fails each request not answered within `timeout` with a
+    /// [TransactionError::Timeout]. Requests wait forever by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Create a [Client] `build`ing a the current [ClientBuilder].
+    ///
+    /// Must be called inside an `actix` runtime, which runs the task that
+    /// owns the connection.
+    pub fn build(self) -> Client {
+        let mut connection = ConnectionHandle::spawn_with(self.uri.to_string(), self.credentials);
+        if let Some(timeout) = self.timeout {
+            connection = connection.with_timeout(timeout);
+        }
+        Client {
+            connection,
+            runtime: Arc::new(Mutex::new(self.runtime)),
+        }
+    }
+}
+
+/// Client.
+///
+/// A handle to a WebSocket `connection`. Clones share the connection, and
+/// can send transactions over it at the same time.
+#[derive(Clone)]
+pub struct Client {
+    connection: ConnectionHandle,
+    runtime: Arc<Mutex<Runtime>>,
+}
+
+impl Client {
+    /// Sends operations to a single repository as a `single repository transaction`.
+    ///
+    /// If the repository votes against committing, the error has the
+    /// `TransactionError` as its context.
+    pub async fn send_single(&self, operations: Vec<Operation>) -> anyhow::Result<Option<Table>> {
+        let tid = Uuid::new_v4();
+        let args = Arguments {
+            timestamp: self.now(),
+            operations,
+        };
+
+        let span = tracing::info_span!("transaction", %tid, kind = "single");
+        async {
+            let res = self.request(MessageWs::Single { tid, args }).await?;
+            let vote = res.into_vote()?;
+            log::info!("Result from {:?} single: {:?}", tid, vote);
+
+            let result = self.get_result(&tid).await;
+            match TransactionError::from_votes([&vote]) {
+                Some(error) => result.context(error),
+                None => result,
+            }
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Sends a whole multi-repository transaction to the `ws gateway` this
+    /// [Client] is connected to, which runs it with the `participants`.
+    ///
+    /// Unlike the other messages, it isn't sent again if the connection
+    /// drops: the gateway would run it as a new transaction. Whether it
+    /// committed is unknown then.
+    pub async fn send_transaction(
+        &self,
+        kind: TransactionKind,
+        participants: Vec<Participant>,
+    ) -> anyhow::Result<TransactionResults> {
+        let msg = MessageWs::Transaction { kind, participants };
+        let span = tracing::info_span!("gateway transaction", ?kind);
+        let response = self.connection.request(msg).instrument(span).await?;
+        let (results, attempts) = response.into_results()?;
+        Ok(TransactionResults { results, attempts })
+    }
+
+    fn now(&self) -> usize {
+        self.runtime
+            .lock()
+            .expect("the runtime is never left inconsistent")
+            .now()
+    }
+
+    /// The uri of the `RepositoryWs` this [Client] is connected to.
+    pub fn uri(&self) -> &str {
+        self.connection.uri()
+    }
+
+    /// Sends `msg` and waits for the response to it.
+    ///
+    /// If the connection drops the message is sent again over a new
+    /// connection: repositories prepare a `tid` and notify its participants
+    /// only once, and count the accept of each participant only once. A
+    /// request that timed out isn't sent again.
+    pub(crate) async fn request(&self, msg: MessageWs) -> anyhow::Result<ResponseBody> {
+        match self.connection.request(msg.clone()).await {
+            Ok(response) => Ok(response),
+            Err(e) if e.downcast_ref::<TransactionError>().is_some() => Err(e),
+            Err(e) => {
+                log::warn!("request to {} failed: {e}. Sending it again", self.uri());
+                self.connection.request(msg).await
+            }
+        }
+    }
+
+    /// Sends a `GetResult` message to a `repository` asking to the result of
+    /// transaction with the given `tid`.
+    async fn get_result(&self, tid: &Uuid) -> anyhow::Result<Option<Table>> {
+        let result = self.request(MessageWs::GetResult { tid: *tid }).await?;
+
+        log::info!("Result from get_result: {:?}", result);
+
+        result.into_result()
+    }
+}
+
+/// For multi-repository transactions.
+///
+/// Holds a [std::vec::Vec] with a list of [Client] s that will
+/// participate in a `multi repository transaction`, and the [RetryPolicy]
+/// for transactions that conflict.
+pub struct Clients {
+    pub participants: Vec<Client>,
+    pub retry_policy: RetryPolicy,
+}
+
+/// The results of a multi-repository transaction, one per participant.
+#[derive(Debug)]
+pub struct TransactionResults {
+    pub results: Vec<Option<Table>>,
+    /// How many times the transaction was sent until it committed.
+    pub attempts: u32,
+}
+
+impl Clients {
+    /// Sends the needed messages for all the participants of a `independent` transaction.
+    pub async fn send_indep(
+        &self,
+        operations: Vec<Vec<Operation>>,
+    ) -> anyhow::Result<TransactionResults> {
+        self.send_with_retry(operations, TransactionKind::Independent)
+            .await
+    }
+
+    /// Sends the needed messages for a `coordinated` transaction.
+    pub async fn send_coord(
+        &self,
+        operations: Vec<Vec<Operation>>,
+    ) -> anyhow::Result<TransactionResults> {
+        self.send_with_retry(operations, TransactionKind::Coordinated)
+            .await
+    }
+
+    /// Sends a multi-repository transaction, and sends it again (with a new
+    /// `tid`) while it conflicts, as allowed by the `retry_policy`.
+    async fn send_with_retry(
+        &self,
+        operations: Vec<Vec<Operation>>,
+        kind: TransactionKind,
+    ) -> anyhow::Result<TransactionResults> {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match self
+                .send_multi(Uuid::new_v4(), operations.clone(), kind)
+                .await
+            {
+                Ok(results) => return Ok(TransactionResults { results, attempts }),
+                Err(e) => match self.retry_policy.backoff(attempts, &e) {
+                    Some(backoff) => {
+                        log::info!("attempt {attempts} failed: {e}. Retrying in {backoff:?}");
+                        actix::clock::sleep(backoff).await;
+                    }
+                    None => return Err(e),
+                },
+            }
+        }
+    }
+
+    /// Sends the messages of each phase of a multi-repository transaction to
+    /// all the participants at once.
+    ///
+    /// If a participant fails to vote, the others are told about a
+    /// `CommitVote::Conflict` instead of their own vote, so the transaction is
+    /// aborted everywhere, and the error is returned. Errors sending the
+    /// accepts between participants are returned as well. If a participant
+    /// votes against committing, a `TransactionError` is returned.
+    pub(crate) async fn send_multi(
+        &self,
+        tid: Uuid,
+        operations: Vec<Vec<Operation>>,
+        kind: TransactionKind,
+    ) -> anyhow::Result<Vec<Option<Table>>> {
+        let span = tracing::info_span!("transaction", %tid, ?kind);
+        self.send_phases(tid, operations, kind)
+            .instrument(span)
+            .await
+    }
+
+    /// Whether the transaction `tid`, whose phases failed without telling
+    /// if it committed (e.g. a participant couldn't be reached), committed.
+    ///
+    /// Its participants are notified again, with a `Conflict` for the ones
+    /// that weren't notified yet, so the accepts that were lost are sent
+    /// again. Then it committed if a participant has its result, and aborted
+    /// if one failed it or never prepared it. Fails if no participant tells.
+    pub(crate) async fn committed(&self, tid: Uuid, kind: TransactionKind) -> anyhow::Result<bool> {
+        let participants: Vec<String> = self
+            .participants
+            .iter()
+            .map(|p| p.uri().to_string())
+            .collect();
+        let notifications = self.participants.iter().map(|participant| {
+            let (vote, participants) = (CommitVote::Conflict, participants.clone());
+            let msg = match kind {
+                TransactionKind::Independent => MessageWs::IndepParticipants {
+                    tid,
+                    vote,
+                    participants,
+                },
+                TransactionKind::Coordinated => MessageWs::CoordParticipants {
+                    tid,
+                    vote,
+                    participants,
+                },
+            };
+            participant.request(msg)
+        });
+        for (participant, notified) in self.participants.iter().zip(join_all(notifications).await) {
+            if let Err(e) = notified.and_then(ResponseBody::into_vote) {
+                log::info!(
+                    "notifying {} of {tid} again failed: {e:#}",
+                    participant.uri()
+                );
+            }
+        }
+
+        let results = self
+            .participants
+            .iter()
+            .map(|participant| participant.request(MessageWs::GetResult { tid }));
+        let mut error = None;
+        for result in join_all(results).await {
+            match result.map(ResponseBody::into_result) {
+                Ok(Ok(_)) => return Ok(true),
+                // Failed or unknown there, so it can't commit anywhere.
+                Ok(Err(_)) => return Ok(false),
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        let e = error.unwrap_or_else(|| anyhow::anyhow!("no participants"));
+        Err(e.context(format!("whether transaction {tid} committed is unknown")))
+    }
+
+    /// The phases of [Clients::send_multi], for the transaction `tid`.
+    async fn send_phases(
+        &self,
+        tid: Uuid,
+        operations: Vec<Vec<Operation>>,
+        kind: TransactionKind,
+    ) -> anyhow::Result<Vec<Option<Table>>> {
+        let participants_len = self.participants.len();
+        let participants_address: Vec<String> = self
+            .participants
+            .iter()
+            .map(|p| p.uri().to_string())
+            .collect();
+
+        let prepares =
+            self.participants
+                .iter()
+                .zip(operations)
+                .map(|(participant, operations)| async move {
+                    let args = Arguments {
+                        timestamp: participant.now(),
+                        operations,
+                    };
+                    let msg = match kind {
+                        TransactionKind::Independent => MessageWs::Indep {
+                            tid,
+                            args,
+                            participants_size: participants_len,
+                        },
+                        TransactionKind::Coordinated => MessageWs::Coord {
+                            tid,
+                            args,
+                            participants_size: participants_len,
+                        },
+                    };
+
+                    let result = participant.request(msg).await?;
+                    let vote = result.into_vote()?;
+                    log::info!("Result from {:?} {:?}: {:?}", tid, kind, vote);
+                    anyhow::Ok(vote)
+                });
+        let votes = join_all(prepares).await;
+
+        let mut error = None;
+        let mut prepared = vec![];
+        for (participant, vote) in self.participants.iter().zip(votes) {
+            match vote {
+                Ok(vote) => prepared.push((participant, vote)),
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        let outcome = TransactionError::from_votes(prepared.iter().map(|(_, vote)| vote));
+        if error.is_some() {
+            for (_, vote) in prepared.iter_mut() {
+                *vote = CommitVote::Conflict;
+            }
+        }
+
+        let notifications = prepared.into_iter().map(|(participant, vote)| {
+            let participants = participants_address.clone();
+            async move {
+                log::debug!("participant: {:?}", participant.uri());
+                let msg = match kind {
+                    TransactionKind::Independent => MessageWs::IndepParticipants {
+                        tid,
+                        vote,
+                        participants,
+                    },
+                    TransactionKind::Coordinated => MessageWs::CoordParticipants {
+                        tid,
+                        vote,
+                        participants,
+                    },
+                };
+
+                let res = participant.request(msg).await?;
+                let res = res.into_vote()?;
+                log::info!("Result from {:?} {:?} participants: {:?}", tid, kind, res);
+                anyhow::Ok(participant)
+            }
+        });
+        let notified = join_all(notifications).await;
+
+        // A participant that wasn't notified, or couldn't send its accept to
+        // the others, leaves some participant without a result.
+        let mut notified_participants = vec![];
+        for notification in notified {
+            match notification {
+                Ok(participant) => notified_participants.push(participant),
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(e) = error {
+            return Err(e);
+        }
+
+        let results = join_all(
+            notified_participants
+                .into_iter()
+                .map(|participant| async move {
+                    let result = participant.get_result(&tid).await;
+                    log::debug!("`get_result` from {:?}, {:?}: {:?}", kind, tid, result);
+                    result
+                }),
+        )
+        .await;
+
+        if let Some(e) = outcome {
+            return Err(e.into());
+        }
+        results.into_iter().collect()
+    }
+}
@@ -0,0 +1,253 @@
+//! Multiplexed connections to a `RepositoryWs`.
+//!
+//! A [`ConnectionHandle`] is a cheap to clone handle to a task that owns the
+//! connection. Many tasks can send requests through it at once: they are
+//! written to the socket right away, and each one gets the [`Response`] with
+//! the `id` of its [`Request`]. The connection is opened with the first
+//! request, and opened again with the next one after it drops. A handle
+//! with a timeout fails the requests not answered in time.
+//!
+//! Each request carries the trace context of the span it is sent from.
+use std::{collections::HashMap, time::Duration};
+
+use awc::{error::WsProtocolError, ws::Frame};
+use cereal_core::messages::TransactionError;
+use cereal_protocol::{Encoding, MessageWs, Request, Response, ResponseBody, SUBPROTOCOLS};
+use futures_util::{SinkExt as _, StreamExt as _};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+use tracing_opentelemetry::OpenTelemetrySpanExt as _;
+
+use crate::credentials::Credentials;
+
+/// A client connection to a `RepositoryWs`.
+type Connection = actix_codec::Framed<awc::BoxedSocket, awc::ws::Codec>;
+
+/// Opens a connection to the `RepositoryWs` at `uri`, asking for the
+/// MessagePack encoding first.
+async fn connect(uri: &str, credentials: &Credentials) -> anyhow::Result<(Connection, Encoding)> {
+    let mut connector = awc::Connector::new();
+    if let Some(tls) = &credentials.tls {
+        connector = connector.rustls_0_23(tls.clone());
+    }
+    let mut request = awc::Client::builder()
+        .connector(connector)
+        .finish()
+        .ws(uri)
+        .protocols(SUBPROTOCOLS);
+    if let Some(token) = &credentials.token {
+        request = request.bearer_auth(token);
+    }
+    let (response, connection) = request
+        .connect()
+        .await
+        .map_err(|e| anyhow::anyhow!("couldn't connect to {uri}: {e}"))?;
+    Ok((connection, Encoding::accepted(response.headers())))
+}
+
+type Reply = oneshot::Sender<anyhow::Result<ResponseBody>>;
+
+/// What the task sends: a message, with its trace context, and when it
+/// stops waiting for the response.
+type Outgoing = (MessageWs, HashMap<String, String>, Reply, Option<Instant>);
+
+/// A handle to the connection to the `RepositoryWs` at `uri`.
+#[derive(Clone, Debug)]
+pub struct ConnectionHandle {
+    uri: String,
+    requests: mpsc::UnboundedSender<Outgoing>,
+    /// How long a request waits for its response, if not forever.
+    timeout: Option<Duration>,
+}
+
+impl ConnectionHandle {
+    /// Spawns the task that owns the connection to `uri`, on the current
+    /// `actix` runtime. The task ends when every handle is dropped.
+    pub fn spawn(uri: String) -> Self {
+        Self::spawn_with(uri, Credentials::default())
+    }
+
+    /// Like [ConnectionHandle::spawn], authenticating with `credentials`.
+    pub fn spawn_with(uri: String, credentials: Credentials) -> Self {
+        let (requests, receiver) = mpsc::unbounded_channel();
+        actix::spawn(ConnectionTask::new(uri.clone(), credentials).run(receiver));
+        ConnectionHandle {
+            uri,
+            requests,
+            timeout: None,
+        }
+    }
+
+    /// Fails the requests sent through this handle with a
+    /// [TransactionError::Timeout] when they get no response within
+    /// `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+
+    /// Sends `message` and waits for the response to it.
+    pub async fn request(&self, message: MessageWs) -> anyhow::Result<ResponseBody> {
+        let mut trace_context = HashMap::new();
+        opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&tracing::Span::current().context(), &mut trace_context)
+        });
+        let (reply, response) = oneshot::channel();
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        self.requests
+            .send((message, trace_context, reply, deadline))
+            .map_err(|_| anyhow::anyhow!("connection to {} is gone", self.uri))?;
+        response
+            .await
+            .map_err(|_| anyhow::anyhow!("connection to {} is gone", self.uri))?
+    }
+}
+
+/// The connection and the requests waiting for a response, until their
+/// deadline if they have one.
+struct ConnectionTask {
+    uri: String,
+    credentials: Credentials,
+    connection: Option<(Connection, Encoding)>,
+    pending: HashMap<u64, (Reply, Option<Instant>)>,
+    next_id: u64,
+}
+
+impl ConnectionTask {
+    fn new(uri: String, credentials: Credentials) -> Self {
+        ConnectionTask {
+            uri,
+            credentials,
+            connection: None,
+            pending: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Sends the `requests` and hands back the responses, until every
+    /// [`ConnectionHandle`] is gone.
+    async fn run(mut self, mut requests: mpsc::UnboundedReceiver<Outgoing>) {
+        loop {
+            let Some((connection, _)) = self.connection.as_mut() else {
+                // Connect only when there is something to send.
+                let Some(request) = requests.recv().await else {
+                    return;
+                };
+                self.send(request).await;
+                continue;
+            };
+            let deadline = self
+                .pending
+                .values()
+                .filter_map(|(_, deadline)| *deadline)
+                .min();
+            tokio::select! {
+                request = requests.recv() => match request {
+                    Some(request) => self.send(request).await,
+                    None => return,
+                },
+                frame = connection.next() => self.receive(frame),
+                () = sleep_until(deadline) => self.expire(),
+            }
+        }
+    }
+
+    async fn send(&mut self, (message, trace_context, reply, deadline): Outgoing) {
+        if self.connection.is_none() {
+            match connect(&self.uri, &self.credentials).await {
+                Ok(connection) => self.connection = Some(connection),
+                Err(e) => {
+                    let _ = reply.send(Err(e));
+                    return;
+                }
+            }
+        }
+        let Some((connection, encoding)) = self.connection.as_mut() else {
+            unreachable!("connected above");
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = Request {
+            id,
+            message,
+            trace_context,
+        };
+        match connection.send(encoding.encode(&request)).await {
+            Ok(()) => {
+                self.pending.insert(id, (reply, deadline));
+            }
+            Err(e) => {
+                let error = anyhow::anyhow!("couldn't send to {}: {e}", self.uri);
+                let _ = reply.send(Err(error));
+                self.disconnect("send failed");
+            }
+        }
+    }
+
+    fn receive(&mut self, frame: Option<Result<Frame, WsProtocolError>>) {
+        match frame {
+            Some(Ok(frame @ (Frame::Text(_) | Frame::Binary(_)))) => {
+                match cereal_protocol::decode_frame::<Response>(&frame) {
+                    Ok(Response { id: Some(id), body }) if self.pending.contains_key(&id) => {
+                        if let Some((reply, _)) = self.pending.remove(&id) {
+                            let _ = reply.send(Ok(body));
+                        }
+                    }
+                    Ok(response) => {
+                        log::error!("unexpected response from {}: {response:?}", self.uri)
+                    }
+                    Err(e) => log::error!("unexpected message from {}: {e}", self.uri),
+                }
+            }
+            Some(Ok(Frame::Close(reason))) => self.disconnect(&format!("closed: {reason:?}")),
+            Some(Ok(_)) => {}
+            Some(Err(e)) => self.disconnect(&e.to_string()),
+            None => self.disconnect("closed"),
+        }
+    }
+
+    /// Drops the connection, failing every request still waiting for a
+    /// response. The next request opens a new one.
+    fn disconnect(&mut self, reason: &str) {
+        log::warn!("connection to {} lost: {reason}", self.uri);
+        self.connection = None;
+        for (_, (reply, _)) in self.pending.drain() {
+            let error = anyhow::anyhow!("connection to {} lost: {reason}", self.uri);
+            let _ = reply.send(Err(error));
+        }
+    }
+
+    /// Fails the requests past their deadline. A response arriving later is
+    /// dropped.
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<u64> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, deadline))| deadline.is_some_and(|deadline| deadline <= now))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            if let Some((reply, _)) = self.pending.remove(&id) {
+                let error = anyhow::Error::new(TransactionError::Timeout)
+                    .context(format!("no response from {}", self.uri));
+                let _ = reply.send(Err(error));
+            }
+        }
+    }
+}
+
+/// Waits until `deadline`, or forever if there is none.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
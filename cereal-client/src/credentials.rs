@@ -0,0 +1,72 @@
+//! Authenticating connections to a `RepositoryWs`.
+//!
+//! A repository configured with tokens only accepts connections presenting
+//! one of them, as a bearer token. The token tells whether the connection is
+//! from a client or from a peer repository. Repositories serving `https`
+//! uris are verified against a CA, and their peers can authenticate with a
+//! certificate signed by it instead of a token.
+use std::{fmt, path::Path, sync::Arc};
+
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ClientConfig, RootCertStore,
+};
+
+/// How a [`crate::ConnectionHandle`] authenticates to a `RepositoryWs`.
+#[derive(Clone, Default)]
+pub struct Credentials {
+    /// Sent as a bearer token at the handshake.
+    pub token: Option<String>,
+    /// Used to connect to `https` uris.
+    pub tls: Option<Arc<ClientConfig>>,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("token", &self.token.as_ref().map(|_| "..."))
+            .field("tls", &self.tls.is_some())
+            .finish()
+    }
+}
+
+impl Credentials {
+    /// Trusts the repositories with a certificate signed by the CA at
+    /// `ca`, presenting the certificate at `identity` (a certificate and key
+    /// pair) if there is one.
+    pub fn with_ca(mut self, ca: &Path, identity: Option<(&Path, &Path)>) -> anyhow::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(ca)? {
+            roots.add(cert)?;
+        }
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        let config = match identity {
+            Some((cert, key)) => {
+                builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        self.tls = Some(Arc::new(config));
+        Ok(self)
+    }
+
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+}
+
+/// The PEM certificates in the file at `path`.
+pub fn load_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow::anyhow!("couldn't read certificates {}: {e}", path.display()))?;
+    anyhow::ensure!(!certs.is_empty(), "no certificates in {}", path.display());
+    Ok(certs)
+}
+
+/// The PEM private key in the file at `path`.
+pub fn load_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|e| anyhow::anyhow!("couldn't read private key {}: {e}", path.display()))
+}
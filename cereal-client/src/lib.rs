@@ -0,0 +1,28 @@
+//! A client library for `Cereal` repositories served by `ws repository`.
+//!
+//! - [`Client`]: a handle to the connection to a repository, for `single
+//!   repository` transactions. Clones share the connection;
+//! - [`Clients`]: for `multi repository` transactions;
+//! - [`ShardedClient`]: for transactions on tables sharded across
+//!   repositories, following a [`RoutingTable`];
+//! - [`Migration`]: to move key ranges of these tables between repositories;
+//! - [`ConnectionHandle`]: the multiplexed connection under a [`Client`],
+//!   authenticated with [`Credentials`];
+//! - the `value!`, `read!`, `del!`, `op!`, `create!`, `update!`, `add!` and
+//!   `sub!` macros, to write [`cereal_core::operations::Operation`]s.
+pub use cereal_core;
+
+mod client;
+mod connection;
+mod credentials;
+mod macros;
+mod migration;
+mod sharding;
+
+pub use client::{Client, ClientBuilder, Clients, TransactionResults};
+pub use connection::ConnectionHandle;
+pub use credentials::{load_certs, load_key, Credentials};
+pub use migration::{Migration, MigrationReport};
+pub use sharding::{
+    KeyRange, RoutingTable, ShardedClient, ShardedOperation, ShardedResults, Sharding,
+};
@@ -0,0 +1,61 @@
+//! Macros to write [`cereal_core::operations::Operation`]s, e.g.
+//! `update!(1, add!(read!(1), value!(Table(1, 1))))`.
+#[macro_export]
+macro_rules! value {
+    ($val:expr) => {
+        $crate::cereal_core::operations::Expr::Value($val)
+    };
+}
+
+#[macro_export]
+macro_rules! read {
+    ($key:expr) => {
+        $crate::cereal_core::operations::Expr::Read($key)
+    };
+}
+
+#[macro_export]
+macro_rules! del {
+    ($key:expr) => {
+        $crate::cereal_core::operations::Expr::Delete($key)
+    };
+}
+
+#[macro_export]
+macro_rules! op {
+    ($exp:expr) => {
+        $crate::cereal_core::operations::Operation::Expr($exp)
+    };
+}
+
+#[macro_export]
+macro_rules! create {
+    ($key:expr, $val:expr) => {
+        $crate::cereal_core::operations::Operation::Statement(
+            $crate::cereal_core::operations::Statement::Create($key, Box::new($val)),
+        )
+    };
+}
+
+#[macro_export]
+macro_rules! update {
+    ($key:expr, $val:expr) => {
+        $crate::cereal_core::operations::Operation::Statement(
+            $crate::cereal_core::operations::Statement::Update($key, Box::new($val)),
+        )
+    };
+}
+
+#[macro_export]
+macro_rules! add {
+    ($self:expr, $rhs:expr) => {
+        $crate::cereal_core::operations::Expr::Add(Box::new($self), Box::new($rhs))
+    };
+}
+
+#[macro_export]
+macro_rules! sub {
+    ($self:expr, $rhs:expr) => {
+        $crate::cereal_core::operations::Expr::Sub(Box::new($self), Box::new($rhs))
+    };
+}
@@ -0,0 +1,316 @@
+//! Moving key ranges between repositories, while transactions go on.
+//!
+//! A [`Migration`] moves a key range of a table sharded by key ranges (see
+//! [`RoutingTable`]) to another repository:
+//! 1. the keys are copied to the new repository, which doesn't serve them
+//!    yet;
+//! 2. a `coordinated` transaction releases them at the old repository and
+//!    acquires them at the new one, so every transaction on them runs before
+//!    the move at the old repository, or after it at the new one;
+//! 3. what changed since the copy is copied again (nothing changes the keys
+//!    anymore), and the copy is checked against the old repository before
+//!    the routing table switches to the new one, which then serves the keys.
+//!
+//! If the copy can't be checked or served, the move is rolled back: the old
+//! repository acquires the keys again and serves them, and the new one
+//! drops them. If the move itself fails, whether it committed is found out
+//! from both repositories: the new one drops the copy if it didn't, and the
+//! migration goes on if it did.
+//!
+//! Transactions on the range fail while it is moved, and with the old
+//! routing table once it moved.
+use std::{collections::BTreeMap, ops::Range, path::PathBuf};
+
+use cereal_core::{
+    application::TransactionKind,
+    messages::TransactionError,
+    operations::{Operation, Statement, Table},
+    retry::RetryPolicy,
+};
+use cereal_protocol::MessageWs;
+use uuid::Uuid;
+
+use crate::{
+    client::{Client, ClientBuilder, Clients},
+    credentials::Credentials,
+    sharding::RoutingTable,
+};
+
+impl Client {
+    /// Every key of `range`, with its value.
+    pub async fn export_range(&self, range: Range<usize>) -> anyhow::Result<Vec<(usize, Table)>> {
+        self.request(MessageWs::ExportRange { range })
+            .await?
+            .into_entries()
+    }
+
+    /// Writes keys of `range` being moved to this repository, `None`
+    /// deleting a key.
+    pub async fn import_range(
+        &self,
+        range: Range<usize>,
+        entries: Vec<(usize, Option<Table>)>,
+    ) -> anyhow::Result<()> {
+        self.request(MessageWs::ImportRange { range, entries })
+            .await?
+            .into_done()
+    }
+
+    /// Starts serving `range`, moved to this repository.
+    pub async fn serve_range(&self, range: Range<usize>) -> anyhow::Result<()> {
+        self.request(MessageWs::ServeRange { range })
+            .await?
+            .into_done()
+    }
+
+    /// Drops the keys of `range`, moved out of this repository or imported
+    /// by a move that was abandoned.
+    pub async fn drop_range(&self, range: Range<usize>) -> anyhow::Result<()> {
+        self.request(MessageWs::DropRange { range })
+            .await?
+            .into_done()
+    }
+}
+
+/// Moves the keys `range` of `table` to the repository at `to`.
+#[derive(Debug, Clone)]
+pub struct Migration {
+    pub table: String,
+    pub range: Range<usize>,
+    pub to: String,
+    /// For the transaction moving the keys, which conflicts with the ones
+    /// holding them.
+    pub retry_policy: RetryPolicy,
+    /// Moving keys takes a peer's credentials, if the repositories
+    /// authenticate connections.
+    pub credentials: Credentials,
+    /// The routing table file, replaced by the new routing table when the
+    /// keys are served by the new repository.
+    pub routing_path: Option<PathBuf>,
+}
+
+/// How a transaction switching the owner of the keys ended.
+enum Switch {
+    /// It committed, after this many attempts.
+    Committed(u32),
+    /// It aborted everywhere, with this error.
+    Aborted(anyhow::Error),
+}
+
+/// What a [Migration] did.
+#[derive(Debug)]
+pub struct MigrationReport {
+    pub from: String,
+    pub to: String,
+    /// Keys copied before moving them.
+    pub copied: usize,
+    /// Keys changed (or deleted) between the first copy and the move.
+    pub changed: usize,
+    /// Keys checked to be the same at both repositories after the move.
+    pub verified: usize,
+    /// The routing table with the keys moved.
+    pub routing: RoutingTable,
+}
+
+impl Migration {
+    pub fn new(table: impl Into<String>, range: Range<usize>, to: impl Into<String>) -> Self {
+        Migration {
+            table: table.into(),
+            range,
+            to: to.into(),
+            retry_policy: RetryPolicy::default(),
+            credentials: Credentials::default(),
+            routing_path: None,
+        }
+    }
+
+    /// Moves the keys, as routed by `routing`.
+    ///
+    /// Must be called inside an `actix` runtime, like [ClientBuilder::build].
+    pub async fn run(&self, routing: &RoutingTable) -> anyhow::Result<MigrationReport> {
+        let range = self.range.clone();
+        let from = routing.owner(&self.table, &range)?.to_string();
+        anyhow::ensure!(from != self.to, "keys {range:?} are already at {from}");
+        let new_routing = routing.with_range_moved(&self.table, &range, &self.to)?;
+
+        let source = ClientBuilder::from_uri(&from)?
+            .credentials(self.credentials.clone())
+            .build();
+        let destination = ClientBuilder::from_uri(&self.to)?
+            .credentials(self.credentials.clone())
+            .build();
+
+        let copied = source.export_range(range.clone()).await?;
+        log::info!("copying {} keys of {range:?} to {}", copied.len(), self.to);
+        let entries = copied.iter().map(|(k, v)| (*k, Some(v.clone()))).collect();
+        let moved = match destination.import_range(range.clone(), entries).await {
+            Ok(()) => {
+                let switched = self.switch(
+                    &source,
+                    &destination,
+                    Statement::Release,
+                    Statement::Acquire,
+                );
+                switched.await.map_err(|e| {
+                    e.context(format!("keys {range:?} may be at {from} or at {}", self.to))
+                })?
+            }
+            Err(e) => Switch::Aborted(e),
+        };
+        let attempts = match moved {
+            Switch::Committed(attempts) => attempts,
+            Switch::Aborted(e) => {
+                return match destination.drop_range(range.clone()).await {
+                    Ok(()) => Err(e.context(format!("keys {range:?} are still at {from}"))),
+                    Err(dropped) => Err(e.context(format!(
+                        "keys {range:?} are still at {from}, dropping their copy at {} \
+                         failed: {dropped:#}",
+                        self.to
+                    ))),
+                };
+            }
+        };
+        log::info!(
+            "moved {range:?} from {from} to {} after {attempts} attempt(s)",
+            self.to
+        );
+
+        let mut switched = false;
+        let cut_over = async {
+            let entries = source.export_range(range.clone()).await?;
+            let changes = changes_since(&copied, &entries);
+            let changed = changes.len();
+            destination.import_range(range.clone(), changes).await?;
+
+            let imported = destination.export_range(range.clone()).await?;
+            anyhow::ensure!(
+                imported == entries,
+                "the keys of {range:?} at {} differ from the ones at {from}",
+                self.to
+            );
+            if let Some(path) = &self.routing_path {
+                new_routing.save(path)?;
+                switched = true;
+            }
+            destination.serve_range(range.clone()).await?;
+            anyhow::Ok((changed, imported.len()))
+        };
+        let (changed, verified) = match cut_over.await {
+            Ok(cut_over) => cut_over,
+            Err(e) => {
+                log::warn!(
+                    "moving {range:?} to {} failed: {e:#}. Rolling it back",
+                    self.to
+                );
+                let routing = switched.then_some(routing);
+                return match self.roll_back(routing, &source, &destination).await {
+                    Ok(()) => Err(e.context(format!("keys {range:?} are back at {from}"))),
+                    Err(rollback) => Err(e.context(format!(
+                        "keys {range:?} are served by neither {from} nor {}, \
+                         rolling back failed: {rollback:#}",
+                        self.to
+                    ))),
+                };
+            }
+        };
+        source.drop_range(range).await?;
+
+        Ok(MigrationReport {
+            from,
+            to: self.to.clone(),
+            copied: copied.len(),
+            changed,
+            verified,
+            routing: new_routing,
+        })
+    }
+
+    /// Moves the keys back to `source` after they were released there and
+    /// acquired at `destination`, restoring `routing` if the routing table
+    /// was already switched.
+    async fn roll_back(
+        &self,
+        routing: Option<&RoutingTable>,
+        source: &Client,
+        destination: &Client,
+    ) -> anyhow::Result<()> {
+        let range = self.range.clone();
+        let switched = self.switch(source, destination, Statement::Acquire, Statement::Release);
+        if let Switch::Aborted(e) = switched.await? {
+            return Err(e);
+        }
+        source.serve_range(range.clone()).await?;
+        if let (Some(path), Some(routing)) = (&self.routing_path, routing) {
+            routing.save(path)?;
+        }
+        destination.drop_range(range).await
+    }
+}
+
+impl Migration {
+    /// Runs a `coordinated` transaction with `at_source` of the keys at
+    /// `source` and `at_destination` at `destination`, trying it again while
+    /// it conflicts. Fails if whether it committed can't be found out.
+    async fn switch(
+        &self,
+        source: &Client,
+        destination: &Client,
+        at_source: fn(Range<usize>) -> Statement,
+        at_destination: fn(Range<usize>) -> Statement,
+    ) -> anyhow::Result<Switch> {
+        let clients = Clients {
+            participants: vec![source.clone(), destination.clone()],
+            retry_policy: self.retry_policy.clone(),
+        };
+        let operations = vec![
+            vec![Operation::Statement(at_source(self.range.clone()))],
+            vec![Operation::Statement(at_destination(self.range.clone()))],
+        ];
+        let kind = TransactionKind::Coordinated;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let tid = Uuid::new_v4();
+            let Err(e) = clients.send_multi(tid, operations.clone(), kind).await else {
+                return Ok(Switch::Committed(attempts));
+            };
+            let voted = matches!(
+                e.downcast_ref::<TransactionError>(),
+                Some(TransactionError::Conflict | TransactionError::Abort)
+            );
+            if !voted {
+                log::warn!("switching the owner of {:?} failed: {e:#}", self.range);
+                let committed = clients.committed(tid, kind).await;
+                return match committed.map_err(|unknown| e.context(format!("{unknown:#}")))? {
+                    true => Ok(Switch::Committed(attempts)),
+                    false => Ok(Switch::Aborted(anyhow::anyhow!(
+                        "transaction {tid} switching the owner of {:?} aborted",
+                        self.range
+                    ))),
+                };
+            }
+            match self.retry_policy.backoff(attempts, &e) {
+                Some(backoff) => actix::clock::sleep(backoff).await,
+                None => return Ok(Switch::Aborted(e)),
+            }
+        }
+    }
+}
+
+/// The writes turning `before` into `after`.
+fn changes_since(
+    before: &[(usize, Table)],
+    after: &[(usize, Table)],
+) -> Vec<(usize, Option<Table>)> {
+    let mut changes: BTreeMap<usize, Option<Table>> =
+        before.iter().map(|(key, _)| (*key, None)).collect();
+    for (key, value) in after {
+        changes.insert(*key, Some(value.clone()));
+    }
+    for (key, value) in before {
+        if changes.get(key) == Some(&Some(value.clone())) {
+            changes.remove(key);
+        }
+    }
+    changes.into_iter().collect()
+}
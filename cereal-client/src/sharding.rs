@@ -0,0 +1,681 @@
+//! Sharding tables across repositories.
+//!
+//! A [`RoutingTable`] maps the keys of each table to a repository, either by
+//! key ranges or by a hash of the key, and is loaded from a TOML file:
+//!
+//! ```toml
+//! [tables.customer]
+//! ranges = [
+//!     { start = 0, end = 100, repository = "http://127.0.0.1:8080/ws/" },
+//!     { start = 100, end = 200, repository = "http://127.0.0.1:8081/ws/" },
+//! ]
+//!
+//! [tables.order]
+//! hash = ["http://127.0.0.1:8082/ws/", "http://127.0.0.1:8083/ws/"]
+//! ```
+//!
+//! Keys are sent to the repositories as they are, so tables sharing a
+//! repository must keep to different keys there: key ranges of two tables
+//! overlapping at a repository, or a table sharded by hash sharing one, are
+//! refused.
+//!
+//! A [`ShardedClient`] runs transactions on the keys of the tables, as a
+//! `single repository` transaction when they touch one repository, or as a
+//! `multi repository` one otherwise.
+use std::{
+    collections::HashMap,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+};
+
+use cereal_core::{
+    application::TransactionKind,
+    messages::TransactionError,
+    operations::{Operation, Table},
+    retry::RetryPolicy,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::{Client, ClientBuilder, Clients},
+    credentials::Credentials,
+};
+
+/// A range of keys, from `start` up to (but not including) `end`, stored
+/// by `repository`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyRange {
+    pub start: usize,
+    pub end: usize,
+    pub repository: String,
+}
+
+/// Where the keys of a table are.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sharding {
+    /// Each key is in the repository of the range it falls in.
+    Ranges(Vec<KeyRange>),
+    /// Keys are spread over the repositories by their hash.
+    Hash(Vec<String>),
+}
+
+impl Sharding {
+    fn validate(&mut self) -> anyhow::Result<()> {
+        match self {
+            Sharding::Ranges(ranges) => {
+                anyhow::ensure!(!ranges.is_empty(), "no key ranges");
+                ranges.sort_by_key(|range| range.start);
+                for range in ranges.iter() {
+                    anyhow::ensure!(
+                        range.start < range.end,
+                        "empty key range {}..{}",
+                        range.start,
+                        range.end
+                    );
+                }
+                for pair in ranges.windows(2) {
+                    anyhow::ensure!(
+                        pair[0].end <= pair[1].start,
+                        "key ranges {}..{} and {}..{} overlap",
+                        pair[0].start,
+                        pair[0].end,
+                        pair[1].start,
+                        pair[1].end
+                    );
+                }
+            }
+            Sharding::Hash(repositories) => {
+                anyhow::ensure!(!repositories.is_empty(), "no repositories to hash keys to");
+            }
+        }
+        Ok(())
+    }
+
+    fn route(&self, key: usize) -> Option<&str> {
+        match self {
+            Sharding::Ranges(ranges) => ranges
+                .iter()
+                .find(|range| (range.start..range.end).contains(&key))
+                .map(|range| range.repository.as_str()),
+            Sharding::Hash(repositories) => {
+                let shard = hash(key) % repositories.len() as u64;
+                Some(repositories[shard as usize].as_str())
+            }
+        }
+    }
+
+    /// The keys of the table `repository` may store.
+    fn keys_at(&self, repository: &str) -> Vec<Range<usize>> {
+        match self {
+            Sharding::Ranges(ranges) => ranges
+                .iter()
+                .filter(|range| range.repository == repository)
+                .map(|range| range.start..range.end)
+                .collect(),
+            Sharding::Hash(repositories) if repositories.iter().any(|r| r == repository) => {
+                std::iter::once(0..usize::MAX).collect()
+            }
+            Sharding::Hash(_) => vec![],
+        }
+    }
+
+    fn repositories(&self) -> Vec<&str> {
+        match self {
+            Sharding::Ranges(ranges) => ranges.iter().map(|r| r.repository.as_str()).collect(),
+            Sharding::Hash(repositories) => repositories.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+/// FNV-1a, so keys hash to the same repository in every process and
+/// version.
+fn hash(key: usize) -> u64 {
+    (key as u64)
+        .to_le_bytes()
+        .iter()
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+        })
+}
+
+/// Maps the keys of each table to the repository storing them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoutingTable {
+    pub tables: HashMap<String, Sharding>,
+}
+
+impl RoutingTable {
+    /// Parses a [RoutingTable] from TOML, checking the key ranges of each
+    /// table don't overlap, nor the keys of two tables at a repository.
+    pub fn from_toml(toml: &str) -> anyhow::Result<Self> {
+        let mut routing: RoutingTable = toml::from_str(toml)?;
+        for (table, sharding) in routing.tables.iter_mut() {
+            sharding
+                .validate()
+                .map_err(|e| e.context(format!("table {table}")))?;
+        }
+        routing.check_shared_repositories()?;
+        Ok(routing)
+    }
+
+    /// Checks no two tables may store the same key at a repository, which
+    /// would be the same key of both.
+    fn check_shared_repositories(&self) -> anyhow::Result<()> {
+        let mut tables: Vec<_> = self.tables.iter().collect();
+        tables.sort_by_key(|(table, _)| *table);
+        for repository in self.repositories() {
+            for (i, (table, sharding)) in tables.iter().enumerate() {
+                for (other, other_sharding) in &tables[i + 1..] {
+                    let keys = sharding.keys_at(repository);
+                    let other_keys = other_sharding.keys_at(repository);
+                    let overlap = keys.iter().any(|a| {
+                        other_keys
+                            .iter()
+                            .any(|b| a.start < b.end && b.start < a.end)
+                    });
+                    anyhow::ensure!(
+                        !overlap,
+                        "tables {table} and {other} share keys at repository {repository}"
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads a [RoutingTable] from the TOML file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let toml = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("couldn't read {}: {e}", path.display()))?;
+        Self::from_toml(&toml).map_err(|e| e.context(format!("routing table {}", path.display())))
+    }
+
+    /// Writes the [RoutingTable] to the TOML file at `path`, replacing it at
+    /// once, so it is never read half written.
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+        let toml = toml::to_string(self)?;
+        let write = || {
+            std::fs::write(&partial, toml)?;
+            std::fs::rename(&partial, path)
+        };
+        write().map_err(|e| anyhow::anyhow!("couldn't write {}: {e}", path.display()))
+    }
+
+    /// Every repository of the [RoutingTable].
+    pub fn repositories(&self) -> Vec<&str> {
+        let mut repositories: Vec<&str> = self
+            .tables
+            .values()
+            .flat_map(Sharding::repositories)
+            .collect();
+        repositories.sort_unstable();
+        repositories.dedup();
+        repositories
+    }
+
+    /// The repository storing every key of `range` of `table`, which must be
+    /// sharded by key ranges.
+    pub fn owner(&self, table: &str, range: &Range<usize>) -> anyhow::Result<&str> {
+        self.key_range(table, range)
+            .map(|key_range| key_range.repository.as_str())
+    }
+
+    fn key_range(&self, table: &str, range: &Range<usize>) -> anyhow::Result<&KeyRange> {
+        let Some(Sharding::Ranges(ranges)) = self.tables.get(table) else {
+            anyhow::bail!("table {table} isn't sharded by key ranges");
+        };
+        ranges
+            .iter()
+            .find(|key_range| key_range.start <= range.start && range.end <= key_range.end)
+            .ok_or_else(|| anyhow::anyhow!("keys {range:?} of table {table} are not in one range"))
+    }
+
+    /// The [RoutingTable] with `range` of `table` moved to the repository at
+    /// `to`, splitting the key range it was part of. Fails if another table
+    /// stores some of those keys at `to`.
+    pub fn with_range_moved(
+        &self,
+        table: &str,
+        range: &Range<usize>,
+        to: &str,
+    ) -> anyhow::Result<RoutingTable> {
+        let from = self.key_range(table, range)?.clone();
+        let mut routing = self.clone();
+        let Some(Sharding::Ranges(ranges)) = routing.tables.get_mut(table) else {
+            unreachable!("checked by key_range");
+        };
+        ranges.retain(|key_range| *key_range != from);
+        let split = [
+            (from.start, range.start, from.repository.as_str()),
+            (range.start, range.end, to),
+            (range.end, from.end, from.repository.as_str()),
+        ];
+        for (start, end, repository) in split {
+            if start < end {
+                ranges.push(KeyRange {
+                    start,
+                    end,
+                    repository: repository.to_string(),
+                });
+            }
+        }
+        ranges.sort_by_key(|key_range| key_range.start);
+        routing.check_shared_repositories()?;
+        Ok(routing)
+    }
+
+    /// The repository storing `key` of `table`.
+    pub fn route(&self, table: &str, key: usize) -> anyhow::Result<&str> {
+        let sharding = self
+            .tables
+            .get(table)
+            .ok_or_else(|| anyhow::anyhow!("unknown table {table}"))?;
+        sharding
+            .route(key)
+            .ok_or_else(|| anyhow::anyhow!("no repository for key {key} of table {table}"))
+    }
+
+    /// The repository storing every key `operation` touches in `table`.
+    fn route_operation(&self, table: &str, operation: &Operation) -> anyhow::Result<&str> {
+        let mut repositories = operation
+            .keys()
+            .into_iter()
+            .map(|key| self.route(table, key))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        repositories.sort_unstable();
+        repositories.dedup();
+        match repositories[..] {
+            [repository] => Ok(repository),
+            [] => anyhow::bail!("{operation:?} touches no key of table {table}"),
+            _ => anyhow::bail!("{operation:?} touches keys of table {table} in many repositories"),
+        }
+    }
+
+    /// Splits `operations` by the repository storing their keys, in the
+    /// order of their first operation.
+    fn shards(
+        &self,
+        operations: Vec<ShardedOperation>,
+    ) -> anyhow::Result<Vec<(String, Vec<Operation>)>> {
+        let mut shards: Vec<(String, Vec<Operation>)> = vec![];
+        for ShardedOperation { table, operation } in operations {
+            let repository = self.route_operation(&table, &operation)?;
+            match shards.iter_mut().find(|(uri, _)| uri == repository) {
+                Some((_, operations)) => operations.push(operation),
+                None => shards.push((repository.to_string(), vec![operation])),
+            }
+        }
+        anyhow::ensure!(!shards.is_empty(), "no operations to send");
+        Ok(shards)
+    }
+}
+
+/// How the `shards` of a transaction run: `None` for a single repository
+/// transaction, `indep` if every operation only reads, and `coord`
+/// otherwise.
+fn kind_of(shards: &[(String, Vec<Operation>)]) -> Option<TransactionKind> {
+    if shards.len() == 1 {
+        return None;
+    }
+    let read_only = shards
+        .iter()
+        .all(|(_, operations)| operations.iter().all(Operation::is_read_only));
+    if read_only {
+        Some(TransactionKind::Independent)
+    } else {
+        Some(TransactionKind::Coordinated)
+    }
+}
+
+/// An [Operation] on the keys of `table`.
+#[derive(Debug, Clone)]
+pub struct ShardedOperation {
+    pub table: String,
+    pub operation: Operation,
+}
+
+impl ShardedOperation {
+    pub fn new(table: impl Into<String>, operation: Operation) -> Self {
+        ShardedOperation {
+            table: table.into(),
+            operation,
+        }
+    }
+}
+
+/// The results of a transaction run by a [ShardedClient]: the result of
+/// each repository it touched, in the order of their first operation.
+#[derive(Debug)]
+pub struct ShardedResults {
+    pub results: Vec<(String, Option<Table>)>,
+    /// How many times the transaction was sent until it committed.
+    pub attempts: u32,
+}
+
+/// Runs transactions on sharded tables, with a [Client] per repository of
+/// the [RoutingTable].
+pub struct ShardedClient {
+    routing: RwLock<RoutingTable>,
+    /// Where the routing table was loaded from, to load it again when it
+    /// changes.
+    path: Option<PathBuf>,
+    clients: Mutex<HashMap<String, Client>>,
+    credentials: Credentials,
+    pub retry_policy: RetryPolicy,
+}
+
+impl ShardedClient {
+    /// Creates a [ShardedClient] for the repositories of `routing`.
+    ///
+    /// Must be called inside an `actix` runtime, like [ClientBuilder::build].
+    pub fn new(routing: RoutingTable) -> anyhow::Result<Self> {
+        let client = ShardedClient {
+            routing: RwLock::new(routing),
+            path: None,
+            clients: Mutex::new(HashMap::new()),
+            credentials: Credentials::default(),
+            retry_policy: RetryPolicy::default(),
+        };
+        for uri in client.routing().repositories() {
+            client.client(uri)?;
+        }
+        Ok(client)
+    }
+
+    /// Creates a [ShardedClient] with the routing table at `path`, loaded
+    /// again when a transaction fails because its keys were moved.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut client = Self::new(RoutingTable::load(path)?)?;
+        client.path = Some(path.to_path_buf());
+        Ok(client)
+    }
+
+    /// Authenticates the connections to the repositories with
+    /// `credentials`.
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = credentials;
+        self.clients = Mutex::new(HashMap::new());
+        self
+    }
+
+    pub fn routing(&self) -> RoutingTable {
+        self.routing
+            .read()
+            .expect("the routing table is never left inconsistent")
+            .clone()
+    }
+
+    pub fn set_routing(&self, routing: RoutingTable) {
+        *self
+            .routing
+            .write()
+            .expect("the routing table is never left inconsistent") = routing;
+    }
+
+    /// Loads the routing table again, telling whether it changed.
+    pub fn reload(&self) -> anyhow::Result<bool> {
+        let Some(path) = &self.path else {
+            return Ok(false);
+        };
+        let routing = RoutingTable::load(path)?;
+        if routing == self.routing() {
+            return Ok(false);
+        }
+        log::info!("routing table {} changed", path.display());
+        self.set_routing(routing);
+        Ok(true)
+    }
+
+    fn client(&self, uri: &str) -> anyhow::Result<Client> {
+        let mut clients = self
+            .clients
+            .lock()
+            .expect("the clients are never left inconsistent");
+        if let Some(client) = clients.get(uri) {
+            return Ok(client.clone());
+        }
+        let client = ClientBuilder::from_uri(uri)?
+            .credentials(self.credentials.clone())
+            .build();
+        clients.insert(uri.to_string(), client.clone());
+        Ok(client)
+    }
+
+    /// Runs `operations` as one transaction.
+    ///
+    /// The operations are split by the repository storing their keys, and
+    /// each operation must only touch keys of one repository. Touching one
+    /// repository runs a `single repository` transaction; touching many runs
+    /// an `independent` transaction if every operation only reads, and a
+    /// `coordinated` one otherwise, so all the repositories agree on whether
+    /// the writes commit.
+    ///
+    /// If a repository refused it because its keys were moved, and the
+    /// routing table changed, it is run again with the new one. Other
+    /// failures, e.g. a timeout that may have committed it, are returned.
+    pub async fn send(&self, operations: Vec<ShardedOperation>) -> anyhow::Result<ShardedResults> {
+        match self.send_once(operations.clone()).await {
+            Err(e) if TransactionError::is_moved(&e) && self.reload()? => {
+                log::info!("transaction failed: {e}. Running it again with the new routing");
+                self.send_once(operations).await
+            }
+            results => results,
+        }
+    }
+
+    async fn send_once(&self, operations: Vec<ShardedOperation>) -> anyhow::Result<ShardedResults> {
+        let shards = self
+            .routing
+            .read()
+            .expect("the routing table is never left inconsistent")
+            .shards(operations)?;
+
+        let Some(kind) = kind_of(&shards) else {
+            let [(repository, operations)] = &shards[..] else {
+                unreachable!("a single repository transaction has one shard");
+            };
+            let result = self
+                .client(repository)?
+                .send_single(operations.clone())
+                .await?;
+            return Ok(ShardedResults {
+                results: vec![(repository.clone(), result)],
+                attempts: 1,
+            });
+        };
+        let (repositories, operations): (Vec<String>, Vec<Vec<Operation>>) =
+            shards.into_iter().unzip();
+        let clients = Clients {
+            participants: repositories
+                .iter()
+                .map(|uri| self.client(uri))
+                .collect::<anyhow::Result<_>>()?,
+            retry_policy: self.retry_policy.clone(),
+        };
+        let results = match kind {
+            TransactionKind::Independent => clients.send_indep(operations).await?,
+            TransactionKind::Coordinated => clients.send_coord(operations).await?,
+        };
+        Ok(ShardedResults {
+            results: repositories.into_iter().zip(results.results).collect(),
+            attempts: results.attempts,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROUTING: &str = r#"
+        [tables.customer]
+        ranges = [
+            { start = 100, end = 200, repository = "b" },
+            { start = 0, end = 100, repository = "a" },
+        ]
+
+        [tables.order]
+        hash = ["c", "d"]
+    "#;
+
+    fn sharded(table: &str, operation: &str) -> ShardedOperation {
+        ShardedOperation::new(table, operation.parse().unwrap())
+    }
+
+    #[test]
+    fn test_validate() {
+        let routing = RoutingTable::from_toml(ROUTING).unwrap();
+        let Some(Sharding::Ranges(ranges)) = routing.tables.get("customer") else {
+            panic!("customer is sharded by key ranges");
+        };
+        assert_eq!(ranges[0].start, 0);
+        assert_eq!(routing.repositories(), vec!["a", "b", "c", "d"]);
+
+        let overlapping = r#"
+            [tables.customer]
+            ranges = [
+                { start = 0, end = 100, repository = "a" },
+                { start = 50, end = 150, repository = "b" },
+            ]
+        "#;
+        let e = RoutingTable::from_toml(overlapping).unwrap_err();
+        assert!(format!("{e:#}").contains("overlap"), "{e:#}");
+
+        let empty = r#"
+            [tables.customer]
+            ranges = [{ start = 10, end = 10, repository = "a" }]
+        "#;
+        let e = RoutingTable::from_toml(empty).unwrap_err();
+        assert!(format!("{e:#}").contains("empty key range"), "{e:#}");
+
+        assert!(RoutingTable::from_toml("[tables.order]\nhash = []").is_err());
+        assert!(RoutingTable::from_toml("[tables.order]\nranges = []").is_err());
+    }
+
+    #[test]
+    fn test_route() {
+        let routing = RoutingTable::from_toml(ROUTING).unwrap();
+        assert_eq!(routing.route("customer", 0).unwrap(), "a");
+        assert_eq!(routing.route("customer", 99).unwrap(), "a");
+        assert_eq!(routing.route("customer", 100).unwrap(), "b");
+        assert!(routing.route("customer", 200).is_err());
+        assert!(routing.route("product", 0).is_err());
+
+        let hashed: Vec<&str> = (0..64)
+            .map(|key| routing.route("order", key).unwrap())
+            .collect();
+        assert!(hashed.contains(&"c") && hashed.contains(&"d"));
+        for (key, repository) in hashed.iter().enumerate() {
+            assert_eq!(routing.route("order", key).unwrap(), *repository);
+        }
+
+        assert_eq!(routing.owner("customer", &(10..20)).unwrap(), "a");
+        assert!(routing.owner("customer", &(90..110)).is_err());
+        assert!(routing.owner("order", &(0..10)).is_err());
+    }
+
+    #[test]
+    fn test_with_range_moved() {
+        let routing = RoutingTable::from_toml(ROUTING).unwrap();
+        let moved = routing
+            .with_range_moved("customer", &(20..40), "e")
+            .unwrap();
+        assert_eq!(moved.route("customer", 19).unwrap(), "a");
+        assert_eq!(moved.route("customer", 20).unwrap(), "e");
+        assert_eq!(moved.route("customer", 39).unwrap(), "e");
+        assert_eq!(moved.route("customer", 40).unwrap(), "a");
+        assert_eq!(moved.route("customer", 150).unwrap(), "b");
+        assert_eq!(routing.route("customer", 20).unwrap(), "a");
+
+        let whole = routing
+            .with_range_moved("customer", &(100..200), "e")
+            .unwrap();
+        let Some(Sharding::Ranges(ranges)) = whole.tables.get("customer") else {
+            panic!("customer is sharded by key ranges");
+        };
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(whole.route("customer", 100).unwrap(), "e");
+
+        assert!(routing
+            .with_range_moved("customer", &(90..110), "e")
+            .is_err());
+        assert!(routing.with_range_moved("order", &(0..10), "e").is_err());
+        // The keys of `order` hashed to `c` may be any.
+        let e = routing
+            .with_range_moved("customer", &(20..40), "c")
+            .unwrap_err();
+        assert!(format!("{e:#}").contains("share keys"), "{e:#}");
+
+        let reloaded = RoutingTable::from_toml(&toml::to_string(&moved).unwrap()).unwrap();
+        assert_eq!(reloaded, moved);
+    }
+
+    #[test]
+    fn test_shared_repository() {
+        let shared = |order: &str| {
+            RoutingTable::from_toml(&format!(
+                r#"
+                [tables.customer]
+                ranges = [{{ start = 0, end = 100, repository = "a" }}]
+
+                [tables.order]
+                {order}
+                "#
+            ))
+        };
+        let routing = shared(r#"ranges = [{ start = 100, end = 200, repository = "a" }]"#).unwrap();
+        let shards = routing
+            .shards(vec![
+                sharded("customer", "READ 1"),
+                sharded("order", "READ 101"),
+            ])
+            .unwrap();
+        assert_eq!(shards.len(), 1);
+        assert_eq!(shards[0].0, "a");
+        assert_eq!(shards[0].1.len(), 2);
+
+        for order in [
+            r#"ranges = [{ start = 50, end = 150, repository = "a" }]"#,
+            r#"hash = ["a", "b"]"#,
+        ] {
+            let e = shared(order).unwrap_err();
+            assert!(
+                format!("{e:#}").contains("share keys at repository a"),
+                "{e:#}"
+            );
+        }
+        assert!(shared(r#"ranges = [{ start = 50, end = 150, repository = "b" }]"#).is_ok());
+    }
+
+    #[test]
+    fn test_kind_of() {
+        let routing = RoutingTable::from_toml(ROUTING).unwrap();
+        let read = |key| sharded("customer", &format!("READ {key}"));
+        let delete = |key| sharded("customer", &format!("DELETE {key}"));
+
+        let single = routing.shards(vec![read(1), delete(2)]).unwrap();
+        assert_eq!(kind_of(&single), None);
+
+        let reads = routing.shards(vec![read(1), read(101), read(2)]).unwrap();
+        assert_eq!(reads.len(), 2);
+        assert_eq!(reads[0].0, "a");
+        assert_eq!(reads[0].1.len(), 2);
+        assert_eq!(kind_of(&reads), Some(TransactionKind::Independent));
+
+        let writes = routing.shards(vec![read(1), delete(101)]).unwrap();
+        assert_eq!(kind_of(&writes), Some(TransactionKind::Coordinated));
+
+        assert!(routing.shards(vec![]).is_err());
+        assert!(routing
+            .shards(vec![sharded("customer", "READ 1 + READ 101")])
+            .is_err());
+    }
+}
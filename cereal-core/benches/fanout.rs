@@ -0,0 +1,119 @@
+//! Latency of a multi-repository transaction, sending the messages of each
+//! phase one participant at a time vs. to all the participants at once (as
+//! `Application` does).
+//!
+//! Each `Repository` runs in its own `Arbiter` (thread), like it would run in
+//! its own process.
+//!
+//! Run with `cargo bench -p cereal-core --bench fanout`.
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use cereal_core::{
+    application::Application,
+    messages::{GetResult, MessagePrepare},
+    operations::{Arguments, Expr, Operation, Statement, Table},
+    repository::Repository,
+};
+use uuid::Uuid;
+
+const ROUNDS: u32 = 200;
+
+fn update() -> Vec<Operation> {
+    vec![Operation::Statement(Statement::Update(
+        1,
+        Box::new(Expr::Add(
+            Box::new(Expr::Read(1)),
+            Box::new(Expr::Value(Table(1, 1))),
+        )),
+    ))]
+}
+
+/// The phases of a coordinated transaction, one participant at a time.
+async fn sequential_coord(repositories: &[Addr<Repository>]) {
+    let tid = Uuid::new_v4();
+
+    let mut votes = vec![];
+    for repository in repositories {
+        let args = Arguments {
+            timestamp: 0,
+            operations: update(),
+        };
+        let msg = MessagePrepare::Coord(tid, args, repositories.len());
+        votes.push(repository.send(msg).await.unwrap().unwrap());
+    }
+
+    for (repository, vote) in repositories.iter().zip(votes) {
+        let msg = MessagePrepare::CoordParticipants(tid, vote, repositories.to_vec());
+        repository.send(msg).await.unwrap().unwrap();
+    }
+
+    for repository in repositories {
+        repository.send(GetResult(tid)).await.unwrap().unwrap();
+    }
+}
+
+/// The phases of a coordinated transaction, all participants at once.
+async fn concurrent_coord(repositories: &[Addr<Repository>]) {
+    let mut txn = Application::txn().coordinated();
+    for repository in repositories {
+        txn = txn.on(repository, update());
+    }
+    txn.run().await.unwrap();
+}
+
+async fn start_repositories(participants: usize) -> Vec<Addr<Repository>> {
+    let mut repositories = vec![];
+    for i in 0..participants {
+        let arbiter = Arbiter::new();
+        let repository = Repository::start_in_arbiter(&arbiter.handle(), move |_| {
+            Repository::new(format!("bench-{participants}-{i}"))
+        });
+        let create = vec![Operation::Statement(Statement::Create(
+            1,
+            Box::new(Expr::Value(Table(0, 0))),
+        ))];
+        Application::txn()
+            .on(&repository, create)
+            .run()
+            .await
+            .unwrap();
+        repositories.push(repository);
+    }
+    repositories
+}
+
+async fn mean_latency<F, Fut>(repositories: &[Addr<Repository>], run: F) -> Duration
+where
+    F: Fn(Vec<Addr<Repository>>) -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        run(repositories.to_vec()).await;
+    }
+    start.elapsed() / ROUNDS
+}
+
+fn main() {
+    System::new().block_on(async {
+        println!("participants  sequential  concurrent  speedup");
+        for participants in [2, 4, 8, 16] {
+            let repositories = start_repositories(participants).await;
+
+            let sequential = mean_latency(&repositories, |repositories| async move {
+                sequential_coord(&repositories).await
+            })
+            .await;
+            let concurrent = mean_latency(&repositories, |repositories| async move {
+                concurrent_coord(&repositories).await
+            })
+            .await;
+
+            println!(
+                "{participants:>12}  {sequential:>10.1?}  {concurrent:>10.1?}  {:>6.2}x",
+                sequential.as_secs_f64() / concurrent.as_secs_f64()
+            );
+        }
+    });
+}
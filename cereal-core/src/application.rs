@@ -1 +1,1605 @@
+//! An `Application` API to run transactions over in-process [`Repository`]s.
+//!
+//! # Example:
+//! ```
+//! use actix::prelude::*;
+//! use cereal_core::{
+//!     application::Application,
+//!     operations::{Expr, Operation, Statement, Table},
+//!     repository::Repository,
+//! };
+//!
+//! # actix::System::new().block_on(async {
+//! let customer = Repository::new("customer".to_string()).start();
+//! let product = Repository::new("product".to_string()).start();
+//!
+//! let create = vec![Operation::Statement(Statement::Create(
+//!     1,
+//!     Box::new(Expr::Value(Table(1, 1))),
+//! ))];
+//! Application::txn()
+//!     .on(&customer, create.clone())
+//!     .on(&product, create)
+//!     .coordinated()
+//!     .run()
+//!     .await
+//!     .unwrap();
+//!
+//! let read = vec![Operation::Expr(Expr::Read(1))];
+//! let result = Application::txn()
+//!     .on(&customer, read.clone())
+//!     .on(&product, read)
+//!     .run()
+//!     .await
+//!     .unwrap();
+//!
+//! assert_eq!(result.get(&customer), Some(&Table(1, 1)));
+//! assert_eq!(result.get(&product), Some(&Table(1, 1)));
+//! # });
+//! ```
+use std::collections::HashMap;
 
+use actix::prelude::*;
+use futures_util::future::join_all;
+use uuid::Uuid;
+
+use crate::{
+    messages::{CommitVote, GetResult, MessagePrepare, TransactionError},
+    operations::{Arguments, Operation, Table},
+    repository::Repository,
+    retry::RetryPolicy,
+};
+
+/// A simple `Application`.
+pub struct Application {}
+
+impl Application {
+    /// Start building a new transaction. See [`TransactionBuilder`].
+    pub fn txn() -> TransactionBuilder {
+        TransactionBuilder::default()
+    }
+
+    pub(crate) async fn single_repository_transaction(
+        repository: &Addr<Repository>,
+        ops: Vec<Operation>,
+        timestamp: usize,
+    ) -> anyhow::Result<Option<Table>> {
+        let tid = Uuid::new_v4();
+        let args = Arguments {
+            timestamp,
+            operations: ops,
+        };
+        let msg = MessagePrepare::Single(tid, args);
+        // TODO: should `MailboxError` be transformed more explicitly?
+        let _commit_vote = repository.send(msg).await?;
+
+        repository.send(GetResult(tid)).await?
+    }
+
+    pub(crate) async fn indep_repository_transaction(
+        repositories: Vec<Addr<Repository>>,
+        ops: Vec<Vec<Operation>>,
+        ts: usize,
+    ) -> anyhow::Result<Vec<Option<Table>>> {
+        Self::multi_repository_transaction(repositories, ops, ts, TransactionKind::Independent)
+            .await
+    }
+
+    pub(crate) async fn coord_repository_transaction(
+        repositories: Vec<Addr<Repository>>,
+        ops: Vec<Vec<Operation>>,
+        ts: usize,
+    ) -> anyhow::Result<Vec<Option<Table>>> {
+        Self::multi_repository_transaction(repositories, ops, ts, TransactionKind::Coordinated)
+            .await
+    }
+
+    /// Run all the phases of a multi-repository transaction, sending the
+    /// messages of each phase to all the participants at once.
+    ///
+    /// If a participant fails to vote, the others are told about a
+    /// [`CommitVote::Conflict`] instead of their own vote, so the transaction
+    /// is aborted everywhere, and the error is returned. If a participant
+    /// votes against committing, a [`TransactionError`] is returned.
+    async fn multi_repository_transaction(
+        repositories: Vec<Addr<Repository>>,
+        ops: Vec<Vec<Operation>>,
+        ts: usize,
+        kind: TransactionKind,
+    ) -> anyhow::Result<Vec<Option<Table>>> {
+        let tid = Uuid::new_v4();
+        let participants_len = repositories.len();
+
+        let prepares = repositories.iter().zip(ops).map(|(repository, ops)| {
+            let args = Arguments {
+                timestamp: ts,
+                operations: ops,
+            };
+            let msg = match kind {
+                TransactionKind::Independent => MessagePrepare::Indep(tid, args, participants_len),
+                TransactionKind::Coordinated => MessagePrepare::Coord(tid, args, participants_len),
+            };
+            // TODO: should `MailboxError` be transformed more explicitly?
+            async move { repository.send(msg).await? }
+        });
+        let votes = join_all(prepares).await;
+
+        let mut error = None;
+        let mut prepared = vec![];
+        for (repository, vote) in repositories.iter().zip(votes) {
+            match vote {
+                Ok(vote) => prepared.push((repository, vote)),
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        let outcome = TransactionError::from_votes(prepared.iter().map(|(_, vote)| vote));
+        if error.is_some() {
+            for (_, vote) in prepared.iter_mut() {
+                *vote = CommitVote::Conflict;
+            }
+        }
+
+        let notifications = prepared.iter().map(|(repository, vote)| {
+            // TODO: rm .clone()
+            let msg = match kind {
+                TransactionKind::Independent => {
+                    MessagePrepare::IndepParticipants(tid, vote.clone(), repositories.clone())
+                }
+                TransactionKind::Coordinated => {
+                    MessagePrepare::CoordParticipants(tid, vote.clone(), repositories.clone())
+                }
+            };
+            async move { repository.send(msg).await? }
+        });
+        let notified = join_all(notifications).await;
+
+        // A participant that wasn't notified will never have a result.
+        let mut notified_repositories = vec![];
+        for ((repository, _), notification) in prepared.iter().zip(notified) {
+            match notification {
+                Ok(_) => notified_repositories.push(*repository),
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+
+        let results = join_all(
+            notified_repositories
+                .iter()
+                .map(|repository| repository.send(GetResult(tid))),
+        )
+        .await;
+
+        if let Some(e) = error {
+            return Err(e);
+        }
+        if let Some(e) = outcome {
+            return Err(e.into());
+        }
+        // XXX: this should be a flatten of response?
+        results.into_iter().map(|result| result?).collect()
+    }
+}
+
+/// How participants of a multi-repository transaction agree on its outcome.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum TransactionKind {
+    /// Each participant decides to commit on its own. Section 4.4.
+    #[default]
+    Independent,
+    /// Participants hold locks until all of them voted. Section 4.5.
+    Coordinated,
+}
+
+/// Builds a transaction over one or more [`Repository`]s.
+///
+/// A transaction with a single participant runs as a `single repository`
+/// transaction, whatever its [`TransactionKind`].
+pub struct TransactionBuilder {
+    participants: Vec<(Addr<Repository>, Vec<Operation>)>,
+    kind: TransactionKind,
+    timestamp: usize,
+    retry: RetryPolicy,
+}
+
+impl Default for TransactionBuilder {
+    fn default() -> Self {
+        TransactionBuilder {
+            participants: vec![],
+            kind: TransactionKind::default(),
+            timestamp: 0,
+            retry: RetryPolicy::never(),
+        }
+    }
+}
+
+impl TransactionBuilder {
+    /// Add `operations` to be run on `repository`. Operations added to the
+    /// same repository more than once are run in the order they were added.
+    pub fn on(mut self, repository: &Addr<Repository>, operations: Vec<Operation>) -> Self {
+        match self
+            .participants
+            .iter_mut()
+            .find(|(participant, _)| participant == repository)
+        {
+            Some((_, ops)) => ops.extend(operations),
+            None => self.participants.push((repository.clone(), operations)),
+        }
+        self
+    }
+
+    /// Run as a [`TransactionKind::Coordinated`] transaction.
+    pub fn coordinated(mut self) -> Self {
+        self.kind = TransactionKind::Coordinated;
+        self
+    }
+
+    /// Run as a [`TransactionKind::Independent`] transaction (the default).
+    pub fn independent(mut self) -> Self {
+        self.kind = TransactionKind::Independent;
+        self
+    }
+
+    /// Propose `timestamp` to the participants. They will pick a later one if
+    /// they already used it.
+    pub fn at(mut self, timestamp: usize) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// Run again, with a new `tid`, when it conflicts with another
+    /// transaction, following `policy`. Never retried by default.
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Run the transaction, returning the result of each participant.
+    pub async fn run(self) -> anyhow::Result<TransactionResult> {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match self.run_once().await {
+                Ok(results) => return Ok(TransactionResult { results, attempts }),
+                Err(e) => match self.retry.backoff(attempts, &e) {
+                    Some(backoff) => {
+                        log::info!("attempt {attempts} failed: {e}. Retrying in {backoff:?}");
+                        actix::clock::sleep(backoff).await;
+                    }
+                    None => return Err(e),
+                },
+            }
+        }
+    }
+
+    async fn run_once(&self) -> anyhow::Result<HashMap<Addr<Repository>, Option<Table>>> {
+        let (repositories, ops): (Vec<_>, Vec<_>) = self.participants.iter().cloned().unzip();
+
+        let results = match (repositories.len(), self.kind) {
+            (0, _) => anyhow::bail!("a transaction needs at least one repository"),
+            (1, _) => vec![
+                Application::single_repository_transaction(
+                    &repositories[0],
+                    ops.into_iter().flatten().collect(),
+                    self.timestamp,
+                )
+                .await?,
+            ],
+            (_, TransactionKind::Independent) => {
+                Application::indep_repository_transaction(repositories.clone(), ops, self.timestamp)
+                    .await?
+            }
+            (_, TransactionKind::Coordinated) => {
+                Application::coord_repository_transaction(repositories.clone(), ops, self.timestamp)
+                    .await?
+            }
+        };
+
+        Ok(repositories.into_iter().zip(results).collect())
+    }
+}
+
+/// The result of each participant of a transaction, by [`Repository`].
+#[derive(Debug)]
+pub struct TransactionResult {
+    results: HashMap<Addr<Repository>, Option<Table>>,
+    attempts: u32,
+}
+
+impl TransactionResult {
+    /// How many times the transaction was run until it committed.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// The value returned by `repository`, if it is a participant and its
+    /// last operation returned one.
+    pub fn get(&self, repository: &Addr<Repository>) -> Option<&Table> {
+        self.results.get(repository)?.as_ref()
+    }
+
+    /// Whether `repository` took part in the transaction.
+    pub fn contains(&self, repository: &Addr<Repository>) -> bool {
+        self.results.contains_key(repository)
+    }
+
+    /// The result of every participant.
+    pub fn iter(&self) -> impl Iterator<Item = (&Addr<Repository>, &Option<Table>)> {
+        self.results.iter()
+    }
+
+    /// Number of participants.
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    /// Whether there are no participants. Never true for a transaction that ran.
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::{
+        introspection::Mode,
+        messages::{
+            AbortTransaction, ChangeEvent, CommitVote, DropRange, ExportRange, ForgetResults,
+            GetMetrics, GetResult, ImportRange, Introspect, MessageAccept, MessagePrepare,
+            PrepareAs, ServeRange, SetLowWatermark, Subscribe, TakeCheckpoint,
+        },
+        operations::{Arguments, Expr, Statement},
+        permissions::{Grant, Permission, Permissions},
+        runtime::Runtime,
+    };
+
+    async fn create_customer_product_tables(
+        runtime: &mut Runtime,
+    ) -> (Addr<Repository>, Addr<Repository>) {
+        let customer = Repository::new("customer".to_string()).start();
+        let operations = vec![
+            Operation::Statement(Statement::Create(1, Box::new(Expr::Value(Table(1, 1))))),
+            Operation::Statement(Statement::Create(2, Box::new(Expr::Value(Table(2, 2))))),
+            Operation::Statement(Statement::Create(3, Box::new(Expr::Value(Table(3, 3))))),
+        ];
+
+        let cust = Application::single_repository_transaction(
+            &customer.clone(),
+            operations,
+            runtime.now(),
+        )
+        .await;
+        println!("Adding customer info. Result: {:?}", cust);
+
+        let prod = Repository::new("product".to_string()).start();
+        let operations = vec![
+            Operation::Statement(Statement::Create(0, Box::new(Expr::Value(Table(4, 4))))),
+            Operation::Statement(Statement::Create(1, Box::new(Expr::Value(Table(5, 5))))),
+            Operation::Statement(Statement::Create(2, Box::new(Expr::Value(Table(6, 6))))),
+        ];
+        let resp =
+            Application::single_repository_transaction(&prod.clone(), operations, runtime.now())
+                .await;
+        println!("Adding product info. Result: {:?}", resp);
+
+        (customer, prod)
+    }
+
+    #[actix_rt::test]
+    async fn test_indep_ok() {
+        let mut runtime = Runtime::new();
+        let (customer, product) = create_customer_product_tables(&mut runtime).await;
+        let operations = vec![
+            vec![Operation::Expr(Expr::Read(1))],
+            vec![Operation::Expr(Expr::Read(1))],
+        ];
+
+        let res = Application::indep_repository_transaction(
+            vec![customer.clone(), product.clone()],
+            operations,
+            runtime.now(),
+        )
+        .await;
+
+        assert!(res.is_ok());
+        let _ = res.unwrap().iter().map(|r| assert!(r.is_some()));
+        println!("Reading a key that exists on both. Should be ok.");
+    }
+
+    #[actix_rt::test]
+    async fn test_indep_not_valid_key() {
+        let mut runtime = Runtime::new();
+        let (customer, product) = create_customer_product_tables(&mut runtime).await;
+        let operations = vec![
+            vec![Operation::Expr(Expr::Read(1))],
+            vec![Operation::Expr(Expr::Read(4))],
+        ];
+
+        let res = Application::indep_repository_transaction(
+            vec![customer.clone(), product.clone()],
+            operations,
+            runtime.now(),
+        )
+        .await;
+        assert!(res.is_err());
+        println!("Read transaction failed because of a primary key violation. Should be err.");
+    }
+
+    #[actix_rt::test]
+    async fn test_indep_update_failed_because_of_primary_key_violation() {
+        let mut runtime = Runtime::new();
+        let (customer, product) = create_customer_product_tables(&mut runtime).await;
+        let operations = vec![Operation::Expr(Expr::Read(1))];
+
+        let cust_before =
+            Application::single_repository_transaction(&customer, operations, runtime.now()).await;
+
+        let operations = vec![
+            vec![Operation::Statement(Statement::Update(
+                1,
+                Box::new(Expr::Value(Table(1000, 1000))),
+            ))],
+            vec![Operation::Expr(Expr::Read(4))],
+        ];
+
+        let res = Application::indep_repository_transaction(
+            vec![customer.clone(), product.clone()],
+            operations,
+            runtime.now(),
+        )
+        .await;
+        assert!(res.is_err());
+
+        let operations = vec![Operation::Expr(Expr::Read(1))];
+
+        let cust =
+            Application::single_repository_transaction(&customer, operations, runtime.now()).await;
+
+        assert!(cust.is_ok());
+        assert_eq!(cust_before.unwrap(), cust.unwrap());
+        println!("An `abort` transaction has not effect on a repo");
+    }
+
+    #[actix_rt::test]
+    async fn test_coord_ok() {
+        let mut runtime = Runtime::new();
+        let (customer, product) = create_customer_product_tables(&mut runtime).await;
+
+        let operations = vec![
+            vec![Operation::Statement(Statement::Update(
+                1,
+                Box::new(Expr::Value(Table(10, 10))),
+            ))],
+            vec![Operation::Statement(Statement::Update(
+                1,
+                Box::new(Expr::Value(Table(40, 40))),
+            ))],
+        ];
+
+        let res = Application::coord_repository_transaction(
+            vec![customer.clone(), product.clone()],
+            operations,
+            runtime.now(),
+        )
+        .await;
+        assert!(res.is_ok());
+
+        let operations = vec![Operation::Expr(Expr::Read(1))];
+        let cust =
+            Application::single_repository_transaction(&customer, operations, runtime.now()).await;
+
+        let operations = vec![Operation::Expr(Expr::Read(1))];
+        let prod =
+            Application::single_repository_transaction(&product, operations, runtime.now()).await;
+
+        assert!(cust.is_ok());
+        assert!(prod.is_ok());
+        assert_eq!(cust.unwrap(), Some(Table(10, 10)));
+        assert_eq!(prod.unwrap(), Some(Table(40, 40)));
+        println!("Coordinated update succeeded.");
+    }
+
+    #[actix_rt::test]
+    async fn test_coord_should_fail() {
+        let mut runtime = Runtime::new();
+        let (customer, product) = create_customer_product_tables(&mut runtime).await;
+
+        let operations = vec![
+            vec![Operation::Statement(Statement::Update(
+                1,
+                Box::new(Expr::Value(Table(10, 10))),
+            ))],
+            vec![
+                Operation::Statement(Statement::Update(1, Box::new(Expr::Value(Table(40, 40))))),
+                // Should fail.
+                Operation::Expr(Expr::Read(5)),
+            ],
+        ];
+
+        let res = Application::coord_repository_transaction(
+            vec![customer.clone(), product.clone()],
+            operations,
+            runtime.now(),
+        )
+        .await;
+        assert!(res.is_err());
+
+        let operations = vec![Operation::Expr(Expr::Read(1))];
+        let cust =
+            Application::single_repository_transaction(&customer, operations, runtime.now()).await;
+
+        let operations = vec![Operation::Expr(Expr::Read(1))];
+        let prod =
+            Application::single_repository_transaction(&product, operations, runtime.now()).await;
+
+        assert!(cust.is_ok());
+        assert!(prod.is_ok());
+        assert_eq!(cust.unwrap(), Some(Table(1, 1)));
+        assert_eq!(prod.unwrap(), Some(Table(5, 5)));
+        println!("Coordinated fail to update due to primary key violation.");
+    }
+
+    #[actix_rt::test]
+    async fn test_read_only_not_blocked_by_coord() {
+        let mut runtime = Runtime::new();
+        let (customer, _product) = create_customer_product_tables(&mut runtime).await;
+
+        // Prepare (but never finish) a coordinated transaction holding key 1.
+        let args = Arguments {
+            timestamp: runtime.now(),
+            operations: vec![Operation::Statement(Statement::Update(
+                1,
+                Box::new(Expr::Value(Table(10, 10))),
+            ))],
+        };
+        let vote = customer
+            .send(MessagePrepare::Coord(Uuid::new_v4(), args, 2))
+            .await
+            .unwrap();
+        assert_eq!(vote.unwrap(), CommitVote::Commit(None));
+
+        let operations = vec![Operation::Expr(Expr::Read(1))];
+        let cust =
+            Application::single_repository_transaction(&customer, operations, runtime.now()).await;
+        assert_eq!(cust.unwrap(), Some(Table(1, 1)));
+        println!("A read-only transaction doesn't wait for locked keys.");
+    }
+
+    #[actix_rt::test]
+    async fn test_indep_read_only_ignores_locks() {
+        let mut runtime = Runtime::new();
+        let (customer, product) = create_customer_product_tables(&mut runtime).await;
+
+        // A coordinated transaction holding key 1, ordered before the read.
+        let holder = Uuid::new_v4();
+        let args = Arguments {
+            timestamp: runtime.now(),
+            operations: vec![Operation::Statement(Statement::Update(
+                1,
+                Box::new(Expr::Value(Table(10, 10))),
+            ))],
+        };
+        let vote = customer
+            .send(MessagePrepare::Coord(holder, args, 1))
+            .await
+            .unwrap();
+        assert_eq!(vote.unwrap(), CommitVote::Commit(None));
+
+        let tid = Uuid::new_v4();
+        let participants = vec![customer.clone(), product.clone()];
+        for repository in &participants {
+            let args = Arguments {
+                timestamp: runtime.now(),
+                operations: vec![Operation::Expr(Expr::Read(1))],
+            };
+            let vote = repository
+                .send(MessagePrepare::Indep(tid, args, 2))
+                .await
+                .unwrap();
+            assert_eq!(vote.unwrap(), CommitVote::Commit(None));
+        }
+        for repository in &participants {
+            let vote = CommitVote::Commit(None);
+            let msg = MessagePrepare::IndepParticipants(tid, vote, participants.clone());
+            repository.send(msg).await.unwrap().unwrap();
+        }
+        // The read took no lock: only the holder is pending.
+        let introspection = customer.send(Introspect).await.unwrap();
+        assert_eq!(introspection.pending.len(), 1);
+        assert_eq!(introspection.pending[0].locks, vec![1]);
+
+        let accepted = customer.send(MessageAccept::Coord(
+            holder,
+            0,
+            CommitVote::Commit(None),
+            "customer".into(),
+        ));
+        assert_eq!(accepted.await.unwrap().unwrap(), CommitVote::InProgress);
+        let cust = customer.send(GetResult(tid)).await.unwrap();
+        assert_eq!(cust.unwrap(), Some(Table(10, 10)));
+        let prod = product.send(GetResult(tid)).await.unwrap();
+        assert_eq!(prod.unwrap(), Some(Table(5, 5)));
+        let metrics = customer.send(GetMetrics).await.unwrap();
+        assert_eq!(metrics.durable_writes.count, 3);
+    }
+
+    #[actix_rt::test]
+    async fn test_indep_read_only_after_coord_abort() {
+        let mut runtime = Runtime::new();
+        let (customer, product) = create_customer_product_tables(&mut runtime).await;
+
+        // A coordinated transaction holding key 1, ordered before the read.
+        let holder = Uuid::new_v4();
+        let args = Arguments {
+            timestamp: runtime.now(),
+            operations: vec![Operation::Statement(Statement::Update(
+                1,
+                Box::new(Expr::Value(Table(10, 10))),
+            ))],
+        };
+        let vote = customer
+            .send(MessagePrepare::Coord(holder, args, 2))
+            .await
+            .unwrap();
+        assert_eq!(vote.unwrap(), CommitVote::Commit(None));
+
+        let tid = Uuid::new_v4();
+        let participants = vec![customer.clone(), product.clone()];
+        for repository in &participants {
+            let args = Arguments {
+                timestamp: runtime.now(),
+                operations: vec![Operation::Expr(Expr::Read(1))],
+            };
+            let vote = repository
+                .send(MessagePrepare::Indep(tid, args, 2))
+                .await
+                .unwrap();
+            assert_eq!(vote.unwrap(), CommitVote::Commit(None));
+        }
+        for repository in &participants {
+            let vote = CommitVote::Commit(None);
+            let msg = MessagePrepare::IndepParticipants(tid, vote, participants.clone());
+            repository.send(msg).await.unwrap().unwrap();
+        }
+
+        let aborted = customer.send(MessageAccept::Coord(
+            holder,
+            0,
+            CommitVote::Abort,
+            "product".into(),
+        ));
+        assert_eq!(aborted.await.unwrap().unwrap(), CommitVote::Abort);
+        let cust = customer.send(GetResult(tid)).await.unwrap();
+        assert_eq!(cust.unwrap(), Some(Table(1, 1)));
+    }
+
+    #[actix_rt::test]
+    async fn test_read_only_rejects_writes() {
+        let customer = Repository::new("customer".to_string()).start();
+        let args = Arguments {
+            timestamp: 0,
+            operations: vec![Operation::Statement(Statement::Create(
+                1,
+                Box::new(Expr::Value(Table(1, 1))),
+            ))],
+        };
+        let vote = customer
+            .send(MessagePrepare::ReadOnly(Uuid::new_v4(), args))
+            .await
+            .unwrap();
+        assert!(vote.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_snapshot_read() {
+        let mut runtime = Runtime::new();
+        let (customer, product) = create_customer_product_tables(&mut runtime).await;
+
+        let args = Arguments {
+            timestamp: runtime.now(),
+            operations: vec![Operation::Expr(Expr::Read(1))],
+        };
+        // Executed at the same timestamp at both, after the tables were created.
+        let res = Application::coord_repository_transaction(
+            vec![customer.clone(), product.clone()],
+            vec![args.operations.clone(), args.operations.clone()],
+            runtime.now(),
+        )
+        .await;
+        assert!(res.is_ok());
+        let mut snapshot_ts = usize::MAX;
+        for repository in [&customer, &product] {
+            let msg = MessagePrepare::ReadOnly(Uuid::new_v4(), args.clone());
+            let vote = repository.send(msg).await.unwrap().unwrap();
+            let CommitVote::Commit(Some(read_ts)) = vote else {
+                panic!("read-only transaction should commit right away");
+            };
+            snapshot_ts = std::cmp::min(snapshot_ts, read_ts);
+        }
+        // Both repositories already executed everything up to the snapshot.
+        for repository in [&customer, &product] {
+            let msg = MessagePrepare::Snapshot(Uuid::new_v4(), args.clone(), snapshot_ts);
+            let vote = repository.send(msg).await.unwrap().unwrap();
+            assert_eq!(vote, CommitVote::Commit(Some(snapshot_ts)));
+        }
+
+        let operations = vec![
+            vec![Operation::Statement(Statement::Update(
+                1,
+                Box::new(Expr::Value(Table(10, 10))),
+            ))],
+            vec![Operation::Statement(Statement::Update(
+                1,
+                Box::new(Expr::Value(Table(40, 40))),
+            ))],
+        ];
+        let res = Application::coord_repository_transaction(
+            vec![customer.clone(), product.clone()],
+            operations,
+            runtime.now(),
+        )
+        .await;
+        assert!(res.is_ok());
+
+        for (repository, expected) in [(&customer, Table(1, 1)), (&product, Table(5, 5))] {
+            let tid = Uuid::new_v4();
+            let msg = MessagePrepare::Snapshot(tid, args.clone(), snapshot_ts);
+            let vote = repository.send(msg).await.unwrap().unwrap();
+            assert_eq!(vote, CommitVote::Commit(Some(snapshot_ts)));
+            let result = repository.send(GetResult(tid)).await.unwrap();
+            assert_eq!(result.unwrap(), Some(expected));
+        }
+
+        // Timestamps no transaction was executed at yet are refused.
+        let introspection = customer.send(Introspect).await.unwrap();
+        let tid = Uuid::new_v4();
+        let msg = MessagePrepare::Snapshot(tid, args.clone(), usize::MAX);
+        let vote = customer.send(msg).await.unwrap().unwrap();
+        assert_eq!(vote, CommitVote::Abort);
+        let result = customer.send(GetResult(tid)).await.unwrap();
+        assert!(result.unwrap_err().to_string().contains("is ahead of"));
+        let last_timestamp = customer.send(Introspect).await.unwrap().last_timestamp;
+        assert_eq!(last_timestamp, introspection.last_timestamp);
+
+        // Versions older than the watermark are gone.
+        customer
+            .send(SetLowWatermark(snapshot_ts + 1))
+            .await
+            .unwrap();
+        let tid = Uuid::new_v4();
+        let msg = MessagePrepare::Snapshot(tid, args, snapshot_ts);
+        let vote = customer.send(msg).await.unwrap().unwrap();
+        assert_eq!(vote, CommitVote::Abort);
+        assert!(customer.send(GetResult(tid)).await.unwrap().is_err());
+        println!("Snapshot reads see the values committed before the coordinated update.");
+    }
+
+    struct ChangeCollector(Vec<ChangeEvent>);
+
+    impl Actor for ChangeCollector {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<ChangeEvent> for ChangeCollector {
+        type Result = ();
+
+        fn handle(&mut self, msg: ChangeEvent, _ctx: &mut Self::Context) -> Self::Result {
+            self.0.push(msg);
+        }
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "Vec<ChangeEvent>")]
+    struct GetChanges;
+
+    impl Handler<GetChanges> for ChangeCollector {
+        type Result = MessageResult<GetChanges>;
+
+        fn handle(&mut self, _msg: GetChanges, _ctx: &mut Self::Context) -> Self::Result {
+            MessageResult(self.0.clone())
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_change_feed() {
+        let mut runtime = Runtime::new();
+        let (customer, product) = create_customer_product_tables(&mut runtime).await;
+
+        let collector = ChangeCollector(vec![]).start();
+        let subscribe = Subscribe {
+            from_ts: 0,
+            subscriber: collector.clone().recipient(),
+        };
+        customer.send(subscribe).await.unwrap().unwrap();
+
+        let operations = vec![
+            vec![Operation::Statement(Statement::Update(
+                1,
+                Box::new(Expr::Value(Table(10, 10))),
+            ))],
+            vec![Operation::Expr(Expr::Delete(1))],
+        ];
+        let res = Application::coord_repository_transaction(
+            vec![customer.clone(), product.clone()],
+            operations,
+            runtime.now(),
+        )
+        .await;
+        assert!(res.is_ok());
+
+        let changes = collector.send(GetChanges).await.unwrap();
+        let keys: Vec<_> = changes.iter().map(|change| change.key).collect();
+        assert_eq!(keys, vec![1, 2, 3, 1]);
+        let update = changes.last().unwrap();
+        assert_eq!(update.old_value, Some(Table(1, 1)));
+        assert_eq!(update.new_value, Some(Table(10, 10)));
+
+        // Resuming from the last change only replays it.
+        let resumed = ChangeCollector(vec![]).start();
+        let subscribe = Subscribe {
+            from_ts: update.commit_ts,
+            subscriber: resumed.clone().recipient(),
+        };
+        customer.send(subscribe).await.unwrap().unwrap();
+        assert_eq!(
+            resumed.send(GetChanges).await.unwrap(),
+            vec![update.clone()]
+        );
+        println!("Committed changes are published and can be replayed.");
+    }
+
+    #[actix_rt::test]
+    async fn test_change_feed_in_commit_order() {
+        let mut runtime = Runtime::new();
+        let (customer, _product) = create_customer_product_tables(&mut runtime).await;
+        let collector = ChangeCollector(vec![]).start();
+        let subscribe = Subscribe {
+            from_ts: 0,
+            subscriber: collector.clone().recipient(),
+        };
+        customer.send(subscribe).await.unwrap().unwrap();
+        let initial = collector.send(GetChanges).await.unwrap().len();
+
+        // Ordered first, but waiting for the accept of another participant.
+        let first = Uuid::new_v4();
+        let args = Arguments {
+            timestamp: runtime.now(),
+            operations: vec![Operation::Statement(Statement::Update(
+                1,
+                Box::new(Expr::Value(Table(10, 10))),
+            ))],
+        };
+        let vote = customer
+            .send(MessagePrepare::Indep(first, args, 1))
+            .await
+            .unwrap();
+        assert_eq!(vote.unwrap(), CommitVote::Commit(None));
+        let args = Arguments {
+            timestamp: runtime.now(),
+            operations: vec![Operation::Statement(Statement::Update(
+                2,
+                Box::new(Expr::Value(Table(20, 20))),
+            ))],
+        };
+        let vote = customer
+            .send(MessagePrepare::Single(Uuid::new_v4(), args))
+            .await
+            .unwrap();
+        assert_eq!(vote.unwrap(), CommitVote::InProgress);
+        assert_eq!(collector.send(GetChanges).await.unwrap().len(), initial);
+
+        let accepted = customer.send(MessageAccept::Indep(
+            first,
+            0,
+            CommitVote::Commit(None),
+            "customer".into(),
+        ));
+        assert_eq!(accepted.await.unwrap().unwrap(), CommitVote::InProgress);
+        let changes = collector.send(GetChanges).await.unwrap();
+        let keys: Vec<_> = changes[initial..].iter().map(|change| change.key).collect();
+        assert_eq!(keys, vec![1, 2]);
+        assert!(changes.windows(2).all(|w| w[0].commit_ts <= w[1].commit_ts));
+
+        // Resuming after the first one doesn't miss the later one.
+        let resumed = ChangeCollector(vec![]).start();
+        let subscribe = Subscribe {
+            from_ts: changes[initial].commit_ts + 1,
+            subscriber: resumed.clone().recipient(),
+        };
+        customer.send(subscribe).await.unwrap().unwrap();
+        let replayed = resumed.send(GetChanges).await.unwrap();
+        assert_eq!(replayed, changes[initial + 1..].to_vec());
+    }
+
+    #[actix_rt::test]
+    async fn test_builder_coord() {
+        let mut runtime = Runtime::new();
+        let (customer, product) = create_customer_product_tables(&mut runtime).await;
+
+        let result = Application::txn()
+            .on(
+                &customer,
+                vec![Operation::Statement(Statement::Update(
+                    1,
+                    Box::new(Expr::Value(Table(10, 10))),
+                ))],
+            )
+            .on(&product, vec![Operation::Expr(Expr::Read(1))])
+            .on(
+                &customer,
+                vec![Operation::Expr(Expr::Add(
+                    Box::new(Expr::Read(1)),
+                    Box::new(Expr::Read(2)),
+                ))],
+            )
+            .coordinated()
+            .run()
+            .await
+            .unwrap();
+
+        assert_eq!(result.len(), 2);
+        assert_eq!(result.get(&customer), Some(&Table(12, 12)));
+        assert_eq!(result.get(&product), Some(&Table(5, 5)));
+    }
+
+    #[actix_rt::test]
+    async fn test_coord_participants_of_the_same_name() {
+        let first = Repository::new("same".to_string()).start();
+        let second = Repository::new("same".to_string()).start();
+        let create = vec![Operation::Statement(Statement::Create(
+            1,
+            Box::new(Expr::Value(Table(1, 1))),
+        ))];
+
+        let run = Application::txn()
+            .on(&first, create.clone())
+            .on(&second, create)
+            .coordinated()
+            .run();
+        let result = actix::clock::timeout(Duration::from_secs(5), run).await;
+        assert!(result.expect("the accepts were told apart").is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_builder_single_and_errors() {
+        let mut runtime = Runtime::new();
+        let (customer, product) = create_customer_product_tables(&mut runtime).await;
+
+        let result = Application::txn()
+            .on(&customer, vec![Operation::Expr(Expr::Read(3))])
+            .at(runtime.now())
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(result.get(&customer), Some(&Table(3, 3)));
+        assert!(!result.contains(&product));
+
+        assert!(Application::txn().run().await.is_err());
+
+        let result = Application::txn()
+            .on(&customer, vec![Operation::Expr(Expr::Read(1))])
+            .on(&product, vec![Operation::Expr(Expr::Read(4))])
+            .run()
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_coord_partial_failure() {
+        let mut runtime = Runtime::new();
+        let (customer, _product) = create_customer_product_tables(&mut runtime).await;
+        // Can't write to its durable log, so it fails to vote.
+        let broken = Repository::new("no/such/dir".to_string()).start();
+
+        let update = vec![Operation::Statement(Statement::Update(
+            1,
+            Box::new(Expr::Value(Table(10, 10))),
+        ))];
+        let res = Application::txn()
+            .on(&customer, update.clone())
+            .on(&broken, update.clone())
+            .coordinated()
+            .run()
+            .await;
+        assert!(res.is_err());
+
+        // `customer` aborted and released its locks.
+        let res = Application::txn()
+            .on(&customer, vec![Operation::Expr(Expr::Read(1))])
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(res.get(&customer), Some(&Table(1, 1)));
+        let res = Application::txn().on(&customer, update).run().await;
+        assert_eq!(res.unwrap().get(&customer), Some(&Table(10, 10)));
+        println!("A participant failing to vote aborts the transaction everywhere.");
+    }
+
+    #[actix_rt::test]
+    async fn test_retry_on_conflict() {
+        let mut runtime = Runtime::new();
+        let (customer, product) = create_customer_product_tables(&mut runtime).await;
+
+        // A coordinated transaction holds key 1 at `customer` for a while.
+        let tid = Uuid::new_v4();
+        let args = Arguments {
+            timestamp: runtime.now(),
+            operations: vec![Operation::Expr(Expr::Read(1))],
+        };
+        let vote = customer
+            .send(MessagePrepare::Coord(tid, args, 1))
+            .await
+            .unwrap()
+            .unwrap();
+        let holder = customer.clone();
+        actix::spawn(async move {
+            actix::clock::sleep(Duration::from_millis(50)).await;
+            let msg = MessagePrepare::CoordParticipants(tid, vote, vec![holder.clone()]);
+            holder.send(msg).await.unwrap().unwrap();
+        });
+
+        let update = |value| {
+            vec![Operation::Statement(Statement::Update(
+                1,
+                Box::new(Expr::Value(Table(value, value))),
+            ))]
+        };
+        let res = Application::txn()
+            .on(&customer, update(10))
+            .on(&product, update(40))
+            .run()
+            .await;
+        let error = res.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<TransactionError>(),
+            Some(&TransactionError::Conflict)
+        );
+
+        let policy = RetryPolicy {
+            max_attempts: 20,
+            initial_backoff: Duration::from_millis(5),
+            max_backoff: Duration::from_millis(20),
+        };
+        let res = Application::txn()
+            .on(&customer, update(10))
+            .on(&product, update(40))
+            .retry(policy)
+            .run()
+            .await
+            .unwrap();
+        assert!(res.attempts() > 1);
+        assert_eq!(res.get(&customer), Some(&Table(10, 10)));
+        println!("A conflicting transaction succeeds once the key is released.");
+    }
+
+    #[actix_rt::test]
+    async fn test_no_retry_on_abort() {
+        let mut runtime = Runtime::new();
+        let (customer, product) = create_customer_product_tables(&mut runtime).await;
+
+        let res = Application::txn()
+            .on(&customer, vec![Operation::Expr(Expr::Read(1))])
+            .on(&product, vec![Operation::Expr(Expr::Read(4))])
+            .retry(RetryPolicy::default())
+            .run()
+            .await;
+        let error = res.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<TransactionError>(),
+            Some(&TransactionError::Abort)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_repeated_prepare_is_not_run_again() {
+        let mut runtime = Runtime::new();
+        let (customer, _product) = create_customer_product_tables(&mut runtime).await;
+
+        let tid = Uuid::new_v4();
+        let args = Arguments {
+            timestamp: runtime.now(),
+            operations: vec![Operation::Statement(Statement::Update(
+                1,
+                Box::new(Expr::Add(
+                    Box::new(Expr::Read(1)),
+                    Box::new(Expr::Value(Table(1, 1))),
+                )),
+            ))],
+        };
+        for _ in 0..2 {
+            let msg = MessagePrepare::Single(tid, args.clone());
+            let vote = customer.send(msg).await.unwrap().unwrap();
+            assert_eq!(vote, CommitVote::InProgress);
+            let result = customer.send(GetResult(tid)).await.unwrap();
+            assert_eq!(result.unwrap(), Some(Table(2, 2)));
+        }
+
+        let read_tid = Uuid::new_v4();
+        let args = Arguments {
+            timestamp: runtime.now(),
+            operations: vec![Operation::Expr(Expr::Read(1))],
+        };
+        let vote = customer.send(MessagePrepare::ReadOnly(read_tid, args));
+        let CommitVote::Commit(Some(read_ts)) = vote.await.unwrap().unwrap() else {
+            panic!("read-only transaction should commit right away");
+        };
+        let result = customer.send(GetResult(read_tid)).await.unwrap();
+        assert_eq!(result.unwrap(), Some(Table(2, 2)));
+
+        // Finished transactions are forgotten once the low watermark passes them.
+        customer.send(SetLowWatermark(read_ts + 1)).await.unwrap();
+        assert!(customer.send(GetResult(tid)).await.unwrap().is_err());
+        println!("A repeated prepare gets the original vote and result.");
+    }
+
+    #[actix_rt::test]
+    async fn test_forget_results() {
+        let mut runtime = Runtime::new();
+        let (customer, _product) = create_customer_product_tables(&mut runtime).await;
+
+        let (done, pending) = (Uuid::new_v4(), Uuid::new_v4());
+        let args = Arguments {
+            timestamp: runtime.now(),
+            operations: vec![Operation::Expr(Expr::Read(1))],
+        };
+        let vote = customer.send(MessagePrepare::Single(done, args.clone()));
+        assert!(vote.await.unwrap().is_ok());
+        let vote = customer.send(MessagePrepare::Coord(pending, args, 2));
+        assert_eq!(vote.await.unwrap().unwrap(), CommitVote::Commit(None));
+
+        customer
+            .send(ForgetResults(Duration::from_secs(60)))
+            .await
+            .unwrap();
+        let result = customer.send(GetResult(done)).await.unwrap();
+        assert_eq!(result.unwrap(), Some(Table(1, 1)));
+
+        customer.send(ForgetResults(Duration::ZERO)).await.unwrap();
+        let result = customer.send(GetResult(done)).await.unwrap();
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("unknown transaction"));
+        let introspection = customer.send(Introspect).await.unwrap();
+        assert_eq!(introspection.pending[0].tid, pending);
+        let vote = customer.send(MessagePrepare::Coord(
+            pending,
+            Arguments {
+                timestamp: 0,
+                operations: vec![],
+            },
+            2,
+        ));
+        assert_eq!(vote.await.unwrap().unwrap(), CommitVote::Commit(None));
+        println!("Results are forgotten once finished and old enough.");
+    }
+
+    #[actix_rt::test]
+    async fn test_move_key_range() {
+        let mut runtime = Runtime::new();
+        let (customer, _product) = create_customer_product_tables(&mut runtime).await;
+        let moved_to = Repository::new("moved_to".to_string()).start();
+
+        // A first copy, while the keys are still served by `customer`.
+        let entries = customer.send(ExportRange(1..3)).await.unwrap().unwrap();
+        assert_eq!(entries, vec![(1, Table(1, 1)), (2, Table(2, 2))]);
+        let entries = entries.into_iter().map(|(k, v)| (k, Some(v))).collect();
+        moved_to
+            .send(ImportRange(1..3, entries))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let result = Application::txn()
+            .on(
+                &customer,
+                vec![Operation::Statement(Statement::Update(
+                    1,
+                    Box::new(Expr::Value(Table(10, 10))),
+                ))],
+            )
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(result.get(&customer), Some(&Table(10, 10)));
+
+        Application::txn()
+            .on(
+                &customer,
+                vec![Operation::Statement(Statement::Release(1..3))],
+            )
+            .on(
+                &moved_to,
+                vec![Operation::Statement(Statement::Acquire(1..3))],
+            )
+            .coordinated()
+            .run()
+            .await
+            .unwrap();
+
+        let read = |repository: &Addr<Repository>, key| {
+            Application::txn()
+                .on(repository, vec![Operation::Expr(Expr::Read(key))])
+                .run()
+        };
+        assert!(read(&customer, 1).await.is_err());
+        assert!(read(&moved_to, 1).await.is_err());
+        assert_eq!(
+            read(&customer, 3).await.unwrap().get(&customer),
+            Some(&Table(3, 3))
+        );
+
+        // Nothing changes the released keys anymore: copy what changed since.
+        let entries = customer.send(ExportRange(1..3)).await.unwrap().unwrap();
+        let copied = moved_to.send(ExportRange(1..3)).await.unwrap().unwrap();
+        let changed = entries
+            .iter()
+            .filter(|entry| !copied.contains(entry))
+            .map(|(k, v)| (*k, Some(v.clone())))
+            .collect();
+        moved_to
+            .send(ImportRange(1..3, changed))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            moved_to.send(ExportRange(1..3)).await.unwrap().unwrap(),
+            entries
+        );
+        moved_to.send(ServeRange(1..3)).await.unwrap().unwrap();
+        customer.send(DropRange(1..3)).await.unwrap().unwrap();
+
+        assert_eq!(
+            read(&moved_to, 1).await.unwrap().get(&moved_to),
+            Some(&Table(10, 10))
+        );
+        assert!(customer
+            .send(ExportRange(1..3))
+            .await
+            .unwrap()
+            .unwrap()
+            .is_empty());
+        println!("Key ranges move between repositories without losing writes.");
+    }
+
+    #[actix_rt::test]
+    async fn test_permissions() {
+        let mut permissions = Permissions::default();
+        permissions.grant(
+            "billing",
+            Grant {
+                keys: Some(1..3),
+                permissions: vec![Permission::Read, Permission::Write],
+            },
+        );
+        let customer = Repository::new("customer".to_string())
+            .with_permissions(permissions)
+            .start();
+        let product = Repository::new("product".to_string()).start();
+        let update = |key| {
+            vec![Operation::Statement(Statement::Update(
+                key,
+                Box::new(Expr::Value(Table(1, 1))),
+            ))]
+        };
+        let create = |key| {
+            vec![Operation::Statement(Statement::Create(
+                key,
+                Box::new(Expr::Value(Table(1, 1))),
+            ))]
+        };
+        let prepare_as =
+            |principal: &str, prepare| customer.send(PrepareAs(principal.to_string(), prepare));
+        let error = |result: anyhow::Result<Option<Table>>| result.unwrap_err().to_string();
+
+        let tid = Uuid::new_v4();
+        let args = Arguments {
+            timestamp: 0,
+            operations: create(1),
+        };
+        let vote = prepare_as("billing", MessagePrepare::Single(tid, args));
+        assert_eq!(vote.await.unwrap().unwrap(), CommitVote::InProgress);
+        assert!(customer.send(GetResult(tid)).await.unwrap().is_ok());
+
+        // Outside of the granted keys, or without the permission.
+        for (principal, operations) in [
+            ("billing", create(3)),
+            ("billing", vec![Operation::Expr(Expr::Delete(1))]),
+            ("shipping", vec![Operation::Expr(Expr::Read(1))]),
+        ] {
+            let tid = Uuid::new_v4();
+            let args = Arguments {
+                timestamp: 0,
+                operations,
+            };
+            let vote = prepare_as(principal, MessagePrepare::Single(tid, args));
+            assert_eq!(vote.await.unwrap().unwrap(), CommitVote::Abort);
+            let result = customer.send(GetResult(tid)).await.unwrap();
+            assert!(error(result).contains("permission denied"));
+        }
+
+        // A denied participant aborts a coordinated transaction.
+        let tid = Uuid::new_v4();
+        let args = |operations| Arguments {
+            timestamp: 0,
+            operations,
+        };
+        let denied = prepare_as("billing", MessagePrepare::Coord(tid, args(update(5)), 2));
+        assert_eq!(denied.await.unwrap().unwrap(), CommitVote::Abort);
+        let prepared = product.send(MessagePrepare::Coord(tid, args(create(5)), 2));
+        assert_eq!(prepared.await.unwrap().unwrap(), CommitVote::Commit(None));
+        for (repository, vote) in [
+            (&customer, CommitVote::Commit(None)),
+            (&product, CommitVote::Abort),
+        ] {
+            let accepted = repository.send(MessageAccept::Coord(tid, 0, vote, "customer".into()));
+            assert_eq!(accepted.await.unwrap().unwrap(), CommitVote::Abort);
+        }
+        let result = customer.send(GetResult(tid)).await.unwrap();
+        assert!(error(result).contains("permission denied"));
+        let result = product.send(GetResult(tid)).await.unwrap();
+        assert!(error(result).contains("another repository"));
+        println!("Principals only run the operations they were granted.");
+    }
+
+    #[actix_rt::test]
+    async fn test_metrics() {
+        let customer = Repository::new("customer".to_string()).start();
+        let product = Repository::new("product".to_string()).start();
+        let create = |key| {
+            vec![Operation::Statement(Statement::Create(
+                key,
+                Box::new(Expr::Value(Table(1, 1))),
+            ))]
+        };
+
+        let single = Application::txn().on(&customer, create(1)).run().await;
+        assert!(single.is_ok(), "{single:?}");
+        let coord = Application::txn()
+            .on(&customer, create(2))
+            .on(&product, create(2))
+            .coordinated()
+            .run()
+            .await;
+        assert!(coord.is_ok(), "{coord:?}");
+        // Key 5 of the product doesn't exist.
+        let aborted = Application::txn()
+            .on(&customer, create(3))
+            .on(&product, vec![Operation::Expr(Expr::Read(5))])
+            .coordinated()
+            .run()
+            .await;
+        assert!(aborted.is_err());
+
+        let metrics = customer.send(GetMetrics).await.unwrap();
+        let transactions: Vec<_> = metrics.transactions.into_iter().collect();
+        assert_eq!(
+            transactions,
+            vec![
+                (("coord", "abort"), 1),
+                (("coord", "commit"), 1),
+                (("single", "commit"), 1),
+            ]
+        );
+        assert_eq!(metrics.commit_latency["single"].count, 1);
+        assert_eq!(metrics.commit_latency["coord"].count, 1);
+        assert_eq!(metrics.active_transactions, 0);
+        assert_eq!(metrics.locked_keys, 0);
+        assert!(metrics.durable_writes.count >= 2);
+        println!("Repositories count their transactions by kind and outcome.");
+    }
+
+    #[actix_rt::test]
+    async fn test_introspection() {
+        let mut runtime = Runtime::new();
+        let (customer, _product) = create_customer_product_tables(&mut runtime).await;
+
+        let tid = Uuid::new_v4();
+        let args = Arguments {
+            timestamp: runtime.now(),
+            operations: vec![Operation::Statement(Statement::Update(
+                2,
+                Box::new(Expr::Read(1)),
+            ))],
+        };
+        let vote = customer.send(MessagePrepare::Coord(tid, args, 2)).await;
+        assert_eq!(vote.unwrap().unwrap(), CommitVote::Commit(None));
+
+        let introspection = customer.send(Introspect).await.unwrap();
+        assert_eq!(introspection.mode, Mode::Locking);
+        let [pending] = &introspection.pending[..] else {
+            panic!("{introspection:?}");
+        };
+        assert_eq!(pending.tid, tid);
+        assert_eq!(pending.kind.as_deref(), Some("coord"));
+        assert_eq!(pending.waiting_for, 2);
+        assert_eq!(pending.locks, vec![1, 2]);
+        assert!(introspection.recent_aborts.is_empty());
+
+        let accepted = customer.send(MessageAccept::Coord(
+            tid,
+            0,
+            CommitVote::Abort,
+            "product".into(),
+        ));
+        assert_eq!(accepted.await.unwrap().unwrap(), CommitVote::Abort);
+
+        let introspection = customer.send(Introspect).await.unwrap();
+        assert_eq!(introspection.mode, Mode::Timestamp);
+        assert!(introspection.pending.is_empty());
+        assert_eq!(introspection.recent_aborts[0].tid, tid);
+        assert!(introspection.recent_aborts[0]
+            .error
+            .contains("another repository"));
+        println!("Repositories tell their pending transactions and locks.");
+    }
+
+    #[actix_rt::test]
+    async fn test_coord_abort_keeps_other_locks() {
+        let mut runtime = Runtime::new();
+        let (customer, _product) = create_customer_product_tables(&mut runtime).await;
+
+        let update = |key| Arguments {
+            timestamp: 0,
+            operations: vec![Operation::Statement(Statement::Update(
+                key,
+                Box::new(Expr::Value(Table(10, 10))),
+            ))],
+        };
+        let (aborted, pending) = (Uuid::new_v4(), Uuid::new_v4());
+        for (tid, key) in [(aborted, 1), (pending, 2)] {
+            let vote = customer
+                .send(MessagePrepare::Coord(tid, update(key), 2))
+                .await;
+            assert_eq!(vote.unwrap().unwrap(), CommitVote::Commit(None));
+        }
+
+        let accepted = customer.send(MessageAccept::Coord(
+            aborted,
+            0,
+            CommitVote::Abort,
+            "product".into(),
+        ));
+        assert_eq!(accepted.await.unwrap().unwrap(), CommitVote::Abort);
+
+        let introspection = customer.send(Introspect).await.unwrap();
+        let [still_pending] = &introspection.pending[..] else {
+            panic!("{introspection:?}");
+        };
+        assert_eq!(still_pending.tid, pending);
+        assert_eq!(still_pending.locks, vec![2]);
+
+        let vote = customer
+            .send(MessagePrepare::Coord(Uuid::new_v4(), update(2), 2))
+            .await;
+        assert_eq!(vote.unwrap().unwrap(), CommitVote::Conflict);
+        let vote = customer
+            .send(MessagePrepare::Coord(Uuid::new_v4(), update(1), 2))
+            .await;
+        assert_eq!(vote.unwrap().unwrap(), CommitVote::Commit(None));
+        println!("Aborting a coordinated transaction keeps the locks of the others.");
+    }
+
+    #[actix_rt::test]
+    async fn test_operator_abort_and_checkpoint() {
+        let mut runtime = Runtime::new();
+        let (customer, _product) = create_customer_product_tables(&mut runtime).await;
+
+        // A coordinated transaction whose other participant never answers.
+        let tid = Uuid::new_v4();
+        let args = Arguments {
+            timestamp: runtime.now(),
+            operations: vec![Operation::Statement(Statement::Update(
+                2,
+                Box::new(Expr::Read(1)),
+            ))],
+        };
+        let vote = customer.send(MessagePrepare::Coord(tid, args, 2)).await;
+        assert_eq!(vote.unwrap().unwrap(), CommitVote::Commit(None));
+
+        customer.send(AbortTransaction(tid)).await.unwrap().unwrap();
+        let introspection = customer.send(Introspect).await.unwrap();
+        assert_eq!(introspection.mode, Mode::Timestamp);
+        assert!(introspection.pending.is_empty());
+        let result = customer.send(GetResult(tid)).await.unwrap();
+        assert!(format!("{:#}", result.unwrap_err()).contains("aborted by an operator"));
+
+        let again = customer.send(AbortTransaction(tid)).await.unwrap();
+        assert!(again.unwrap_err().to_string().contains("already finished"));
+        let unknown = customer
+            .send(AbortTransaction(Uuid::new_v4()))
+            .await
+            .unwrap();
+        assert!(unknown.unwrap_err().to_string().contains("no pending"));
+
+        let checkpoint = customer.send(TakeCheckpoint).await.unwrap().unwrap();
+        assert_eq!(checkpoint.keys, 3);
+        let written = std::fs::read_to_string(&checkpoint.path).unwrap();
+        assert_eq!(written.lines().count(), 4);
+        assert!(written.contains("2, Table(2, 2)"));
+        println!("Operators abort stuck transactions and take checkpoints.");
+    }
+
+    #[actix_rt::test]
+    async fn test_participants_notified_by_the_principal() {
+        let mut runtime = Runtime::new();
+        let (customer, _product) = create_customer_product_tables(&mut runtime).await;
+
+        let tid = Uuid::new_v4();
+        let args = Arguments {
+            timestamp: runtime.now(),
+            operations: vec![Operation::Statement(Statement::Update(
+                1,
+                Box::new(Expr::Value(Table(10, 10))),
+            ))],
+        };
+        let msg = MessagePrepare::Coord(tid, args, 2);
+        let vote = customer.send(PrepareAs("billing".to_string(), msg)).await;
+        assert_eq!(vote.unwrap().unwrap(), CommitVote::Commit(None));
+
+        let notify = || MessagePrepare::CoordParticipants(tid, CommitVote::Commit(None), vec![]);
+        let other = customer
+            .send(PrepareAs("audit".to_string(), notify()))
+            .await;
+        let e = other.unwrap().unwrap_err();
+        assert!(e.to_string().contains("wasn't prepared for this principal"));
+        let anonymous = customer.send(notify()).await.unwrap();
+        assert!(anonymous.is_err());
+
+        let vote = customer
+            .send(PrepareAs("billing".to_string(), notify()))
+            .await;
+        assert_eq!(vote.unwrap().unwrap(), CommitVote::InProgress);
+    }
+
+    #[actix_rt::test]
+    async fn test_operator_abort_of_a_coord() {
+        let mut runtime = Runtime::new();
+        let (customer, product) = create_customer_product_tables(&mut runtime).await;
+        let timestamp = runtime.now();
+        let coord = |tid, key| {
+            let args = Arguments {
+                timestamp,
+                operations: vec![Operation::Statement(Statement::Update(
+                    key,
+                    Box::new(Expr::Value(Table(10, 10))),
+                ))],
+            };
+            MessagePrepare::Coord(tid, args, 2)
+        };
+        let notify = |tid, participants| {
+            MessagePrepare::CoordParticipants(tid, CommitVote::Commit(None), participants)
+        };
+
+        // The other participants may commit it already.
+        let accepted = Uuid::new_v4();
+        let vote = customer.send(coord(accepted, 1)).await.unwrap();
+        assert_eq!(vote.unwrap(), CommitVote::Commit(None));
+        let vote = customer.send(notify(accepted, vec![])).await.unwrap();
+        assert_eq!(vote.unwrap(), CommitVote::InProgress);
+        let refused = customer.send(AbortTransaction(accepted)).await.unwrap();
+        assert!(refused.unwrap_err().to_string().contains("may commit it"));
+
+        let tid = Uuid::new_v4();
+        let vote = customer.send(coord(tid, 2)).await.unwrap();
+        assert_eq!(vote.unwrap(), CommitVote::Commit(None));
+        let vote = product.send(coord(tid, 1)).await.unwrap();
+        assert_eq!(vote.unwrap(), CommitVote::Commit(None));
+        customer.send(AbortTransaction(tid)).await.unwrap().unwrap();
+        let vote = customer.send(coord(tid, 2)).await.unwrap();
+        assert_eq!(vote.unwrap(), CommitVote::Abort);
+        // The client is back: the product is told it aborted.
+        let _ = customer
+            .send(notify(tid, vec![product.clone()]))
+            .await
+            .unwrap();
+        let result = product.send(GetResult(tid)).await.unwrap();
+        assert!(format!("{:#}", result.unwrap_err()).contains("another repository"));
+    }
+}
@@ -1,23 +1,58 @@
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ops::Range,
+};
 use uuid::Uuid;
 
-use crate::operations::{Expr, Operation, PrimaryKey, Statement, Table};
+use crate::{
+    messages::{ChangeEvent, CommitVote},
+    operations::{Expr, Operation, PrimaryKey, Statement, Table},
+};
+
+/// Every committed value of a key, by commit timestamp. `None` marks a delete.
+pub(crate) type Versions = BTreeMap<PrimaryKey, BTreeMap<usize, Option<Table>>>;
 
 #[derive(Debug, Clone)]
 pub(crate) struct Database {
+    /// Latest value of each key.
     pub(crate) data_structure: BTreeMap<PrimaryKey, Table>,
+    /// Older values of each key, for reads at a timestamp.
+    pub(crate) versions: Versions,
+    /// Versions older than this may have been garbage collected.
+    pub(crate) low_watermark: usize,
     pub(crate) active_transactions: BTreeMap<Uuid, Transaction>,
     pub(crate) locked_keys: HashSet<PrimaryKey>,
+    /// The keys locked by each transaction holding locks.
+    pub(crate) lock_holders: HashMap<Uuid, Vec<PrimaryKey>>,
     pub(crate) tid_to_ts_end_xaction_ends: HashMap<Uuid, usize>,
+    /// Timestamp of the latest transaction applied to `data_structure`.
+    pub(crate) last_executed_ts: usize,
+    /// Changes applied to `data_structure` not yet published.
+    pub(crate) changes: Vec<ChangeEvent>,
+    /// Key ranges moved to another repository, no longer served here.
+    pub(crate) moved_out: Vec<Range<PrimaryKey>>,
+    /// Key ranges being moved here, not served until they are imported.
+    pub(crate) moving_in: Vec<Range<PrimaryKey>>,
+    /// The ranges of `moving_in` a move acquired, which are only dropped
+    /// once released again.
+    pub(crate) acquired: Vec<Range<PrimaryKey>>,
 }
 
 impl Database {
     pub(crate) fn new() -> Self {
         Database {
             data_structure: BTreeMap::new(),
+            versions: BTreeMap::new(),
+            low_watermark: 0,
             active_transactions: BTreeMap::new(),
             locked_keys: HashSet::new(),
+            lock_holders: HashMap::new(),
             tid_to_ts_end_xaction_ends: HashMap::new(),
+            last_executed_ts: 0,
+            changes: vec![],
+            moved_out: vec![],
+            moving_in: vec![],
+            acquired: vec![],
         }
     }
 
@@ -32,10 +67,17 @@ impl Database {
         self.active_transactions.insert(*tid, xaction);
     }
 
-    pub(crate) fn decrement_reply_count(&mut self, tid: &Uuid) {
-        self.active_transactions
-            .entry(*tid)
-            .and_modify(|xaction| xaction.waiting_for -= 1);
+    /// Count the accept of `participant` for `tid`. Returns `false` if it
+    /// isn't waited for, e.g. it was already counted.
+    pub(crate) fn decrement_reply_count(&mut self, tid: &Uuid, participant: &str) -> bool {
+        let Some(xaction) = self.active_transactions.get_mut(tid) else {
+            return false;
+        };
+        if xaction.waiting_for == 0 || !xaction.accepted_from.insert(participant.to_string()) {
+            return false;
+        }
+        xaction.waiting_for -= 1;
+        true
     }
 
     // XXX: This could have a better naming...
@@ -57,86 +99,138 @@ impl Database {
         self.tid_to_ts_end_xaction_ends.insert(*tid, ts);
     }
 
-    pub(crate) fn get_proposed_ts_for_tid(&self, tid: &Uuid) -> usize {
+    /// The timestamp of `tid`, if it is pending or its end is still known.
+    pub(crate) fn get_proposed_ts_for_tid(&self, tid: &Uuid) -> Option<usize> {
         if let Some(xaction) = self.active_transactions.get(tid) {
-            return xaction.proposed_ts;
-        }
-        if let Some(ts) = self.tid_to_ts_end_xaction_ends.get(tid) {
-            return *ts;
+            return Some(xaction.proposed_ts);
         }
-        unreachable!()
+        self.tid_to_ts_end_xaction_ends.get(tid).copied()
     }
 
     pub(crate) fn set_next_to_run(&mut self) -> Option<Uuid> {
         let mut next_to_run_tid = None;
-        let mut smaller_ts = usize::max_value();
+        let mut smaller_ts = usize::MAX;
         for xaction in &mut self.active_transactions {
             xaction.1.next_to_run = false;
             if xaction.1.proposed_ts < smaller_ts && xaction.1.waiting_for == 0 {
-                next_to_run_tid = Some(xaction.0.clone());
-                smaller_ts = xaction.1.proposed_ts.clone();
+                next_to_run_tid = Some(*xaction.0);
+                smaller_ts = xaction.1.proposed_ts;
             }
         }
 
-        next_to_run_tid.and_then(|next_tid| {
+        next_to_run_tid.inspect(|next_tid| {
             self.active_transactions
-                .entry(next_tid)
+                .entry(*next_tid)
                 .and_modify(|xaction| xaction.next_to_run = true);
-            Some(next_tid.clone())
         })
     }
 
-    fn check_for_problems_per_operation(&self, op: &Operation) -> bool {
+    /// Whether `key` can't be used here because it was (or is being) moved.
+    fn ownership_problem(&self, key: &PrimaryKey) -> Option<Problem> {
+        if self.moved_out.iter().any(|range| range.contains(key)) {
+            return Some(Problem::Moved);
+        }
+        if self.moving_in.iter().any(|range| range.contains(key)) {
+            return Some(Problem::LockedKey);
+        }
+        None
+    }
+
+    /// Like [`Database::ownership_problem`], for every key of `operations`.
+    pub(crate) fn check_ownership(&self, operations: &[Operation]) -> Option<Problem> {
+        operations
+            .iter()
+            .flat_map(Operation::keys)
+            .find_map(|key| self.ownership_problem(&key))
+    }
+
+    fn check_for_problems_per_operation(&self, op: &Operation) -> Option<Problem> {
+        if let Some(problem) = op.keys().iter().find_map(|key| self.ownership_problem(key)) {
+            return Some(problem);
+        }
         match op {
+            Operation::Statement(Statement::Release(range)) => {
+                if self.moved_out.iter().any(|moved| overlaps(moved, range)) {
+                    return Some(Problem::Moved);
+                }
+                if self.locked_keys.iter().any(|key| range.contains(key)) {
+                    return Some(Problem::LockedKey);
+                }
+            }
+            Operation::Statement(Statement::Acquire(range)) => {
+                // Moving part of a range back isn't supported.
+                if self
+                    .moved_out
+                    .iter()
+                    .any(|moved| overlaps(moved, range) && !contains(range, moved))
+                {
+                    return Some(Problem::Moved);
+                }
+            }
             Operation::Statement(Statement::Create(key, expr)) => {
                 if self.locked_keys.contains(key) {
-                    return true;
+                    return Some(Problem::LockedKey);
                 }
                 return self.check_for_problems_per_operation(&Operation::Expr((**expr).clone()));
             }
             Operation::Expr(Expr::Read(key)) => {
                 if self.locked_keys.contains(key) {
-                    return true;
+                    return Some(Problem::LockedKey);
                 }
                 if !self.data_structure.contains_key(key) {
-                    return true;
+                    return Some(Problem::MissingKey);
                 }
             }
             Operation::Statement(Statement::Update(key, expr)) => {
                 if self.locked_keys.contains(key) {
-                    return true;
+                    return Some(Problem::LockedKey);
                 }
 
                 return self.check_for_problems_per_operation(&Operation::Expr((**expr).clone()));
             }
+            Operation::Expr(Expr::ReadAt(key, ts)) => {
+                // Past versions are immutable, so locks don't matter here.
+                if *ts < self.low_watermark {
+                    return Some(Problem::MissingKey);
+                }
+                if self.read_at(key, *ts).is_err() {
+                    // Not stable yet, it will be.
+                    return Some(Problem::LockedKey);
+                }
+            }
             Operation::Expr(Expr::Delete(key)) => {
                 if self.locked_keys.contains(key) {
-                    return true;
+                    return Some(Problem::LockedKey);
                 }
                 if !self.data_structure.contains_key(key) {
-                    return true;
+                    return Some(Problem::MissingKey);
                 }
             }
             Operation::Expr(Expr::Value(_)) => (),
             Operation::Expr(Expr::Add(e1, e2)) | Operation::Expr(Expr::Sub(e1, e2)) => {
-                return self.check_for_problems_per_operation(&Operation::Expr((**e1).clone()))
-                    || self.check_for_problems_per_operation(&Operation::Expr((**e2).clone()));
+                return self
+                    .check_for_problems_per_operation(&Operation::Expr((**e1).clone()))
+                    .or_else(|| {
+                        self.check_for_problems_per_operation(&Operation::Expr((**e2).clone()))
+                    });
             }
         }
-        false
+        None
     }
 
     /// Check that all needed keys exist and are `free` (not held by a `Coord` transaction).
     pub(crate) fn check_for_conflicts_and_primary_key(&self, tid: &Uuid) -> bool {
-        if let Some(xaction) = self.active_transactions.get(tid) {
-            let operations = &xaction.operations;
-            for op in operations {
-                if self.check_for_problems_per_operation(op) {
-                    return true;
-                }
-            }
-        }
-        false
+        self.check_for_problems(tid).is_some()
+    }
+
+    /// Like [`Database::check_for_conflicts_and_primary_key`], telling which
+    /// problem was found first.
+    pub(crate) fn check_for_problems(&self, tid: &Uuid) -> Option<Problem> {
+        let xaction = self.active_transactions.get(tid)?;
+        xaction
+            .operations
+            .iter()
+            .find_map(|op| self.check_for_problems_per_operation(op))
     }
 
     pub fn get_lock_per_operation(&mut self, op: &Operation) {
@@ -155,7 +249,17 @@ impl Database {
             Operation::Expr(Expr::Delete(key)) => {
                 self.locked_keys.insert(*key);
             }
-            Operation::Expr(Expr::Value(_)) => (),
+            Operation::Statement(Statement::Release(range)) => {
+                let keys: Vec<PrimaryKey> = self
+                    .data_structure
+                    .range(range.clone())
+                    .map(|(k, _)| *k)
+                    .collect();
+                self.locked_keys.extend(keys);
+            }
+            Operation::Expr(Expr::Value(_))
+            | Operation::Expr(Expr::ReadAt(_, _))
+            | Operation::Statement(Statement::Acquire(_)) => (),
             Operation::Expr(Expr::Add(e1, e2)) | Operation::Expr(Expr::Sub(e1, e2)) => {
                 self.get_lock_per_operation(&Operation::Expr((**e1).clone()));
                 self.get_lock_per_operation(&Operation::Expr((**e2).clone()));
@@ -167,20 +271,34 @@ impl Database {
         if let Some(xaction) = self.active_transactions.get(tid) {
             // TODO: Do I really need to clone here?
             let operations = &xaction.operations.clone();
+            // Lock into an empty set first, to tell which keys `tid` locked.
+            let locked_before = std::mem::take(&mut self.locked_keys);
             for op in operations {
                 self.get_lock_per_operation(op);
             }
+            let mut locked: Vec<PrimaryKey> = self.locked_keys.iter().copied().collect();
+            locked.sort_unstable();
+            self.lock_holders.insert(*tid, locked);
+            self.locked_keys.extend(locked_before);
         }
     }
 
-    pub(crate) fn release_locks(&mut self) {
-        self.locked_keys.clear();
+    /// Releases only the locks held by `tid`.
+    pub(crate) fn release_locks_of(&mut self, tid: &Uuid) {
+        for key in self.lock_holders.remove(tid).unwrap_or_default() {
+            self.locked_keys.remove(&key);
+        }
     }
 
-    fn eval_operation(database: &mut BTreeMap<PrimaryKey, Table>, op: &Operation) -> Option<Table> {
+    fn eval_operation(
+        database: &mut BTreeMap<PrimaryKey, Table>,
+        versions: &Versions,
+        op: &Operation,
+    ) -> Option<Table> {
         match op {
             Operation::Statement(Statement::Create(key, expr)) => {
-                let value = Self::eval_operation(database, &Operation::Expr(*expr.to_owned()));
+                let value =
+                    Self::eval_operation(database, versions, &Operation::Expr(*expr.to_owned()));
                 log::info!("{:?}", value);
                 database.insert(
                     key.to_owned(),
@@ -189,8 +307,10 @@ impl Database {
             }
             // TODO: can I remove the cloned?
             Operation::Expr(Expr::Read(key)) => database.get(key).cloned(),
+            Operation::Expr(Expr::ReadAt(key, ts)) => Self::version_at(versions, key, *ts),
             Operation::Statement(Statement::Update(key, expr)) => {
-                let value = Self::eval_operation(database, &Operation::Expr(*expr.to_owned()));
+                let value =
+                    Self::eval_operation(database, versions, &Operation::Expr(*expr.to_owned()));
                 database
                     .entry(key.to_owned())
                     .and_modify(|v| {
@@ -202,26 +322,346 @@ impl Database {
                 value
             }
             Operation::Expr(Expr::Delete(key)) => database.remove(key),
+            // Applied by `Database::move_range`.
+            Operation::Statement(Statement::Release(_) | Statement::Acquire(_)) => None,
+            Operation::Expr(Expr::Value(value)) => Some(value.clone()),
+            Operation::Expr(Expr::Add(expr, rhs)) => Some(
+                Self::eval_operation(database, versions, &Operation::Expr(*expr.to_owned()))?
+                    + Self::eval_operation(database, versions, &Operation::Expr(*rhs.to_owned()))?,
+            ),
+            Operation::Expr(Expr::Sub(expr, rhs)) => Some(
+                Self::eval_operation(database, versions, &Operation::Expr(*expr.to_owned()))?
+                    - Self::eval_operation(database, versions, &Operation::Expr(*rhs.to_owned()))?,
+            ),
+        }
+    }
+
+    /// Evaluate a read-only [`Operation`] without touching `data_structure`.
+    /// With a `snapshot` timestamp, every `Read` is done as of that timestamp.
+    fn eval_read_only(&self, op: &Operation, snapshot: Option<usize>) -> Option<Table> {
+        match op {
+            Operation::Expr(Expr::Read(key)) => match snapshot {
+                Some(ts) => Self::version_at(&self.versions, key, ts),
+                None => self.data_structure.get(key).cloned(),
+            },
+            Operation::Expr(Expr::ReadAt(key, ts)) => Self::version_at(&self.versions, key, *ts),
             Operation::Expr(Expr::Value(value)) => Some(value.clone()),
             Operation::Expr(Expr::Add(expr, rhs)) => Some(
-                Self::eval_operation(database, &Operation::Expr(*expr.to_owned()))?
-                    + Self::eval_operation(database, &Operation::Expr(*rhs.to_owned()))?,
+                self.eval_read_only(&Operation::Expr(*expr.to_owned()), snapshot)?
+                    + self.eval_read_only(&Operation::Expr(*rhs.to_owned()), snapshot)?,
             ),
             Operation::Expr(Expr::Sub(expr, rhs)) => Some(
-                Self::eval_operation(database, &Operation::Expr(*expr.to_owned()))?
-                    - Self::eval_operation(database, &Operation::Expr(*rhs.to_owned()))?,
+                self.eval_read_only(&Operation::Expr(*expr.to_owned()), snapshot)?
+                    - self.eval_read_only(&Operation::Expr(*rhs.to_owned()), snapshot)?,
             ),
+            Operation::Expr(Expr::Delete(_)) | Operation::Statement(_) => {
+                unreachable!("eval_read_only called with a write operation.")
+            }
+        }
+    }
+
+    /// Latest version of `key` committed at or before `ts`.
+    fn version_at(versions: &Versions, key: &PrimaryKey, ts: usize) -> Option<Table> {
+        versions
+            .get(key)?
+            .range(..=ts)
+            .next_back()
+            .and_then(|(_, value)| value.clone())
+    }
+
+    /// Read the value of `key` at timestamp `ts`.
+    ///
+    /// Fails if `ts` isn't stable yet (a pending transaction is ordered at or
+    /// before it) or if its versions were already garbage collected.
+    pub(crate) fn read_at(&self, key: &PrimaryKey, ts: usize) -> anyhow::Result<Option<Table>> {
+        self.check_snapshot_ts(ts)?;
+        Ok(Self::version_at(&self.versions, key, ts))
+    }
+
+    /// Check that the versions visible at `ts` are all known and kept.
+    ///
+    /// A `ts` later than `last_executed_ts` is fine, as long as the caller
+    /// makes sure transactions proposed from now on are ordered after it.
+    fn check_snapshot_ts(&self, ts: usize) -> anyhow::Result<()> {
+        if ts < self.low_watermark {
+            anyhow::bail!(
+                "timestamp {ts} is below the low watermark {}",
+                self.low_watermark
+            );
+        }
+        if self
+            .active_transactions
+            .values()
+            .any(|xaction| xaction.proposed_ts <= ts)
+        {
+            anyhow::bail!("timestamp {ts} is not stable yet");
+        }
+        Ok(())
+    }
+
+    /// Return the timestamp at which a read-only transaction can be served
+    /// right now, if any.
+    ///
+    /// `data_structure` reflects exactly the transactions executed up to
+    /// `last_executed_ts`, so a read at that timestamp is serializable as long
+    /// as no pending transaction is ordered before it. Pending `proposed_ts`
+    /// only ever grow, so this can't be invalidated later.
+    pub(crate) fn read_only_ts(&self) -> Option<usize> {
+        let read_ts = self.last_executed_ts;
+        self.is_stable(read_ts).then_some(read_ts)
+    }
+
+    /// Whether every transaction ordered at or before `ts` was executed, so
+    /// the snapshot at `ts` won't change anymore, as long as transactions
+    /// proposed from now on are ordered after it.
+    pub(crate) fn is_stable(&self, ts: usize) -> bool {
+        self.active_transactions
+            .values()
+            .all(|xaction| xaction.proposed_ts > ts)
+    }
+
+    /// Like [`Database::check_for_problems`], for read-only `operations`
+    /// served from a snapshot: locks don't matter, only moved or missing keys.
+    pub(crate) fn check_read_only(&self, operations: &[Operation]) -> Option<Problem> {
+        fn reads_missing_key(database: &Database, expr: &Expr) -> bool {
+            match expr {
+                Expr::Read(key) => !database.data_structure.contains_key(key),
+                Expr::Add(e1, e2) | Expr::Sub(e1, e2) => {
+                    reads_missing_key(database, e1) || reads_missing_key(database, e2)
+                }
+                Expr::Value(_) | Expr::ReadAt(_, _) | Expr::Delete(_) => false,
+            }
         }
+
+        self.check_ownership(operations).or_else(|| {
+            operations
+                .iter()
+                .any(|op| matches!(op, Operation::Expr(expr) if reads_missing_key(self, expr)))
+                .then_some(Problem::MissingKey)
+        })
+    }
+
+    /// Run read-only `operations` against the current state. Doesn't need
+    /// (nor take) any lock.
+    pub(crate) fn run_read_only(&self, operations: &[Operation]) -> Option<Table> {
+        let mut result = vec![];
+        for op in operations {
+            result.push(self.eval_read_only(op, None));
+        }
+        result.last()?.clone()
+    }
+
+    /// Run read-only `operations` against the snapshot of the database at
+    /// timestamp `ts`.
+    pub(crate) fn run_snapshot(
+        &self,
+        operations: &[Operation],
+        ts: usize,
+    ) -> anyhow::Result<Option<Table>> {
+        self.check_snapshot_ts(ts)?;
+        let mut result = vec![];
+        for op in operations {
+            result.push(self.eval_read_only(op, Some(ts)));
+        }
+        Ok(result.last().cloned().flatten())
+    }
+
+    /// Drop every version no longer visible at or after `low_watermark`.
+    ///
+    /// For each key, the latest version committed at or before the watermark
+    /// is kept (reads at the watermark still need it), unless it is a delete.
+    pub(crate) fn collect_garbage(&mut self, low_watermark: usize) {
+        if low_watermark <= self.low_watermark {
+            return;
+        }
+        self.low_watermark = low_watermark;
+
+        self.versions.retain(|_, key_versions| {
+            let mut newer = key_versions.split_off(&(low_watermark + 1));
+            if let Some((ts, Some(value))) = key_versions.pop_last() {
+                newer.insert(ts, Some(value));
+            }
+            *key_versions = newer;
+            !key_versions.is_empty()
+        });
+    }
+
+    /// Keys written by `op`.
+    fn written_keys(op: &Operation, keys: &mut Vec<PrimaryKey>) {
+        match op {
+            Operation::Statement(Statement::Create(key, expr))
+            | Operation::Statement(Statement::Update(key, expr)) => {
+                keys.push(*key);
+                Self::written_keys(&Operation::Expr((**expr).clone()), keys);
+            }
+            Operation::Expr(Expr::Delete(key)) => keys.push(*key),
+            Operation::Expr(Expr::Add(e1, e2)) | Operation::Expr(Expr::Sub(e1, e2)) => {
+                Self::written_keys(&Operation::Expr((**e1).clone()), keys);
+                Self::written_keys(&Operation::Expr((**e2).clone()), keys);
+            }
+            Operation::Expr(Expr::Read(_))
+            | Operation::Expr(Expr::ReadAt(_, _))
+            | Operation::Expr(Expr::Value(_))
+            | Operation::Statement(Statement::Release(_) | Statement::Acquire(_)) => (),
+        }
+    }
+
+    /// Apply a change of ownership of a key range.
+    fn move_range(&mut self, op: &Operation) {
+        match op {
+            Operation::Statement(Statement::Release(range)) => {
+                self.moved_out.push(range.clone());
+                self.acquired.retain(|acquired| !contains(range, acquired));
+            }
+            Operation::Statement(Statement::Acquire(range)) => {
+                self.moved_out.retain(|moved| !contains(range, moved));
+                if !self.moving_in.iter().any(|moving| contains(moving, range)) {
+                    self.moving_in.push(range.clone());
+                }
+                self.acquired.push(range.clone());
+            }
+            _ => (),
+        }
+    }
+
+    /// Every key in `range`, with its value.
+    pub(crate) fn export_range(&self, range: &Range<PrimaryKey>) -> Vec<(PrimaryKey, Table)> {
+        self.data_structure
+            .range(range.clone())
+            .map(|(key, value)| (*key, value.clone()))
+            .collect()
+    }
+
+    /// Write the `entries` of a key range being moved here, `None` deleting a
+    /// key. The range starts being moved here if none of its keys is here.
+    ///
+    /// The writes are versioned and recorded as changes at the latest
+    /// executed timestamp, with the nil `tid`, since no transaction made them.
+    pub(crate) fn import_range(
+        &mut self,
+        range: &Range<PrimaryKey>,
+        entries: Vec<(PrimaryKey, Option<Table>)>,
+    ) -> anyhow::Result<()> {
+        if !self.moving_in.iter().any(|moving| contains(moving, range)) {
+            if self.data_structure.range(range.clone()).next().is_some() {
+                anyhow::bail!("keys {range:?} are served here");
+            }
+            self.moving_in.push(range.clone());
+        }
+        if let Some((key, _)) = entries.iter().find(|(key, _)| !range.contains(key)) {
+            anyhow::bail!("key {key} is not in {range:?}");
+        }
+        for (key, value) in entries {
+            let old_value = match &value {
+                Some(value) => self.data_structure.insert(key, value.clone()),
+                None => self.data_structure.remove(&key),
+            };
+            self.versions
+                .entry(key)
+                .or_default()
+                .insert(self.last_executed_ts, value.clone());
+            if old_value != value {
+                self.changes.push(ChangeEvent {
+                    commit_ts: self.last_executed_ts,
+                    tid: Uuid::nil(),
+                    key,
+                    old_value,
+                    new_value: value,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Start serving a key range moved here.
+    pub(crate) fn serve_range(&mut self, range: &Range<PrimaryKey>) -> anyhow::Result<()> {
+        let moving = self.moving_in.len();
+        self.moving_in.retain(|moving| !contains(range, moving));
+        if self.moving_in.len() == moving {
+            anyhow::bail!("keys {range:?} are not being moved here");
+        }
+        self.acquired.retain(|acquired| !contains(range, acquired));
+        Ok(())
+    }
+
+    /// Drop the keys of a range moved to another repository, whose move
+    /// here was rolled back, or imported here by a move that was abandoned
+    /// before acquiring it.
+    pub(crate) fn drop_range(&mut self, range: &Range<PrimaryKey>) -> anyhow::Result<()> {
+        let moved_out = self.moved_out.iter().any(|moved| contains(moved, range));
+        let abandoned = self.moving_in.iter().any(|moving| contains(moving, range))
+            && !self
+                .acquired
+                .iter()
+                .any(|acquired| overlaps(acquired, range));
+        if !moved_out && !abandoned {
+            anyhow::bail!("keys {range:?} weren't moved out, nor only imported");
+        }
+        self.moving_in.retain(|moving| !contains(range, moving));
+        let keys: Vec<PrimaryKey> = self
+            .data_structure
+            .range(range.clone())
+            .map(|(key, _)| *key)
+            .collect();
+        for key in keys {
+            self.data_structure.remove(&key);
+        }
+        self.versions.retain(|key, _| !range.contains(key));
+        Ok(())
     }
 
     pub(crate) fn run_operations(&mut self, tid: &Uuid) -> Option<Table> {
         let mut result = vec![];
         if let Some(xaction) = self.active_transactions.get(tid) {
             let operations = &xaction.operations;
+            let mut written = vec![];
+            for op in operations {
+                Self::written_keys(op, &mut written);
+            }
+            written.sort_unstable();
+            written.dedup();
+            let old_values: Vec<Option<Table>> = written
+                .iter()
+                .map(|key| self.data_structure.get(key).cloned())
+                .collect();
+
             for op in operations {
-                result.push(Self::eval_operation(&mut self.data_structure, op));
+                result.push(Self::eval_operation(
+                    &mut self.data_structure,
+                    &self.versions,
+                    op,
+                ));
+            }
+            let proposed_ts = xaction.proposed_ts;
+            let moves: Vec<Operation> = operations
+                .iter()
+                .filter(|op| {
+                    matches!(
+                        op,
+                        Operation::Statement(Statement::Release(_) | Statement::Acquire(_))
+                    )
+                })
+                .cloned()
+                .collect();
+            for op in &moves {
+                self.move_range(op);
+            }
+            for (key, old_value) in written.into_iter().zip(old_values) {
+                let new_value = self.data_structure.get(&key).cloned();
+                self.versions
+                    .entry(key)
+                    .or_default()
+                    .insert(proposed_ts, new_value.clone());
+                self.changes.push(ChangeEvent {
+                    commit_ts: proposed_ts,
+                    tid: *tid,
+                    key,
+                    old_value,
+                    new_value,
+                });
             }
-            self.finalize(tid, xaction.proposed_ts);
+            self.last_executed_ts = std::cmp::max(self.last_executed_ts, proposed_ts);
+            self.finalize(tid, proposed_ts);
         }
         result.last()?.clone()
     }
@@ -231,20 +671,53 @@ impl Database {
         let mut result = HashMap::new();
         while let Some(tid) = self.set_next_to_run() {
             if self.check_for_conflicts_and_primary_key(&tid) {
-                result.insert(tid.clone(), None);
+                result.insert(tid, None);
                 return result;
             }
-            result.insert(tid.clone(), self.run_operations(&tid));
+            result.insert(tid, self.run_operations(&tid));
         }
 
         result
     }
 }
 
+/// Why a transaction can't run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Problem {
+    /// A key is held by a `Coord` transaction (or not readable yet). It may
+    /// be free later.
+    LockedKey,
+    /// A key doesn't exist (or its version was garbage collected).
+    MissingKey,
+    /// A key was moved to another repository.
+    Moved,
+}
+
+fn overlaps(a: &Range<PrimaryKey>, b: &Range<PrimaryKey>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// Whether `outer` has every key of `inner`.
+fn contains(outer: &Range<PrimaryKey>, inner: &Range<PrimaryKey>) -> bool {
+    outer.start <= inner.start && inner.end <= outer.end
+}
+
+impl From<Problem> for CommitVote {
+    fn from(problem: Problem) -> Self {
+        match problem {
+            Problem::LockedKey => CommitVote::Conflict,
+            Problem::MissingKey => CommitVote::Abort,
+            Problem::Moved => CommitVote::Moved,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct Transaction {
     pub(crate) proposed_ts: usize,
     pub(crate) waiting_for: usize,
+    /// The participants whose accept was counted.
+    pub(crate) accepted_from: HashSet<String>,
     pub(crate) next_to_run: bool,
     pub(crate) operations: Vec<Operation>,
 }
@@ -259,12 +732,14 @@ impl Transaction {
         Transaction {
             proposed_ts,
             waiting_for,
+            accepted_from: HashSet::new(),
             next_to_run,
             operations,
         }
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -274,19 +749,32 @@ mod tests {
         let tid = Uuid::new_v4();
         let participants_len = 2;
         database.add_xaction(&tid, 0, vec![], participants_len);
-        database.decrement_reply_count(&tid);
+        database.decrement_reply_count(&tid, "customer");
         if let Some(xaction) = database.active_transactions.get(&tid) {
             assert_eq!(xaction.waiting_for, participants_len - 1);
         }
     }
 
+    #[test]
+    fn test_accept_counted_once_per_participant() {
+        let mut database = Database::new();
+        let tid = Uuid::new_v4();
+        database.add_xaction(&tid, 0, vec![], 2);
+        assert!(database.decrement_reply_count(&tid, "customer"));
+        assert!(!database.decrement_reply_count(&tid, "customer"));
+        assert_eq!(database.active_transactions[&tid].waiting_for, 1);
+        assert!(database.decrement_reply_count(&tid, "product"));
+        assert!(!database.decrement_reply_count(&tid, "order"));
+        assert_eq!(database.active_transactions[&tid].waiting_for, 0);
+    }
+
     #[test]
     fn test_set_next_to_run_waiting_0() {
         let mut database = Database::new();
         let tid = Uuid::new_v4();
         let participants_len = 1;
         database.add_xaction(&tid, 0, vec![], participants_len);
-        database.decrement_reply_count(&tid);
+        database.decrement_reply_count(&tid, "customer");
         let tid_next = database.set_next_to_run();
         assert_eq!(Some(tid), tid_next);
     }
@@ -297,7 +785,7 @@ mod tests {
         let tid = Uuid::new_v4();
         let participants_len = 2;
         database.add_xaction(&tid, 0, vec![], participants_len);
-        database.decrement_reply_count(&tid);
+        database.decrement_reply_count(&tid, "customer");
         let tid_next = database.set_next_to_run();
         assert_eq!(None, tid_next);
     }
@@ -406,4 +894,193 @@ mod tests {
         assert!(database.active_transactions.is_empty());
         assert_eq!(database.tid_to_ts_end_xaction_ends.get(&tid), Some(&1));
     }
+
+    #[test]
+    fn test_read_only_ts() {
+        let mut database = Database::new();
+        database.data_structure.insert(0, Table(0, 0));
+
+        let tid_0 = Uuid::new_v4();
+        database.add_xaction(&tid_0, 5, vec![Operation::Expr(Expr::Read(0))], 0);
+        database.run_nexts();
+        assert_eq!(database.read_only_ts(), Some(5));
+
+        // A pending transaction ordered after the last executed one doesn't matter.
+        let tid_1 = Uuid::new_v4();
+        database.add_xaction(&tid_1, 8, vec![], 1);
+        assert_eq!(database.read_only_ts(), Some(5));
+
+        // But one ordered before it does.
+        let tid_2 = Uuid::new_v4();
+        database.add_xaction(&tid_2, 3, vec![], 1);
+        assert_eq!(database.read_only_ts(), None);
+    }
+
+    #[test]
+    fn test_run_read_only_ignores_locks() {
+        let mut database = Database::new();
+        database.data_structure.insert(0, Table(1, 1));
+        database.locked_keys.insert(0);
+
+        let operations = vec![Operation::Expr(Expr::Add(
+            Box::new(Expr::Read(0)),
+            Box::new(Expr::Value(Table(1, 1))),
+        ))];
+        assert_eq!(database.run_read_only(&operations), Some(Table(2, 2)));
+        assert_eq!(database.data_structure.get(&0), Some(&Table(1, 1)));
+    }
+
+    #[test]
+    fn test_versions_and_read_at() {
+        let mut database = Database::new();
+
+        let tid_0 = Uuid::new_v4();
+        let create = Operation::Statement(Statement::Create(0, Box::new(Expr::Value(Table(1, 1)))));
+        database.add_xaction(&tid_0, 10, vec![create], 0);
+        let tid_1 = Uuid::new_v4();
+        let update = Operation::Statement(Statement::Update(0, Box::new(Expr::Value(Table(2, 2)))));
+        database.add_xaction(&tid_1, 20, vec![update], 0);
+        let tid_2 = Uuid::new_v4();
+        database.add_xaction(&tid_2, 30, vec![Operation::Expr(Expr::Delete(0))], 0);
+        database.run_nexts();
+
+        assert_eq!(database.read_at(&0, 5).unwrap(), None);
+        assert_eq!(database.read_at(&0, 10).unwrap(), Some(Table(1, 1)));
+        assert_eq!(database.read_at(&0, 25).unwrap(), Some(Table(2, 2)));
+        assert_eq!(database.read_at(&0, 30).unwrap(), None);
+
+        // Not stable yet.
+        let tid_3 = Uuid::new_v4();
+        database.add_xaction(&tid_3, 40, vec![], 1);
+        assert!(database.read_at(&0, 40).is_err());
+        assert!(database.read_at(&0, 39).is_ok());
+
+        let snapshot = vec![Operation::Expr(Expr::Read(0))];
+        assert_eq!(
+            database.run_snapshot(&snapshot, 15).unwrap(),
+            Some(Table(1, 1))
+        );
+    }
+
+    #[test]
+    fn test_changes() {
+        let mut database = Database::new();
+        database.data_structure.insert(0, Table(0, 0));
+        database.data_structure.insert(1, Table(1, 1));
+
+        let tid = Uuid::new_v4();
+        let increment = Expr::Add(Box::new(Expr::Read(0)), Box::new(Expr::Value(Table(1, 1))));
+        let operations = vec![
+            Operation::Statement(Statement::Update(0, Box::new(increment.clone()))),
+            Operation::Statement(Statement::Update(0, Box::new(increment))),
+            Operation::Expr(Expr::Delete(1)),
+            Operation::Statement(Statement::Create(2, Box::new(Expr::Value(Table(2, 2))))),
+        ];
+        database.add_xaction(&tid, 10, operations, 0);
+        database.run_nexts();
+
+        let change = |key, old_value, new_value| ChangeEvent {
+            commit_ts: 10,
+            tid,
+            key,
+            old_value,
+            new_value,
+        };
+        assert_eq!(
+            database.changes,
+            vec![
+                change(0, Some(Table(0, 0)), Some(Table(2, 2))),
+                change(1, Some(Table(1, 1)), None),
+                change(2, None, Some(Table(2, 2))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_import_range_changes() {
+        let mut database = Database::new();
+        database.last_executed_ts = 5;
+        let entries = vec![(1, Some(Table(1, 1))), (2, Some(Table(2, 2)))];
+        database.import_range(&(0..10), entries).unwrap();
+        let entries = vec![(1, Some(Table(1, 1))), (2, None)];
+        database.import_range(&(0..10), entries).unwrap();
+
+        let change = |key, old_value, new_value| ChangeEvent {
+            commit_ts: 5,
+            tid: Uuid::nil(),
+            key,
+            old_value,
+            new_value,
+        };
+        assert_eq!(
+            database.changes,
+            vec![
+                change(1, None, Some(Table(1, 1))),
+                change(2, None, Some(Table(2, 2))),
+                change(2, Some(Table(2, 2)), None),
+            ]
+        );
+        assert_eq!(database.read_at(&2, 5).unwrap(), None);
+        assert!(database.import_range(&(0..10), vec![(10, None)]).is_err());
+    }
+
+    #[test]
+    fn test_drop_abandoned_import() {
+        let mut database = Database::new();
+        let entries = || vec![(1, Some(Table(1, 1)))];
+        database.import_range(&(0..10), entries()).unwrap();
+        database.drop_range(&(0..10)).unwrap();
+        assert_eq!(database.export_range(&(0..10)), vec![]);
+        assert!(database.moving_in.is_empty());
+
+        // Once acquired, the keys are only dropped after releasing them.
+        database.import_range(&(0..10), entries()).unwrap();
+        let acquire = Operation::Statement(Statement::Acquire(0..10));
+        database.move_range(&acquire);
+        assert!(database.drop_range(&(0..10)).is_err());
+        database.move_range(&Operation::Statement(Statement::Release(0..10)));
+        database.drop_range(&(0..10)).unwrap();
+        assert!(database.drop_range(&(10..20)).is_err());
+    }
+
+    #[test]
+    fn test_collect_garbage() {
+        let mut database = Database::new();
+        for (ts, value) in [(10, 1), (20, 2), (30, 3)] {
+            let tid = Uuid::new_v4();
+            let update = Operation::Statement(Statement::Update(
+                0,
+                Box::new(Expr::Value(Table(value, value))),
+            ));
+            database.add_xaction(&tid, ts, vec![update], 0);
+        }
+        database.run_nexts();
+
+        database.collect_garbage(25);
+
+        assert_eq!(database.versions.get(&0).unwrap().len(), 2);
+        assert!(database.read_at(&0, 15).is_err());
+        assert_eq!(database.read_at(&0, 25).unwrap(), Some(Table(2, 2)));
+        assert_eq!(database.read_at(&0, 30).unwrap(), Some(Table(3, 3)));
+    }
+
+    #[test]
+    fn test_check_for_problems() {
+        let mut database = Database::new();
+        database.data_structure.insert(0, Table(0, 0));
+        let holder = Uuid::new_v4();
+        database.locked_keys.insert(0);
+        database.lock_holders.insert(holder, vec![0]);
+
+        let tid = Uuid::new_v4();
+        database.add_xaction(&tid, 0, vec![Operation::Expr(Expr::Read(1))], 0);
+        assert_eq!(database.check_for_problems(&tid), Some(Problem::MissingKey));
+
+        let tid = Uuid::new_v4();
+        database.add_xaction(&tid, 0, vec![Operation::Expr(Expr::Read(0))], 0);
+        assert_eq!(database.check_for_problems(&tid), Some(Problem::LockedKey));
+
+        database.release_locks_of(&holder);
+        assert_eq!(database.check_for_problems(&tid), None);
+    }
 }
@@ -0,0 +1,74 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::operations::PrimaryKey;
+
+/// How many aborted transactions a [crate::repository::Repository] remembers
+/// for [Introspection::recent_aborts].
+pub const RECENT_ABORTS: usize = 32;
+
+/// The state of a [crate::repository::Repository], see
+/// [crate::messages::Introspect].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Introspection {
+    /// The last used timestamp.
+    pub last_timestamp: usize,
+    /// The timestamp of the last transaction run.
+    pub last_executed_ts: usize,
+    pub low_watermark: usize,
+    pub mode: Mode,
+    /// The transactions not run yet, by `proposed_ts`.
+    pub pending: Vec<PendingTransaction>,
+    /// The latest aborted transactions, the last one first.
+    pub recent_aborts: Vec<Abort>,
+}
+
+/// The Granola mode of a repository.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Transactions are ordered by timestamp only.
+    Timestamp,
+    /// Coordinated transactions hold locks on keys.
+    Locking,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingTransaction {
+    pub tid: Uuid,
+    /// `single`, `indep` or `coord`, if the transaction was prepared here.
+    pub kind: Option<String>,
+    pub proposed_ts: usize,
+    /// How many participants it still waits the vote of.
+    pub waiting_for: usize,
+    /// The keys it holds locks on.
+    pub locks: Vec<PrimaryKey>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Abort {
+    pub tid: Uuid,
+    pub error: String,
+}
+
+/// The last [RECENT_ABORTS] aborted transactions.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RecentAborts(VecDeque<Abort>);
+
+impl RecentAborts {
+    pub(crate) fn push(&mut self, tid: Uuid, error: &anyhow::Error) {
+        if self.0.len() == RECENT_ABORTS {
+            self.0.pop_back();
+        }
+        self.0.push_front(Abort {
+            tid,
+            error: format!("{error:#}"),
+        });
+    }
+
+    pub(crate) fn to_vec(&self) -> Vec<Abort> {
+        self.0.iter().cloned().collect()
+    }
+}
@@ -1,9 +1,10 @@
-use crate::{operations::*, repository::Repository};
+use crate::{application::TransactionKind, operations::*, repository::Repository};
 use actix::{
     dev::{MessageResponse, OneshotSender},
     prelude::*,
 };
 use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, ops::Range};
 use uuid::Uuid;
 
 /// [actix::Message] for the the first `half` of the `2PhaseProtocol`.
@@ -12,6 +13,15 @@ use uuid::Uuid;
 pub enum MessagePrepare {
     /// For `single` repository transaction. Only one phase is needed.
     Single(Uuid, Arguments),
+    /// For `single` repository read-only transaction. Served right away at
+    /// the latest stable timestamp, without logging or taking locks.
+    /// [Single] transactions that only read are handled the same way.
+    ReadOnly(Uuid, Arguments),
+    /// For `single` repository read-only transaction over the snapshot of
+    /// the repository at the given timestamp. All `Read`s see the values
+    /// committed at or before it.
+    // tid, ops, snapshot timestamp
+    Snapshot(Uuid, Arguments, usize),
     /// For Indep repositories transaction.
     // tid, ops, participants.length()
     Indep(Uuid, Arguments, usize),
@@ -24,15 +34,71 @@ pub enum MessagePrepare {
     CoordParticipants(Uuid, CommitVote, Vec<Addr<Repository>>),
 }
 
-/// [actix::Message] for the second `half` of the `2PhaseProtocol`.
+impl MessagePrepare {
+    /// The `tid` of the transaction the message is about.
+    pub fn tid(&self) -> Uuid {
+        match self {
+            MessagePrepare::Single(tid, _)
+            | MessagePrepare::ReadOnly(tid, _)
+            | MessagePrepare::Snapshot(tid, _, _)
+            | MessagePrepare::Indep(tid, _, _)
+            | MessagePrepare::IndepParticipants(tid, _, _)
+            | MessagePrepare::Coord(tid, _, _)
+            | MessagePrepare::CoordParticipants(tid, _, _) => *tid,
+        }
+    }
+
+    /// The [Arguments] of the transaction, if the message has them.
+    pub fn arguments(&self) -> Option<&Arguments> {
+        match self {
+            MessagePrepare::Single(_, args)
+            | MessagePrepare::ReadOnly(_, args)
+            | MessagePrepare::Snapshot(_, args, _)
+            | MessagePrepare::Indep(_, args, _)
+            | MessagePrepare::Coord(_, args, _) => Some(args),
+            MessagePrepare::IndepParticipants(..) | MessagePrepare::CoordParticipants(..) => None,
+        }
+    }
+}
+
+/// [actix::Message] to prepare a transaction on behalf of a principal, which
+/// may only run the operations it was granted (see
+/// [crate::permissions::Permissions]).
 #[derive(Message, Debug)]
 #[rtype(result = "Result<CommitVote, anyhow::Error>")]
+pub struct PrepareAs(pub String, pub MessagePrepare);
+
+/// [actix::Message] to handle a message within a [tracing::Span], e.g. the
+/// span of the request it came in, so the spans of the `Repository` are
+/// part of the trace of the transaction.
+#[derive(Debug)]
+pub struct Traced<M>(pub tracing::Span, pub M);
+
+impl<M: Message> Message for Traced<M> {
+    type Result = M::Result;
+}
+
+/// [actix::Message] for the second `half` of the `2PhaseProtocol`.
+///
+/// Carries the name of the participant sending it: a participant's accept is
+/// only counted once, however many times it is received.
+#[derive(Message, Debug, Clone)]
+#[rtype(result = "Result<CommitVote, anyhow::Error>")]
 pub enum MessageAccept {
     /// For independent repositories transactions.
-    // tid, proposed_ts
-    Indep(Uuid, usize, CommitVote),
+    // tid, proposed_ts, vote, participant
+    Indep(Uuid, usize, CommitVote, String),
     /// For Coordinated repositories transactions.
-    Coord(Uuid, usize, CommitVote),
+    Coord(Uuid, usize, CommitVote, String),
+}
+
+impl MessageAccept {
+    /// The vote of the participant sending it.
+    pub fn vote(&self) -> &CommitVote {
+        match self {
+            MessageAccept::Indep(_, _, vote, _) | MessageAccept::Coord(_, _, vote, _) => vote,
+        }
+    }
 }
 
 /// [actix::Message] to `get` the result for a given `tid`.
@@ -40,22 +106,237 @@ pub enum MessageAccept {
 #[rtype(result = "Result<Option<Table>, anyhow::Error>")]
 pub struct GetResult(pub Uuid);
 
-/// [actix::Message] to `get` current proposed timestamp for a given `tid`.
-/// Only needed for `RepositoryWs`.
+/// [actix::Message] to raise the `low watermark` of a repository. Versions
+/// no longer visible at or after it are garbage collected, and reads below it
+/// fail.
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct SetLowWatermark(pub usize);
+
+/// [actix::Message] to forget the results of the transactions prepared
+/// more than the given time ago, once they finished. [GetResult] fails for
+/// them afterwards, and preparing them again runs them as new transactions.
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct ForgetResults(pub std::time::Duration);
+
+/// A committed change to a key, published to [Subscribe]rs.
+///
+/// `None` as `old_value` means the key was created, `None` as `new_value`
+/// means it was deleted. A transaction writing the same key more than once
+/// produces a single event. Keys moved here by [ImportRange] have the nil
+/// `tid`.
+#[derive(Message, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[rtype(result = "()")]
+pub struct ChangeEvent {
+    pub commit_ts: usize,
+    pub tid: Uuid,
+    pub key: PrimaryKey,
+    pub old_value: Option<Table>,
+    pub new_value: Option<Table>,
+}
+
+/// [actix::Message] to subscribe to the [ChangeEvent]s of a repository.
+///
+/// Every change committed at or after `from_ts` is replayed in commit order,
+/// then new ones are sent as they are committed, still in commit order: a
+/// change is only sent once no pending transaction may commit before it. So
+/// resuming from the timestamp after the last change seen misses none.
+/// Fails if `from_ts` is below the low watermark, since that history is gone.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), anyhow::Error>")]
+pub struct Subscribe {
+    pub from_ts: usize,
+    pub subscriber: Recipient<ChangeEvent>,
+}
+
+/// [actix::Message] to read every key of a range, to move it to another
+/// repository.
+///
+/// Keys are moved with a `Coord` transaction that releases them
+/// ([Statement::Release]) at the repository they are moved from and
+/// acquires them ([Statement::Acquire]) at the one they are moved to. Once
+/// it commits they can be exported for the last time, since nothing can
+/// change them anymore.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Vec<(PrimaryKey, Table)>, anyhow::Error>")]
+pub struct ExportRange(pub Range<PrimaryKey>);
+
+/// [actix::Message] to write the keys of a range being moved here, `None`
+/// deleting a key. Until [ServeRange], transactions on the range are
+/// refused as if its keys were locked.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), anyhow::Error>")]
+pub struct ImportRange(pub Range<PrimaryKey>, pub Vec<(PrimaryKey, Option<Table>)>);
+
+/// [actix::Message] to start serving a key range moved here.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), anyhow::Error>")]
+pub struct ServeRange(pub Range<PrimaryKey>);
+
+/// [actix::Message] to drop the keys of a range moved to another repository,
+/// or imported by a move that was abandoned.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), anyhow::Error>")]
+pub struct DropRange(pub Range<PrimaryKey>);
+
+/// [actix::Message] to get the accept of a transaction, for a
+/// `RepositoryWs` to send it to the other participants over its own
+/// connections. Like [MessagePrepare::IndepParticipants] and
+/// [MessagePrepare::CoordParticipants], the accept is built once per `tid`:
+/// a repeated notification gets the same one, to send to the participants
+/// that didn't acknowledge it yet (see [AcceptDelivered]).
+///
+/// It is refused from another principal than the one the transaction was
+/// prepared for. The accept carries the vote this repository prepared the
+/// transaction with: the vote of the client can only turn a `Commit` into a
+/// `Conflict` or an `Abort`, e.g. when another participant couldn't be
+/// reached.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Notification, anyhow::Error>")]
+// tid, kind, vote, principal
+pub struct NotifyParticipants(
+    pub Uuid,
+    pub TransactionKind,
+    pub CommitVote,
+    pub Option<String>,
+);
+
+/// The answer to a [NotifyParticipants].
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub accept: MessageAccept,
+    /// The participants, by uri, that acknowledged the accept already.
+    pub delivered_to: HashSet<String>,
+}
+
+/// [actix::Message] to record the participant at the uri acknowledged the
+/// accept of a transaction, so it isn't sent there again.
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+// tid, uri
+pub struct AcceptDelivered(pub Uuid, pub String);
+
+/// [actix::Message] to get the [crate::metrics::Metrics] of a `Repository`.
+#[derive(Message, Debug)]
+#[rtype(result = "crate::metrics::Metrics")]
+pub struct GetMetrics;
+
+/// [actix::Message] to get the [crate::introspection::Introspection] of a
+/// `Repository`.
 #[derive(Message, Debug)]
-#[rtype(result = "usize")]
-pub struct GetProposedTs(pub Uuid);
+#[rtype(result = "crate::introspection::Introspection")]
+pub struct Introspect;
+
+/// [actix::Message] to check the log of a `Repository` can be written. The
+/// log isn't replayed when a repository starts, so there is nothing else to
+/// recover.
+#[derive(Message, Debug)]
+#[rtype(result = "anyhow::Result<()>")]
+pub struct CheckLog;
+
+/// [actix::Message] for an operator to abort a pending transaction, e.g. a
+/// coordinated transaction stuck holding locks because a participant or the
+/// client is gone. The other participants are sent `Abort` when they are
+/// notified of it. It is refused once they were sent a `Commit`, since they
+/// may have committed it then.
+#[derive(Message, Debug)]
+#[rtype(result = "anyhow::Result<()>")]
+pub struct AbortTransaction(pub Uuid);
+
+/// [actix::Message] to write every key of a `Repository`, as of its last
+/// executed transaction, next to its log.
+#[derive(Message, Debug)]
+#[rtype(result = "anyhow::Result<Checkpoint>")]
+pub struct TakeCheckpoint;
+
+/// A checkpoint written for [TakeCheckpoint].
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Checkpoint {
+    /// The timestamp of the last transaction in it.
+    pub ts: usize,
+    pub keys: usize,
+    pub path: std::path::PathBuf,
+}
 
 /// Result of a transaction.
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum CommitVote {
     // XXX: what this should hold??
     Commit(Option<usize>),
+    /// The transaction can't run here, e.g. a key is missing.
     Abort,
+    /// A key was moved to another repository: the transaction can't run
+    /// here, but may with the new owner of the key.
+    Moved,
+    /// A key is held by another transaction. It may run later.
     Conflict,
     InProgress,
 }
 
+/// Why a multi-repository transaction didn't commit, from the votes of its
+/// participants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionError {
+    /// A participant voted [CommitVote::Conflict]. Running the transaction
+    /// again may succeed.
+    Conflict,
+    /// A participant voted [CommitVote::Abort]. Running the transaction again
+    /// won't help.
+    Abort,
+    /// A participant voted [CommitVote::Moved]. Running the transaction again
+    /// may succeed with the new routing of the keys.
+    Moved,
+    /// A request got no response in time. Whether the transaction committed
+    /// is unknown.
+    Timeout,
+}
+
+impl TransactionError {
+    /// The error implied by `votes`, if any. An `Abort` wins over a `Moved`,
+    /// which wins over a `Conflict`.
+    pub fn from_votes<'a>(votes: impl IntoIterator<Item = &'a CommitVote>) -> Option<Self> {
+        votes.into_iter().fold(None, |error, vote| match vote {
+            CommitVote::Abort => Some(TransactionError::Abort),
+            CommitVote::Moved => match error {
+                Some(TransactionError::Abort) => error,
+                _ => Some(TransactionError::Moved),
+            },
+            CommitVote::Conflict => error.or(Some(TransactionError::Conflict)),
+            CommitVote::Commit(_) | CommitVote::InProgress => error,
+        })
+    }
+
+    /// Whether `error` is a [TransactionError::Conflict].
+    pub fn is_conflict(error: &anyhow::Error) -> bool {
+        matches!(
+            error.downcast_ref::<TransactionError>(),
+            Some(TransactionError::Conflict)
+        )
+    }
+
+    /// Whether `error` is a [TransactionError::Moved].
+    pub fn is_moved(error: &anyhow::Error) -> bool {
+        matches!(
+            error.downcast_ref::<TransactionError>(),
+            Some(TransactionError::Moved)
+        )
+    }
+}
+
+impl std::fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransactionError::Conflict => write!(f, "transaction conflicted with a locked key"),
+            TransactionError::Abort => write!(f, "transaction aborted by a participant"),
+            TransactionError::Moved => write!(f, "keys moved to another repository"),
+            TransactionError::Timeout => write!(f, "transaction timed out"),
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
 impl<A, M> MessageResponse<A, M> for CommitVote
 where
     A: Actor,
@@ -0,0 +1,86 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use crate::messages::MessagePrepare;
+
+/// Upper bounds, in seconds, of the buckets of a [Histogram].
+pub const LATENCY_BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0,
+];
+
+/// Durations, counted in the buckets of [LATENCY_BUCKETS].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    /// How many durations fit in each bucket (and not in the previous ones).
+    /// The last one has the durations longer than every bound.
+    pub buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    /// The sum of every duration, in seconds.
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: [0; LATENCY_BUCKETS.len() + 1],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+/// What a [crate::repository::Repository] did since it started, see
+/// [crate::messages::GetMetrics].
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    /// Finished transactions, by kind (`single`, `indep` or `coord`) and
+    /// outcome (`commit` or `abort`).
+    pub transactions: BTreeMap<(&'static str, &'static str), u64>,
+    /// From the prepare of the committed transactions to their commit, by
+    /// kind.
+    pub commit_latency: BTreeMap<&'static str, Histogram>,
+    /// Transactions waiting to run, or for the other participants.
+    pub active_transactions: usize,
+    /// Keys locked by `coordinated` transactions.
+    pub locked_keys: usize,
+    pub durable_writes: Histogram,
+}
+
+impl Metrics {
+    /// Counts a finished transaction of `kind`, prepared `elapsed` ago.
+    pub(crate) fn finish(&mut self, kind: &'static str, committed: bool, elapsed: Duration) {
+        let outcome = if committed { "commit" } else { "abort" };
+        *self.transactions.entry((kind, outcome)).or_default() += 1;
+        if committed {
+            self.commit_latency
+                .entry(kind)
+                .or_default()
+                .observe(elapsed);
+        }
+    }
+}
+
+impl MessagePrepare {
+    /// The kind of the transaction, for [Metrics].
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            MessagePrepare::Single(..)
+            | MessagePrepare::ReadOnly(..)
+            | MessagePrepare::Snapshot(..) => "single",
+            MessagePrepare::Indep(..) | MessagePrepare::IndepParticipants(..) => "indep",
+            MessagePrepare::Coord(..) | MessagePrepare::CoordParticipants(..) => "coord",
+        }
+    }
+}
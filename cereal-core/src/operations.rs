@@ -1,4 +1,21 @@
-use std::ops::{Add, Sub};
+//! The operations of transactions, and their textual syntax.
+//!
+//! Every [Operation] is written as text with [std::fmt::Display], and read
+//! back with [std::str::FromStr]:
+//! - values are `(a, b)`;
+//! - expressions are `READ key`, `READ key AT ts`, `DELETE key`, values,
+//!   and `expr + expr` or `expr - expr`, left associative, with parentheses
+//!   to group them otherwise;
+//! - statements are `CREATE key = expr`, `UPDATE key = expr`,
+//!   `RELEASE start..end` and `ACQUIRE start..end`.
+//!
+//! Keywords are case insensitive. The operations of a transaction are
+//! separated by `;`, see [format_operations] and [parse_operations].
+use std::{
+    fmt,
+    ops::{Add, Range, Sub},
+    str::FromStr,
+};
 
 /// This should be a `blob` (bytes) like thing.
 #[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
@@ -12,6 +29,8 @@ pub enum Expr {
     Add(Box<Expr>, Box<Expr>),
     Sub(Box<Expr>, Box<Expr>),
     Read(PrimaryKey),
+    /// Read the value `key` had at timestamp `ts`: `ReadAt(key, ts)`.
+    ReadAt(PrimaryKey, usize),
     Delete(PrimaryKey),
 }
 
@@ -19,6 +38,11 @@ pub enum Expr {
 pub enum Statement {
     Create(PrimaryKey, Box<Expr>),
     Update(PrimaryKey, Box<Expr>),
+    /// Stop serving the keys in the range, moved to another repository.
+    Release(Range<PrimaryKey>),
+    /// Take over the keys in the range, moved from another repository. They
+    /// are served once imported, see [crate::messages::ServeRange].
+    Acquire(Range<PrimaryKey>),
 }
 
 #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
@@ -27,12 +51,75 @@ pub enum Operation {
     Statement(Statement),
 }
 
+impl Expr {
+    /// Whether evaluating this expression leaves the database untouched.
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            Expr::Value(_) | Expr::Read(_) | Expr::ReadAt(_, _) => true,
+            Expr::Delete(_) => false,
+            Expr::Add(e1, e2) | Expr::Sub(e1, e2) => e1.is_read_only() && e2.is_read_only(),
+        }
+    }
+
+    /// Every key this expression reads or deletes.
+    pub fn keys(&self) -> Vec<usize> {
+        match self {
+            Expr::Value(_) => vec![],
+            Expr::Read(key) | Expr::ReadAt(key, _) | Expr::Delete(key) => vec![*key],
+            Expr::Add(e1, e2) | Expr::Sub(e1, e2) => {
+                let mut keys = e1.keys();
+                keys.extend(e2.keys());
+                keys
+            }
+        }
+    }
+}
+
+impl Operation {
+    /// Whether this operation only reads from the database.
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            Operation::Expr(expr) => expr.is_read_only(),
+            Operation::Statement(_) => false,
+        }
+    }
+
+    /// Whether this operation moves a key range, which only peers may do.
+    pub fn moves_keys(&self) -> bool {
+        matches!(
+            self,
+            Operation::Statement(Statement::Release(_) | Statement::Acquire(_))
+        )
+    }
+
+    /// Every key this operation touches. Moving a key range touches none.
+    pub fn keys(&self) -> Vec<usize> {
+        match self {
+            Operation::Expr(expr) => expr.keys(),
+            Operation::Statement(Statement::Create(key, expr))
+            | Operation::Statement(Statement::Update(key, expr)) => {
+                let mut keys = vec![*key];
+                keys.extend(expr.keys());
+                keys
+            }
+            Operation::Statement(Statement::Release(_) | Statement::Acquire(_)) => vec![],
+        }
+    }
+}
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Arguments {
     pub timestamp: usize,
     pub operations: Vec<Operation>,
 }
 
+impl Arguments {
+    /// Whether all the `operations` only read from the database.
+    pub fn is_read_only(&self) -> bool {
+        self.operations.iter().all(Operation::is_read_only)
+    }
+}
+
 impl Add for Table {
     type Output = Table;
 
@@ -48,3 +135,397 @@ impl Sub for Table {
         Table(self.0 - rhs.0, self.1 - rhs.1)
     }
 }
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({}, {})", self.0, self.1)
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Value(value) => write!(f, "{value}"),
+            Expr::Read(key) => write!(f, "READ {key}"),
+            Expr::ReadAt(key, ts) => write!(f, "READ {key} AT {ts}"),
+            Expr::Delete(key) => write!(f, "DELETE {key}"),
+            Expr::Add(e1, e2) | Expr::Sub(e1, e2) => {
+                let symbol = if matches!(self, Expr::Add(..)) {
+                    '+'
+                } else {
+                    '-'
+                };
+                // Left associative: only a sum on the right needs parentheses.
+                match **e2 {
+                    Expr::Add(..) | Expr::Sub(..) => write!(f, "{e1} {symbol} ({e2})"),
+                    _ => write!(f, "{e1} {symbol} {e2}"),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Statement::Create(key, expr) => write!(f, "CREATE {key} = {expr}"),
+            Statement::Update(key, expr) => write!(f, "UPDATE {key} = {expr}"),
+            Statement::Release(range) => write!(f, "RELEASE {}..{}", range.start, range.end),
+            Statement::Acquire(range) => write!(f, "ACQUIRE {}..{}", range.start, range.end),
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Expr(expr) => write!(f, "{expr}"),
+            Operation::Statement(statement) => write!(f, "{statement}"),
+        }
+    }
+}
+
+impl fmt::Display for Arguments {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "at {}: {}",
+            self.timestamp,
+            format_operations(&self.operations)
+        )
+    }
+}
+
+/// The `operations`, separated by `;`.
+pub fn format_operations(operations: &[Operation]) -> String {
+    let operations: Vec<String> = operations.iter().map(ToString::to_string).collect();
+    operations.join("; ")
+}
+
+/// Reads operations separated by `;`, as written by [format_operations]. A
+/// `;` after the last one is allowed.
+pub fn parse_operations(text: &str) -> anyhow::Result<Vec<Operation>> {
+    text.split(';')
+        .map(str::trim)
+        .filter(|operation| !operation.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+impl FromStr for Expr {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> anyhow::Result<Self> {
+        Parser::new(text)?.all(Parser::expr)
+    }
+}
+
+impl FromStr for Statement {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> anyhow::Result<Self> {
+        match Parser::new(text)?.all(Parser::operation)? {
+            Operation::Statement(statement) => Ok(statement),
+            Operation::Expr(expr) => anyhow::bail!("{expr} is an expression, not a statement"),
+        }
+    }
+}
+
+impl FromStr for Operation {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> anyhow::Result<Self> {
+        Parser::new(text)?.all(Parser::operation)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(String),
+    Word(String),
+    Symbol(char),
+    /// `..`
+    Range,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(text) | Token::Word(text) => write!(f, "`{text}`"),
+            Token::Symbol(symbol) => write!(f, "`{symbol}`"),
+            Token::Range => write!(f, "`..`"),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> anyhow::Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() {
+            let mut number = String::new();
+            while let Some(&d) = chars.peek().filter(|d| d.is_ascii_digit()) {
+                number.push(d);
+                chars.next();
+            }
+            tokens.push(Token::Number(number));
+        } else if c.is_ascii_alphabetic() {
+            let mut word = String::new();
+            while let Some(&d) = chars.peek().filter(|d| d.is_ascii_alphabetic()) {
+                word.push(d);
+                chars.next();
+            }
+            tokens.push(Token::Word(word));
+        } else if c == '.' {
+            chars.next();
+            anyhow::ensure!(chars.next() == Some('.'), "expected `..`");
+            tokens.push(Token::Range);
+        } else if "(),+-=".contains(c) {
+            chars.next();
+            tokens.push(Token::Symbol(c));
+        } else {
+            anyhow::bail!("unexpected {c:?}");
+        }
+    }
+    Ok(tokens)
+}
+
+/// A recursive descent parser of the syntax of operations.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(text: &str) -> anyhow::Result<Self> {
+        Ok(Parser {
+            tokens: tokenize(text)?,
+            pos: 0,
+        })
+    }
+
+    /// Parses the whole text with `parse`.
+    fn all<T>(mut self, parse: fn(&mut Self) -> anyhow::Result<T>) -> anyhow::Result<T> {
+        let parsed = parse(&mut self)?;
+        if let Some(token) = self.peek() {
+            anyhow::bail!("unexpected {token} after the end");
+        }
+        Ok(parsed)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> anyhow::Result<Token> {
+        let token = self
+            .peek()
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("unexpected end"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    /// Whether the next token is `keyword`, consuming it if so.
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the next token is `symbol`, consuming it if so.
+    fn symbol(&mut self, symbol: char) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol)) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, symbol: char) -> anyhow::Result<()> {
+        anyhow::ensure!(self.symbol(symbol), "expected `{symbol}`");
+        Ok(())
+    }
+
+    fn operation(&mut self) -> anyhow::Result<Operation> {
+        let statement = if self.keyword("CREATE") {
+            let key = self.number()?;
+            self.expect('=')?;
+            Statement::Create(key, Box::new(self.expr()?))
+        } else if self.keyword("UPDATE") {
+            let key = self.number()?;
+            self.expect('=')?;
+            Statement::Update(key, Box::new(self.expr()?))
+        } else if self.keyword("RELEASE") {
+            Statement::Release(self.range()?)
+        } else if self.keyword("ACQUIRE") {
+            Statement::Acquire(self.range()?)
+        } else {
+            return Ok(Operation::Expr(self.expr()?));
+        };
+        Ok(Operation::Statement(statement))
+    }
+
+    fn number<T: FromStr>(&mut self) -> anyhow::Result<T>
+    where
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        match self.next()? {
+            Token::Number(number) => Ok(number.parse()?),
+            other => anyhow::bail!("expected a number, got {other}"),
+        }
+    }
+
+    fn range(&mut self) -> anyhow::Result<Range<PrimaryKey>> {
+        let start = self.number()?;
+        anyhow::ensure!(self.next()? == Token::Range, "expected `..`");
+        Ok(start..self.number()?)
+    }
+
+    /// `term (+ term | - term)*`.
+    fn expr(&mut self) -> anyhow::Result<Expr> {
+        let mut expr = self.term()?;
+        loop {
+            if self.symbol('+') {
+                expr = Expr::Add(Box::new(expr), Box::new(self.term()?));
+            } else if self.symbol('-') {
+                expr = Expr::Sub(Box::new(expr), Box::new(self.term()?));
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn term(&mut self) -> anyhow::Result<Expr> {
+        if self.keyword("READ") {
+            let key = self.number()?;
+            if self.keyword("AT") {
+                return Ok(Expr::ReadAt(key, self.number()?));
+            }
+            return Ok(Expr::Read(key));
+        }
+        if self.keyword("DELETE") {
+            return Ok(Expr::Delete(self.number()?));
+        }
+        if !self.symbol('(') {
+            match self.peek() {
+                Some(token) => anyhow::bail!("expected an expression, got {token}"),
+                None => anyhow::bail!("expected an expression"),
+            }
+        }
+        // `(a, b)` is a value, anything else in parentheses an expression.
+        let value = matches!(
+            (self.peek(), self.tokens.get(self.pos + 1)),
+            (Some(Token::Number(_)), Some(Token::Symbol(',')))
+                | (Some(Token::Symbol('-')), Some(Token::Number(_)))
+        );
+        if !value {
+            let expr = self.expr()?;
+            self.expect(')')?;
+            return Ok(expr);
+        }
+        let a = self.integer()?;
+        self.expect(',')?;
+        let b = self.integer()?;
+        self.expect(')')?;
+        Ok(Expr::Value(Table(a, b)))
+    }
+
+    fn integer(&mut self) -> anyhow::Result<i64> {
+        let sign = if self.symbol('-') { "-" } else { "" };
+        match self.next()? {
+            Token::Number(number) => Ok(format!("{sign}{number}").parse()?),
+            other => anyhow::bail!("expected a number, got {other}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn table() -> impl Strategy<Value = Table> {
+        (any::<i64>(), any::<i64>()).prop_map(|(a, b)| Table(a, b))
+    }
+
+    fn expr() -> impl Strategy<Value = Expr> {
+        let leaf = prop_oneof![
+            table().prop_map(Expr::Value),
+            any::<usize>().prop_map(Expr::Read),
+            (any::<usize>(), any::<usize>()).prop_map(|(key, ts)| Expr::ReadAt(key, ts)),
+            any::<usize>().prop_map(Expr::Delete),
+        ];
+        leaf.prop_recursive(4, 32, 2, |inner| {
+            prop_oneof![
+                (inner.clone(), inner.clone()).prop_map(|(e1, e2)| Expr::Add(e1.into(), e2.into())),
+                (inner.clone(), inner).prop_map(|(e1, e2)| Expr::Sub(e1.into(), e2.into())),
+            ]
+        })
+    }
+
+    fn operation() -> impl Strategy<Value = Operation> {
+        prop_oneof![
+            expr().prop_map(Operation::Expr),
+            (any::<usize>(), expr())
+                .prop_map(|(key, e)| Operation::Statement(Statement::Create(key, e.into()))),
+            (any::<usize>(), expr())
+                .prop_map(|(key, e)| Operation::Statement(Statement::Update(key, e.into()))),
+            (any::<usize>(), any::<usize>())
+                .prop_map(|(start, end)| Operation::Statement(Statement::Release(start..end))),
+            (any::<usize>(), any::<usize>())
+                .prop_map(|(start, end)| Operation::Statement(Statement::Acquire(start..end))),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn expressions_round_trip(expr in expr()) {
+            prop_assert_eq!(expr.to_string().parse::<Expr>().unwrap(), expr);
+        }
+
+        #[test]
+        fn operations_round_trip(operations in prop::collection::vec(operation(), 0..5)) {
+            let text = format_operations(&operations);
+            prop_assert_eq!(parse_operations(&text).unwrap(), operations);
+        }
+    }
+
+    #[test]
+    fn test_syntax() {
+        let operation: Operation = "update 1 = READ 1 + (1,-1) - (read 2 at 3 - DELETE 4)"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            operation,
+            Operation::Statement(Statement::Update(
+                1,
+                Box::new(Expr::Sub(
+                    Box::new(Expr::Add(
+                        Box::new(Expr::Read(1)),
+                        Box::new(Expr::Value(Table(1, -1)))
+                    )),
+                    Box::new(Expr::Sub(
+                        Box::new(Expr::ReadAt(2, 3)),
+                        Box::new(Expr::Delete(4))
+                    ))
+                ))
+            ))
+        );
+        assert_eq!(
+            operation.to_string(),
+            "UPDATE 1 = READ 1 + (1, -1) - (READ 2 AT 3 - DELETE 4)"
+        );
+        assert!("RELEASE 1..2".parse::<Expr>().is_err());
+        assert!("READ 1".parse::<Statement>().is_err());
+        assert!("UPDATE 1 = ".parse::<Operation>().is_err());
+        assert!("READ 1 2".parse::<Operation>().is_err());
+        println!("Operations are written and read back as text.");
+    }
+}
@@ -0,0 +1,102 @@
+use std::{collections::HashMap, fmt, ops::Range};
+
+use crate::operations::{Expr, Operation, PrimaryKey, Statement};
+
+/// What an operation does to a key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    /// Create or update.
+    Write,
+    Delete,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Permission::Read => write!(f, "read"),
+            Permission::Write => write!(f, "write"),
+            Permission::Delete => write!(f, "delete"),
+        }
+    }
+}
+
+/// The `permissions` on the `keys`, or on every key if there are no `keys`.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Grant {
+    #[serde(default)]
+    pub keys: Option<Range<usize>>,
+    pub permissions: Vec<Permission>,
+}
+
+impl Grant {
+    fn allows(&self, key: PrimaryKey, permission: Permission) -> bool {
+        self.keys.as_ref().is_none_or(|keys| keys.contains(&key))
+            && self.permissions.contains(&permission)
+    }
+}
+
+/// The [Grant]s of each principal. A principal may only do what it was
+/// granted.
+#[derive(Clone, Debug, Default)]
+pub struct Permissions {
+    grants: HashMap<String, Vec<Grant>>,
+}
+
+impl Permissions {
+    pub fn grant(&mut self, principal: impl Into<String>, grant: Grant) {
+        self.grants.entry(principal.into()).or_default().push(grant);
+    }
+
+    /// Fails with the first of `operations` `principal` may not run.
+    /// Moving keys is never granted.
+    pub fn check(&self, principal: &str, operations: &[Operation]) -> anyhow::Result<()> {
+        let grants = self.grants.get(principal).map_or(&[][..], Vec::as_slice);
+        for operation in operations {
+            if let Operation::Statement(Statement::Release(range) | Statement::Acquire(range)) =
+                operation
+            {
+                anyhow::bail!("permission denied: {principal} may not move keys {range:?}");
+            }
+            for (key, permission) in operation.accesses() {
+                anyhow::ensure!(
+                    grants.iter().any(|grant| grant.allows(key, permission)),
+                    "permission denied: {principal} may not {permission} key {key}"
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Expr {
+    fn accesses(&self, accesses: &mut Vec<(PrimaryKey, Permission)>) {
+        match self {
+            Expr::Value(_) => {}
+            Expr::Read(key) | Expr::ReadAt(key, _) => accesses.push((*key, Permission::Read)),
+            Expr::Delete(key) => accesses.push((*key, Permission::Delete)),
+            Expr::Add(e1, e2) | Expr::Sub(e1, e2) => {
+                e1.accesses(accesses);
+                e2.accesses(accesses);
+            }
+        }
+    }
+}
+
+impl Operation {
+    /// Every key this operation touches, with what it does to it.
+    pub fn accesses(&self) -> Vec<(PrimaryKey, Permission)> {
+        let mut accesses = vec![];
+        match self {
+            Operation::Expr(expr) => expr.accesses(&mut accesses),
+            Operation::Statement(Statement::Create(key, expr))
+            | Operation::Statement(Statement::Update(key, expr)) => {
+                accesses.push((*key, Permission::Write));
+                expr.accesses(&mut accesses);
+            }
+            Operation::Statement(Statement::Release(_) | Statement::Acquire(_)) => {}
+        }
+        accesses
+    }
+}
@@ -1,11 +1,23 @@
-use std::collections::HashMap;
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    time::Instant,
+};
 
 use uuid::Uuid;
 
 use crate::{
-    database::Database,
-    messages::{CommitVote, GetProposedTs, GetResult, MessageAccept, MessagePrepare},
-    operations::{Arguments, Table},
+    application::TransactionKind,
+    database::{Database, Problem},
+    introspection::{Introspection, Mode, PendingTransaction, RecentAborts},
+    messages::{
+        AbortTransaction, AcceptDelivered, ChangeEvent, CheckLog, Checkpoint, CommitVote,
+        DropRange, ExportRange, ForgetResults, GetMetrics, GetResult, ImportRange, Introspect,
+        MessageAccept, MessagePrepare, Notification, NotifyParticipants, PrepareAs, ServeRange,
+        SetLowWatermark, Subscribe, TakeCheckpoint, Traced,
+    },
+    metrics::Metrics,
+    operations::{format_operations, Arguments, Operation, PrimaryKey, Table},
+    permissions::Permissions,
     runtime::Runtime,
 };
 use actix::prelude::*;
@@ -23,8 +35,62 @@ pub struct Repository {
     pub(crate) last_timestamp: usize,
     /// Map from `tid` to a transaction result.
     pub(crate) done_xactions: HashMap<Uuid, anyhow::Result<Option<Table>>>,
+    /// Map from `tid` to the prepared transactions, so a repeated prepare
+    /// doesn't run them again. Finished transactions are kept (with their
+    /// results) until the low watermark passes them, or until they are
+    /// forgotten by [`ForgetResults`].
+    pub(crate) submissions: HashMap<Uuid, Submission>,
     /// Filename for durability.
     pub(crate) filename: String,
+    /// Names it in its accepts: unlike `filename`, unique among the
+    /// participants of a transaction.
+    pub(crate) id: Uuid,
+    /// Every published [`ChangeEvent`] not below the low watermark, in commit order.
+    pub(crate) change_history: Vec<ChangeEvent>,
+    /// Recipients of new [`ChangeEvent`]s.
+    pub(crate) subscribers: Vec<Recipient<ChangeEvent>>,
+    /// What the principals of [`PrepareAs`] may do. Every principal may do
+    /// anything if there are none.
+    pub(crate) permissions: Option<Permissions>,
+    pub(crate) metrics: Metrics,
+    /// The kind of each transaction not finished yet, and when it was
+    /// prepared.
+    pub(crate) prepared_at: HashMap<Uuid, (&'static str, Instant)>,
+    pub(crate) recent_aborts: RecentAborts,
+    /// Read-only `Indep` transactions not run yet, see
+    /// [`Repository::handle_indep_read_only`].
+    pub(crate) pending_reads: HashMap<Uuid, PendingRead>,
+}
+
+/// What a [`Repository`] remembers of a prepared transaction.
+pub(crate) struct Submission {
+    /// The last used timestamp when the transaction was prepared.
+    pub(crate) ts: usize,
+    /// When the transaction was prepared.
+    pub(crate) at: Instant,
+    /// The vote answered to the prepares, `Abort` once an operator aborted
+    /// it.
+    pub(crate) vote: CommitVote,
+    /// The accept sent to the other participants, once they were notified.
+    pub(crate) accept: Option<MessageAccept>,
+    /// The participants, by uri, that acknowledged the accept: a repeated
+    /// notification sends it to the others only.
+    pub(crate) delivered_to: HashSet<String>,
+    /// The principal it was prepared for, the only one notifying its
+    /// participants.
+    pub(crate) principal: Option<String>,
+}
+
+/// A read-only `Indep` transaction, run once every participant accepted it
+/// and the snapshot at its timestamp is stable.
+pub(crate) struct PendingRead {
+    /// The highest timestamp proposed so far.
+    pub(crate) ts: usize,
+    /// How many participants it still waits the accept of.
+    pub(crate) waiting_for: usize,
+    /// The participants whose accept was counted.
+    pub(crate) accepted_from: HashSet<String>,
+    pub(crate) operations: Vec<Operation>,
 }
 
 impl Repository {
@@ -37,12 +103,85 @@ impl Repository {
     /// let repo = Repository::new("db.txt".to_string());
     /// ```
     pub fn new(filename: String) -> Self {
+        Self::with_runtime(filename, Runtime::new())
+    }
+
+    /// Create a new `Repository` keeping time and durability with `runtime`.
+    pub fn with_runtime(filename: String, runtime: Runtime) -> Self {
         Repository {
             database: Database::new(),
-            runtime: Runtime::new(),
+            runtime,
             last_timestamp: 0,
             done_xactions: HashMap::new(),
+            submissions: HashMap::new(),
             filename,
+            id: Uuid::new_v4(),
+            change_history: vec![],
+            subscribers: vec![],
+            permissions: None,
+            metrics: Metrics::default(),
+            prepared_at: HashMap::new(),
+            recent_aborts: RecentAborts::default(),
+            pending_reads: HashMap::new(),
+        }
+    }
+
+    /// Only runs the transactions of principals granted their operations.
+    pub fn with_permissions(mut self, permissions: Permissions) -> Self {
+        self.permissions = Some(permissions);
+        self
+    }
+}
+
+impl Repository {
+    /// Keeps the result of `tid`, unless it already has one.
+    fn finish(&mut self, tid: Uuid, result: anyhow::Result<Option<Table>>) {
+        if let Entry::Vacant(entry) = self.done_xactions.entry(tid) {
+            match &result {
+                Ok(_) => tracing::info!(%tid, "committed"),
+                Err(e) => {
+                    tracing::info!(%tid, error = %e, "aborted");
+                    self.recent_aborts.push(tid, e);
+                }
+            }
+            if let Some((kind, prepared_at)) = self.prepared_at.remove(&tid) {
+                self.metrics
+                    .finish(kind, result.is_ok(), prepared_at.elapsed());
+            }
+            entry.insert(result);
+        }
+    }
+
+    /// Send the changes of the transactions just run to all the subscribers,
+    /// in commit order: transactions don't always finish in timestamp order,
+    /// so a change is held back while a pending transaction may still commit
+    /// before it.
+    fn publish_changes(&mut self) {
+        let pending_ts = self
+            .database
+            .active_transactions
+            .values()
+            .map(|xaction| xaction.proposed_ts)
+            .min();
+        let unpublished = &mut self.database.changes;
+        // A stable sort, so the changes of a transaction keep their order.
+        unpublished.sort_by_key(|change| change.commit_ts);
+        let committed = match pending_ts {
+            Some(ts) => unpublished.partition_point(|change| change.commit_ts < ts),
+            None => unpublished.len(),
+        };
+        let changes: Vec<ChangeEvent> = unpublished.drain(..committed).collect();
+        let Some(last) = changes.last() else {
+            return;
+        };
+        // Transactions proposed from now on are ordered after them.
+        self.last_timestamp = std::cmp::max(self.last_timestamp, last.commit_ts);
+        self.subscribers.retain(|subscriber| subscriber.connected());
+        for change in changes {
+            for subscriber in &self.subscribers {
+                subscriber.do_send(change.clone());
+            }
+            self.change_history.push(change);
         }
     }
 }
@@ -62,26 +201,127 @@ impl Repository {
         &mut self,
         tid: Uuid,
         args: Arguments,
+    ) -> anyhow::Result<crate::messages::CommitVote, anyhow::Error> {
+        if args.is_read_only() {
+            return self.handle_read_only(tid, args);
+        }
+        if let Some(vote) = self.refuse_moved_keys(tid, &args) {
+            return Ok(vote);
+        }
+        self.enqueue_single(tid, args, true)
+    }
+
+    /// Single-repository transactions on keys moved to another repository, or
+    /// still being moved here, are refused right away instead of running on
+    /// keys that aren't served here.
+    fn refuse_moved_keys(&mut self, tid: Uuid, args: &Arguments) -> Option<CommitVote> {
+        let problem = self.database.check_ownership(&args.operations)?;
+        let error = match problem {
+            Problem::Moved => anyhow::anyhow!("keys moved to another repository"),
+            _ => anyhow::anyhow!("keys are being moved here"),
+        };
+        self.finish(tid, Err(error));
+        Some(CommitVote::from(problem))
+    }
+
+    /// Order a single-repository transaction after the already proposed ones,
+    /// and run everything that is ready.
+    fn enqueue_single(
+        &mut self,
+        tid: Uuid,
+        args: Arguments,
+        durable: bool,
     ) -> anyhow::Result<crate::messages::CommitVote, anyhow::Error> {
         let runtime = &mut self.runtime;
         let current_time = runtime.now();
         let proposed_ts = find_max!(args.timestamp, current_time, self.last_timestamp) + 1;
 
-        let operations_str = format!("{:?}", args.operations);
-        runtime.write_to_durable(&self.filename, &operations_str, proposed_ts)?;
+        if durable {
+            let operations_str = format_operations(&args.operations);
+            runtime.write_to_durable(&self.filename, &operations_str, proposed_ts)?;
+        }
 
         self.database
             .add_xaction(&tid, proposed_ts, args.operations, 0);
 
         self.last_timestamp = proposed_ts;
 
-        let result = self.database.run_nexts();
+        self.run_ready();
 
-        for r in result {
-            self.done_xactions.insert(r.0, Ok(r.1));
+        Ok(CommitVote::InProgress)
+    }
+
+    /// Read-only Single-Repository Transactions.
+    ///
+    /// A read-only transaction doesn't change state, so there is nothing to
+    /// log or to recover. It is served at the timestamp of the latest executed
+    /// transaction, without taking locks, so it never waits for (nor blocks) a
+    /// writer. If a pending transaction is ordered before that timestamp the
+    /// state can't be read yet, and it goes through the regular ordering
+    /// instead (still without logging).
+    fn handle_read_only(
+        &mut self,
+        tid: Uuid,
+        args: Arguments,
+    ) -> anyhow::Result<crate::messages::CommitVote, anyhow::Error> {
+        if let Some(vote) = self.refuse_moved_keys(tid, &args) {
+            return Ok(vote);
         }
+        let Some(read_ts) = self.database.read_only_ts() else {
+            return self.enqueue_single(tid, args, false);
+        };
 
-        Ok(CommitVote::InProgress)
+        let result = self.database.run_read_only(&args.operations);
+        self.finish(tid, Ok(result));
+        // Transactions proposed from now on must be ordered after this read.
+        self.last_timestamp = std::cmp::max(self.last_timestamp, read_ts);
+
+        Ok(CommitVote::Commit(Some(read_ts)))
+    }
+
+    /// Snapshot Read-only Transactions.
+    ///
+    /// Like [`Repository::handle_read_only`], but reads the versions visible
+    /// at `snapshot_ts` instead of the latest ones. Since the same snapshot
+    /// can be read at every repository, no coordination is needed for a
+    /// consistent multi-repository read: the smallest of the timestamps of
+    /// read-only transactions at every repository is a snapshot of them all.
+    ///
+    /// A `snapshot_ts` ahead of the last executed transaction is refused,
+    /// since later transactions would have to be ordered after a timestamp
+    /// picked by the client.
+    fn handle_snapshot(
+        &mut self,
+        tid: Uuid,
+        args: Arguments,
+        snapshot_ts: usize,
+    ) -> anyhow::Result<crate::messages::CommitVote, anyhow::Error> {
+        if !args.is_read_only() {
+            anyhow::bail!("`Snapshot` transaction {tid} has write operations");
+        }
+        if let Some(vote) = self.refuse_moved_keys(tid, &args) {
+            return Ok(vote);
+        }
+        let last_executed_ts = self.database.last_executed_ts;
+        if snapshot_ts > last_executed_ts {
+            let error = anyhow::anyhow!(
+                "timestamp {snapshot_ts} is ahead of the last executed transaction, at {last_executed_ts}"
+            );
+            self.finish(tid, Err(error));
+            return Ok(CommitVote::Abort);
+        }
+        match self.database.run_snapshot(&args.operations, snapshot_ts) {
+            Ok(result) => {
+                self.finish(tid, Ok(result));
+                // The snapshot must not change: later transactions are ordered after it.
+                self.last_timestamp = std::cmp::max(self.last_timestamp, snapshot_ts);
+                Ok(CommitVote::Commit(Some(snapshot_ts)))
+            }
+            Err(e) => {
+                self.finish(tid, Err(e));
+                Ok(CommitVote::Abort)
+            }
+        }
     }
 
     /// Independent Distributed Transactions
@@ -98,38 +338,120 @@ impl Repository {
         let current_time = runtime.now();
         let proposed_ts = find_max!(args.timestamp, current_time, self.last_timestamp) + 1;
 
+        if args.is_read_only() {
+            return Ok(self.handle_indep_read_only(tid, args, participants_len, proposed_ts));
+        }
+
         self.database
             .add_xaction(&tid, proposed_ts, args.operations.clone(), participants_len);
 
-        let vote = if self.database.check_for_conflicts_and_primary_key(&tid) {
+        let vote = if let Some(problem) = self.database.check_for_problems(&tid) {
             self.database.finalize(&tid, proposed_ts);
-            Ok(CommitVote::Conflict)
+            Ok(CommitVote::from(problem))
         } else {
             Ok(CommitVote::Commit(None))
         };
 
-        let operations_str = format!("{:?}, {:?}", args.operations, vote);
+        let operations_str = format!("{}, {:?}", format_operations(&args.operations), vote);
         runtime.write_to_durable(&self.filename, &operations_str, proposed_ts)?;
 
         vote
     }
 
+    /// Read-only Independent Distributed Transactions
+    ///
+    /// Agrees on a timestamp with the other participants like any other
+    /// independent transaction, but is run as a snapshot read at that
+    /// timestamp, once every transaction ordered before it was executed. It
+    /// is neither logged nor ordered among the pending transactions, so it
+    /// doesn't take locks nor conflict with them: a locked key is only written
+    /// when its holder is executed, at a timestamp the snapshot either
+    /// includes or not, the same at every participant.
+    fn handle_indep_read_only(
+        &mut self,
+        tid: Uuid,
+        args: Arguments,
+        participants_len: usize,
+        proposed_ts: usize,
+    ) -> CommitVote {
+        if let Some(problem) = self.database.check_read_only(&args.operations) {
+            // So the accepts of the other participants find it finished.
+            self.database.finalize(&tid, proposed_ts);
+            return CommitVote::from(problem);
+        }
+        // Transactions proposed from now on are ordered after the read.
+        self.last_timestamp = proposed_ts;
+        self.pending_reads.insert(
+            tid,
+            PendingRead {
+                ts: proposed_ts,
+                waiting_for: participants_len,
+                accepted_from: HashSet::new(),
+                operations: args.operations,
+            },
+        );
+        CommitVote::Commit(None)
+    }
+
+    /// Run the read-only `Indep` transactions every participant accepted,
+    /// once their snapshot is stable.
+    fn run_pending_reads(&mut self) {
+        let ready: Vec<Uuid> = self
+            .pending_reads
+            .iter()
+            .filter(|(_, read)| read.waiting_for == 0 && self.database.is_stable(read.ts))
+            .map(|(tid, _)| *tid)
+            .collect();
+        for tid in ready {
+            if let Some(read) = self.pending_reads.remove(&tid) {
+                let result = self.database.run_snapshot(&read.operations, read.ts);
+                // Its participants may still be notified, with its timestamp.
+                self.database.finalize(&tid, read.ts);
+                self.finish(tid, result);
+            }
+        }
+    }
+
+    /// Run every transaction that is ready, then the reads waiting for
+    /// them, and publish their changes.
+    fn run_ready(&mut self) {
+        let result = self.database.run_nexts();
+
+        // Other coordinated transactions keep their locks until they finish.
+        for tid in result.keys() {
+            self.database.release_locks_of(tid);
+        }
+        for (tid, result) in result {
+            self.finish(tid, Ok(result));
+        }
+        self.publish_changes();
+        self.run_pending_reads();
+    }
+
+    /// The timestamp proposed for `tid` by this repository.
+    fn proposed_ts(&self, tid: &Uuid) -> anyhow::Result<usize> {
+        match self.pending_reads.get(tid) {
+            Some(read) => Ok(read.ts),
+            None => self
+                .database
+                .get_proposed_ts_for_tid(tid)
+                .ok_or_else(|| anyhow::anyhow!("no timestamp proposed for {tid}")),
+        }
+    }
+
     /// Independent Distributed Transactions
     ///
     /// Section 4.4. https://pmg.csail.mit.edu/papers/granola-usenix12.pdf
     /// Step 3.
     fn send_message_accept_indep_to_participants(
         &mut self,
-        tid: Uuid,
-        vote: CommitVote,
+        accept: MessageAccept,
         other_participants: &Vec<Addr<Repository>>,
     ) -> anyhow::Result<crate::messages::CommitVote, anyhow::Error> {
-        let proposed_ts = self.database.get_proposed_ts_for_tid(&tid);
         for participant in other_participants {
-            participant.do_send(MessageAccept::Indep(tid, proposed_ts, vote.clone()));
+            participant.do_send(accept.clone());
         }
 
-        self.last_timestamp = proposed_ts;
         Ok(CommitVote::InProgress)
     }
 
@@ -142,17 +464,26 @@ impl Repository {
         tid: Uuid,
         proposed_ts: usize,
         vote: CommitVote,
+        participant: String,
     ) -> anyhow::Result<CommitVote, anyhow::Error> {
-        // XXX: can it be abort??
-        if vote == CommitVote::Conflict {
+        if self.pending_reads.contains_key(&tid) {
+            return Ok(self.handle_indep_read_only_accept(tid, proposed_ts, vote, participant));
+        }
+        // Another participant voted against committing.
+        if matches!(
+            vote,
+            CommitVote::Conflict | CommitVote::Abort | CommitVote::Moved
+        ) {
             self.database.finalize(&tid, proposed_ts);
-            self.done_xactions
-                .insert(tid, Err(anyhow::anyhow!("Problem at another repository")));
+            self.finish(tid, Err(anyhow::anyhow!("Problem at another repository")));
+            // The transactions ordered after it may run now.
+            self.run_ready();
             return Ok(CommitVote::Abort);
         }
         // A conflict happened locally and the transaction should be aborted.
-        if let Some(_) = self.database.tid_to_ts_end_xaction_ends.get(&tid) {
-            self.done_xactions.insert(
+        // A transaction refused at the prepare keeps its error.
+        if self.database.tid_to_ts_end_xaction_ends.contains_key(&tid) {
+            self.finish(
                 tid,
                 Err(anyhow::anyhow!(
                     "Local problem, locked key or missing primary key"
@@ -166,18 +497,49 @@ impl Repository {
             return Ok(CommitVote::Abort);
         }
 
-        self.database.decrement_reply_count(&tid);
+        if !self.database.decrement_reply_count(&tid, &participant) {
+            log::warn!("accept of {tid} from {participant} received again");
+            return Ok(CommitVote::InProgress);
+        }
         self.database
             .update_proposed_ts_to_highest(&tid, proposed_ts);
-        let result = self.database.run_nexts();
-
-        for r in result {
-            self.done_xactions.insert(r.0, Ok(r.1));
-        }
+        self.run_ready();
 
         Ok(CommitVote::InProgress)
     }
 
+    /// Read-only Independent Distributed Transactions, see
+    /// [`Repository::handle_indep_read_only`].
+    fn handle_indep_read_only_accept(
+        &mut self,
+        tid: Uuid,
+        proposed_ts: usize,
+        vote: CommitVote,
+        participant: String,
+    ) -> CommitVote {
+        if matches!(
+            vote,
+            CommitVote::Conflict | CommitVote::Abort | CommitVote::Moved
+        ) {
+            if let Some(read) = self.pending_reads.remove(&tid) {
+                self.database.finalize(&tid, read.ts);
+            }
+            self.finish(tid, Err(anyhow::anyhow!("Problem at another repository")));
+            return CommitVote::Abort;
+        }
+        if let Some(read) = self.pending_reads.get_mut(&tid) {
+            if read.waiting_for == 0 || !read.accepted_from.insert(participant) {
+                return CommitVote::InProgress;
+            }
+            read.ts = std::cmp::max(read.ts, proposed_ts);
+            read.waiting_for -= 1;
+            // The snapshot must not change: later transactions are ordered after it.
+            self.last_timestamp = std::cmp::max(self.last_timestamp, read.ts);
+        }
+        self.run_pending_reads();
+        CommitVote::InProgress
+    }
+
     /// Coordinated Distributed Transactions
     ///
     /// Section 4.5. https://pmg.csail.mit.edu/papers/granola-usenix12.pdf
@@ -192,26 +554,22 @@ impl Repository {
         let current_time = runtime.now();
         let proposed_ts = find_max!(args.timestamp, current_time, self.last_timestamp) + 1;
 
-        let operations_str = format!("{:?}", args.operations);
+        let operations_str = format_operations(&args.operations);
         runtime.write_to_durable(&self.filename, &operations_str, proposed_ts)?;
 
         self.database
             .add_xaction(&tid, proposed_ts, args.operations.clone(), participants_len);
 
-        log::error!(
-            "check for conflicts: {:?}",
-            self.database.check_for_conflicts_and_primary_key(&tid)
-        );
-        let vote = if self.database.check_for_conflicts_and_primary_key(&tid) {
-            log::error!("================TO AQUI {:?}", tid);
+        let vote = if let Some(problem) = self.database.check_for_problems(&tid) {
+            tracing::debug!(?problem, "refused to lock the keys");
             self.database.finalize(&tid, proposed_ts);
-            Ok(CommitVote::Conflict)
+            Ok(CommitVote::from(problem))
         } else {
             self.database.get_all_locks(&tid);
             Ok(CommitVote::Commit(None))
         };
 
-        let operations_str = format!("{:?}, {:?}", args.operations, vote);
+        let operations_str = format!("{}, {:?}", format_operations(&args.operations), vote);
         runtime.write_to_durable(&self.filename, &operations_str, proposed_ts)?;
 
         vote
@@ -223,16 +581,13 @@ impl Repository {
     /// Step 4.
     fn send_message_accept_coord_to_participants(
         &mut self,
-        tid: Uuid,
-        vote: CommitVote,
+        accept: MessageAccept,
         other_participants: &Vec<Addr<Repository>>,
     ) -> anyhow::Result<crate::messages::CommitVote, anyhow::Error> {
-        let proposed_ts = self.database.get_proposed_ts_for_tid(&tid);
         for participant in other_participants {
-            participant.do_send(MessageAccept::Coord(tid, proposed_ts, vote.clone()));
+            participant.do_send(accept.clone());
         }
 
-        self.last_timestamp = proposed_ts;
         Ok(CommitVote::InProgress)
     }
 
@@ -245,17 +600,25 @@ impl Repository {
         tid: Uuid,
         proposed_ts: usize,
         vote: CommitVote,
+        participant: String,
     ) -> anyhow::Result<CommitVote, anyhow::Error> {
-        // XXX: can it be abort??
-        if vote == CommitVote::Conflict {
+        // Another participant voted against committing.
+        if matches!(
+            vote,
+            CommitVote::Conflict | CommitVote::Abort | CommitVote::Moved
+        ) {
             self.database.finalize(&tid, proposed_ts);
-            self.done_xactions
-                .insert(tid, Err(anyhow::anyhow!("Problem at another repository")));
+            // Locks taken when this repository voted to commit.
+            self.database.release_locks_of(&tid);
+            self.finish(tid, Err(anyhow::anyhow!("Problem at another repository")));
+            // The transactions ordered after it may run now.
+            self.run_ready();
             return Ok(CommitVote::Abort);
         }
         // A conflict happened locally and the transaction should be aborted.
-        if let Some(_) = self.database.tid_to_ts_end_xaction_ends.get(&tid) {
-            self.done_xactions.insert(
+        // A transaction refused at the prepare keeps its error.
+        if self.database.tid_to_ts_end_xaction_ends.contains_key(&tid) {
+            self.finish(
                 tid,
                 Err(anyhow::anyhow!(
                     "Local problem, locked key or missing primary key"
@@ -269,15 +632,22 @@ impl Repository {
             return Ok(CommitVote::Abort);
         }
 
-        self.database.decrement_reply_count(&tid);
+        if !self.database.decrement_reply_count(&tid, &participant) {
+            log::warn!("accept of {tid} from {participant} received again");
+            return Ok(CommitVote::InProgress);
+        }
         self.database
             .update_proposed_ts_to_highest(&tid, proposed_ts);
-        let result = self.database.run_nexts();
-
-        self.database.release_locks();
-        for r in result {
-            self.done_xactions.insert(r.0, Ok(r.1));
+        // Every participant voted: its own locks mustn't keep it from running.
+        if self
+            .database
+            .active_transactions
+            .get(&tid)
+            .is_some_and(|xaction| xaction.waiting_for == 0)
+        {
+            self.database.release_locks_of(&tid);
         }
+        self.run_ready();
 
         Ok(CommitVote::InProgress)
     }
@@ -287,7 +657,7 @@ impl Actor for Repository {
     type Context = Context<Self>;
 
     fn started(&mut self, _ctx: &mut Context<Self>) {
-        let actor_name = format!("{}", self.filename);
+        let actor_name = self.filename.to_string();
         println!("Starting actor: {actor_name}.");
     }
 }
@@ -296,20 +666,187 @@ impl Handler<MessagePrepare> for Repository {
     type Result = anyhow::Result<CommitVote, anyhow::Error>;
 
     /// Handle for [`MessagePrepare`] for [`Repository`].
+    /// A transaction is prepared only once per `tid`: a repeated prepare gets
+    /// the original vote back, and the other participants are sent the
+    /// accept only once, so clients can safely retry after network errors.
     fn handle(&mut self, msg: MessagePrepare, _ctx: &mut Self::Context) -> Self::Result {
+        let _span = tracing::info_span!("prepare", tid = %msg.tid(), kind = msg.kind()).entered();
+        self.submit(msg, None)
+    }
+}
+
+impl Handler<PrepareAs> for Repository {
+    type Result = anyhow::Result<CommitVote, anyhow::Error>;
+
+    /// Handle for [`PrepareAs`] for [`Repository`].
+    /// Like [`MessagePrepare`], refusing the transaction if the principal
+    /// wasn't granted its operations.
+    fn handle(&mut self, msg: PrepareAs, _ctx: &mut Self::Context) -> Self::Result {
+        let PrepareAs(principal, msg) = msg;
+        let _span = tracing::info_span!(
+            "prepare",
+            tid = %msg.tid(),
+            kind = msg.kind(),
+            %principal
+        )
+        .entered();
+        self.submit(msg, Some(&principal))
+    }
+}
+
+impl Repository {
+    /// Prepare a transaction once per `tid`, see [`MessagePrepare`].
+    fn submit(
+        &mut self,
+        msg: MessagePrepare,
+        principal: Option<&str>,
+    ) -> anyhow::Result<CommitVote, anyhow::Error> {
+        let tid = msg.tid();
+        match msg {
+            // The mailboxes of the participants don't lose the accept, it
+            // isn't sent again.
+            MessagePrepare::IndepParticipants(tid, vote, participants) => {
+                let kind = TransactionKind::Independent;
+                match self.take_notification(&tid, kind, principal, vote)? {
+                    (accept, true) => {
+                        self.send_message_accept_indep_to_participants(accept, &participants)
+                    }
+                    (_, false) => Ok(CommitVote::InProgress),
+                }
+            }
+            MessagePrepare::CoordParticipants(tid, vote, participants) => {
+                let kind = TransactionKind::Coordinated;
+                match self.take_notification(&tid, kind, principal, vote)? {
+                    (accept, true) => {
+                        self.send_message_accept_coord_to_participants(accept, &participants)
+                    }
+                    (_, false) => Ok(CommitVote::InProgress),
+                }
+            }
+            msg => {
+                if let Some(submission) = self.submissions.get(&tid) {
+                    return Ok(submission.vote.clone());
+                }
+                let ts = self.last_timestamp;
+                self.prepared_at.insert(tid, (msg.kind(), Instant::now()));
+                let vote = match self.check_permissions(&msg, principal) {
+                    Ok(()) => self.prepare(msg).inspect_err(|_| {
+                        // It was never prepared, so it never finishes.
+                        self.prepared_at.remove(&tid);
+                    })?,
+                    Err(e) => self.refuse_denied(&msg, e),
+                };
+                self.submissions.insert(
+                    tid,
+                    Submission {
+                        ts,
+                        at: Instant::now(),
+                        vote: vote.clone(),
+                        accept: None,
+                        delivered_to: HashSet::new(),
+                        principal: principal.map(str::to_string),
+                    },
+                );
+                Ok(vote)
+            }
+        }
+    }
+
+    /// Take the notification of the participants of `tid` by `principal`.
+    /// Returns the accept to send them, see [`NotifyParticipants`], built
+    /// the first time only, and whether it was built just now.
+    fn take_notification(
+        &mut self,
+        tid: &Uuid,
+        kind: TransactionKind,
+        principal: Option<&str>,
+        vote: CommitVote,
+    ) -> anyhow::Result<(MessageAccept, bool), anyhow::Error> {
+        let Some(submission) = self.submissions.get(tid) else {
+            anyhow::bail!("unknown transaction {tid}");
+        };
+        anyhow::ensure!(
+            submission.principal.as_deref() == principal,
+            "transaction {tid} wasn't prepared for this principal"
+        );
+        if let Some(accept) = &submission.accept {
+            return Ok((accept.clone(), false));
+        }
+        let vote = match (&submission.vote, vote) {
+            (
+                CommitVote::Commit(_),
+                vote @ (CommitVote::Conflict | CommitVote::Abort | CommitVote::Moved),
+            ) => vote,
+            (prepared, _) => prepared.clone(),
+        };
+        let proposed_ts = self.proposed_ts(tid)?;
+        self.last_timestamp = std::cmp::max(self.last_timestamp, proposed_ts);
+        let participant = self.id.to_string();
+        let accept = match kind {
+            TransactionKind::Independent => {
+                MessageAccept::Indep(*tid, proposed_ts, vote, participant)
+            }
+            TransactionKind::Coordinated => {
+                MessageAccept::Coord(*tid, proposed_ts, vote, participant)
+            }
+        };
+        if let Some(submission) = self.submissions.get_mut(tid) {
+            submission.accept = Some(accept.clone());
+        }
+        Ok((accept, true))
+    }
+}
+
+impl Repository {
+    fn check_permissions(
+        &self,
+        msg: &MessagePrepare,
+        principal: Option<&str>,
+    ) -> anyhow::Result<()> {
+        match (&self.permissions, principal, msg.arguments()) {
+            (Some(permissions), Some(principal), Some(args)) => {
+                permissions.check(principal, &args.operations)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Transactions running operations their principal wasn't granted are
+    /// refused before they are prepared, voting `Abort`.
+    fn refuse_denied(&mut self, msg: &MessagePrepare, error: anyhow::Error) -> CommitVote {
+        if let MessagePrepare::Indep(tid, args, _) | MessagePrepare::Coord(tid, args, _) = msg {
+            // So the accepts of the other participants find it finished.
+            let current_time = self.runtime.now();
+            let proposed_ts = find_max!(args.timestamp, current_time, self.last_timestamp) + 1;
+            self.database.finalize(tid, proposed_ts);
+        }
+        self.finish(msg.tid(), Err(error));
+        CommitVote::Abort
+    }
+
+    /// Prepare a transaction that wasn't seen before.
+    fn prepare(&mut self, msg: MessagePrepare) -> anyhow::Result<CommitVote, anyhow::Error> {
         match msg {
             MessagePrepare::Single(tid, args) => self.handle_single(tid, args),
+            MessagePrepare::ReadOnly(tid, args) => {
+                if !args.is_read_only() {
+                    anyhow::bail!("`ReadOnly` transaction {tid} has write operations");
+                }
+                self.handle_read_only(tid, args)
+            }
+            MessagePrepare::Snapshot(tid, args, snapshot_ts) => {
+                self.handle_snapshot(tid, args, snapshot_ts)
+            }
             MessagePrepare::Indep(tid, args, participants_len) => {
                 self.handle_indep_prepare(tid, args, participants_len)
             }
-            MessagePrepare::IndepParticipants(tid, vote, other_participants) => {
-                self.send_message_accept_indep_to_participants(tid, vote, &other_participants)
-            }
             MessagePrepare::Coord(tid, args, participants_len) => {
                 self.handle_coord_prepare(tid, args, participants_len)
             }
-            MessagePrepare::CoordParticipants(tid, vote, other_participants) => {
-                self.send_message_accept_coord_to_participants(tid, vote, &other_participants)
+            // Taken by `submit`, which keeps their accept.
+            MessagePrepare::IndepParticipants(tid, ..)
+            | MessagePrepare::CoordParticipants(tid, ..) => {
+                anyhow::bail!("transaction {tid} notifies its participants, it isn't prepared")
             }
         }
     }
@@ -320,28 +857,61 @@ impl Handler<MessageAccept> for Repository {
 
     /// Handle for [`MessageAccept`] for [`Repository`].
     fn handle(&mut self, msg: MessageAccept, _ctx: &mut Self::Context) -> Self::Result {
+        let (MessageAccept::Indep(tid, proposed_ts, vote, participant)
+        | MessageAccept::Coord(tid, proposed_ts, vote, participant)) = &msg;
+        let _span = tracing::info_span!("accept", %tid, proposed_ts, ?vote, %participant).entered();
         match msg {
-            MessageAccept::Indep(tid, proposed_ts, vote) => {
-                self.handle_indep_accept(tid, proposed_ts, vote)
+            MessageAccept::Indep(tid, proposed_ts, vote, participant) => {
+                self.handle_indep_accept(tid, proposed_ts, vote, participant)
             }
-            MessageAccept::Coord(tid, proposed_ts, vote) => {
-                self.handle_coord_accept(tid, proposed_ts, vote)
+            MessageAccept::Coord(tid, proposed_ts, vote, participant) => {
+                self.handle_coord_accept(tid, proposed_ts, vote, participant)
             }
         }
     }
 }
 
+/// Implements [`Handler`] of [`Traced`] messages of each type, entering
+/// the span while handling the message.
+macro_rules! handle_traced {
+    ($($message:ty),*) => {$(
+        impl Handler<Traced<$message>> for Repository {
+            type Result = <Repository as Handler<$message>>::Result;
+
+            fn handle(&mut self, msg: Traced<$message>, ctx: &mut Self::Context) -> Self::Result {
+                let Traced(span, msg) = msg;
+                let _span = span.entered();
+                <Repository as Handler<$message>>::handle(self, msg, ctx)
+            }
+        }
+    )*};
+}
+
+handle_traced!(
+    MessagePrepare,
+    PrepareAs,
+    MessageAccept,
+    GetResult,
+    NotifyParticipants
+);
+
 impl Handler<GetResult> for Repository {
     type Result = ResponseFuture<anyhow::Result<Option<Table>, anyhow::Error>>;
 
     /// Handle for [`GetResult`] for [`Repository`].
     /// If a result for the given `tid` is already in [`Repository::done_xaction`],
-    /// remove it and return the value. If not, send a `GetResult` for the actor
-    /// to try to get a result.
+    /// return a copy of it, so it can be asked again. If not, send a
+    /// `GetResult` for the actor to try to get a result.
     fn handle(&mut self, msg: GetResult, ctx: &mut Self::Context) -> Self::Result {
         let tid = msg.0;
-        if let Some(result) = self.done_xactions.remove(&tid) {
-            return Box::pin(async move { result });
+        if let Some(result) = self.done_xactions.get(&tid) {
+            let result = match result {
+                Ok(table) => Ok(table.clone()),
+                Err(e) => Err(anyhow::anyhow!("{e:#}")),
+            };
+            Box::pin(async move { result })
+        } else if !self.submissions.contains_key(&tid) {
+            Box::pin(async move { Err(anyhow::anyhow!("unknown transaction {tid}")) })
         } else {
             let request = ctx.address().send(GetResult(tid));
             Box::pin(async move { request.await.unwrap() })
@@ -349,12 +919,260 @@ impl Handler<GetResult> for Repository {
     }
 }
 
-impl Handler<GetProposedTs> for Repository {
-    type Result = usize;
+impl Handler<SetLowWatermark> for Repository {
+    type Result = ();
+
+    /// Handle for [`SetLowWatermark`] for [`Repository`].
+    fn handle(&mut self, msg: SetLowWatermark, _ctx: &mut Self::Context) -> Self::Result {
+        self.database.collect_garbage(msg.0);
+        let low_watermark = self.database.low_watermark;
+        self.change_history
+            .retain(|change| change.commit_ts >= low_watermark);
+        let done_xactions = &mut self.done_xactions;
+        self.submissions.retain(|tid, submission| {
+            if submission.ts >= low_watermark || !done_xactions.contains_key(tid) {
+                return true;
+            }
+            done_xactions.remove(tid);
+            false
+        });
+    }
+}
+
+impl Handler<ForgetResults> for Repository {
+    type Result = ();
+
+    /// Handle for [`ForgetResults`] for [`Repository`].
+    fn handle(&mut self, msg: ForgetResults, _ctx: &mut Self::Context) -> Self::Result {
+        let done_xactions = &mut self.done_xactions;
+        self.submissions.retain(|tid, submission| {
+            if submission.at.elapsed() < msg.0 || !done_xactions.contains_key(tid) {
+                return true;
+            }
+            done_xactions.remove(tid);
+            false
+        });
+    }
+}
+
+impl Handler<Subscribe> for Repository {
+    type Result = anyhow::Result<(), anyhow::Error>;
+
+    /// Handle for [`Subscribe`] for [`Repository`].
+    fn handle(&mut self, msg: Subscribe, _ctx: &mut Self::Context) -> Self::Result {
+        if msg.from_ts < self.database.low_watermark {
+            anyhow::bail!(
+                "changes before {} are no longer available",
+                self.database.low_watermark
+            );
+        }
+        for change in self
+            .change_history
+            .iter()
+            .filter(|change| change.commit_ts >= msg.from_ts)
+        {
+            msg.subscriber.do_send(change.clone());
+        }
+        self.subscribers.push(msg.subscriber);
+        Ok(())
+    }
+}
+
+impl Handler<NotifyParticipants> for Repository {
+    type Result = anyhow::Result<Notification, anyhow::Error>;
 
-    /// Handle for [`GetProposedTs`] for [`Repository`].
+    /// Handle for [`NotifyParticipants`] for [`Repository`].
     /// Needed for [`RepositoryWs`].
-    fn handle(&mut self, msg: GetProposedTs, _ctx: &mut Self::Context) -> Self::Result {
-        self.database.get_proposed_ts_for_tid(&msg.0)
+    fn handle(&mut self, msg: NotifyParticipants, _ctx: &mut Self::Context) -> Self::Result {
+        let NotifyParticipants(tid, kind, vote, principal) = msg;
+        let (accept, _) = self.take_notification(&tid, kind, principal.as_deref(), vote)?;
+        let delivered_to = self
+            .submissions
+            .get(&tid)
+            .map(|submission| submission.delivered_to.clone())
+            .unwrap_or_default();
+        Ok(Notification {
+            accept,
+            delivered_to,
+        })
+    }
+}
+
+impl Handler<AcceptDelivered> for Repository {
+    type Result = ();
+
+    /// Handle for [`AcceptDelivered`] for [`Repository`].
+    fn handle(&mut self, msg: AcceptDelivered, _ctx: &mut Self::Context) -> Self::Result {
+        let AcceptDelivered(tid, uri) = msg;
+        if let Some(submission) = self.submissions.get_mut(&tid) {
+            submission.delivered_to.insert(uri);
+        }
+    }
+}
+
+impl Handler<GetMetrics> for Repository {
+    type Result = MessageResult<GetMetrics>;
+
+    /// Handle for [`GetMetrics`] for [`Repository`].
+    fn handle(&mut self, _msg: GetMetrics, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(Metrics {
+            active_transactions: self.database.active_transactions.len(),
+            locked_keys: self.database.locked_keys.len(),
+            durable_writes: self.runtime.durable_writes.clone(),
+            ..self.metrics.clone()
+        })
+    }
+}
+
+impl Handler<Introspect> for Repository {
+    type Result = MessageResult<Introspect>;
+
+    /// Handle for [`Introspect`] for [`Repository`].
+    fn handle(&mut self, _msg: Introspect, _ctx: &mut Self::Context) -> Self::Result {
+        let database = &self.database;
+        let mut pending: Vec<PendingTransaction> = database
+            .active_transactions
+            .iter()
+            .map(|(tid, xaction)| PendingTransaction {
+                tid: *tid,
+                kind: self.prepared_at.get(tid).map(|(kind, _)| kind.to_string()),
+                proposed_ts: xaction.proposed_ts,
+                waiting_for: xaction.waiting_for,
+                locks: database.lock_holders.get(tid).cloned().unwrap_or_default(),
+            })
+            .collect();
+        pending.sort_by_key(|xaction| xaction.proposed_ts);
+        let mode = if database.locked_keys.is_empty() {
+            Mode::Timestamp
+        } else {
+            Mode::Locking
+        };
+        MessageResult(Introspection {
+            last_timestamp: self.last_timestamp,
+            last_executed_ts: database.last_executed_ts,
+            low_watermark: database.low_watermark,
+            mode,
+            pending,
+            recent_aborts: self.recent_aborts.to_vec(),
+        })
+    }
+}
+
+impl Handler<AbortTransaction> for Repository {
+    type Result = anyhow::Result<(), anyhow::Error>;
+
+    /// Handle for [`AbortTransaction`] for [`Repository`].
+    /// Releases the locks of the transaction, and runs the transactions
+    /// that were waiting for it. Refused once its participants were sent a
+    /// `Commit`.
+    fn handle(&mut self, msg: AbortTransaction, _ctx: &mut Self::Context) -> Self::Result {
+        let tid = msg.0;
+        let Some(xaction) = self.database.active_transactions.get(&tid) else {
+            if self.done_xactions.contains_key(&tid) {
+                anyhow::bail!("transaction {tid} already finished");
+            }
+            anyhow::bail!("no pending transaction {tid}");
+        };
+        let proposed_ts = xaction.proposed_ts;
+        if let Some(submission) = self.submissions.get_mut(&tid) {
+            anyhow::ensure!(
+                !submission
+                    .accept
+                    .as_ref()
+                    .is_some_and(|accept| matches!(accept.vote(), CommitVote::Commit(_))),
+                "transaction {tid} was accepted by the other participants, which may commit it"
+            );
+            // Its participants are sent `Abort` once notified.
+            submission.vote = CommitVote::Abort;
+        }
+        self.runtime
+            .write_to_durable(&self.filename, &format!("abort {tid}"), proposed_ts)?;
+        self.database.finalize(&tid, proposed_ts);
+        self.database.release_locks_of(&tid);
+        self.finish(tid, Err(anyhow::anyhow!("aborted by an operator")));
+        self.run_ready();
+        Ok(())
+    }
+}
+
+impl Handler<TakeCheckpoint> for Repository {
+    type Result = anyhow::Result<Checkpoint, anyhow::Error>;
+
+    /// Handle for [`TakeCheckpoint`] for [`Repository`].
+    fn handle(&mut self, _msg: TakeCheckpoint, _ctx: &mut Self::Context) -> Self::Result {
+        let ts = self.database.last_executed_ts;
+        let data = &self.database.data_structure;
+        let path = self.runtime.write_checkpoint(&self.filename, ts, data)?;
+        Ok(Checkpoint {
+            ts,
+            keys: data.len(),
+            path,
+        })
+    }
+}
+
+impl Handler<CheckLog> for Repository {
+    type Result = anyhow::Result<(), anyhow::Error>;
+
+    /// Handle for [`CheckLog`] for [`Repository`].
+    fn handle(&mut self, _msg: CheckLog, _ctx: &mut Self::Context) -> Self::Result {
+        self.runtime.check_log(&self.filename)
+    }
+}
+
+impl Handler<ExportRange> for Repository {
+    type Result = anyhow::Result<Vec<(PrimaryKey, Table)>, anyhow::Error>;
+
+    /// Handle for [`ExportRange`] for [`Repository`].
+    /// Exports the keys as left by the latest executed transaction.
+    fn handle(&mut self, msg: ExportRange, _ctx: &mut Self::Context) -> Self::Result {
+        Ok(self.database.export_range(&msg.0))
+    }
+}
+
+impl Handler<ImportRange> for Repository {
+    type Result = anyhow::Result<(), anyhow::Error>;
+
+    /// Handle for [`ImportRange`] for [`Repository`].
+    /// The imported keys are logged, and published like committed changes.
+    fn handle(&mut self, msg: ImportRange, _ctx: &mut Self::Context) -> Self::Result {
+        let ImportRange(range, entries) = msg;
+        let entries_str: Vec<String> = entries
+            .iter()
+            .map(|(key, value)| match value {
+                Some(value) => format!("{key} = {value}"),
+                None => format!("{key} deleted"),
+            })
+            .collect();
+        let request = format!(
+            "import {}..{}: {}",
+            range.start,
+            range.end,
+            entries_str.join("; ")
+        );
+        let ts = self.database.last_executed_ts;
+        self.runtime
+            .write_to_durable(&self.filename, &request, ts)?;
+        self.database.import_range(&range, entries)?;
+        self.publish_changes();
+        Ok(())
+    }
+}
+
+impl Handler<ServeRange> for Repository {
+    type Result = anyhow::Result<(), anyhow::Error>;
+
+    /// Handle for [`ServeRange`] for [`Repository`].
+    fn handle(&mut self, msg: ServeRange, _ctx: &mut Self::Context) -> Self::Result {
+        self.database.serve_range(&msg.0)
+    }
+}
+
+impl Handler<DropRange> for Repository {
+    type Result = anyhow::Result<(), anyhow::Error>;
+
+    /// Handle for [`DropRange`] for [`Repository`].
+    fn handle(&mut self, msg: DropRange, _ctx: &mut Self::Context) -> Self::Result {
+        self.database.drop_range(&msg.0)
     }
 }
@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::messages::TransactionError;
+
+/// When and how often to run again a transaction that failed with a
+/// [`TransactionError::Conflict`].
+///
+/// The delay before the `n`th retry grows exponentially from `initial_backoff`
+/// up to `max_backoff`, and a random part of it (up to half) is skipped, so
+/// transactions that conflicted with each other don't retry in lockstep.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry.
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between attempts.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn never() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// How long to wait before trying again, after `attempts` attempts failed
+    /// with `error`. `None` if it shouldn't be tried again.
+    pub fn backoff(&self, attempts: u32, error: &anyhow::Error) -> Option<Duration> {
+        if attempts >= self.max_attempts || !TransactionError::is_conflict(error) {
+            return None;
+        }
+
+        let exponent = attempts.saturating_sub(1).min(31);
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+        let jitter = rand::thread_rng().gen_range(0.0..=0.5);
+        Some(backoff.mul_f64(1.0 - jitter))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(250),
+        };
+        let conflict = anyhow::Error::new(TransactionError::Conflict);
+
+        let first = policy.backoff(1, &conflict).unwrap();
+        assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+        let second = policy.backoff(2, &conflict).unwrap();
+        assert!(second >= Duration::from_millis(100) && second <= Duration::from_millis(200));
+        let third = policy.backoff(3, &conflict).unwrap();
+        assert!(third >= Duration::from_millis(125) && third <= Duration::from_millis(250));
+        assert_eq!(policy.backoff(4, &conflict), None);
+
+        let abort = anyhow::Error::new(TransactionError::Abort);
+        assert_eq!(policy.backoff(1, &abort), None);
+        let other = anyhow::anyhow!("mailbox closed");
+        assert_eq!(policy.backoff(1, &other), None);
+    }
+}
@@ -1,23 +1,84 @@
-use std::fs::File;
-use std::io::Write;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 use tempfile::TempDir;
 
+use crate::{
+    metrics::Histogram,
+    operations::{PrimaryKey, Table},
+};
+
+/// How [Runtime::write_to_durable] persists requests.
+///
+/// The log is only ever appended to: a repository doesn't read it back when
+/// it starts, so it records what was run rather than letting a repository
+/// recover its keys. Checkpoints are the same.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// Requests aren't written at all.
+    None,
+    /// Requests are written, and flushed to disk by the OS eventually.
+    #[default]
+    Write,
+    /// Requests are written and flushed to disk before answering.
+    Sync,
+}
+
+const INITIAL_TIME: usize = 10;
+
 #[derive(Debug)]
 pub struct Runtime {
-    dir: TempDir,
+    dir: PathBuf,
+    /// Removed with the [Runtime], when there is no data directory.
+    _temp_dir: Option<TempDir>,
+    durability: Durability,
     current_time: usize,
+    /// How long [Runtime::write_to_durable] took.
+    pub(crate) durable_writes: Histogram,
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Runtime {
     pub fn new() -> Self {
         let tmp_dir = tempfile::tempdir().unwrap();
 
-        let initial_time: usize = 10;
+        Runtime {
+            dir: tmp_dir.path().to_path_buf(),
+            _temp_dir: Some(tmp_dir),
+            durability: Durability::default(),
+            current_time: INITIAL_TIME,
+            durable_writes: Histogram::default(),
+        }
+    }
+
+    /// A [Runtime] writing to `data_dir`, created if needed, and kept after
+    /// the [Runtime] is gone.
+    pub fn with_data_dir(data_dir: &Path, durability: Durability) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(data_dir)
+            .map_err(|e| anyhow::anyhow!("couldn't create data dir {}: {e}", data_dir.display()))?;
+        Ok(Runtime {
+            dir: data_dir.to_path_buf(),
+            _temp_dir: None,
+            durability,
+            current_time: INITIAL_TIME,
+            durable_writes: Histogram::default(),
+        })
+    }
 
+    /// A [Runtime] writing to a temporary directory, removed with it.
+    pub fn with_durability(durability: Durability) -> Self {
         Runtime {
-            dir: tmp_dir,
-            current_time: initial_time,
+            durability,
+            ..Self::new()
         }
     }
 
@@ -27,15 +88,64 @@ impl Runtime {
         self.current_time
     }
 
-    pub(crate) fn write_to_durable(
+    /// Writes every key of `data` to `{filename}.checkpoint`, replacing the
+    /// previous checkpoint only once the new one is on disk.
+    pub(crate) fn write_checkpoint(
         &self,
         filename: &str,
+        ts: usize,
+        data: &BTreeMap<PrimaryKey, Table>,
+    ) -> anyhow::Result<PathBuf> {
+        let path = self.dir.join(format!("{filename}.checkpoint"));
+        let partial = self.dir.join(format!("{filename}.checkpoint.partial"));
+        let write = || {
+            let mut file = BufWriter::new(File::create(&partial)?);
+            writeln!(file, "checkpoint at {ts}")?;
+            for (key, value) in data {
+                writeln!(file, "{key}, {value:?}")?;
+            }
+            file.into_inner()?.sync_all()?;
+            std::fs::rename(&partial, &path)?;
+            anyhow::Ok(())
+        };
+        write().map_err(|e| anyhow::anyhow!("couldn't write {}: {e}", path.display()))?;
+        Ok(path)
+    }
+
+    /// Fails if the log at `filename` can't be written.
+    pub(crate) fn check_log(&self, filename: &str) -> anyhow::Result<()> {
+        if self.durability == Durability::None {
+            return Ok(());
+        }
+        let path = self.dir.join(filename);
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| anyhow::anyhow!("can't write the log {}: {e}", path.display()))?;
+        Ok(())
+    }
+
+    pub(crate) fn write_to_durable(
+        &mut self,
+        filename: &str,
         request: &str,
         ts: usize,
     ) -> anyhow::Result<()> {
-        let mut file = File::create(self.dir.path().join(filename))?;
+        if self.durability == Durability::None {
+            return Ok(());
+        }
+        let start = Instant::now();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(filename))?;
 
-        let _ = writeln!(file, "{request}, {ts}\n")?;
+        writeln!(file, "{request}, {ts}\n")?;
+        if self.durability == Durability::Sync {
+            file.sync_data()?;
+        }
+        self.durable_writes.observe(start.elapsed());
         Ok(())
     }
 }
@@ -0,0 +1,515 @@
+//! The messages exchanged with a `RepositoryWs`, and their encodings.
+//!
+//! Shared by the `ws` server and the `cereal-client` library.
+//!
+//! Each [`MessageWs`] is sent in a [`Request`] with an `id`, answered with
+//! the [`Response`] with the same `id`. So responses can come in any order,
+//! and many transactions can share a connection.
+//!
+//! The encoding is negotiated at the WebSocket handshake, with the
+//! `Sec-WebSocket-Protocol` header:
+//! - [`MSGPACK_V2`]: messages are MessagePack `Binary` frames;
+//! - [`JSON_V2`]: messages are JSON `Text` frames. Handy to debug, and used
+//!   when no subprotocol is asked for.
+//!
+//! Frames are decoded by their kind, so a `Text` frame is always read as
+//! JSON, whatever was negotiated.
+//!
+//! A [`Request`] also carries the W3C trace context of the span sending it,
+//! so the spans of a transaction in every process are part of one trace.
+use actix_http::{
+    header::{HeaderMap, SEC_WEBSOCKET_PROTOCOL},
+    ws::{self, Frame},
+};
+use cereal_core::{
+    application::TransactionKind,
+    introspection::Introspection,
+    messages::{Checkpoint, CommitVote},
+    operations::{Arguments, Operation, Table},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{collections::HashMap, ops::Range};
+use uuid::Uuid;
+
+/// Version 2 of the protocol, encoded with MessagePack.
+pub const MSGPACK_V2: &str = "cereal.v2.msgpack";
+/// Version 2 of the protocol, encoded with JSON.
+pub const JSON_V2: &str = "cereal.v2.json";
+
+/// The subprotocols a `RepositoryWs` speaks, preferred first.
+pub const SUBPROTOCOLS: [&str; 2] = [MSGPACK_V2, JSON_V2];
+
+/// A `network` wrap over [cereal_core::messages].
+///
+/// Sent inside a [Request], and answered with a [Response] with the same `id`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum MessageWs {
+    Single {
+        tid: Uuid,
+        args: Arguments,
+    },
+    Snapshot {
+        tid: Uuid,
+        args: Arguments,
+        snapshot_ts: usize,
+    },
+    // tid, ops, participants.length()
+    Indep {
+        tid: Uuid,
+        args: Arguments,
+        participants_size: usize,
+    },
+    // TODO: change Vec<String> to something better?
+    IndepParticipants {
+        tid: Uuid,
+        vote: CommitVote,
+        participants: Vec<String>,
+    },
+    // TODO: change to be actors instead of u64.
+    Coord {
+        tid: Uuid,
+        args: Arguments,
+        participants_size: usize,
+    },
+    CoordParticipants {
+        tid: Uuid,
+        vote: CommitVote,
+        participants: Vec<String>,
+    },
+    AcceptIndep {
+        tid: Uuid,
+        proposed_ts: usize,
+        vote: CommitVote,
+        /// The repository accepting, whose accept is only counted once.
+        participant: String,
+    },
+    AcceptCoord {
+        tid: Uuid,
+        proposed_ts: usize,
+        vote: CommitVote,
+        /// The repository accepting, whose accept is only counted once.
+        participant: String,
+    },
+    GetResult {
+        tid: Uuid,
+    },
+    /// A whole multi-repository transaction, for a `ws gateway`, which runs
+    /// all its phases with the `participants`. Answered with the results of
+    /// each participant, in order.
+    Transaction {
+        kind: TransactionKind,
+        participants: Vec<Participant>,
+    },
+    /// Reads every key of the range. Answered with its [ResponseBody::Entries].
+    ExportRange {
+        range: Range<usize>,
+    },
+    /// Writes the keys of a range being moved to the repository, `None`
+    /// deleting a key.
+    ImportRange {
+        range: Range<usize>,
+        entries: Vec<(usize, Option<Table>)>,
+    },
+    /// Starts serving a range moved to the repository.
+    ServeRange {
+        range: Range<usize>,
+    },
+    /// Drops the keys of a range moved out of the repository, or imported
+    /// by a move that was abandoned.
+    DropRange {
+        range: Range<usize>,
+    },
+    /// Answered with [ResponseBody::Done], to check a repository is
+    /// reachable.
+    Ping,
+    /// Answered with the [ResponseBody::Introspection] of the repository.
+    Introspect,
+    /// Aborts a pending transaction of the repository, for operators.
+    Abort {
+        tid: Uuid,
+    },
+    /// Writes a checkpoint of the repository. Answered with its
+    /// [ResponseBody::Checkpoint].
+    Checkpoint,
+}
+
+impl MessageWs {
+    /// The name of the message, as in its `type` tag.
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessageWs::Single { .. } => "Single",
+            MessageWs::Snapshot { .. } => "Snapshot",
+            MessageWs::Indep { .. } => "Indep",
+            MessageWs::IndepParticipants { .. } => "IndepParticipants",
+            MessageWs::Coord { .. } => "Coord",
+            MessageWs::CoordParticipants { .. } => "CoordParticipants",
+            MessageWs::AcceptIndep { .. } => "AcceptIndep",
+            MessageWs::AcceptCoord { .. } => "AcceptCoord",
+            MessageWs::GetResult { .. } => "GetResult",
+            MessageWs::Transaction { .. } => "Transaction",
+            MessageWs::ExportRange { .. } => "ExportRange",
+            MessageWs::ImportRange { .. } => "ImportRange",
+            MessageWs::ServeRange { .. } => "ServeRange",
+            MessageWs::DropRange { .. } => "DropRange",
+            MessageWs::Ping => "Ping",
+            MessageWs::Introspect => "Introspect",
+            MessageWs::Abort { .. } => "Abort",
+            MessageWs::Checkpoint => "Checkpoint",
+        }
+    }
+
+    /// The transaction the message is about, if it is about one.
+    pub fn tid(&self) -> Option<Uuid> {
+        match self {
+            MessageWs::Single { tid, .. }
+            | MessageWs::Snapshot { tid, .. }
+            | MessageWs::Indep { tid, .. }
+            | MessageWs::IndepParticipants { tid, .. }
+            | MessageWs::Coord { tid, .. }
+            | MessageWs::CoordParticipants { tid, .. }
+            | MessageWs::AcceptIndep { tid, .. }
+            | MessageWs::AcceptCoord { tid, .. }
+            | MessageWs::GetResult { tid }
+            | MessageWs::Abort { tid } => Some(*tid),
+            MessageWs::Transaction { .. }
+            | MessageWs::ExportRange { .. }
+            | MessageWs::ImportRange { .. }
+            | MessageWs::ServeRange { .. }
+            | MessageWs::DropRange { .. }
+            | MessageWs::Ping
+            | MessageWs::Introspect
+            | MessageWs::Checkpoint => None,
+        }
+    }
+}
+
+/// A repository taking part in a [MessageWs::Transaction].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Participant {
+    /// Where the `RepositoryWs` is, e.g. `http://127.0.0.1:8080/ws/`.
+    pub uri: String,
+    pub operations: Vec<Operation>,
+}
+
+/// The answer to a [MessageWs::GetResult].
+#[derive(Debug, Serialize, Deserialize)]
+pub enum GetResultResponse {
+    Ok(Option<Table>),
+    Err(String),
+}
+
+/// A [MessageWs] sent to a `RepositoryWs`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Request {
+    pub id: u64,
+    pub message: MessageWs,
+    /// The `traceparent` (and `tracestate`) of the span sending the
+    /// `message`, if it is traced.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub trace_context: HashMap<String, String>,
+}
+
+/// The answer to the [Request] with the same `id`. The `id` is `None` when
+/// the request couldn't be decoded at all.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    pub id: Option<u64>,
+    pub body: ResponseBody,
+}
+
+/// What a [Response] carries.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ResponseBody {
+    /// The vote of the `Repository`.
+    Vote(CommitVote),
+    /// The result of a transaction, for a `GetResult`.
+    Result(GetResultResponse),
+    /// The results of each participant of a [MessageWs::Transaction], and how
+    /// many times it was sent until it committed.
+    Results {
+        results: Vec<Option<Table>>,
+        attempts: u32,
+    },
+    /// The keys of a range, for an `ExportRange`.
+    Entries(Vec<(usize, Table)>),
+    /// The state of the repository, for an `Introspect`.
+    Introspection(Introspection),
+    /// The checkpoint written for a `Checkpoint`.
+    Checkpoint(Checkpoint),
+    /// The request was done.
+    Done,
+    /// The request failed.
+    Error(String),
+}
+
+impl ResponseBody {
+    /// The answer of the `Repository` to a prepare or an accept.
+    pub fn from_vote(vote: anyhow::Result<CommitVote>) -> Self {
+        match vote {
+            Ok(vote) => ResponseBody::Vote(vote),
+            Err(e) => ResponseBody::Error(e.to_string()),
+        }
+    }
+
+    pub fn into_vote(self) -> anyhow::Result<CommitVote> {
+        match self {
+            ResponseBody::Vote(vote) => Ok(vote),
+            ResponseBody::Error(e) => anyhow::bail!(e),
+            other => anyhow::bail!("expected a vote, got {other:?}"),
+        }
+    }
+
+    pub fn into_result(self) -> anyhow::Result<Option<Table>> {
+        match self {
+            ResponseBody::Result(GetResultResponse::Ok(table)) => Ok(table),
+            ResponseBody::Result(GetResultResponse::Err(e)) | ResponseBody::Error(e) => {
+                anyhow::bail!(e)
+            }
+            other => anyhow::bail!("expected a result, got {other:?}"),
+        }
+    }
+
+    /// The results of a [MessageWs::Transaction], and its attempts.
+    pub fn into_results(self) -> anyhow::Result<(Vec<Option<Table>>, u32)> {
+        match self {
+            ResponseBody::Results { results, attempts } => Ok((results, attempts)),
+            ResponseBody::Error(e) => anyhow::bail!(e),
+            other => anyhow::bail!("expected the results of a transaction, got {other:?}"),
+        }
+    }
+
+    /// The answer to a request with nothing to answer but whether it failed.
+    pub fn from_done(done: anyhow::Result<()>) -> Self {
+        match done {
+            Ok(()) => ResponseBody::Done,
+            Err(e) => ResponseBody::Error(format!("{e:#}")),
+        }
+    }
+
+    pub fn into_done(self) -> anyhow::Result<()> {
+        match self {
+            ResponseBody::Done => Ok(()),
+            ResponseBody::Error(e) => anyhow::bail!(e),
+            other => anyhow::bail!("expected it to be done, got {other:?}"),
+        }
+    }
+
+    pub fn into_entries(self) -> anyhow::Result<Vec<(usize, Table)>> {
+        match self {
+            ResponseBody::Entries(entries) => Ok(entries),
+            ResponseBody::Error(e) => anyhow::bail!(e),
+            other => anyhow::bail!("expected the keys of a range, got {other:?}"),
+        }
+    }
+
+    pub fn into_introspection(self) -> anyhow::Result<Introspection> {
+        match self {
+            ResponseBody::Introspection(introspection) => Ok(introspection),
+            ResponseBody::Error(e) => anyhow::bail!(e),
+            other => anyhow::bail!("expected the state of a repository, got {other:?}"),
+        }
+    }
+
+    pub fn into_checkpoint(self) -> anyhow::Result<Checkpoint> {
+        match self {
+            ResponseBody::Checkpoint(checkpoint) => Ok(checkpoint),
+            ResponseBody::Error(e) => anyhow::bail!(e),
+            other => anyhow::bail!("expected a checkpoint, got {other:?}"),
+        }
+    }
+}
+
+/// How messages are encoded over a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    MessagePack,
+    #[default]
+    Json,
+}
+
+impl Encoding {
+    /// The [Encoding] of a subprotocol, if it is a known one.
+    pub fn from_subprotocol(subprotocol: &str) -> Option<Self> {
+        match subprotocol.trim() {
+            MSGPACK_V2 => Some(Encoding::MessagePack),
+            JSON_V2 => Some(Encoding::Json),
+            _ => None,
+        }
+    }
+
+    /// The [Encoding] the server picks from the `headers` of a handshake
+    /// request: the first subprotocol asked for that is known, the same way
+    /// `actix_web_actors::ws::start_with_protocols` picks it.
+    pub fn negotiate(headers: &HeaderMap) -> Self {
+        headers
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|protocols| protocols.to_str().ok())
+            .and_then(|protocols| protocols.split(',').find_map(Self::from_subprotocol))
+            .unwrap_or_default()
+    }
+
+    /// The [Encoding] chosen by the server, from the `headers` of its
+    /// handshake response.
+    pub fn accepted(headers: &HeaderMap) -> Self {
+        headers
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|protocol| protocol.to_str().ok())
+            .and_then(Self::from_subprotocol)
+            .unwrap_or_default()
+    }
+
+    /// Encode `value` as a message.
+    pub fn encode<T: Serialize>(self, value: &T) -> ws::Message {
+        match self {
+            Encoding::MessagePack => ws::Message::Binary(
+                rmp_serde::to_vec_named(value)
+                    .expect("this can be serialized")
+                    .into(),
+            ),
+            Encoding::Json => ws::Message::Text(
+                serde_json::to_string(value)
+                    .expect("this can be serialized")
+                    .into(),
+            ),
+        }
+    }
+}
+
+/// Decode a `Text` (JSON) or `Binary` (MessagePack) message.
+pub fn decode<T: DeserializeOwned>(message: &ws::Message) -> anyhow::Result<T> {
+    match message {
+        ws::Message::Text(text) => Ok(serde_json::from_str(text)?),
+        ws::Message::Binary(bytes) => Ok(rmp_serde::from_slice(bytes)?),
+        other => anyhow::bail!("not a `Text` or `Binary` message: {other:?}"),
+    }
+}
+
+/// Decode a `Text` (JSON) or `Binary` (MessagePack) frame.
+pub fn decode_frame<T: DeserializeOwned>(frame: &Frame) -> anyhow::Result<T> {
+    match frame {
+        Frame::Text(text) => Ok(serde_json::from_slice(text)?),
+        Frame::Binary(bytes) => Ok(rmp_serde::from_slice(bytes)?),
+        other => anyhow::bail!("not a `Text` or `Binary` frame: {other:?}"),
+    }
+}
+
+/// The `id` of a request that couldn't be decoded, if it has one.
+pub fn request_id(message: &ws::Message) -> Option<u64> {
+    #[derive(Deserialize)]
+    struct Id {
+        id: u64,
+    }
+    decode::<Id>(message).ok().map(|request| request.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_http::header::HeaderValue;
+    use cereal_core::operations::Expr;
+
+    use super::*;
+
+    fn request() -> Request {
+        let args = Arguments {
+            timestamp: 3,
+            operations: vec![Operation::Expr(Expr::Read(1))],
+        };
+        Request {
+            id: 7,
+            message: MessageWs::Snapshot {
+                tid: Uuid::new_v4(),
+                args,
+                snapshot_ts: 2,
+            },
+            trace_context: HashMap::from([("traceparent".to_string(), "00-ab-cd-01".to_string())]),
+        }
+    }
+
+    fn headers(protocols: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(protocols));
+        headers
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let sent = request();
+        for encoding in [Encoding::MessagePack, Encoding::Json] {
+            let message = encoding.encode(&sent);
+            let frame = match &message {
+                ws::Message::Binary(bytes) => {
+                    assert_eq!(encoding, Encoding::MessagePack);
+                    Frame::Binary(bytes.clone())
+                }
+                ws::Message::Text(text) => {
+                    assert_eq!(encoding, Encoding::Json);
+                    Frame::Text(text.clone().into_bytes())
+                }
+                other => panic!("unexpected message {other:?}"),
+            };
+            for received in [decode::<Request>(&message), decode_frame(&frame)] {
+                let received = received.unwrap();
+                assert_eq!(received.id, sent.id);
+                assert_eq!(received.message.tid(), sent.message.tid());
+                assert_eq!(received.trace_context, sent.trace_context);
+                let MessageWs::Snapshot {
+                    args, snapshot_ts, ..
+                } = received.message
+                else {
+                    panic!("expected a snapshot, got {:?}", received.message);
+                };
+                assert_eq!((args.timestamp, snapshot_ts), (3, 2));
+            }
+
+            let response = Response {
+                id: Some(7),
+                body: ResponseBody::Vote(CommitVote::Commit(Some(2))),
+            };
+            let response: Response = decode(&encoding.encode(&response)).unwrap();
+            assert_eq!(response.id, Some(7));
+            assert_eq!(
+                response.body.into_vote().unwrap(),
+                CommitVote::Commit(Some(2))
+            );
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let negotiate = |protocols| Encoding::negotiate(&headers(protocols));
+        assert_eq!(negotiate(MSGPACK_V2), Encoding::MessagePack);
+        assert_eq!(negotiate("cereal.v1, cereal.v2.json"), Encoding::Json);
+        assert_eq!(
+            negotiate("cereal.v1, cereal.v2.msgpack, cereal.v2.json"),
+            Encoding::MessagePack
+        );
+        // Nothing known, or nothing asked for: JSON.
+        assert_eq!(negotiate("cereal.v1"), Encoding::Json);
+        assert_eq!(Encoding::negotiate(&HeaderMap::new()), Encoding::Json);
+
+        assert_eq!(
+            Encoding::accepted(&headers(MSGPACK_V2)),
+            Encoding::MessagePack
+        );
+        assert_eq!(Encoding::accepted(&headers("cereal.v1")), Encoding::Json);
+        assert_eq!(Encoding::accepted(&HeaderMap::new()), Encoding::Json);
+    }
+
+    #[test]
+    fn test_invalid_request() {
+        let unknown = r#"{"id": 9, "message": {"type": "Unknown"}}"#;
+        let message = ws::Message::Text(unknown.into());
+        assert!(decode::<Request>(&message).is_err());
+        assert_eq!(request_id(&message), Some(9));
+
+        let mut value = rmp_serde::to_vec_named(&request()).unwrap();
+        value.truncate(value.len() / 2);
+        let message = ws::Message::Binary(value.into());
+        assert!(decode::<Request>(&message).is_err());
+        assert_eq!(request_id(&message), None);
+
+        assert!(decode::<Request>(&ws::Message::Ping("".into())).is_err());
+        assert_eq!(request_id(&ws::Message::Text("not json".into())), None);
+    }
+}
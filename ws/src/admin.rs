@@ -0,0 +1,253 @@
+//! `ws admin`: inspect and operate a running `ws repository`, e.g. to abort
+//! a coordinated transaction stuck holding locks without restarting it.
+//!
+//! The commands are sent over the `/ws/` endpoint of the repository, as a
+//! peer, since the repository only takes them from its peers.
+use cereal_client::ConnectionHandle;
+use cereal_core::introspection::{Introspection, Mode};
+use cereal_protocol::MessageWs;
+use clap::Subcommand;
+use uuid::Uuid;
+
+use crate::config::{ClusterConfig, RepositoryConfig};
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum AdminCommand {
+    /// the timestamps, mode and counts of the repository.
+    Status,
+    /// the pending transactions, with their locks, and the latest aborts.
+    Txns,
+    /// abort a pending transaction, releasing its locks. Its other
+    /// participants are sent an abort when it notifies them, and it is
+    /// refused once it sent them a commit, since they may have committed.
+    Abort { tid: Uuid },
+    /// write every key of the repository next to its log.
+    Checkpoint,
+    /// print every key of the repository.
+    Dump {
+        /// dump the repository storing this table of the cluster config,
+        /// if it stores no other table.
+        #[arg(long)]
+        table: Option<String>,
+    },
+}
+
+/// The repository of `cluster` to run `command` against: the one storing
+/// the table to dump, or the one called `name`.
+pub(crate) fn repository<'a>(
+    cluster: &'a ClusterConfig,
+    command: &AdminCommand,
+    name: Option<&str>,
+) -> anyhow::Result<&'a RepositoryConfig> {
+    let AdminCommand::Dump { table: Some(table) } = command else {
+        return cluster.repository(name);
+    };
+    anyhow::ensure!(
+        name.is_none(),
+        "dump either --table or the repository --name"
+    );
+    let repository = cluster.repository_of(table)?;
+    // The tables of a repository share its keys, so the keys of one can't be
+    // told from the others'.
+    anyhow::ensure!(
+        repository.tables.len() == 1,
+        "table {table} shares its keys with the other tables of repository {}, \
+         dump the repository by --name",
+        repository.name
+    );
+    Ok(repository)
+}
+
+/// Runs `command` against the repository at the other end of `connection`.
+pub(crate) async fn run(
+    connection: &ConnectionHandle,
+    command: AdminCommand,
+) -> anyhow::Result<()> {
+    match command {
+        AdminCommand::Status => {
+            let introspection = introspect(connection).await?;
+            print_status(connection.uri(), &introspection);
+        }
+        AdminCommand::Txns => {
+            let introspection = introspect(connection).await?;
+            print_transactions(&introspection);
+        }
+        AdminCommand::Abort { tid } => {
+            connection
+                .request(MessageWs::Abort { tid })
+                .await?
+                .into_done()?;
+            println!("aborted {tid}");
+        }
+        AdminCommand::Checkpoint => {
+            let checkpoint = connection
+                .request(MessageWs::Checkpoint)
+                .await?
+                .into_checkpoint()?;
+            println!(
+                "checkpoint at {}: {} keys written to {}",
+                checkpoint.ts,
+                checkpoint.keys,
+                checkpoint.path.display()
+            );
+        }
+        AdminCommand::Dump { .. } => {
+            let entries = connection
+                .request(MessageWs::ExportRange {
+                    range: 0..usize::MAX,
+                })
+                .await?
+                .into_entries()?;
+            for (key, value) in entries {
+                println!("{key}\t{value}");
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn introspect(connection: &ConnectionHandle) -> anyhow::Result<Introspection> {
+    connection
+        .request(MessageWs::Introspect)
+        .await?
+        .into_introspection()
+}
+
+fn print_status(uri: &str, introspection: &Introspection) {
+    let mode = match introspection.mode {
+        Mode::Timestamp => "timestamp",
+        Mode::Locking => "locking",
+    };
+    let locks: usize = introspection
+        .pending
+        .iter()
+        .map(|xaction| xaction.locks.len())
+        .sum();
+    println!("repository:       {uri}");
+    println!("mode:             {mode}");
+    println!("last timestamp:   {}", introspection.last_timestamp);
+    println!("last executed ts: {}", introspection.last_executed_ts);
+    println!("low watermark:    {}", introspection.low_watermark);
+    println!("pending:          {}", introspection.pending.len());
+    println!("locked keys:      {locks}");
+    println!("recent aborts:    {}", introspection.recent_aborts.len());
+}
+
+fn print_transactions(introspection: &Introspection) {
+    if introspection.pending.is_empty() {
+        println!("no pending transactions");
+    } else {
+        println!("tid\tkind\tproposed_ts\twaiting_for\tlocks");
+        for xaction in &introspection.pending {
+            println!(
+                "{}\t{}\t{}\t{}\t{:?}",
+                xaction.tid,
+                xaction.kind.as_deref().unwrap_or("-"),
+                xaction.proposed_ts,
+                xaction.waiting_for,
+                xaction.locks
+            );
+        }
+    }
+    if !introspection.recent_aborts.is_empty() {
+        println!();
+        println!("recent aborts, the last one first:");
+        for abort in &introspection.recent_aborts {
+            println!("{}\t{}", abort.tid, abort.error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cereal_client::{create, read, update, value};
+    use cereal_core::{
+        messages::CommitVote,
+        operations::{Arguments, Table},
+        repository::Repository,
+    };
+
+    use super::*;
+    use crate::testing::TestRepository;
+
+    #[actix_web::test]
+    async fn test_abort_a_stuck_transaction() {
+        let repository = TestRepository::serve(Repository::new("admin".to_string()));
+        let connection = ConnectionHandle::spawn(repository.uri());
+        let args = Arguments {
+            timestamp: 0,
+            operations: vec![create!(1, value!(Table(1, 1)))],
+        };
+        let create = MessageWs::Single {
+            tid: Uuid::new_v4(),
+            args,
+        };
+        connection
+            .request(create)
+            .await
+            .unwrap()
+            .into_vote()
+            .unwrap();
+
+        // A coordinated transaction whose other participant never answers.
+        let tid = Uuid::new_v4();
+        let args = Arguments {
+            timestamp: 0,
+            operations: vec![update!(2, read!(1))],
+        };
+        let message = MessageWs::Coord {
+            tid,
+            args,
+            participants_size: 2,
+        };
+        let vote = connection.request(message).await.unwrap().into_vote();
+        assert_eq!(vote.unwrap(), CommitVote::Commit(None));
+        let introspection = introspect(&connection).await.unwrap();
+        assert_eq!(introspection.pending[0].tid, tid);
+        assert_eq!(introspection.pending[0].locks, vec![1, 2]);
+
+        run(&connection, AdminCommand::Txns).await.unwrap();
+        run(&connection, AdminCommand::Abort { tid }).await.unwrap();
+        let introspection = introspect(&connection).await.unwrap();
+        assert!(introspection.pending.is_empty());
+        assert_eq!(introspection.recent_aborts[0].tid, tid);
+        run(&connection, AdminCommand::Status).await.unwrap();
+
+        let again = run(&connection, AdminCommand::Abort { tid }).await;
+        let e = again.unwrap_err();
+        assert!(format!("{e:#}").contains("already finished"), "{e:#}");
+    }
+
+    #[test]
+    fn test_repository() {
+        let cluster: ClusterConfig = toml::from_str(
+            r#"
+            [[repositories]]
+            name = "customer"
+            listen = "127.0.0.1:8080"
+            tables = ["customer"]
+
+            [[repositories]]
+            name = "order"
+            listen = "127.0.0.1:8081"
+            tables = ["order", "product"]
+            "#,
+        )
+        .unwrap();
+        let dump = |table: &str| AdminCommand::Dump {
+            table: Some(table.to_string()),
+        };
+
+        let customer = repository(&cluster, &dump("customer"), None).unwrap();
+        assert_eq!(customer.name, "customer");
+        let order = repository(&cluster, &AdminCommand::Status, Some("order"));
+        assert_eq!(order.unwrap().name, "order");
+        let shared = repository(&cluster, &dump("order"), None).unwrap_err();
+        assert!(
+            format!("{shared:#}").contains("shares its keys"),
+            "{shared:#}"
+        );
+        assert!(repository(&cluster, &dump("customer"), Some("order")).is_err());
+        assert!(repository(&cluster, &dump("stock"), None).is_err());
+    }
+}
@@ -0,0 +1,409 @@
+//! Authenticating the connections to a `ws repository`.
+//!
+//! Once tokens are configured, a connection must present one as a bearer
+//! token at the handshake: the client token or the peer token. A connection
+//! presenting a certificate signed by the cluster CA, over TLS, is a peer
+//! too. Only peers may send accepts and move key ranges, which would let a
+//! client commit or abort the transactions of other clients.
+//!
+//! With a CA but no tokens, connections without a certificate are clients.
+//! With neither, every connection is trusted as a peer: this is insecure,
+//! and only meant for a repository reachable by trusted hosts alone.
+//!
+//! A principal's token authenticates a client as the principal, whose
+//! transactions only run the operations it was granted (see
+//! [cereal_core::permissions]).
+use std::{any::Any, path::Path, sync::Arc};
+
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::{dev::Extensions, error::ErrorUnauthorized, http::header, rt::net::TcpStream};
+use actix_web::{HttpRequest, Result};
+use cereal_client::{load_certs, load_key};
+use cereal_core::operations::Operation;
+use cereal_protocol::MessageWs;
+use rustls::{server::WebPkiClientVerifier, RootCertStore, ServerConfig};
+
+/// Who is at the other end of a connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Role {
+    /// A client, authenticated as a principal if it presented its token.
+    Client(Option<String>),
+    /// Another repository of the cluster, or an operator moving keys.
+    Peer,
+}
+
+impl Role {
+    /// Fails if a connection with this role may not send `message`. Key
+    /// ranges are moved by the range messages, and by the `RELEASE` and
+    /// `ACQUIRE` statements of any transaction.
+    pub(crate) fn check(&self, message: &MessageWs) -> anyhow::Result<()> {
+        let peers_only = match message {
+            MessageWs::AcceptIndep { .. } | MessageWs::AcceptCoord { .. } => "accepts",
+            MessageWs::ExportRange { .. }
+            | MessageWs::ImportRange { .. }
+            | MessageWs::ServeRange { .. }
+            | MessageWs::DropRange { .. } => "key range moves",
+            MessageWs::Single { args, .. }
+            | MessageWs::Snapshot { args, .. }
+            | MessageWs::Indep { args, .. }
+            | MessageWs::Coord { args, .. }
+                if args.operations.iter().any(Operation::moves_keys) =>
+            {
+                "key range moves"
+            }
+            MessageWs::Introspect | MessageWs::Abort { .. } | MessageWs::Checkpoint => {
+                "admin commands"
+            }
+            _ => return Ok(()),
+        };
+        anyhow::ensure!(
+            *self == Role::Peer,
+            "{peers_only} are only taken from peer repositories"
+        );
+        Ok(())
+    }
+
+    /// The principal the transactions of the connection run as.
+    pub(crate) fn principal(&self) -> Option<&str> {
+        match self {
+            Role::Client(principal) => principal.as_deref(),
+            Role::Peer => None,
+        }
+    }
+}
+
+/// Set on the connections authenticated by a client certificate, by
+/// [on_connect].
+#[derive(Clone, Copy)]
+struct PeerCertificate;
+
+/// For `HttpServer::on_connect`: marks the TLS connections whose client
+/// certificate was verified as peers.
+pub(crate) fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    if let Some(tls) = connection.downcast_ref::<TlsStream<TcpStream>>() {
+        if tls.get_ref().1.peer_certificates().is_some() {
+            data.insert(PeerCertificate);
+        }
+    }
+}
+
+/// Tells the [Role] of each connection.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Authenticator {
+    pub(crate) client_token: Option<String>,
+    pub(crate) peer_token: Option<String>,
+    /// The name of each principal, with its token.
+    pub(crate) principals: Vec<(String, String)>,
+    /// Whether peers present a certificate signed by the cluster CA, so
+    /// connections without one are clients.
+    pub(crate) peer_certificates: bool,
+}
+
+impl Authenticator {
+    /// Whether every connection is trusted as a peer, with no token or CA to
+    /// authenticate it.
+    pub(crate) fn is_insecure(&self) -> bool {
+        !self.peer_certificates && !self.has_tokens()
+    }
+
+    fn has_tokens(&self) -> bool {
+        self.client_token.is_some() || self.peer_token.is_some() || !self.principals.is_empty()
+    }
+
+    /// The [Role] of the connection of `req`. Every connection is a peer if
+    /// no token or CA is configured, see [Authenticator::is_insecure].
+    pub(crate) fn authenticate(&self, req: &HttpRequest) -> Result<Role> {
+        if req.conn_data::<PeerCertificate>().is_some() {
+            return Ok(Role::Peer);
+        }
+        if !self.has_tokens() {
+            return Ok(if self.peer_certificates {
+                Role::Client(None)
+            } else {
+                Role::Peer
+            });
+        }
+        let token = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| ErrorUnauthorized("a bearer token is needed"))?;
+        if matches(self.peer_token.as_deref(), token) {
+            return Ok(Role::Peer);
+        }
+        if matches(self.client_token.as_deref(), token) {
+            return Ok(Role::Client(None));
+        }
+        self.principals
+            .iter()
+            .find(|(_, expected)| matches(Some(expected), token))
+            .map(|(name, _)| Role::Client(Some(name.clone())))
+            .ok_or_else(|| ErrorUnauthorized("invalid token"))
+    }
+}
+
+/// Compares the tokens in a time independent of where they differ.
+fn matches(expected: Option<&str>, token: &str) -> bool {
+    let Some(expected) = expected else {
+        return false;
+    };
+    expected.len() == token.len()
+        && expected
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Serves the certificate at `cert` with the key at `key`, taking client
+/// certificates signed by the CA at `ca` (but not requiring them) if there
+/// is one.
+pub(crate) fn server_config(
+    cert: &Path,
+    key: &Path,
+    ca: Option<&Path>,
+) -> anyhow::Result<ServerConfig> {
+    let builder = ServerConfig::builder();
+    let builder = match ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca)? {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .allow_unauthenticated()
+                .build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(builder.with_single_cert(load_certs(cert)?, load_key(key)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, net::SocketAddr};
+
+    use actix::prelude::*;
+    use actix_web::{http::StatusCode, test::TestRequest, web, App, HttpServer};
+    use cereal_client::{create, value, ClientBuilder, ConnectionHandle, Credentials};
+    use cereal_core::{
+        operations::{parse_operations, Arguments, Table},
+        repository::Repository,
+    };
+    use cereal_protocol::{MessageWs, ResponseBody};
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::peers::PeerManager;
+
+    const CLIENT_TOKEN: &str = "client-token";
+    const PEER_TOKEN: &str = "peer-token";
+
+    /// Writes a CA, and a certificate for `127.0.0.1` signed by it.
+    fn generate_certs() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(vec![]).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["127.0.0.1".to_string()])
+            .unwrap()
+            .signed_by(&key, &ca, &ca_key)
+            .unwrap();
+
+        fs::write(dir.path().join("ca.pem"), ca.pem()).unwrap();
+        fs::write(dir.path().join("repository.pem"), cert.pem()).unwrap();
+        fs::write(dir.path().join("repository.key"), key.serialize_pem()).unwrap();
+        dir
+    }
+
+    /// Serves a repository over TLS, authenticating with `authenticator`.
+    fn serve(certs: &Path, authenticator: Authenticator) -> SocketAddr {
+        let tls = server_config(
+            &certs.join("repository.pem"),
+            &certs.join("repository.key"),
+            Some(&certs.join("ca.pem")),
+        )
+        .unwrap();
+        let repo_actor = web::Data::new(Repository::new("auth".to_string()).start());
+        let peers = web::Data::new(PeerManager::default().start());
+        let authenticator = web::Data::new(authenticator);
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::clone(&repo_actor))
+                .app_data(web::Data::clone(&peers))
+                .app_data(web::Data::clone(&authenticator))
+                .route("/ws/", web::get().to(crate::index))
+        })
+        .on_connect(on_connect)
+        .workers(1)
+        .bind_rustls_0_23(("127.0.0.1", 0), tls)
+        .unwrap();
+        let address = server.addrs()[0];
+        actix::spawn(server.run());
+        address
+    }
+
+    fn credentials(certs: &Path, token: Option<&str>, identity: bool) -> Credentials {
+        let identity =
+            identity.then(|| (certs.join("repository.pem"), certs.join("repository.key")));
+        let identity = identity.as_ref().map(|(c, k)| (c.as_path(), k.as_path()));
+        let mut credentials = Credentials::default()
+            .with_ca(&certs.join("ca.pem"), identity)
+            .unwrap();
+        credentials.token = token.map(str::to_string);
+        credentials
+    }
+
+    async fn export(uri: &str, credentials: Credentials) -> anyhow::Result<ResponseBody> {
+        let connection = ConnectionHandle::spawn_with(uri.to_string(), credentials);
+        connection
+            .request(MessageWs::ExportRange { range: 0..10 })
+            .await
+    }
+
+    #[actix_web::test]
+    async fn test_roles() {
+        let certs = generate_certs();
+        let authenticator = Authenticator {
+            client_token: Some(CLIENT_TOKEN.to_string()),
+            peer_token: Some(PEER_TOKEN.to_string()),
+            principals: vec![],
+            peer_certificates: true,
+        };
+        let address = serve(certs.path(), authenticator);
+        let uri = format!("https://{address}/ws/");
+
+        let client = ClientBuilder::from_uri(&uri)
+            .unwrap()
+            .credentials(credentials(certs.path(), Some(CLIENT_TOKEN), false))
+            .build();
+        let created = client
+            .send_single(vec![create!(1, value!(Table(1, 1)))])
+            .await;
+        assert!(created.is_ok(), "{created:?}");
+
+        let refused = export(&uri, credentials(certs.path(), Some(CLIENT_TOKEN), false)).await;
+        assert!(matches!(refused, Ok(ResponseBody::Error(_))), "{refused:?}");
+
+        let by_token = export(&uri, credentials(certs.path(), Some(PEER_TOKEN), false)).await;
+        assert!(
+            matches!(by_token, Ok(ResponseBody::Entries(_))),
+            "{by_token:?}"
+        );
+
+        let by_cert = export(&uri, credentials(certs.path(), None, true)).await;
+        assert!(
+            matches!(by_cert, Ok(ResponseBody::Entries(_))),
+            "{by_cert:?}"
+        );
+
+        let anonymous = export(&uri, credentials(certs.path(), None, false)).await;
+        assert!(anonymous.is_err(), "{anonymous:?}");
+
+        let forged = export(&uri, credentials(certs.path(), Some("forged"), false)).await;
+        assert!(forged.is_err(), "{forged:?}");
+    }
+
+    #[test]
+    fn test_check_moves() {
+        let transaction = |text: &str| MessageWs::Coord {
+            tid: uuid::Uuid::new_v4(),
+            args: Arguments {
+                timestamp: 0,
+                operations: parse_operations(text).unwrap(),
+            },
+            participants_size: 2,
+        };
+        let client = Role::Client(None);
+        let principal = Role::Client(Some("billing".to_string()));
+
+        let update = transaction("UPDATE 1 = READ 1 + (1, 1)");
+        assert!(client.check(&update).is_ok());
+        let release = transaction("READ 1; RELEASE 0..10");
+        assert!(client.check(&release).is_err());
+        assert!(principal.check(&release).is_err());
+        assert!(Role::Peer.check(&release).is_ok());
+        let acquire = MessageWs::Single {
+            tid: uuid::Uuid::new_v4(),
+            args: Arguments {
+                timestamp: 0,
+                operations: parse_operations("ACQUIRE 0..10").unwrap(),
+            },
+        };
+        assert!(client.check(&acquire).is_err());
+        assert!(Role::Peer.check(&acquire).is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_certificate_roles() {
+        let certs = generate_certs();
+        let authenticator = Authenticator {
+            peer_certificates: true,
+            ..Authenticator::default()
+        };
+        assert!(!authenticator.is_insecure());
+        assert!(Authenticator::default().is_insecure());
+        let address = serve(certs.path(), authenticator);
+        let uri = format!("https://{address}/ws/");
+
+        let client = ClientBuilder::from_uri(&uri)
+            .unwrap()
+            .credentials(credentials(certs.path(), None, false))
+            .build();
+        let created = client
+            .send_single(vec![create!(1, value!(Table(1, 1)))])
+            .await;
+        assert!(created.is_ok(), "{created:?}");
+
+        let anonymous = export(&uri, credentials(certs.path(), None, false)).await;
+        assert!(
+            matches!(anonymous, Ok(ResponseBody::Error(_))),
+            "{anonymous:?}"
+        );
+
+        let by_cert = export(&uri, credentials(certs.path(), None, true)).await;
+        assert!(
+            matches!(by_cert, Ok(ResponseBody::Entries(_))),
+            "{by_cert:?}"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_change_feed_peers_only() {
+        let repo_actor = web::Data::new(Repository::new("auth".to_string()).start());
+        let authenticator = web::Data::new(Authenticator {
+            client_token: Some(CLIENT_TOKEN.to_string()),
+            peer_token: Some(PEER_TOKEN.to_string()),
+            principals: vec![],
+            peer_certificates: false,
+        });
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(repo_actor)
+                .app_data(authenticator)
+                .route("/changes/", web::get().to(crate::changes)),
+        )
+        .await;
+        let feed = |token: &str| {
+            TestRequest::get()
+                .uri("/changes/")
+                .insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+                .insert_header((header::CONNECTION, "upgrade"))
+                .insert_header((header::UPGRADE, "websocket"))
+                .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+                .insert_header((header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="))
+                .to_request()
+        };
+
+        let client = actix_web::test::call_service(&app, feed(CLIENT_TOKEN)).await;
+        assert_eq!(client.status(), StatusCode::FORBIDDEN);
+        let peer = actix_web::test::call_service(&app, feed(PEER_TOKEN)).await;
+        assert_eq!(peer.status(), StatusCode::SWITCHING_PROTOCOLS);
+    }
+}
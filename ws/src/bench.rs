@@ -0,0 +1,488 @@
+//! `ws bench`: run a configurable workload against repositories, and report
+//! its throughput, latencies and aborts as JSON, to track regressions.
+//!
+//! The keys `0..keys` of every repository are created first. Then each
+//! client runs transactions back to back until the `duration` ends, picking
+//! the kind of each one by the `single`, `indep` and `coord` weights, and its
+//! keys with the `skew`. A transaction reads its keys (`READ k`), or with
+//! `1 - read_ratio` odds writes them (`UPDATE k = READ k + (1, 1)`).
+//! Multi-repository transactions have one operation on each of
+//! `participants` distinct repositories. Nothing is retried: a conflict
+//! counts as such.
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use cereal_client::{Client, ClientBuilder, Clients, Credentials};
+use cereal_core::{
+    messages::TransactionError,
+    operations::{Expr, Operation, Statement, Table},
+    retry::RetryPolicy,
+};
+use clap::{Args, ValueEnum};
+use futures_util::future::join_all;
+use rand::{rngs::StdRng, seq::index, Rng, SeedableRng};
+use serde::Serialize;
+
+/// How many keys are created by each transaction of the setup.
+const POPULATE_BATCH: usize = 100;
+
+#[derive(Args, Debug, Clone, Serialize)]
+pub(crate) struct BenchArgs {
+    /// uri of a repository to run the workload on, once per repository.
+    #[arg(short, long = "uri", required_unless_present("config"))]
+    pub(crate) uris: Vec<String>,
+    /// run the workload on every repository of the cluster configured in
+    /// this file.
+    #[arg(long, conflicts_with("uris"))]
+    pub(crate) config: Option<PathBuf>,
+    /// authenticate as this principal of the cluster config.
+    #[arg(long, requires("config"))]
+    pub(crate) principal: Option<String>,
+    /// keys `0..keys` are used at every repository.
+    #[arg(long, default_value_t = 1000)]
+    keys: usize,
+    /// how the keys of transactions are picked.
+    #[arg(long, value_enum, default_value_t = Skew::Uniform)]
+    skew: Skew,
+    /// exponent of the zipfian skew, the higher the hotter the first keys.
+    #[arg(long, default_value_t = 0.99)]
+    zipf_exponent: f64,
+    /// weight of single repository transactions.
+    #[arg(long, default_value_t = 1)]
+    single: u32,
+    /// weight of `indep` transactions.
+    #[arg(long, default_value_t = 0)]
+    indep: u32,
+    /// weight of `coord` transactions.
+    #[arg(long, default_value_t = 0)]
+    coord: u32,
+    /// repositories taking part in each multi-repository transaction.
+    #[arg(long, default_value_t = 2)]
+    participants: usize,
+    /// share of the transactions that only read.
+    #[arg(long, default_value_t = 0.5)]
+    read_ratio: f64,
+    /// clients running transactions at once, each with its own connections.
+    #[arg(long, default_value_t = 4)]
+    clients: usize,
+    /// how long to run the workload, in seconds.
+    #[arg(long, default_value_t = 10)]
+    duration: u64,
+    /// seed of the random choices, for a repeatable workload.
+    #[arg(long)]
+    seed: Option<u64>,
+    /// write the report to this file instead of stdout.
+    #[arg(long)]
+    #[serde(skip)]
+    pub(crate) output: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Skew {
+    Uniform,
+    Zipfian,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Kind {
+    Single,
+    Indep,
+    Coord,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Single => "single",
+            Kind::Indep => "indep",
+            Kind::Coord => "coord",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    Committed,
+    /// A participant voted [cereal_core::messages::CommitVote::Conflict].
+    Conflict,
+    /// A participant voted [cereal_core::messages::CommitVote::Abort], or
+    /// [cereal_core::messages::CommitVote::Moved].
+    Abort,
+    /// Anything else, e.g. a connection error or a timeout.
+    Error,
+}
+
+impl Outcome {
+    fn of<T>(result: &anyhow::Result<T>) -> Self {
+        match result {
+            Ok(_) => Outcome::Committed,
+            Err(e) => match e.downcast_ref::<TransactionError>() {
+                Some(TransactionError::Conflict) => Outcome::Conflict,
+                Some(TransactionError::Abort | TransactionError::Moved) => Outcome::Abort,
+                Some(TransactionError::Timeout) | None => Outcome::Error,
+            },
+        }
+    }
+}
+
+struct Sample {
+    kind: Kind,
+    outcome: Outcome,
+    latency: Duration,
+}
+
+/// Picks keys in `0..keys`.
+enum KeyPicker {
+    Uniform(usize),
+    /// The cumulative odds of each key, key `i` weighing `1 / (i + 1)^s`.
+    Zipfian(Vec<f64>),
+}
+
+impl KeyPicker {
+    fn new(skew: Skew, keys: usize, exponent: f64) -> Self {
+        match skew {
+            Skew::Uniform => KeyPicker::Uniform(keys),
+            Skew::Zipfian => {
+                let mut total = 0.0;
+                let mut cdf: Vec<f64> = (0..keys)
+                    .map(|i| {
+                        total += 1.0 / ((i + 1) as f64).powf(exponent);
+                        total
+                    })
+                    .collect();
+                cdf.iter_mut().for_each(|odds| *odds /= total);
+                KeyPicker::Zipfian(cdf)
+            }
+        }
+    }
+
+    fn pick(&self, rng: &mut impl Rng) -> usize {
+        match self {
+            KeyPicker::Uniform(keys) => rng.gen_range(0..*keys),
+            KeyPicker::Zipfian(cdf) => {
+                let odds: f64 = rng.gen();
+                cdf.partition_point(|&cumulative| cumulative < odds)
+                    .min(cdf.len() - 1)
+            }
+        }
+    }
+}
+
+/// What `ws bench` reports.
+#[derive(Debug, Serialize)]
+pub(crate) struct Report {
+    workload: BenchArgs,
+    repositories: Vec<String>,
+    elapsed_secs: f64,
+    total: Stats,
+    by_kind: BTreeMap<&'static str, Stats>,
+}
+
+#[derive(Debug, Serialize)]
+struct Stats {
+    transactions: usize,
+    committed: usize,
+    conflicts: usize,
+    aborts: usize,
+    errors: usize,
+    /// Committed transactions per second.
+    throughput: f64,
+    /// Share of the transactions that conflicted or aborted.
+    abort_rate: f64,
+    /// Latencies of the committed transactions.
+    latency_ms: Latencies,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Latencies {
+    mean: f64,
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    max: f64,
+}
+
+impl Stats {
+    fn new<'a>(samples: impl Iterator<Item = &'a Sample>, elapsed: Duration) -> Self {
+        let mut stats = Stats {
+            transactions: 0,
+            committed: 0,
+            conflicts: 0,
+            aborts: 0,
+            errors: 0,
+            throughput: 0.0,
+            abort_rate: 0.0,
+            latency_ms: Latencies::default(),
+        };
+        let mut latencies = vec![];
+        for sample in samples {
+            stats.transactions += 1;
+            match sample.outcome {
+                Outcome::Committed => {
+                    stats.committed += 1;
+                    latencies.push(sample.latency.as_secs_f64() * 1000.0);
+                }
+                Outcome::Conflict => stats.conflicts += 1,
+                Outcome::Abort => stats.aborts += 1,
+                Outcome::Error => stats.errors += 1,
+            }
+        }
+        stats.throughput = stats.committed as f64 / elapsed.as_secs_f64();
+        if stats.transactions > 0 {
+            stats.abort_rate = (stats.conflicts + stats.aborts) as f64 / stats.transactions as f64;
+        }
+        stats.latency_ms = Latencies::new(latencies);
+        stats
+    }
+}
+
+impl Latencies {
+    fn new(mut latencies: Vec<f64>) -> Self {
+        if latencies.is_empty() {
+            return Latencies::default();
+        }
+        latencies.sort_by(f64::total_cmp);
+        Latencies {
+            mean: latencies.iter().sum::<f64>() / latencies.len() as f64,
+            p50: percentile(&latencies, 50.0),
+            p90: percentile(&latencies, 90.0),
+            p99: percentile(&latencies, 99.0),
+            max: latencies[latencies.len() - 1],
+        }
+    }
+}
+
+/// The nearest-rank percentile `p` of the `sorted` values.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Runs the workload of `args` on the repositories at `uris`.
+pub(crate) async fn run(
+    args: BenchArgs,
+    uris: Vec<String>,
+    credentials: Credentials,
+) -> anyhow::Result<Report> {
+    anyhow::ensure!(!uris.is_empty(), "no repositories to run the workload on");
+    anyhow::ensure!(args.keys > 0, "--keys must be positive");
+    anyhow::ensure!(
+        (0.0..=1.0).contains(&args.read_ratio),
+        "--read-ratio must be between 0 and 1"
+    );
+    let weights = [
+        (Kind::Single, args.single),
+        (Kind::Indep, args.indep),
+        (Kind::Coord, args.coord),
+    ];
+    let total_weight: u32 = weights.iter().map(|(_, weight)| weight).sum();
+    anyhow::ensure!(total_weight > 0, "every kind of transaction weighs 0");
+    if args.indep > 0 || args.coord > 0 {
+        anyhow::ensure!(
+            (2..=uris.len()).contains(&args.participants),
+            "--participants must be between 2 and the {} repositories",
+            uris.len()
+        );
+    }
+
+    let connect = |uri: &String| {
+        anyhow::Ok(
+            ClientBuilder::from_uri(uri)?
+                .credentials(credentials.clone())
+                .build(),
+        )
+    };
+    for uri in &uris {
+        populate(&connect(uri)?, args.keys).await?;
+    }
+
+    let picker = KeyPicker::new(args.skew, args.keys, args.zipf_exponent);
+    let duration = Duration::from_secs(args.duration);
+    let start = Instant::now();
+    let deadline = start + duration;
+    let mut workers = vec![];
+    for worker in 0..args.clients {
+        let repositories = uris.iter().map(connect).collect::<anyhow::Result<_>>()?;
+        let rng = match args.seed {
+            Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(worker as u64)),
+            None => StdRng::from_entropy(),
+        };
+        let worker = Worker {
+            repositories,
+            rng,
+            picker: &picker,
+            weights,
+            total_weight,
+            args: &args,
+        };
+        workers.push(worker.run(deadline));
+    }
+    let samples: Vec<Sample> = join_all(workers).await.into_iter().flatten().collect();
+    let elapsed = start.elapsed();
+
+    let by_kind = weights
+        .iter()
+        .filter(|(_, weight)| *weight > 0)
+        .map(|(kind, _)| {
+            let samples = samples.iter().filter(|sample| sample.kind == *kind);
+            (kind.name(), Stats::new(samples, elapsed))
+        })
+        .collect();
+    Ok(Report {
+        total: Stats::new(samples.iter(), elapsed),
+        by_kind,
+        elapsed_secs: elapsed.as_secs_f64(),
+        repositories: uris,
+        workload: args,
+    })
+}
+
+/// Sets the keys `0..keys` to `(0, 0)`. An `Update` creates the missing
+/// keys and resets the existing ones, so the workload can run again against
+/// the same repositories.
+async fn populate(client: &Client, keys: usize) -> anyhow::Result<()> {
+    for batch in (0..keys).collect::<Vec<_>>().chunks(POPULATE_BATCH) {
+        let operations = batch
+            .iter()
+            .map(|&key| {
+                Operation::Statement(Statement::Update(key, Box::new(Expr::Value(Table(0, 0)))))
+            })
+            .collect();
+        client
+            .send_single(operations)
+            .await
+            .map_err(|e| anyhow::anyhow!("couldn't set the keys at {}: {e:#}", client.uri()))?;
+    }
+    Ok(())
+}
+
+/// A client of the workload, with a connection to every repository.
+struct Worker<'a> {
+    repositories: Vec<Client>,
+    rng: StdRng,
+    picker: &'a KeyPicker,
+    weights: [(Kind, u32); 3],
+    total_weight: u32,
+    args: &'a BenchArgs,
+}
+
+impl Worker<'_> {
+    async fn run(mut self, deadline: Instant) -> Vec<Sample> {
+        let mut samples = vec![];
+        while Instant::now() < deadline {
+            samples.push(self.transaction().await);
+        }
+        samples
+    }
+
+    async fn transaction(&mut self) -> Sample {
+        let kind = self.kind();
+        let read_only = self.rng.gen_bool(self.args.read_ratio);
+        let start = Instant::now();
+        let outcome = match kind {
+            Kind::Single => {
+                let repository = self.rng.gen_range(0..self.repositories.len());
+                let operation = self.operation(read_only);
+                let result = self.repositories[repository]
+                    .send_single(vec![operation])
+                    .await;
+                Outcome::of(&result)
+            }
+            Kind::Indep | Kind::Coord => {
+                let chosen = index::sample(
+                    &mut self.rng,
+                    self.repositories.len(),
+                    self.args.participants,
+                );
+                let participants = chosen
+                    .iter()
+                    .map(|repository| self.repositories[repository].clone())
+                    .collect();
+                let operations = (0..self.args.participants)
+                    .map(|_| vec![self.operation(read_only)])
+                    .collect();
+                let clients = Clients {
+                    participants,
+                    retry_policy: RetryPolicy::never(),
+                };
+                let result = if kind == Kind::Indep {
+                    clients.send_indep(operations).await
+                } else {
+                    clients.send_coord(operations).await
+                };
+                Outcome::of(&result)
+            }
+        };
+        Sample {
+            kind,
+            outcome,
+            latency: start.elapsed(),
+        }
+    }
+
+    fn kind(&mut self) -> Kind {
+        let mut pick = self.rng.gen_range(0..self.total_weight);
+        for (kind, weight) in self.weights {
+            if pick < weight {
+                return kind;
+            }
+            pick -= weight;
+        }
+        unreachable!("the pick is below the total weight")
+    }
+
+    fn operation(&mut self, read_only: bool) -> Operation {
+        let key = self.picker.pick(&mut self.rng);
+        if read_only {
+            return Operation::Expr(Expr::Read(key));
+        }
+        let add_one = Expr::Add(
+            Box::new(Expr::Read(key)),
+            Box::new(Expr::Value(Table(1, 1))),
+        );
+        Operation::Statement(Statement::Update(key, Box::new(add_one)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cereal_core::repository::Repository;
+
+    use super::*;
+    use crate::testing::TestRepository;
+
+    #[test]
+    fn test_keys_and_percentiles() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let zipfian = KeyPicker::new(Skew::Zipfian, 100, 1.0);
+        let picks: Vec<usize> = (0..10_000).map(|_| zipfian.pick(&mut rng)).collect();
+        assert!(picks.iter().all(|&key| key < 100));
+        let first = picks.iter().filter(|&&key| key == 0).count();
+        let last = picks.iter().filter(|&&key| key == 99).count();
+        assert!(first > 10 * last, "{first} picks of 0, {last} of 99");
+
+        let latencies: Vec<f64> = (1..=100).map(f64::from).collect();
+        assert_eq!(percentile(&latencies, 50.0), 50.0);
+        assert_eq!(percentile(&latencies, 99.0), 99.0);
+        assert_eq!(percentile(&[3.0], 90.0), 3.0);
+    }
+
+    #[actix_web::test]
+    async fn test_populate_again() {
+        let repository = TestRepository::serve(Repository::new("bench".to_string()));
+        let client = ClientBuilder::from_uri(&repository.uri()).unwrap().build();
+        populate(&client, 10).await.unwrap();
+        let update = Operation::Statement(Statement::Update(5, Box::new(Expr::Value(Table(5, 5)))));
+        client.send_single(vec![update]).await.unwrap();
+
+        populate(&client, 10).await.unwrap();
+        let read = client
+            .send_single(vec![Operation::Expr(Expr::Read(5))])
+            .await;
+        assert_eq!(read.unwrap(), Some(Table(0, 0)));
+    }
+}
@@ -0,0 +1,86 @@
+//! A WebSocket stream of the committed changes of a `Repository`.
+use actix::prelude::*;
+use actix_web::web;
+use actix_web_actors::ws;
+use cereal_core::{
+    messages::{ChangeEvent, Subscribe},
+    repository::Repository,
+};
+use serde::Deserialize;
+
+/// Query of a `/changes/` request.
+#[derive(Debug, Deserialize)]
+pub(crate) struct ChangeFeedQuery {
+    /// Replay the changes committed at or after this timestamp.
+    #[serde(default)]
+    pub(crate) from: usize,
+}
+
+/// A Ws subscriber of the [ChangeEvent]s of a `Repository`.
+///
+/// Each change is sent as a JSON `Text` frame.
+pub(crate) struct ChangeFeedWs {
+    repo_actor: web::Data<Addr<Repository>>,
+    from_ts: usize,
+}
+
+impl ChangeFeedWs {
+    pub(crate) fn new(repo_actor: web::Data<Addr<Repository>>, from_ts: usize) -> Self {
+        ChangeFeedWs {
+            repo_actor,
+            from_ts,
+        }
+    }
+}
+
+impl Actor for ChangeFeedWs {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let subscribe = Subscribe {
+            from_ts: self.from_ts,
+            subscriber: ctx.address().recipient(),
+        };
+        self.repo_actor
+            .send(subscribe)
+            .into_actor(self)
+            .then(|res, _, ctx| {
+                let error = match res {
+                    Ok(Ok(())) => return fut::ready(()),
+                    Ok(Err(e)) => e.to_string(),
+                    Err(e) => e.to_string(),
+                };
+                log::warn!("Change feed subscription failed: {error}");
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Policy,
+                    description: Some(error),
+                }));
+                ctx.stop();
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+}
+
+impl Handler<ChangeEvent> for ChangeFeedWs {
+    type Result = ();
+
+    fn handle(&mut self, msg: ChangeEvent, ctx: &mut Self::Context) -> Self::Result {
+        let change = serde_json::to_string(&msg).expect("ChangeEvent can be serialized");
+        ctx.text(change);
+    }
+}
+
+/// Handler for ws::Message message
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChangeFeedWs {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            _ => (),
+        }
+    }
+}
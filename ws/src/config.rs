@@ -0,0 +1,765 @@
+//! The cluster configuration file, shared by every `ws` command.
+//!
+//! ```toml
+//! [[repositories]]
+//! name = "customer"
+//! listen = "127.0.0.1:8080"
+//! tables = ["customer"]
+//! data_dir = "data/customer"
+//! durability = "sync"
+//! result_retention_secs = 600
+//! version_retention_secs = 3600
+//!
+//! [[repositories]]
+//! name = "order"
+//! listen = "0.0.0.0:8081"
+//! advertise = "order.example.com:8081"
+//! tables = ["order", "product"]
+//! cert = "certs/order.pem"
+//! key = "certs/order.key"
+//!
+//! [gateway]
+//! listen = "127.0.0.1:8090"
+//!
+//! [security]
+//! ca = "certs/ca.pem"
+//! peer_token = "..."
+//! operator_cert = "certs/operator.pem"
+//! operator_key = "certs/operator.key"
+//!
+//! [[security.principals]]
+//! name = "billing"
+//! token = "..."
+//! grants = [
+//!     { table = "customer", keys = { start = 0, end = 100 }, permissions = ["read", "write"] },
+//! ]
+//! ```
+//!
+//! Repositories without a `data_dir` write to a temporary directory.
+//! `durability` is `none`, `write` (the default) or `sync`, and only
+//! decides how the log is written: it isn't read back at startup. Repositories
+//! keep the results of finished transactions for `result_retention_secs`
+//! (600 by default), for clients asking for them again, and past versions
+//! of keys for `version_retention_secs` (600 by default), for snapshot reads
+//! and change feeds. A repository is reached at its `listen` address, or
+//! at the one it `advertise`s, which it must when it listens on every
+//! address like `0.0.0.0`. Repositories
+//! with a `cert` serve `https`, and present it to their peers, which
+//! verify it with the `ca`. The `gateway`, if there is one, is where `ws
+//! gateway` listens, and serves `https` with a `cert` too, advertising an
+//! address like the repositories. See
+//! [crate::auth] for the tokens. `ws admin` and `ws migrate` connect as
+//! peers, with the `peer_token` or presenting the `operator_cert`, signed
+//! by the `ca`. A repository storing many tables shares its keys between them, so a grant on one of
+//! them would cover the keys of every one: tables are only granted on
+//! repositories storing them alone.
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use cereal_client::Credentials;
+use cereal_core::{
+    permissions::{Grant, Permission, Permissions},
+    runtime::{Durability, Runtime},
+};
+use rustls::ServerConfig;
+use serde::Deserialize;
+
+use crate::{
+    auth::{self, Authenticator},
+    retention::{DEFAULT_RESULT_RETENTION, DEFAULT_VERSION_RETENTION},
+};
+
+/// A repository of the cluster.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RepositoryConfig {
+    pub(crate) name: String,
+    /// Address to bind to, e.g. `127.0.0.1:8080`.
+    pub(crate) listen: SocketAddr,
+    /// The `host:port` the others connect to, if it isn't `listen`, which
+    /// it can't be when `listen` is an unspecified address like `0.0.0.0`.
+    pub(crate) advertise: Option<String>,
+    /// The tables stored by the repository. Every table has the same
+    /// schema, a `Table`.
+    #[serde(default)]
+    pub(crate) tables: Vec<String>,
+    pub(crate) data_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub(crate) durability: Durability,
+    /// The PEM certificate served over TLS, signed by the cluster CA.
+    pub(crate) cert: Option<PathBuf>,
+    /// The PEM private key of the `cert`.
+    pub(crate) key: Option<PathBuf>,
+    pub(crate) result_retention_secs: Option<u64>,
+    pub(crate) version_retention_secs: Option<u64>,
+}
+
+/// The `ws gateway` of the cluster.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct GatewayConfig {
+    /// Address to bind to, e.g. `127.0.0.1:8090`.
+    pub(crate) listen: SocketAddr,
+    /// The `host:port` clients connect to, if it isn't `listen`.
+    pub(crate) advertise: Option<String>,
+    /// The PEM certificate served over TLS, signed by the cluster CA.
+    pub(crate) cert: Option<PathBuf>,
+    /// The PEM private key of the `cert`.
+    pub(crate) key: Option<PathBuf>,
+}
+
+impl GatewayConfig {
+    /// The uri of the `GatewayWs` of the gateway.
+    pub(crate) fn uri(&self) -> String {
+        let scheme = if self.cert.is_some() { "https" } else { "http" };
+        format!(
+            "{scheme}://{}/ws/",
+            advertised(self.advertise.as_deref(), self.listen)
+        )
+    }
+}
+
+/// How the connections of the cluster are authenticated.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SecurityConfig {
+    /// The PEM certificate of the CA signing the certificates of the
+    /// repositories.
+    pub(crate) ca: Option<PathBuf>,
+    pub(crate) client_token: Option<String>,
+    pub(crate) peer_token: Option<String>,
+    /// The PEM certificate, signed by the CA, `ws admin` and `ws migrate`
+    /// present to connect as a peer, when there is no `peer_token`.
+    pub(crate) operator_cert: Option<PathBuf>,
+    /// The PEM private key of the `operator_cert`.
+    pub(crate) operator_key: Option<PathBuf>,
+    /// Clients only allowed to run what they were granted. Clients can't
+    /// use the `client_token` then.
+    #[serde(default)]
+    pub(crate) principals: Vec<PrincipalConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PrincipalConfig {
+    pub(crate) name: String,
+    pub(crate) token: String,
+    #[serde(default)]
+    pub(crate) grants: Vec<GrantConfig>,
+}
+
+/// A [Grant] on the keys of a table.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct GrantConfig {
+    pub(crate) table: String,
+    /// Every key of the table if there are none.
+    pub(crate) keys: Option<Range<usize>>,
+    pub(crate) permissions: Vec<Permission>,
+}
+
+/// The `host:port` others connect to, `listen` unless one is `advertise`d.
+fn advertised(advertise: Option<&str>, listen: SocketAddr) -> String {
+    advertise.map_or_else(|| listen.to_string(), str::to_string)
+}
+
+/// Fails unless others can connect to `what` at `advertise`, or at `listen`
+/// if nothing is advertised.
+fn check_advertised(what: &str, advertise: Option<&str>, listen: SocketAddr) -> anyhow::Result<()> {
+    match advertise {
+        Some(advertise) => {
+            let port = advertise
+                .rsplit_once(':')
+                .map(|(_, port)| port.parse::<u16>());
+            anyhow::ensure!(
+                matches!(port, Some(Ok(_))),
+                "{what} advertises {advertise}, which isn't a host:port"
+            );
+        }
+        None => anyhow::ensure!(
+            !listen.ip().is_unspecified(),
+            "{what} listens on {listen}, and needs an address to advertise"
+        ),
+    }
+    Ok(())
+}
+
+impl SecurityConfig {
+    /// The certificate and key pair of the operator, if there is one.
+    fn operator_identity(&self) -> Option<(&Path, &Path)> {
+        Some((
+            self.operator_cert.as_deref()?,
+            self.operator_key.as_deref()?,
+        ))
+    }
+}
+
+impl RepositoryConfig {
+    /// The uri of the `RepositoryWs` of the repository.
+    pub(crate) fn uri(&self) -> String {
+        let scheme = if self.cert.is_some() { "https" } else { "http" };
+        format!(
+            "{scheme}://{}/ws/",
+            advertised(self.advertise.as_deref(), self.listen)
+        )
+    }
+
+    /// The certificate and key pair, if the repository serves TLS.
+    pub(crate) fn identity(&self) -> Option<(&Path, &Path)> {
+        Some((self.cert.as_deref()?, self.key.as_deref()?))
+    }
+
+    /// How long the results of finished transactions are kept.
+    pub(crate) fn result_retention(&self) -> Duration {
+        self.result_retention_secs
+            .map_or(DEFAULT_RESULT_RETENTION, Duration::from_secs)
+    }
+
+    /// How long past versions of keys are kept.
+    pub(crate) fn version_retention(&self) -> Duration {
+        self.version_retention_secs
+            .map_or(DEFAULT_VERSION_RETENTION, Duration::from_secs)
+    }
+
+    pub(crate) fn runtime(&self) -> anyhow::Result<Runtime> {
+        match &self.data_dir {
+            Some(data_dir) => Runtime::with_data_dir(data_dir, self.durability),
+            None => Ok(Runtime::with_durability(self.durability)),
+        }
+    }
+}
+
+/// The repositories of the cluster.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ClusterConfig {
+    pub(crate) repositories: Vec<RepositoryConfig>,
+    pub(crate) gateway: Option<GatewayConfig>,
+    #[serde(default)]
+    pub(crate) security: SecurityConfig,
+}
+
+impl ClusterConfig {
+    /// Loads the [ClusterConfig] at `path`, checking it is consistent.
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        let parse = || {
+            let toml = std::fs::read_to_string(path)?;
+            let config: ClusterConfig = toml::from_str(&toml)?;
+            config.validate()?;
+            anyhow::Ok(config)
+        };
+        parse().map_err(|e| e.context(format!("invalid cluster config {}", path.display())))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(!self.repositories.is_empty(), "no repositories");
+        let mut names = HashSet::new();
+        let mut addresses = HashSet::new();
+        let mut uris = HashSet::new();
+        let mut tables = HashSet::new();
+        for repository in &self.repositories {
+            anyhow::ensure!(
+                names.insert(&repository.name),
+                "repository {} is defined twice",
+                repository.name
+            );
+            anyhow::ensure!(
+                addresses.insert(repository.listen),
+                "repositories listen on the same address {}",
+                repository.listen
+            );
+            let what = format!("repository {}", repository.name);
+            check_advertised(&what, repository.advertise.as_deref(), repository.listen)?;
+            anyhow::ensure!(
+                uris.insert(repository.uri()),
+                "repositories are reached at the same uri {}",
+                repository.uri()
+            );
+            for table in &repository.tables {
+                anyhow::ensure!(
+                    tables.insert(table),
+                    "table {table} is in more than one repository"
+                );
+            }
+            anyhow::ensure!(
+                repository.cert.is_some() == repository.key.is_some(),
+                "repository {} needs both a cert and a key",
+                repository.name
+            );
+            anyhow::ensure!(
+                repository.cert.is_none() || self.security.ca.is_some(),
+                "repository {} serves TLS, but there is no ca to verify it",
+                repository.name
+            );
+        }
+
+        if let Some(gateway) = &self.gateway {
+            anyhow::ensure!(
+                !addresses.contains(&gateway.listen),
+                "the gateway listens on the address of a repository {}",
+                gateway.listen
+            );
+            check_advertised("the gateway", gateway.advertise.as_deref(), gateway.listen)?;
+            anyhow::ensure!(
+                gateway.cert.is_some() == gateway.key.is_some(),
+                "the gateway needs both a cert and a key"
+            );
+            anyhow::ensure!(
+                gateway.cert.is_none() || self.security.ca.is_some(),
+                "the gateway serves TLS, but there is no ca to verify it"
+            );
+        }
+
+        let SecurityConfig {
+            client_token,
+            peer_token,
+            operator_cert,
+            operator_key,
+            principals,
+            ca,
+        } = &self.security;
+        anyhow::ensure!(
+            operator_cert.is_some() == operator_key.is_some(),
+            "the operator needs both a cert and a key"
+        );
+        anyhow::ensure!(
+            operator_cert.is_none() || ca.is_some(),
+            "the operator has a cert, but there is no ca to verify it"
+        );
+        anyhow::ensure!(
+            client_token.is_none() || client_token != peer_token,
+            "the client and peer tokens are the same"
+        );
+        anyhow::ensure!(
+            client_token.is_none() || principals.is_empty(),
+            "clients can't use a client_token once there are principals"
+        );
+        let mut principal_names = HashSet::new();
+        let mut tokens: HashSet<&String> = peer_token.iter().collect();
+        for principal in principals {
+            anyhow::ensure!(
+                principal_names.insert(&principal.name),
+                "principal {} is defined twice",
+                principal.name
+            );
+            anyhow::ensure!(
+                tokens.insert(&principal.token),
+                "principal {} has the token of another principal or of the peers",
+                principal.name
+            );
+            for grant in &principal.grants {
+                anyhow::ensure!(
+                    tables.contains(&grant.table),
+                    "principal {} is granted table {}, which no repository stores",
+                    principal.name,
+                    grant.table
+                );
+                let repository = self.repository_of(&grant.table)?;
+                anyhow::ensure!(
+                    repository.tables.len() == 1,
+                    "principal {} is granted table {}, whose keys are shared with the other \
+                     tables of repository {}",
+                    principal.name,
+                    grant.table,
+                    repository.name
+                );
+                if let Some(keys) = &grant.keys {
+                    anyhow::ensure!(
+                        !keys.is_empty(),
+                        "principal {} is granted no keys {keys:?} of table {}",
+                        principal.name,
+                        grant.table
+                    );
+                }
+            }
+        }
+        if (client_token.is_some() || !principals.is_empty() || ca.is_some())
+            && peer_token.is_none()
+        {
+            if let Some(repository) = self.repositories.iter().find(|r| r.cert.is_none()) {
+                anyhow::bail!(
+                    "repository {} can't authenticate to its peers without a cert or a peer_token",
+                    repository.name
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The repository called `name`, or the only one if no `name` is given.
+    pub(crate) fn repository(&self, name: Option<&str>) -> anyhow::Result<&RepositoryConfig> {
+        match (name, &self.repositories[..]) {
+            (None, [repository]) => Ok(repository),
+            (None, _) => anyhow::bail!("many repositories are configured, pick one by name"),
+            (Some(name), repositories) => repositories
+                .iter()
+                .find(|repository| repository.name == name)
+                .ok_or_else(|| anyhow::anyhow!("no repository called {name}")),
+        }
+    }
+
+    /// The repository storing `table`.
+    pub(crate) fn repository_of(&self, table: &str) -> anyhow::Result<&RepositoryConfig> {
+        self.repositories
+            .iter()
+            .find(|repository| repository.tables.iter().any(|t| t == table))
+            .ok_or_else(|| anyhow::anyhow!("no repository stores table {table}"))
+    }
+
+    /// The gateway of the cluster.
+    pub(crate) fn gateway(&self) -> anyhow::Result<&GatewayConfig> {
+        self.gateway
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("no gateway is configured"))
+    }
+
+    /// The [Authenticator] of the connections to the repositories.
+    pub(crate) fn authenticator(&self) -> Authenticator {
+        Authenticator {
+            client_token: self.security.client_token.clone(),
+            peer_token: self.security.peer_token.clone(),
+            principals: self
+                .security
+                .principals
+                .iter()
+                .map(|principal| (principal.name.clone(), principal.token.clone()))
+                .collect(),
+            peer_certificates: self.security.ca.is_some(),
+        }
+    }
+
+    /// What the principals may do at `repository`, if there are principals.
+    pub(crate) fn permissions(&self, repository: &RepositoryConfig) -> Option<Permissions> {
+        if self.security.principals.is_empty() {
+            return None;
+        }
+        let mut permissions = Permissions::default();
+        for principal in &self.security.principals {
+            for grant in &principal.grants {
+                if repository.tables.contains(&grant.table) {
+                    let grant = Grant {
+                        keys: grant.keys.clone(),
+                        permissions: grant.permissions.clone(),
+                    };
+                    permissions.grant(&principal.name, grant);
+                }
+            }
+        }
+        Some(permissions)
+    }
+
+    /// The TLS config of `repository`, if it serves TLS.
+    pub(crate) fn server_config(
+        &self,
+        repository: &RepositoryConfig,
+    ) -> anyhow::Result<Option<ServerConfig>> {
+        let Some((cert, key)) = repository.identity() else {
+            return Ok(None);
+        };
+        let config = auth::server_config(cert, key, self.security.ca.as_deref())?;
+        Ok(Some(config))
+    }
+
+    /// The [Credentials] of the clients of the repositories, authenticated
+    /// as `principal` if there is one.
+    pub(crate) fn client_credentials(
+        &self,
+        principal: Option<&str>,
+    ) -> anyhow::Result<Credentials> {
+        let token = match principal {
+            Some(name) => Some(
+                &self
+                    .security
+                    .principals
+                    .iter()
+                    .find(|principal| principal.name == name)
+                    .ok_or_else(|| anyhow::anyhow!("no principal called {name}"))?
+                    .token,
+            ),
+            None => self.security.client_token.as_ref(),
+        };
+        self.credentials(token, None)
+    }
+
+    /// The [Credentials] of the clients authenticated as each principal.
+    pub(crate) fn principal_credentials(&self) -> anyhow::Result<HashMap<String, Credentials>> {
+        self.security
+            .principals
+            .iter()
+            .map(|principal| {
+                let credentials = self.credentials(Some(&principal.token), None)?;
+                Ok((principal.name.clone(), credentials))
+            })
+            .collect()
+    }
+
+    /// The [Credentials] of the peers of the repositories, presenting the
+    /// certificate of `repository` if there is one, or the operator's one
+    /// without a `repository`.
+    pub(crate) fn peer_credentials(
+        &self,
+        repository: Option<&RepositoryConfig>,
+    ) -> anyhow::Result<Credentials> {
+        let identity = match repository {
+            Some(repository) => repository.identity(),
+            None => self.security.operator_identity(),
+        };
+        anyhow::ensure!(
+            identity.is_some()
+                || self.security.peer_token.is_some()
+                || self.authenticator().is_insecure(),
+            "there is neither a peer_token nor an operator_cert to connect as a peer"
+        );
+        self.credentials(self.security.peer_token.as_ref(), identity)
+    }
+
+    fn credentials(
+        &self,
+        token: Option<&String>,
+        identity: Option<(&Path, &Path)>,
+    ) -> anyhow::Result<Credentials> {
+        let mut credentials = Credentials::default();
+        if let Some(token) = token {
+            credentials = credentials.with_token(token);
+        }
+        if let Some(ca) = &self.security.ca {
+            credentials = credentials.with_ca(ca, identity)?;
+        }
+        Ok(credentials)
+    }
+
+    /// The uris of every repository.
+    pub(crate) fn uris(&self) -> Vec<String> {
+        self.repositories
+            .iter()
+            .map(RepositoryConfig::uri)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLUSTER: &str = r#"
+        [[repositories]]
+        name = "customer"
+        listen = "127.0.0.1:8080"
+        tables = ["customer"]
+
+        [[repositories]]
+        name = "order"
+        listen = "127.0.0.1:8081"
+        tables = ["order", "product"]
+    "#;
+
+    fn parse(toml: &str) -> anyhow::Result<ClusterConfig> {
+        let config: ClusterConfig = toml::from_str(toml)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// [CLUSTER] with `extra` appended, which must be invalid because of
+    /// `reason`.
+    fn assert_invalid(extra: &str, reason: &str) {
+        let e = parse(&format!("{CLUSTER}{extra}")).unwrap_err();
+        assert!(format!("{e:#}").contains(reason), "{e:#}");
+    }
+
+    #[test]
+    fn test_valid() {
+        let config = parse(CLUSTER).unwrap();
+        assert_eq!(config.repository_of("product").unwrap().name, "order");
+        assert!(config.repository(None).is_err());
+        assert!(config.repository(Some("stock")).is_err());
+        assert_eq!(config.uris()[1], "http://127.0.0.1:8081/ws/");
+
+        let tls = r#"
+            [security]
+            ca = "ca.pem"
+            peer_token = "peer"
+        "#;
+        assert!(parse(&format!("{CLUSTER}{tls}")).is_ok());
+    }
+
+    #[test]
+    fn test_repositories() {
+        assert!(parse("repositories = []").is_err());
+        let again = |name, listen, table| {
+            format!(
+                "[[repositories]]\nname = \"{name}\"\nlisten = \"{listen}\"\ntables = [\"{table}\"]\n"
+            )
+        };
+        assert_invalid(&again("order", "127.0.0.1:8082", "stock"), "defined twice");
+        assert_invalid(&again("stock", "127.0.0.1:8081", "stock"), "same address");
+        assert_invalid(
+            &again("stock", "127.0.0.1:8082", "product"),
+            "more than one",
+        );
+        assert_invalid(
+            "[[repositories]]\nname = \"stock\"\nlisten = \"127.0.0.1:8082\"\npeers = []\n",
+            "unknown field",
+        );
+    }
+
+    #[test]
+    fn test_principals() {
+        let principal = |name, token, table, keys| {
+            format!(
+                "[[security.principals]]\nname = \"{name}\"\ntoken = \"{token}\"\n\
+                 grants = [{{ table = \"{table}\", keys = {keys}, permissions = [\"read\"] }}]\n"
+            )
+        };
+        let billing = principal("billing", "a", "customer", "{ start = 0, end = 100 }");
+        let peers = "[security]\npeer_token = \"peer\"\n";
+        assert!(parse(&format!("{CLUSTER}{peers}{billing}")).is_ok());
+
+        let twice = principal("billing", "b", "customer", "{ start = 0, end = 100 }");
+        assert_invalid(&format!("{peers}{billing}{twice}"), "defined twice");
+        let token = principal("audit", "a", "customer", "{ start = 0, end = 100 }");
+        assert_invalid(&format!("{peers}{billing}{token}"), "token of another");
+        let peer_token = principal("audit", "peer", "customer", "{ start = 0, end = 100 }");
+        assert_invalid(&format!("{peers}{peer_token}"), "token of another");
+        let unknown = principal("audit", "b", "stock", "{ start = 0, end = 100 }");
+        assert_invalid(&format!("{peers}{unknown}"), "no repository stores");
+        let empty = principal("audit", "b", "customer", "{ start = 100, end = 100 }");
+        assert_invalid(&format!("{peers}{empty}"), "granted no keys");
+        // The keys of "order" are the keys of "product" too.
+        let shared = principal("audit", "b", "order", "{ start = 0, end = 100 }");
+        assert_invalid(&format!("{peers}{shared}"), "shared with the other tables");
+
+        let client_token = "[security]\nclient_token = \"client\"\npeer_token = \"peer\"\n";
+        assert_invalid(&format!("{client_token}{billing}"), "client_token");
+    }
+
+    #[test]
+    fn test_security() {
+        assert_invalid(
+            "[security]\nclient_token = \"same\"\npeer_token = \"same\"\n",
+            "are the same",
+        );
+        // Peers can't tell each other from clients without a peer_token or
+        // a certificate.
+        assert_invalid(
+            "[security]\nclient_token = \"client\"\n",
+            "can't authenticate to its peers",
+        );
+        assert_invalid(
+            "[security]\nca = \"ca.pem\"\n",
+            "can't authenticate to its peers",
+        );
+
+        let tls = |cert: &str, key: &str, ca: &str| {
+            format!(
+                "[[repositories]]\nname = \"stock\"\nlisten = \"127.0.0.1:8082\"\n{cert}{key}\
+                 [security]\npeer_token = \"peer\"\n{ca}"
+            )
+        };
+        let cert = "cert = \"stock.pem\"\n";
+        let key = "key = \"stock.key\"\n";
+        let ca = "ca = \"ca.pem\"\n";
+        assert!(parse(&format!("{CLUSTER}{}", tls(cert, key, ca))).is_ok());
+        assert_invalid(&tls(cert, "", ca), "both a cert and a key");
+        assert_invalid(&tls("", key, ca), "both a cert and a key");
+        assert_invalid(&tls(cert, key, ""), "no ca to verify it");
+    }
+
+    #[test]
+    fn test_advertise() {
+        let stock = |listen: &str, advertise: &str| {
+            format!("[[repositories]]\nname = \"stock\"\nlisten = \"{listen}\"\n{advertise}")
+        };
+        let advertise = "advertise = \"stock.example.com:8082\"\n";
+        let config = parse(&format!("{CLUSTER}{}", stock("0.0.0.0:8082", advertise))).unwrap();
+        assert_eq!(config.uris()[2], "http://stock.example.com:8082/ws/");
+        assert!(parse(&format!("{CLUSTER}{}", stock("[::]:8082", advertise))).is_ok());
+
+        assert_invalid(&stock("0.0.0.0:8082", ""), "needs an address to advertise");
+        assert_invalid(&stock("[::]:8082", ""), "needs an address to advertise");
+        assert_invalid(
+            &stock("0.0.0.0:8082", "advertise = \"stock.example.com\"\n"),
+            "isn't a host:port",
+        );
+        assert_invalid(
+            &stock("0.0.0.0:8082", "advertise = \"127.0.0.1:8081\"\n"),
+            "the same uri",
+        );
+        assert_invalid(
+            "[gateway]\nlisten = \"0.0.0.0:8090\"\n",
+            "needs an address to advertise",
+        );
+    }
+
+    #[test]
+    fn test_operator() {
+        let security = |peer_token: &str, operator: &str| {
+            format!(
+                "[security]\nca = \"ca.pem\"\nclient_token = \"client\"\n{peer_token}{operator}"
+            )
+        };
+        let peer_token = "peer_token = \"peer\"\n";
+        let operator = "operator_cert = \"operator.pem\"\noperator_key = \"operator.key\"\n";
+        assert!(parse(&format!("{CLUSTER}{}", security(peer_token, operator))).is_ok());
+        assert_invalid(
+            &security(peer_token, "operator_cert = \"operator.pem\"\n"),
+            "both a cert and a key",
+        );
+        assert_invalid(
+            &format!("[security]\npeer_token = \"peer\"\n{operator}"),
+            "no ca to verify it",
+        );
+
+        // A cluster of repositories with certs, and no peer_token.
+        let certs = r#"
+            [[repositories]]
+            name = "stock"
+            listen = "127.0.0.1:8082"
+            cert = "stock.pem"
+            key = "stock.key"
+
+            [security]
+            ca = "ca.pem"
+        "#;
+        let config: ClusterConfig = toml::from_str(certs).unwrap();
+        config.validate().unwrap();
+        let e = config.peer_credentials(None).unwrap_err();
+        assert!(format!("{e:#}").contains("operator_cert"), "{e:#}");
+        assert!(parse(CLUSTER).unwrap().peer_credentials(None).is_ok());
+    }
+
+    #[test]
+    fn test_gateway() {
+        assert!(parse(CLUSTER).unwrap().gateway().is_err());
+        let gateway = |listen: &str, tls: &str, ca: &str| {
+            format!(
+                "[gateway]\nlisten = \"{listen}\"\n{tls}\
+                 [security]\npeer_token = \"peer\"\n{ca}"
+            )
+        };
+        let config = parse(&format!("{CLUSTER}{}", gateway("127.0.0.1:8090", "", ""))).unwrap();
+        assert_eq!(config.gateway().unwrap().uri(), "http://127.0.0.1:8090/ws/");
+
+        let tls = "cert = \"gateway.pem\"\nkey = \"gateway.key\"\n";
+        let ca = "ca = \"ca.pem\"\n";
+        let config = parse(&format!("{CLUSTER}{}", gateway("127.0.0.1:8090", tls, ca))).unwrap();
+        assert_eq!(
+            config.gateway().unwrap().uri(),
+            "https://127.0.0.1:8090/ws/"
+        );
+
+        assert_invalid(
+            &gateway("127.0.0.1:8081", "", ""),
+            "address of a repository",
+        );
+        assert_invalid(
+            &gateway("127.0.0.1:8090", "cert = \"gateway.pem\"\n", ca),
+            "both a cert and a key",
+        );
+        assert_invalid(&gateway("127.0.0.1:8090", tls, ""), "no ca to verify it");
+    }
+}
@@ -0,0 +1,396 @@
+//! A gateway running multi-repository transactions for thin clients.
+//!
+//! A [`GatewayWs`] accepts a whole [`MessageWs::Transaction`] over one
+//! connection, and the [`Gateway`] runs its prepare, participants and result
+//! phases with every repository, over one [`Client`] per repository.
+//!
+//! A transaction runs as the principal its client authenticated as, so the
+//! repositories check its permissions; the gateway needs the token of every
+//! principal for that. Other clients run theirs with the gateway's own
+//! credentials. With a cluster config, transactions only run with its
+//! repositories, each a participant once.
+use std::collections::{HashMap, HashSet};
+
+use actix::prelude::*;
+use actix_web::web;
+use actix_web_actors::ws::{self, WebsocketContext};
+use cereal_client::{Client, ClientBuilder, Clients, Credentials, TransactionResults};
+use cereal_core::{application::TransactionKind, retry::RetryPolicy};
+use cereal_protocol::{Encoding, MessageWs, Participant, Request, Response, ResponseBody};
+use tracing::Instrument as _;
+
+use crate::telemetry;
+
+/// [actix::Message] to run a multi-repository transaction with the
+/// `participants`.
+#[derive(Message, Debug)]
+#[rtype(result = "anyhow::Result<TransactionResults>")]
+pub(crate) struct RunTransaction {
+    pub(crate) kind: TransactionKind,
+    pub(crate) participants: Vec<Participant>,
+    /// The principal the transaction runs as, if any.
+    pub(crate) principal: Option<String>,
+    /// The span the transaction runs in.
+    pub(crate) span: tracing::Span,
+}
+
+/// Holds a [`Client`] per repository and principal, shared by every
+/// [`GatewayWs`].
+#[derive(Default)]
+pub(crate) struct Gateway {
+    clients: HashMap<(String, Option<String>), Client>,
+    /// How the gateway authenticates to the repositories, as a client.
+    credentials: Credentials,
+    /// How the gateway authenticates as each principal.
+    principals: HashMap<String, Credentials>,
+    /// The uris of the repositories of the cluster, if it is configured.
+    /// Transactions only run with them.
+    members: HashSet<String>,
+}
+
+impl Gateway {
+    pub(crate) fn new(credentials: Credentials) -> Self {
+        Gateway {
+            clients: HashMap::new(),
+            credentials,
+            principals: HashMap::new(),
+            members: HashSet::new(),
+        }
+    }
+
+    /// Runs the transactions of the clients authenticated as one of the
+    /// `principals` with its credentials.
+    pub(crate) fn with_principals(mut self, principals: HashMap<String, Credentials>) -> Self {
+        self.principals = principals;
+        self
+    }
+
+    /// Only runs transactions with the repositories at `members`.
+    pub(crate) fn with_members(mut self, members: impl IntoIterator<Item = String>) -> Self {
+        self.members = members.into_iter().collect();
+        self
+    }
+
+    /// Checks every participant is a distinct repository, of the cluster if
+    /// it is configured.
+    fn check_participants(&self, participants: &[Participant]) -> anyhow::Result<()> {
+        anyhow::ensure!(!participants.is_empty(), "a transaction needs participants");
+        let mut uris = HashSet::new();
+        for Participant { uri, .. } in participants {
+            anyhow::ensure!(uris.insert(uri), "repository {uri} is a participant twice");
+            anyhow::ensure!(
+                self.members.is_empty() || self.members.contains(uri),
+                "repository {uri} is not a member of the cluster"
+            );
+        }
+        Ok(())
+    }
+
+    fn client(&mut self, uri: &str, principal: Option<&str>) -> anyhow::Result<Client> {
+        let key = (uri.to_string(), principal.map(str::to_string));
+        if let Some(client) = self.clients.get(&key) {
+            return Ok(client.clone());
+        }
+        let credentials = match principal {
+            Some(principal) => self
+                .principals
+                .get(principal)
+                .ok_or_else(|| anyhow::anyhow!("no credentials for principal {principal}"))?,
+            None => &self.credentials,
+        };
+        let client = ClientBuilder::from_uri(uri)?
+            .credentials(credentials.clone())
+            .build();
+        self.clients.insert(key, client.clone());
+        Ok(client)
+    }
+}
+
+impl Actor for Gateway {
+    type Context = Context<Self>;
+}
+
+impl Handler<RunTransaction> for Gateway {
+    type Result = ResponseFuture<anyhow::Result<TransactionResults>>;
+
+    /// Handle for [`RunTransaction`] for [`Gateway`].
+    /// Answers with the results of each participant, retrying the
+    /// transaction while it conflicts.
+    fn handle(&mut self, msg: RunTransaction, _ctx: &mut Self::Context) -> Self::Result {
+        let RunTransaction {
+            kind,
+            participants,
+            principal,
+            span,
+        } = msg;
+        if let Err(e) = self.check_participants(&participants) {
+            return Box::pin(async move { Err(e) });
+        }
+        let mut clients = vec![];
+        let mut operations = vec![];
+        for Participant {
+            uri,
+            operations: ops,
+        } in participants
+        {
+            match self.client(&uri, principal.as_deref()) {
+                Ok(client) => clients.push(client),
+                Err(e) => return Box::pin(async move { Err(e) }),
+            }
+            operations.push(ops);
+        }
+        let clients = Clients {
+            participants: clients,
+            retry_policy: RetryPolicy::default(),
+        };
+
+        Box::pin(
+            async move {
+                match kind {
+                    TransactionKind::Independent => clients.send_indep(operations).await,
+                    TransactionKind::Coordinated => clients.send_coord(operations).await,
+                }
+            }
+            .instrument(span),
+        )
+    }
+}
+
+/// A connection to the [`Gateway`].
+pub(crate) struct GatewayWs {
+    gateway: web::Data<Addr<Gateway>>,
+    /// The [Encoding] of the responses, negotiated at the handshake.
+    encoding: Encoding,
+    /// The principal the client authenticated as, if any.
+    principal: Option<String>,
+}
+
+impl GatewayWs {
+    pub(crate) fn new(
+        gateway: web::Data<Addr<Gateway>>,
+        encoding: Encoding,
+        principal: Option<String>,
+    ) -> Self {
+        GatewayWs {
+            gateway,
+            encoding,
+            principal,
+        }
+    }
+
+    fn respond(&self, id: Option<u64>, body: ResponseBody, ctx: &mut WebsocketContext<Self>) {
+        log::info!("gateway response {:?}: {:?}", id, body);
+        ctx.write_raw(self.encoding.encode(&Response { id, body }));
+    }
+
+    fn handle_request(&mut self, request: Request, ctx: &mut WebsocketContext<Self>) {
+        let Request {
+            id,
+            message,
+            trace_context,
+        } = request;
+        let MessageWs::Transaction { kind, participants } = message else {
+            let body =
+                ResponseBody::Error(format!("a gateway only runs transactions: {message:?}"));
+            self.respond(Some(id), body, ctx);
+            return;
+        };
+
+        log::info!("gateway {:?} transaction: {:?}", kind, participants);
+        let span = tracing::info_span!("request", message = "Transaction", ?kind);
+        let span = telemetry::continue_trace(span, &trace_context);
+        let request = self.gateway.send(RunTransaction {
+            kind,
+            participants,
+            principal: self.principal.clone(),
+            span,
+        });
+        async move {
+            match request.await.unwrap_or_else(|e| Err(e.into())) {
+                Ok(TransactionResults { results, attempts }) => {
+                    ResponseBody::Results { results, attempts }
+                }
+                Err(e) => ResponseBody::Error(format!("{e:#}")),
+            }
+        }
+        .into_actor(self)
+        .map(move |body, this, ctx| this.respond(Some(id), body, ctx))
+        .spawn(ctx);
+    }
+}
+
+impl Actor for GatewayWs {
+    type Context = ws::WebsocketContext<Self>;
+}
+
+/// Handler for ws::Message message
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for GatewayWs {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(message @ (ws::Message::Text(_) | ws::Message::Binary(_))) => {
+                match cereal_protocol::decode::<Request>(&message) {
+                    Ok(request) => self.handle_request(request, ctx),
+                    Err(e) => {
+                        log::warn!("Error deserialize ws message, {:?}", e);
+                        let body = ResponseBody::Error(format!("invalid request: {e}"));
+                        self.respond(cereal_protocol::request_id(&message), body, ctx);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, HttpServer};
+    use cereal_client::{create, op, read, value};
+    use cereal_core::{
+        operations::{Operation, Table},
+        permissions::{Grant, Permission, Permissions},
+        repository::Repository,
+    };
+
+    use super::*;
+    use crate::{auth::Authenticator, testing::TestRepository};
+
+    const READER: &str = "reader-token";
+    const WRITER: &str = "writer-token";
+
+    fn authenticator() -> Authenticator {
+        Authenticator {
+            peer_token: Some("peer-token".to_string()),
+            principals: vec![
+                ("reader".to_string(), READER.to_string()),
+                ("writer".to_string(), WRITER.to_string()),
+            ],
+            ..Authenticator::default()
+        }
+    }
+
+    /// Serves a repository only the `writer` may write to.
+    fn repository(name: &str) -> TestRepository {
+        let mut permissions = Permissions::default();
+        let grant = |permissions| Grant {
+            keys: None,
+            permissions,
+        };
+        permissions.grant("reader", grant(vec![Permission::Read]));
+        permissions.grant("writer", grant(vec![Permission::Read, Permission::Write]));
+        let repository = Repository::new(name.to_string()).with_permissions(permissions);
+        TestRepository::serve_with(repository, authenticator())
+    }
+
+    /// Serves `gateway`, returning its uri.
+    fn serve(gateway: Gateway) -> String {
+        let gateway = web::Data::new(gateway.start());
+        let authenticator = web::Data::new(authenticator());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::clone(&gateway))
+                .app_data(web::Data::clone(&authenticator))
+                .route("/ws/", web::get().to(crate::gateway_index))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let address = server.addrs()[0];
+        actix::spawn(server.run());
+        format!("http://{address}/ws/")
+    }
+
+    #[actix_web::test]
+    async fn test_runs_as_the_principal() {
+        let (customer, order) = (repository("gateway-customer"), repository("gateway-order"));
+        let principals = HashMap::from([
+            (
+                "reader".to_string(),
+                Credentials::default().with_token(READER),
+            ),
+            (
+                "writer".to_string(),
+                Credentials::default().with_token(WRITER),
+            ),
+        ]);
+        let uri = serve(Gateway::new(Credentials::default()).with_principals(principals));
+        let client = |token: &str| {
+            ClientBuilder::from_uri(&uri)
+                .unwrap()
+                .credentials(Credentials::default().with_token(token))
+                .build()
+        };
+        let participants = |operations: fn(usize) -> Operation| {
+            vec![
+                Participant {
+                    uri: customer.uri(),
+                    operations: vec![operations(1)],
+                },
+                Participant {
+                    uri: order.uri(),
+                    operations: vec![operations(2)],
+                },
+            ]
+        };
+        let kind = TransactionKind::Coordinated;
+
+        let created = client(WRITER)
+            .send_transaction(kind, participants(|key| create!(key, value!(Table(1, 1)))))
+            .await;
+        assert!(created.is_ok(), "{created:?}");
+        let denied = client(READER)
+            .send_transaction(kind, participants(|key| create!(key, value!(Table(9, 9)))))
+            .await;
+        assert!(denied.is_err(), "{denied:?}");
+        let read = client(READER)
+            .send_transaction(kind, participants(|key| op!(read!(key))))
+            .await
+            .unwrap();
+        assert_eq!(read.results, vec![Some(Table(1, 1)); 2]);
+
+        let anonymous = ClientBuilder::from_uri(&uri).unwrap().build();
+        let refused = anonymous.send_transaction(kind, vec![]).await;
+        assert!(refused.is_err(), "{refused:?}");
+    }
+
+    #[actix_web::test]
+    async fn test_refuses_participants() {
+        let (member, other) = (repository("gateway-member"), repository("gateway-other"));
+        let principals = HashMap::from([(
+            "writer".to_string(),
+            Credentials::default().with_token(WRITER),
+        )]);
+        let gateway = Gateway::new(Credentials::default())
+            .with_principals(principals)
+            .with_members([member.uri()]);
+        let client = ClientBuilder::from_uri(&serve(gateway))
+            .unwrap()
+            .credentials(Credentials::default().with_token(WRITER))
+            .build();
+        let participant = |uri: String| Participant {
+            uri,
+            operations: vec![create!(1, value!(Table(1, 1)))],
+        };
+        let kind = TransactionKind::Coordinated;
+
+        for (participants, error) in [
+            (vec![], "needs participants"),
+            (
+                vec![participant(member.uri()), participant(member.uri())],
+                "participant twice",
+            ),
+            (vec![participant(other.uri())], "not a member"),
+        ] {
+            let e = client
+                .send_transaction(kind, participants)
+                .await
+                .unwrap_err();
+            assert!(format!("{e:#}").contains(error), "{e:#}");
+        }
+        let created = client
+            .send_transaction(kind, vec![participant(member.uri())])
+            .await;
+        assert!(created.is_ok(), "{created:?}");
+    }
+}
@@ -1,49 +1,134 @@
-// TODO: change all ws communications to `Binary` instead of `Text`.
-use std::{net::Ipv4Addr, time::Duration};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
 
 use actix::prelude::*;
-use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer};
+use actix_web::{error::ErrorForbidden, web, App, Error, HttpRequest, HttpResponse, HttpServer};
 use actix_web_actors::ws;
 use clap::{Parser, Subcommand};
 
+use cereal_client::{add, create, op, read, sub, update, value, Client, ClientBuilder, Clients};
+use cereal_client::{ConnectionHandle, Credentials};
+use cereal_client::{Migration, RoutingTable, TransactionResults};
 use cereal_core::{
-    operations::{Expr, Operation, Statement, Table},
+    application::TransactionKind,
+    messages::GetMetrics,
+    operations::{Operation, Table},
     repository::Repository,
+    retry::RetryPolicy,
 };
+use cereal_protocol::{Encoding, Participant, SUBPROTOCOLS};
 use rand::{thread_rng, Rng};
-use serde::{Deserialize, Serialize};
 
-mod client;
-mod macros;
+mod admin;
+mod auth;
+mod bench;
+mod changefeed;
+mod config;
+mod gateway;
+mod metrics;
+mod peers;
 mod repositoryws;
+mod retention;
+mod shell;
+mod status;
+mod telemetry;
+#[cfg(test)]
+mod testing;
 
 use crate::{
-    client::*,
-    macros::{add, create, op, read, sub, update, value},
+    admin::AdminCommand,
+    auth::{Authenticator, Role},
+    changefeed::{ChangeFeedQuery, ChangeFeedWs},
+    config::ClusterConfig,
+    gateway::{Gateway, GatewayWs},
+    peers::{GetConnectionErrors, PeerManager},
     repositoryws::*,
+    retention::Retention,
 };
 
-#[derive(Debug, Serialize, Deserialize)]
-pub(crate) enum GetResultResponse {
-    Ok(Option<Table>),
-    Err(String),
+async fn index(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
+    let repo = req.app_data::<web::Data<Addr<Repository>>>().unwrap();
+    let peers = req.app_data::<web::Data<Addr<PeerManager>>>().unwrap();
+    let auth = req.app_data::<web::Data<Authenticator>>().unwrap();
+    let role = auth.authenticate(&req)?;
+    let repows = RepositoryWs::new(
+        repo.clone(),
+        peers.clone(),
+        Encoding::negotiate(req.headers()),
+        role,
+    );
+    ws::WsResponseBuilder::new(repows, &req, stream)
+        .protocols(&SUBPROTOCOLS)
+        .start()
+}
+
+async fn gateway_index(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
+    let gateway = req.app_data::<web::Data<Addr<Gateway>>>().unwrap();
+    let auth = req.app_data::<web::Data<Authenticator>>().unwrap();
+    let role = auth.authenticate(&req)?;
+    let principal = role.principal().map(str::to_string);
+    let gatewayws = GatewayWs::new(
+        gateway.clone(),
+        Encoding::negotiate(req.headers()),
+        principal,
+    );
+    ws::WsResponseBuilder::new(gatewayws, &req, stream)
+        .protocols(&SUBPROTOCOLS)
+        .start()
 }
 
-async fn index(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
+async fn changes(
+    req: HttpRequest,
+    stream: web::Payload,
+    query: web::Query<ChangeFeedQuery>,
+) -> Result<HttpResponse, Error> {
     let repo = req.app_data::<web::Data<Addr<Repository>>>().unwrap();
-    let repows = RepositoryWs::new(repo.clone());
-    ws::start(repows, &req, stream)
+    let auth = req.app_data::<web::Data<Authenticator>>().unwrap();
+    // Events carry every key and value of the repository, whatever the
+    // grants of a principal.
+    if auth.authenticate(&req)? != Role::Peer {
+        return Err(ErrorForbidden("the change feed is only streamed to peers"));
+    }
+    let feed = ChangeFeedWs::new(repo.clone(), query.from);
+    ws::start(feed, &req, stream)
+}
+
+/// Not authenticated, like the Prometheus scrapers: it only tells counts.
+async fn metrics(req: HttpRequest) -> Result<HttpResponse, Error> {
+    let repo = req.app_data::<web::Data<Addr<Repository>>>().unwrap();
+    let peers = req.app_data::<web::Data<Addr<PeerManager>>>().unwrap();
+    let internal = actix_web::error::ErrorInternalServerError;
+    let repository_metrics = repo.send(GetMetrics).await.map_err(internal)?;
+    let connection_errors = peers.send(GetConnectionErrors).await.map_err(internal)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render(&repository_metrics, &connection_errors)))
+}
+
+/// The routes of a `ws repository`.
+fn repository_routes(config: &mut web::ServiceConfig) {
+    config
+        .route("/ws/", web::get().to(index))
+        .route("/changes/", web::get().to(changes))
+        .route("/metrics", web::get().to(metrics))
+        .route("/health", web::get().to(status::health))
+        .route("/ready", web::get().to(status::ready))
+        .route("/debug/transactions", web::get().to(status::transactions));
+}
+
+fn config_error(error: anyhow::Error) -> std::io::Error {
+    std::io::Error::other(format!("{error:#}"))
 }
 
 fn to_io_error(error: anyhow::Error) -> std::io::Error {
     let context = format!("failed to send operations. {}", error);
-    std::io::Error::new(std::io::ErrorKind::Other, context)
+    std::io::Error::other(context)
 }
 
-async fn populate_customer_and_product(
-    customer: &mut Client,
-    product: &mut Client,
-) -> anyhow::Result<()> {
+async fn populate_customer_and_product(customer: &Client, product: &Client) -> anyhow::Result<()> {
     let operations = vec![
         create!(1, value!(Table(10, 10))),
         create!(2, value!(Table(20, 20))),
@@ -62,10 +147,43 @@ async fn populate_customer_and_product(
     Ok(())
 }
 
+/// Runs a multi-repository transaction with the `participants`, through the
+/// `gateway` if there is one.
+async fn send_multi(
+    gateway: Option<&Client>,
+    kind: TransactionKind,
+    participants: Vec<(&Client, Vec<Operation>)>,
+) -> anyhow::Result<TransactionResults> {
+    if let Some(gateway) = gateway {
+        let participants = participants
+            .into_iter()
+            .map(|(client, operations)| Participant {
+                uri: client.uri().to_string(),
+                operations,
+            })
+            .collect();
+        return gateway.send_transaction(kind, participants).await;
+    }
+
+    let (participants, operations) = participants
+        .into_iter()
+        .map(|(client, operations)| (client.clone(), operations))
+        .unzip();
+    let clients = Clients {
+        participants,
+        retry_policy: RetryPolicy::default(),
+    };
+    match kind {
+        TransactionKind::Independent => clients.send_indep(operations).await,
+        TransactionKind::Coordinated => clients.send_coord(operations).await,
+    }
+}
+
 async fn check_invariant(
-    customer: &mut Client,
-    _order: &mut Client,
-    product: &mut Client,
+    customer: &Client,
+    _order: &Client,
+    product: &Client,
+    gateway: Option<&Client>,
 ) -> anyhow::Result<()> {
     loop {
         let mut rng = thread_rng();
@@ -76,19 +194,22 @@ async fn check_invariant(
         let operation_customer = vec![op!(read!(key))];
         let operation_product = vec![op!(read!(key))];
 
-        let mut clients = Clients {
-            participants: vec![customer, product],
-        };
-
-        let results = clients
-            .send_indep(vec![operation_customer, operation_product])
-            .await?;
+        let results = send_multi(
+            gateway,
+            TransactionKind::Independent,
+            vec![(customer, operation_customer), (product, operation_product)],
+        )
+        .await?;
 
-        log::info!("for key = {key}, indep result: {:?}", results);
+        log::info!(
+            "for key = {key}, indep result after {} attempt(s): {:?}",
+            results.attempts,
+            results.results
+        );
 
         // TODO: make this less horrible
         if let (Some(Some(result_customer)), Some(Some(result_product))) =
-            (results.get(0), results.get(1))
+            (results.results.first(), results.results.get(1))
         {
             let key: i64 = i64::try_from(key)?;
             assert_eq!(
@@ -99,14 +220,14 @@ async fn check_invariant(
 
         actix::clock::sleep(Duration::from_secs(1)).await;
     }
-    Ok(())
 }
 
 // TODO: handle the loop better
 async fn create_order(
-    customer: &mut Client,
-    order: &mut Client,
-    product: &mut Client,
+    customer: &Client,
+    order: &Client,
+    product: &Client,
+    gateway: Option<&Client>,
 ) -> anyhow::Result<()> {
     loop {
         let mut rng = thread_rng();
@@ -124,15 +245,25 @@ async fn create_order(
         let operation_customer = vec![update!(key, add!(read!(key), value!(Table(1, 1))))];
         let operation_product = vec![update!(key, sub!(read!(key), value!(Table(1, 1))))];
 
-        let mut clients = Clients {
-            participants: vec![customer, order, product],
-        };
-
-        let results = clients
-            .send_coord(vec![operation_customer, operation_order, operation_product])
-            .await;
+        let results = send_multi(
+            gateway,
+            TransactionKind::Coordinated,
+            vec![
+                (customer, operation_customer),
+                (order, operation_order),
+                (product, operation_product),
+            ],
+        )
+        .await;
 
-        log::info!("coord result: {:?}", results);
+        match results {
+            Ok(results) => log::info!(
+                "coord result after {} attempt(s): {:?}",
+                results.attempts,
+                results.results
+            ),
+            Err(e) => log::info!("coord result: {e}"),
+        }
 
         let op_read = vec![op!(read!(key))];
         let result = order.send_single(op_read.clone()).await?;
@@ -146,7 +277,6 @@ async fn create_order(
 
         actix::clock::sleep(Duration::from_secs(1)).await;
     }
-    Ok(())
 }
 
 #[derive(Parser, Debug)]
@@ -154,25 +284,151 @@ async fn create_order(
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// write the spans of the transactions to this file, as OTLP JSON lines
+    #[arg(long, global = true)]
+    trace_file: Option<PathBuf>,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// start a new `Repository`
     Repository {
+        #[arg(
+            short,
+            long,
+            required_unless_present("config"),
+            conflicts_with("config")
+        )]
+        port: Option<u16>,
+        /// address to listen on, with the `port`.
+        #[arg(short, long, default_value = "127.0.0.1", conflicts_with("config"))]
+        bind: IpAddr,
+        /// start a repository of the cluster configured in this file.
+        #[arg(long)]
+        config: Option<PathBuf>,
+        /// the name of the repository in the cluster config, if it has many.
+        #[arg(short, long, requires("config"))]
+        name: Option<String>,
+        /// seconds to keep the results of finished transactions for.
+        #[arg(long, default_value_t = 600, conflicts_with("config"))]
+        result_retention: u64,
+        /// seconds to keep past versions of keys for, raising the low
+        /// watermark above older ones.
+        #[arg(long, default_value_t = 600, conflicts_with("config"))]
+        version_retention: u64,
+    },
+    /// start a gateway, running multi-repository transactions for clients.
+    Gateway {
+        /// the port to listen on, instead of the gateway of the cluster
+        /// config.
+        #[arg(short, long, required_unless_present("config"))]
+        port: Option<u16>,
+        /// address to listen on, with the `port`.
+        #[arg(short, long, default_value = "127.0.0.1", requires("port"))]
+        bind: IpAddr,
+        /// authenticate to the repositories of the cluster configured in this
+        /// file, and authenticate clients with its tokens.
+        #[arg(long)]
+        config: Option<PathBuf>,
+        /// run the transactions of clients that aren't a principal of the
+        /// cluster config as this one. Principals run theirs as themselves.
+        #[arg(long, requires("config"))]
+        principal: Option<String>,
+        /// serve TLS with this PEM certificate.
+        #[arg(long, requires("key"), requires("port"))]
+        cert: Option<PathBuf>,
+        /// the PEM private key of the `cert`.
+        #[arg(long, requires("cert"), requires("port"))]
+        key: Option<PathBuf>,
+    },
+    /// move a key range of a table to another repository, updating the
+    /// routing table once it moved.
+    Migrate {
+        /// the routing table file.
         #[arg(short, long)]
-        port: u16,
+        routing: PathBuf,
+        #[arg(short, long)]
+        table: String,
+        /// first key to move.
+        #[arg(long)]
+        start: usize,
+        /// key after the last one to move.
+        #[arg(long)]
+        end: usize,
+        /// uri of the repository to move the keys to.
+        #[arg(long)]
+        to: String,
+        /// authenticate as a peer of the cluster configured in this file.
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+    /// inspect or operate a running repository.
+    Admin {
+        #[command(subcommand)]
+        command: AdminCommand,
+        /// uri of the repository, e.g. `http://127.0.0.1:8080/ws/`.
+        #[arg(
+            short,
+            long,
+            required_unless_present("config"),
+            conflicts_with("config")
+        )]
+        uri: Option<String>,
+        /// authenticate as a peer of the cluster configured in this file.
+        #[arg(long)]
+        config: Option<PathBuf>,
+        /// the name of the repository in the cluster config, if it has many.
+        #[arg(short, long, requires("config"))]
+        name: Option<String>,
+    },
+    /// run transactions typed in a SQL-like language, see `HELP;`.
+    Shell {
+        /// uri of the repository to start on, e.g. `http://127.0.0.1:8080/ws/`.
+        #[arg(short, long)]
+        uri: Option<String>,
+        /// name repositories by the tables and repositories of the cluster
+        /// configured in this file.
+        #[arg(long, conflicts_with("uri"))]
+        config: Option<PathBuf>,
+        /// the table or repository of the cluster config to start on.
+        #[arg(short, long, requires("config"))]
+        name: Option<String>,
+        /// authenticate as this principal of the cluster config.
+        #[arg(long, requires("config"))]
+        principal: Option<String>,
+    },
+    /// run a workload on repositories, and report its throughput,
+    /// latencies and aborts as JSON.
+    Bench {
+        #[command(flatten)]
+        args: bench::BenchArgs,
     },
     /// start a loosely inspired TPC-like testing.
     TPCFake {
         #[command(subcommand)]
         tpc_command: TPCFakeCommand,
-        #[arg(short, long, required(true))]
-        customer_port: u16,
-        #[arg(short, long, required(true))]
-        order_port: u16,
-        #[arg(short, long, required(true))]
-        product_port: u16,
+        #[arg(short, long, required_unless_present("config"))]
+        customer_port: Option<u16>,
+        #[arg(short, long, required_unless_present("config"))]
+        order_port: Option<u16>,
+        #[arg(short, long, required_unless_present("config"))]
+        product_port: Option<u16>,
+        /// use the repositories storing the `customer`, `order` and `product`
+        /// tables in the cluster configured in this file, unless their port
+        /// is given.
+        #[arg(long)]
+        config: Option<PathBuf>,
+        /// authenticate as this principal of the cluster config.
+        #[arg(long, requires("config"))]
+        principal: Option<String>,
+        /// send the multi-repository transactions through the `gateway` at
+        /// this port.
+        #[arg(short, long)]
+        gateway_port: Option<u16>,
+        /// send the multi-repository transactions through the gateway of
+        /// the cluster config.
+        #[arg(long, requires("config"), conflicts_with("gateway_port"))]
+        gateway: bool,
     },
 }
 
@@ -181,57 +437,829 @@ enum TPCFakeCommand {
     Start,
     Management,
     Buyer,
+    /// Run `management` and `buyer` at once, sharing the connections.
+    Mixed,
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init();
-
     let cli: Cli = Cli::parse();
+    let service = match &cli.command {
+        Commands::Repository { .. } => "repository",
+        Commands::Gateway { .. } => "gateway",
+        Commands::Migrate { .. } => "migrate",
+        Commands::Admin { .. } => "admin",
+        Commands::Shell { .. } => "shell",
+        Commands::Bench { .. } => "bench",
+        Commands::TPCFake { .. } => "tpc-fake",
+    };
+    telemetry::init(service, cli.trace_file.as_deref()).map_err(config_error)?;
 
     match cli.command {
-        Commands::Repository { port } => {
-            let repo_actor: web::Data<Addr<Repository>> =
-                web::Data::new(Repository::new(format!("repository-{port}")).start());
-            return HttpServer::new(move || {
+        Commands::Repository {
+            port,
+            bind,
+            config,
+            name,
+            result_retention,
+            version_retention,
+        } => {
+            let (repository, address, peers, authenticator, tls, retention) = match (config, port) {
+                (Some(path), _) => {
+                    let cluster = ClusterConfig::load(&path).map_err(config_error)?;
+                    let config = cluster.repository(name.as_deref()).map_err(config_error)?;
+                    let runtime = config.runtime().map_err(config_error)?;
+                    let credentials = cluster
+                        .peer_credentials(Some(config))
+                        .map_err(config_error)?;
+                    let mut repository = Repository::with_runtime(config.name.clone(), runtime);
+                    if let Some(permissions) = cluster.permissions(config) {
+                        repository = repository.with_permissions(permissions);
+                    }
+                    (
+                        repository,
+                        config.listen,
+                        PeerManager::new(cluster.uris(), credentials),
+                        cluster.authenticator(),
+                        cluster.server_config(config).map_err(config_error)?,
+                        (config.result_retention(), config.version_retention()),
+                    )
+                }
+                (None, Some(port)) => (
+                    Repository::new(format!("repository-{port}")),
+                    SocketAddr::new(bind, port),
+                    PeerManager::default(),
+                    Authenticator::default(),
+                    None,
+                    (
+                        Duration::from_secs(result_retention),
+                        Duration::from_secs(version_retention),
+                    ),
+                ),
+                (None, None) => unreachable!("clap requires a port or a config"),
+            };
+            if authenticator.is_insecure() {
+                log::warn!(
+                    "no tokens or ca are configured: every connection is trusted as a peer, \
+                     which is only safe if trusted hosts alone can reach {address}"
+                );
+            }
+            let repo_actor: web::Data<Addr<Repository>> = web::Data::new(repository.start());
+            let (results, versions) = retention;
+            Retention::new(Addr::clone(&repo_actor), results, versions).start();
+            let peers: web::Data<Addr<PeerManager>> = web::Data::new(peers.start());
+            let authenticator = web::Data::new(authenticator);
+            let server = HttpServer::new(move || {
                 App::new()
                     .app_data(web::Data::clone(&repo_actor))
-                    .route("/ws/", web::get().to(index))
+                    .app_data(web::Data::clone(&peers))
+                    .app_data(web::Data::clone(&authenticator))
+                    .configure(repository_routes)
+            })
+            .on_connect(auth::on_connect);
+            return match tls {
+                Some(tls) => server.bind_rustls_0_23(address, tls)?,
+                None => server.bind(address)?,
+            }
+            .run()
+            .await;
+        }
+        Commands::Gateway {
+            port,
+            bind,
+            config,
+            principal,
+            cert,
+            key,
+        } => {
+            let cluster = config
+                .map(|path| ClusterConfig::load(&path))
+                .transpose()
+                .map_err(config_error)?;
+            let (gateway, authenticator, ca) = match &cluster {
+                Some(cluster) => {
+                    let credentials = cluster
+                        .client_credentials(principal.as_deref())
+                        .map_err(config_error)?;
+                    let principals = cluster.principal_credentials().map_err(config_error)?;
+                    (
+                        Gateway::new(credentials)
+                            .with_principals(principals)
+                            .with_members(cluster.uris()),
+                        cluster.authenticator(),
+                        cluster.security.ca.clone(),
+                    )
+                }
+                None => (
+                    Gateway::new(Credentials::default()),
+                    Authenticator::default(),
+                    None,
+                ),
+            };
+            let (address, cert, key) = match (port, &cluster) {
+                (Some(port), _) => (SocketAddr::new(bind, port), cert, key),
+                (None, Some(cluster)) => {
+                    let gateway = cluster.gateway().map_err(config_error)?;
+                    (gateway.listen, gateway.cert.clone(), gateway.key.clone())
+                }
+                (None, None) => unreachable!("clap requires a port or a config"),
+            };
+            let tls = match cert.zip(key) {
+                Some((cert, key)) => {
+                    Some(auth::server_config(&cert, &key, ca.as_deref()).map_err(config_error)?)
+                }
+                None => None,
+            };
+            let gateway: web::Data<Addr<Gateway>> = web::Data::new(gateway.start());
+            let authenticator = web::Data::new(authenticator);
+            let server = HttpServer::new(move || {
+                App::new()
+                    .app_data(web::Data::clone(&gateway))
+                    .app_data(web::Data::clone(&authenticator))
+                    .route("/ws/", web::get().to(gateway_index))
             })
-            .bind(("127.0.0.1", port))?
+            .on_connect(auth::on_connect);
+            return match tls {
+                Some(tls) => server.bind_rustls_0_23(address, tls)?,
+                None => server.bind(address)?,
+            }
             .run()
             .await;
         }
+        Commands::Migrate {
+            routing,
+            table,
+            start,
+            end,
+            to,
+            config,
+        } => {
+            let migrate = async {
+                let current = RoutingTable::load(&routing)?;
+                let mut migration = Migration::new(table, start..end, to);
+                migration.routing_path = Some(routing);
+                if let Some(path) = config {
+                    migration.credentials = ClusterConfig::load(&path)?.peer_credentials(None)?;
+                }
+                let report = migration.run(&current).await?;
+                println!(
+                    "moved {start}..{end} from {} to {}: {} keys copied, {} changed since, {} verified",
+                    report.from, report.to, report.copied, report.changed, report.verified
+                );
+                anyhow::Ok(())
+            };
+            migrate.await.map_err(to_io_error)?;
+        }
+        Commands::Admin {
+            command,
+            uri,
+            config,
+            name,
+        } => {
+            let admin = async {
+                let connection = match (config, uri) {
+                    (Some(path), _) => {
+                        let cluster = ClusterConfig::load(&path)?;
+                        let repository = admin::repository(&cluster, &command, name.as_deref())?;
+                        let credentials = cluster.peer_credentials(None)?;
+                        ConnectionHandle::spawn_with(repository.uri(), credentials)
+                    }
+                    (None, Some(uri)) => {
+                        anyhow::ensure!(
+                            !matches!(command, AdminCommand::Dump { table: Some(_) }),
+                            "dump --table names a table of the cluster --config"
+                        );
+                        ConnectionHandle::spawn(uri)
+                    }
+                    (None, None) => unreachable!("clap requires a uri or a config"),
+                };
+                admin::run(&connection, command).await
+            };
+            admin.await.map_err(config_error)?;
+        }
+        Commands::Shell {
+            uri,
+            config,
+            name,
+            principal,
+        } => {
+            let (cluster, credentials, current) = match config {
+                Some(path) => {
+                    let cluster = ClusterConfig::load(&path).map_err(config_error)?;
+                    let credentials = cluster
+                        .client_credentials(principal.as_deref())
+                        .map_err(config_error)?;
+                    (Some(cluster), credentials, name)
+                }
+                None => (None, Credentials::default(), uri),
+            };
+            let history =
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".ws_history"));
+            shell::Shell::new(cluster, credentials, current)
+                .run(history)
+                .await
+                .map_err(config_error)?;
+        }
+        Commands::Bench { args } => {
+            let bench = async {
+                let (uris, credentials) = match &args.config {
+                    Some(path) => {
+                        let cluster = ClusterConfig::load(path)?;
+                        let credentials = cluster.client_credentials(args.principal.as_deref())?;
+                        (cluster.uris(), credentials)
+                    }
+                    None => (args.uris.clone(), Credentials::default()),
+                };
+                let output = args.output.clone();
+                let report = bench::run(args, uris, credentials).await?;
+                let report = serde_json::to_string_pretty(&report)?;
+                match output {
+                    Some(path) => std::fs::write(&path, report + "\n")?,
+                    None => println!("{report}"),
+                }
+                anyhow::Ok(())
+            };
+            bench.await.map_err(config_error)?;
+        }
         Commands::TPCFake {
             tpc_command,
             customer_port,
             order_port,
             product_port,
+            config,
+            principal,
+            gateway_port,
+            gateway,
         } => {
-            let customer_builder = ClientBuilder::new(Ipv4Addr::new(127, 0, 0, 1), customer_port);
-            let mut customer = customer_builder.build().await;
-
-            let product_builder = ClientBuilder::new(Ipv4Addr::new(127, 0, 0, 1), product_port);
-            let mut product = product_builder.build().await;
-
-            let order_builder = ClientBuilder::new(Ipv4Addr::new(127, 0, 0, 1), order_port);
-            let mut order = order_builder.build().await;
+            let cluster = config
+                .map(|path| ClusterConfig::load(&path))
+                .transpose()
+                .map_err(config_error)?;
+            let credentials = match &cluster {
+                Some(cluster) => cluster
+                    .client_credentials(principal.as_deref())
+                    .map_err(config_error)?,
+                None => Credentials::default(),
+            };
+            let client = |table: &str, port: Option<u16>| {
+                let builder = match (&cluster, port) {
+                    (_, Some(port)) => ClientBuilder::new(Ipv4Addr::LOCALHOST, port),
+                    (Some(cluster), None) => {
+                        ClientBuilder::from_uri(&cluster.repository_of(table)?.uri())?
+                    }
+                    (None, None) => unreachable!("clap requires a port or a config"),
+                };
+                anyhow::Ok(builder.credentials(credentials.clone()).build())
+            };
+            let customer = client("customer", customer_port).map_err(config_error)?;
+            let product = client("product", product_port).map_err(config_error)?;
+            let order = client("order", order_port).map_err(config_error)?;
+            let gateway = match (&cluster, gateway_port, gateway) {
+                (_, Some(port), _) => Some(ClientBuilder::new(Ipv4Addr::LOCALHOST, port)),
+                (Some(cluster), None, true) => Some(
+                    cluster
+                        .gateway()
+                        .and_then(|gateway| ClientBuilder::from_uri(&gateway.uri()))
+                        .map_err(config_error)?,
+                ),
+                (_, None, _) => None,
+            }
+            .map(|builder| builder.credentials(credentials.clone()).build());
+            let gateway = gateway.as_ref();
 
             match tpc_command {
-                TPCFakeCommand::Start => populate_customer_and_product(&mut customer, &mut product)
+                TPCFakeCommand::Start => populate_customer_and_product(&customer, &product)
                     .await
                     .map_err(to_io_error)?,
-                TPCFakeCommand::Management => {
-                    check_invariant(&mut customer, &mut order, &mut product)
-                        .await
-                        .map_err(to_io_error)?
-                }
-                TPCFakeCommand::Buyer => create_order(&mut customer, &mut order, &mut product)
+                TPCFakeCommand::Management => check_invariant(&customer, &order, &product, gateway)
+                    .await
+                    .map_err(to_io_error)?,
+                TPCFakeCommand::Buyer => create_order(&customer, &order, &product, gateway)
                     .await
                     .map_err(to_io_error)?,
+                TPCFakeCommand::Mixed => futures_util::future::try_join(
+                    check_invariant(&customer, &order, &product, gateway),
+                    create_order(&customer, &order, &product, gateway),
+                )
+                .await
+                .map(|_| ())
+                .map_err(to_io_error)?,
             };
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use cereal_client::{ShardedClient, ShardedOperation};
+    use cereal_core::{
+        messages::{CommitVote, TransactionError},
+        operations::Arguments,
+    };
+    use cereal_protocol::{MessageWs, ResponseBody};
+    use futures_util::future::join_all;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::testing::{Silent, TestRepository};
+
+    /// Every key of `customer` at the repository at `uri`.
+    fn routing(uri: &str) -> RoutingTable {
+        RoutingTable::from_toml(&format!(
+            "[tables.customer]\nranges = [{{ start = 0, end = 100, repository = \"{uri}\" }}]"
+        ))
+        .unwrap()
+    }
+
+    async fn read(client: &Client, key: usize) -> anyhow::Result<Option<Table>> {
+        client.send_single(vec![op!(read!(key))]).await
+    }
+
+    /// Serves a source repository with the keys `0..5`, and an empty
+    /// destination, with a client to each.
+    async fn repositories() -> (TestRepository, Client, TestRepository, Client) {
+        let source = TestRepository::serve(Repository::new("source".to_string()));
+        let destination = TestRepository::serve(Repository::new("destination".to_string()));
+        let source_client = ClientBuilder::from_uri(&source.uri()).unwrap().build();
+        let destination_client = ClientBuilder::from_uri(&destination.uri()).unwrap().build();
+        let keys = (0..5)
+            .map(|key| create!(key, value!(Table(key as i64, key as i64))))
+            .collect();
+        source_client.send_single(keys).await.unwrap();
+        (source, source_client, destination, destination_client)
+    }
+
+    fn migration(destination: &TestRepository, routing_path: &Path) -> Migration {
+        let mut migration = Migration::new("customer", 2..50, destination.uri());
+        migration.routing_path = Some(routing_path.to_path_buf());
+        migration
+    }
+
+    #[actix_web::test]
+    async fn test_migration() {
+        let (source, source_client, destination, destination_client) = repositories().await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("routing.toml");
+        let current = routing(&source.uri());
+        current.save(&path).unwrap();
+
+        let report = migration(&destination, &path).run(&current).await.unwrap();
+        assert_eq!((report.copied, report.changed, report.verified), (3, 0, 3));
+        assert_eq!(RoutingTable::load(&path).unwrap(), report.routing);
+        assert_eq!(
+            report.routing.route("customer", 2).unwrap(),
+            destination.uri()
+        );
+        assert_eq!(report.routing.route("customer", 1).unwrap(), source.uri());
+
+        assert_eq!(
+            read(&destination_client, 2).await.unwrap(),
+            Some(Table(2, 2))
+        );
+        assert!(read(&source_client, 2).await.is_err());
+        assert_eq!(read(&source_client, 1).await.unwrap(), Some(Table(1, 1)));
+    }
+
+    #[actix_web::test]
+    async fn test_migration_rolled_back() {
+        let (source, source_client, destination, destination_client) = repositories().await;
+        let dir = tempfile::tempdir().unwrap();
+        let current = routing(&source.uri());
+
+        let unwritable = dir.path().join("missing").join("routing.toml");
+        let e = migration(&destination, &unwritable)
+            .run(&current)
+            .await
+            .unwrap_err();
+        assert!(format!("{e:#}").contains("are back at"), "{e:#}");
+        assert_eq!(read(&source_client, 2).await.unwrap(), Some(Table(2, 2)));
+        assert!(read(&destination_client, 2).await.is_err());
+
+        let path = dir.path().join("routing.toml");
+        migration(&destination, &path).run(&current).await.unwrap();
+        assert_eq!(
+            read(&destination_client, 2).await.unwrap(),
+            Some(Table(2, 2))
+        );
+        assert!(read(&source_client, 2).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_sharded_client_after_migration() {
+        let (source, _, destination, _) = repositories().await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("routing.toml");
+        let stopped = Silent::serve();
+        let unreachable = routing(&stopped.uri());
+        stopped.stop().await;
+        unreachable.save(&path).unwrap();
+        let client = ShardedClient::load(&path).unwrap();
+        let read = |key| vec![ShardedOperation::new("customer", op!(read!(key)))];
+
+        // Not run again with the new routing table when the repository
+        // wasn't reached: it may have run it.
+        let current = routing(&source.uri());
+        current.save(&path).unwrap();
+        assert!(client.send(read(2)).await.is_err());
+        assert_eq!(client.routing(), unreachable);
+        assert!(client.reload().unwrap());
+
+        migration(&destination, &path).run(&current).await.unwrap();
+        let results = client.send(read(2)).await.unwrap();
+        assert_eq!(
+            results.results,
+            vec![(destination.uri(), Some(Table(2, 2)))]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_migration_abandoned() {
+        let (source, source_client, destination, destination_client) = repositories().await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("routing.toml");
+        let current = routing(&source.uri());
+        // Holds the lock on a key of the range, so the move conflicts.
+        let holder = MessageWs::Coord {
+            tid: Uuid::new_v4(),
+            args: Arguments {
+                timestamp: 0,
+                operations: vec![update!(2, value!(Table(9, 9)))],
+            },
+            participants_size: 2,
+        };
+        let connection = ConnectionHandle::spawn(source.uri());
+        connection
+            .request(holder)
+            .await
+            .unwrap()
+            .into_vote()
+            .unwrap();
+
+        let mut abandoned = migration(&destination, &path);
+        abandoned.retry_policy.max_attempts = 1;
+        let e = abandoned.run(&current).await.unwrap_err();
+        assert!(format!("{e:#}").contains("are still at"), "{e:#}");
+        assert_eq!(
+            destination_client.export_range(2..50).await.unwrap(),
+            vec![]
+        );
+        assert!(!path.exists());
+        assert_eq!(read(&source_client, 1).await.unwrap(), Some(Table(1, 1)));
+    }
+
+    /// Sends `operations` as the single repository transaction `tid`.
+    async fn single(
+        connection: &ConnectionHandle,
+        tid: Uuid,
+        operations: Vec<Operation>,
+    ) -> anyhow::Result<CommitVote> {
+        let args = Arguments {
+            timestamp: 0,
+            operations,
+        };
+        let response = connection.request(MessageWs::Single { tid, args }).await?;
+        response.into_vote()
+    }
+
+    #[actix_web::test]
+    async fn test_connection_multiplexing() {
+        let repository = TestRepository::serve(Repository::new("multiplexing".to_string()));
+        let connection = ConnectionHandle::spawn(repository.uri());
+        let keys = (0..20)
+            .map(|key| create!(key, value!(Table(key as i64, key as i64))))
+            .collect();
+        single(&connection, Uuid::new_v4(), keys).await.unwrap();
+
+        let reads = (0..20).map(|key| {
+            let connection = connection.clone();
+            async move {
+                let tid = Uuid::new_v4();
+                single(&connection, tid, vec![op!(read!(key))]).await?;
+                let response = connection.request(MessageWs::GetResult { tid }).await?;
+                response.into_result()
+            }
+        });
+        for (key, result) in join_all(reads).await.into_iter().enumerate() {
+            assert_eq!(result.unwrap(), Some(Table(key as i64, key as i64)));
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_connection_dropped() {
+        let silent = Silent::serve();
+        let address = silent.address;
+        let connection = ConnectionHandle::spawn(silent.uri());
+        let pending = actix::spawn({
+            let connection = connection.clone();
+            async move { single(&connection, Uuid::new_v4(), vec![op!(read!(1))]).await }
+        });
+        actix::clock::sleep(Duration::from_millis(100)).await;
+        assert!(!pending.is_finished());
+
+        silent.stop().await;
+        let e = pending.await.unwrap().unwrap_err();
+        assert!(format!("{e:#}").contains("lost"), "{e:#}");
+
+        // The next request connects again.
+        let _repository = TestRepository::serve_at(Repository::new("dropped".to_string()), address);
+        let vote = single(&connection, Uuid::new_v4(), vec![op!(read!(1))]).await;
+        assert!(vote.is_ok(), "{vote:?}");
+    }
+
+    #[actix_web::test]
+    async fn test_request_timeout() {
+        let silent = Silent::serve();
+        let connection =
+            ConnectionHandle::spawn(silent.uri()).with_timeout(Duration::from_millis(100));
+        let e = single(&connection, Uuid::new_v4(), vec![op!(read!(1))])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<TransactionError>(),
+            Some(&TransactionError::Timeout)
+        );
+        assert!(format!("{e:#}").contains("no response"), "{e:#}");
+    }
+
+    #[actix_web::test]
+    async fn test_prepare_sent_twice() {
+        let repository = TestRepository::serve(Repository::new("sent-twice".to_string()));
+        let connection = ConnectionHandle::spawn(repository.uri());
+        let create = vec![create!(1, value!(Table(1, 1)))];
+        single(&connection, Uuid::new_v4(), create).await.unwrap();
+
+        let tid = Uuid::new_v4();
+        let increment = vec![update!(1, add!(read!(1), value!(Table(1, 1))))];
+        let vote = single(&connection, tid, increment.clone()).await.unwrap();
+        assert_eq!(single(&connection, tid, increment).await.unwrap(), vote);
+
+        let client = ClientBuilder::from_uri(&repository.uri()).unwrap().build();
+        assert_eq!(read(&client, 1).await.unwrap(), Some(Table(2, 2)));
+    }
+
+    /// The transactions pending at the repository of `connection`.
+    async fn pending(connection: &ConnectionHandle) -> Vec<(Uuid, usize)> {
+        match connection.request(MessageWs::Introspect).await.unwrap() {
+            ResponseBody::Introspection(introspection) => introspection
+                .pending
+                .iter()
+                .map(|xaction| (xaction.tid, xaction.waiting_for))
+                .collect(),
+            other => panic!("expected an introspection, got {other:?}"),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_participants_notified_twice() {
+        let repositories: Vec<_> = (0..3)
+            .map(|i| TestRepository::serve(Repository::new(format!("notified-{i}"))))
+            .collect();
+        let connections: Vec<_> = repositories
+            .iter()
+            .map(|repository| ConnectionHandle::spawn(repository.uri()))
+            .collect();
+        for connection in &connections {
+            let create = vec![create!(1, value!(Table(1, 1)))];
+            single(connection, Uuid::new_v4(), create).await.unwrap();
+        }
+
+        let tid = Uuid::new_v4();
+        let increment = vec![update!(1, add!(read!(1), value!(Table(1, 1))))];
+        for connection in &connections {
+            let args = Arguments {
+                timestamp: 0,
+                operations: increment.clone(),
+            };
+            let indep = MessageWs::Indep {
+                tid,
+                args,
+                participants_size: 2,
+            };
+            connection
+                .request(indep)
+                .await
+                .unwrap()
+                .into_vote()
+                .unwrap();
+        }
+        let notify = |i: usize| MessageWs::IndepParticipants {
+            tid,
+            vote: CommitVote::Commit(None),
+            participants: repositories
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, repository)| repository.uri())
+                .collect(),
+        };
+        for _ in 0..2 {
+            let vote = connections[0].request(notify(0)).await.unwrap();
+            assert_eq!(vote.into_vote().unwrap(), CommitVote::InProgress);
+        }
+        // The others still wait for each other.
+        assert_eq!(pending(&connections[1]).await, vec![(tid, 1)]);
+        assert_eq!(pending(&connections[2]).await, vec![(tid, 1)]);
+
+        for (i, connection) in connections.iter().enumerate().skip(1) {
+            connection.request(notify(i)).await.unwrap();
+        }
+        for connection in &connections {
+            let result = connection.request(MessageWs::GetResult { tid }).await;
+            assert_eq!(result.unwrap().into_result().unwrap(), Some(Table(2, 2)));
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_participants_notified_again_after_a_lost_accept() {
+        let first = TestRepository::serve(Repository::new("resent-first".to_string()));
+        // Not serving yet when the first participant is notified.
+        let silent = Silent::serve();
+        let address = silent.address;
+        silent.stop().await;
+        let to_first = ConnectionHandle::spawn(first.uri());
+        let to_second = ConnectionHandle::spawn(format!("http://{address}/ws/"));
+
+        let tid = Uuid::new_v4();
+        let indep = || MessageWs::Indep {
+            tid,
+            args: Arguments {
+                timestamp: 0,
+                operations: vec![create!(1, value!(Table(1, 1)))],
+            },
+            participants_size: 1,
+        };
+        to_first
+            .request(indep())
+            .await
+            .unwrap()
+            .into_vote()
+            .unwrap();
+        let notify = |uri| MessageWs::IndepParticipants {
+            tid,
+            vote: CommitVote::Commit(None),
+            participants: vec![uri],
+        };
+        let second_uri = format!("http://{address}/ws/");
+        let e = to_first
+            .request(notify(second_uri.clone()))
+            .await
+            .unwrap()
+            .into_vote()
+            .unwrap_err();
+        assert!(format!("{e:#}").contains(&second_uri), "{e:#}");
+
+        // The accept is sent again, to the participant that didn't get it.
+        let _second =
+            TestRepository::serve_at(Repository::new("resent-second".to_string()), address);
+        to_second
+            .request(indep())
+            .await
+            .unwrap()
+            .into_vote()
+            .unwrap();
+        assert_eq!(pending(&to_second).await, vec![(tid, 1)]);
+        let vote = to_first.request(notify(second_uri)).await.unwrap();
+        assert_eq!(vote.into_vote().unwrap(), CommitVote::InProgress);
+        assert_eq!(pending(&to_second).await, vec![]);
+
+        to_second.request(notify(first.uri())).await.unwrap();
+        for connection in [&to_first, &to_second] {
+            let result = connection.request(MessageWs::GetResult { tid }).await;
+            assert!(result.unwrap().into_result().is_ok());
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_participants_notified_after_the_read() {
+        let repository = TestRepository::serve(Repository::new("notified-late".to_string()));
+        let connection = ConnectionHandle::spawn(repository.uri());
+        let create = vec![create!(1, value!(Table(1, 1)))];
+        single(&connection, Uuid::new_v4(), create).await.unwrap();
+
+        let tid = Uuid::new_v4();
+        let args = Arguments {
+            timestamp: 0,
+            operations: vec![op!(read!(1))],
+        };
+        let indep = MessageWs::Indep {
+            tid,
+            args,
+            participants_size: 1,
+        };
+        connection
+            .request(indep)
+            .await
+            .unwrap()
+            .into_vote()
+            .unwrap();
+        // The other participant was notified first: the read runs.
+        let accept = MessageWs::AcceptIndep {
+            tid,
+            proposed_ts: 0,
+            vote: CommitVote::Commit(None),
+            participant: "other".to_string(),
+        };
+        connection
+            .request(accept)
+            .await
+            .unwrap()
+            .into_vote()
+            .unwrap();
+        let result = connection.request(MessageWs::GetResult { tid }).await;
+        assert_eq!(result.unwrap().into_result().unwrap(), Some(Table(1, 1)));
+
+        let notify = |tid| MessageWs::IndepParticipants {
+            tid,
+            vote: CommitVote::Commit(None),
+            participants: vec![],
+        };
+        let vote = connection.request(notify(tid)).await.unwrap();
+        assert_eq!(vote.into_vote().unwrap(), CommitVote::InProgress);
+        let e = connection
+            .request(notify(Uuid::new_v4()))
+            .await
+            .unwrap()
+            .into_vote()
+            .unwrap_err();
+        assert!(format!("{e:#}").contains("unknown transaction"), "{e:#}");
+    }
+
+    #[actix_web::test]
+    async fn test_participants_get_the_prepared_vote() {
+        let customer = TestRepository::serve(Repository::new("forged-customer".to_string()));
+        let order = TestRepository::serve(Repository::new("forged-order".to_string()));
+        let (to_customer, to_order) = (
+            ConnectionHandle::spawn(customer.uri()),
+            ConnectionHandle::spawn(order.uri()),
+        );
+        let create = vec![create!(1, value!(Table(1, 1)))];
+        single(&to_customer, Uuid::new_v4(), create).await.unwrap();
+        let coord = |tid, operations| MessageWs::Coord {
+            tid,
+            args: Arguments {
+                timestamp: 0,
+                operations,
+            },
+            participants_size: 2,
+        };
+        let increment = vec![update!(1, add!(read!(1), value!(Table(1, 1))))];
+        // Holds the lock on the key.
+        let holder = coord(Uuid::new_v4(), increment.clone());
+        let vote = to_customer.request(holder).await.unwrap().into_vote();
+        assert_eq!(vote.unwrap(), CommitVote::Commit(None));
+
+        let tid = Uuid::new_v4();
+        let vote = to_customer.request(coord(tid, increment)).await.unwrap();
+        assert_eq!(vote.into_vote().unwrap(), CommitVote::Conflict);
+        let create = vec![create!(2, value!(Table(2, 2)))];
+        let vote = to_order.request(coord(tid, create)).await.unwrap();
+        assert_eq!(vote.into_vote().unwrap(), CommitVote::Commit(None));
+
+        // The client claims the customer voted to commit.
+        let forged = MessageWs::CoordParticipants {
+            tid,
+            vote: CommitVote::Commit(None),
+            participants: vec![order.uri()],
+        };
+        to_customer
+            .request(forged)
+            .await
+            .unwrap()
+            .into_vote()
+            .unwrap();
+        let result = to_order.request(MessageWs::GetResult { tid }).await;
+        let e = result.unwrap().into_result().unwrap_err();
+        assert!(format!("{e:#}").contains("another repository"), "{e:#}");
+        assert!(pending(&to_order).await.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_gateway_transaction_not_resent() {
+        let silent = Silent::serve();
+        let client = ClientBuilder::from_uri(&silent.uri()).unwrap().build();
+        let transaction = actix::spawn({
+            let client = client.clone();
+            async move {
+                client
+                    .send_transaction(TransactionKind::Coordinated, vec![])
+                    .await
+            }
+        });
+        let single = actix::spawn({
+            let client = client.clone();
+            async move { read(&client, 1).await }
+        });
+        actix::clock::sleep(Duration::from_millis(100)).await;
+
+        silent.stop().await;
+        // The single transaction is sent again, over a new connection.
+        let e = single.await.unwrap().unwrap_err();
+        assert!(format!("{e:#}").contains("couldn't connect"), "{e:#}");
+        let e = transaction.await.unwrap().unwrap_err();
+        assert!(format!("{e:#}").contains("lost"), "{e:#}");
+    }
+}
@@ -0,0 +1,212 @@
+//! The `/metrics` of a `ws repository`, in the Prometheus text format.
+//!
+//! Latencies are in seconds. `cereal_commit_latency_seconds` goes from the
+//! prepare of a transaction to its commit, by kind of transaction.
+use std::fmt::Write;
+
+use cereal_core::metrics::{Histogram, Metrics, LATENCY_BUCKETS};
+
+/// Renders the metrics of a repository, and the connection errors to each
+/// of its peers.
+pub(crate) fn render(metrics: &Metrics, connection_errors: &[(String, u64)]) -> String {
+    let mut out = String::new();
+    header(
+        &mut out,
+        "cereal_transactions_total",
+        "counter",
+        "Finished transactions, by kind and outcome.",
+    );
+    for ((kind, outcome), count) in &metrics.transactions {
+        let _ = writeln!(
+            out,
+            "cereal_transactions_total{{kind=\"{kind}\",outcome=\"{outcome}\"}} {count}"
+        );
+    }
+
+    header(
+        &mut out,
+        "cereal_commit_latency_seconds",
+        "histogram",
+        "From the prepare of committed transactions to their commit.",
+    );
+    for (kind, histogram) in &metrics.commit_latency {
+        let labels = format!("kind=\"{kind}\",");
+        histogram_lines(
+            &mut out,
+            "cereal_commit_latency_seconds",
+            &labels,
+            histogram,
+        );
+    }
+
+    header(
+        &mut out,
+        "cereal_active_transactions",
+        "gauge",
+        "Transactions waiting to run, or for the other participants.",
+    );
+    let _ = writeln!(
+        out,
+        "cereal_active_transactions {}",
+        metrics.active_transactions
+    );
+
+    header(
+        &mut out,
+        "cereal_locked_keys",
+        "gauge",
+        "Keys locked by coordinated transactions.",
+    );
+    let _ = writeln!(out, "cereal_locked_keys {}", metrics.locked_keys);
+
+    header(
+        &mut out,
+        "cereal_durable_write_seconds",
+        "histogram",
+        "Writes of transactions to the durable log.",
+    );
+    histogram_lines(
+        &mut out,
+        "cereal_durable_write_seconds",
+        "",
+        &metrics.durable_writes,
+    );
+
+    header(
+        &mut out,
+        "cereal_peer_connection_errors_total",
+        "counter",
+        "Requests to peer repositories that failed before they answered.",
+    );
+    for (uri, errors) in connection_errors {
+        let _ = writeln!(
+            out,
+            "cereal_peer_connection_errors_total{{peer=\"{}\"}} {errors}",
+            escape(uri)
+        );
+    }
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// The cumulative buckets of `histogram`, with its sum and count.
+/// `labels` is empty, or ends with a comma.
+fn histogram_lines(out: &mut String, name: &str, labels: &str, histogram: &Histogram) {
+    let mut cumulative = 0;
+    for (bound, count) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+        cumulative += count;
+        let _ = writeln!(out, "{name}_bucket{{{labels}le=\"{bound}\"}} {cumulative}");
+    }
+    let _ = writeln!(
+        out,
+        "{name}_bucket{{{labels}le=\"+Inf\"}} {}",
+        histogram.count
+    );
+    let labels = labels.trim_end_matches(',');
+    let labels = if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{labels}}}")
+    };
+    let _ = writeln!(out, "{name}_sum{labels} {}", histogram.sum);
+    let _ = writeln!(out, "{name}_count{labels} {}", histogram.count);
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use actix::prelude::*;
+    use actix_web::{http::header::CONTENT_TYPE, test, web, App};
+    use cereal_client::{add, create, op, read, update, value};
+    use cereal_core::{
+        messages::{MessagePrepare, SetLowWatermark},
+        operations::{Arguments, Operation, Table},
+        repository::Repository,
+    };
+    use uuid::Uuid;
+
+    use crate::peers::PeerManager;
+
+    /// Parses the samples of the Prometheus text format, checking every
+    /// metric is described first.
+    fn samples(text: &str) -> HashMap<String, f64> {
+        let mut described = vec![];
+        let mut samples = HashMap::new();
+        for line in text.lines() {
+            if let Some(help) = line.strip_prefix("# HELP ") {
+                described.push(help.split(' ').next().unwrap().to_string());
+            } else if !line.starts_with('#') {
+                let (name, value) = line.rsplit_once(' ').unwrap();
+                let metric = name.split('{').next().unwrap();
+                assert!(
+                    described.iter().any(|d| metric.starts_with(d.as_str())),
+                    "{metric} isn't described"
+                );
+                samples.insert(name.to_string(), value.parse().unwrap());
+            }
+        }
+        samples
+    }
+
+    #[actix_web::test]
+    async fn test_scrape() {
+        let repository = Repository::new("metrics".to_string()).start();
+        let arguments = |operation: Operation| Arguments {
+            timestamp: 0,
+            operations: vec![operation],
+        };
+        let writes = [
+            create!(1, value!(Table(1, 1))),
+            update!(1, add!(read!(1), value!(Table(1, 1)))),
+        ];
+        for operation in writes {
+            let prepare = MessagePrepare::Single(Uuid::new_v4(), arguments(operation));
+            repository.send(prepare).await.unwrap().unwrap();
+        }
+        // A snapshot below the low watermark aborts.
+        repository.send(SetLowWatermark(2)).await.unwrap();
+        let read = arguments(op!(read!(1)));
+        let prepare = MessagePrepare::Snapshot(Uuid::new_v4(), read, 1);
+        let _ = repository.send(prepare).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(repository))
+                .app_data(web::Data::new(PeerManager::default().start()))
+                .route("/metrics", web::get().to(crate::metrics)),
+        )
+        .await;
+
+        let request = test::TestRequest::get().uri("/metrics").to_request();
+        let response = test::call_service(&app, request).await;
+        assert!(response.status().is_success());
+        let content_type = response.headers().get(CONTENT_TYPE).unwrap();
+        assert!(content_type.to_str().unwrap().starts_with("text/plain"));
+        let body = test::read_body(response).await;
+        let samples = samples(std::str::from_utf8(&body).unwrap());
+
+        let single = |outcome: &str| {
+            samples[&format!("cereal_transactions_total{{kind=\"single\",outcome=\"{outcome}\"}}")]
+        };
+        assert_eq!((single("commit"), single("abort")), (2.0, 1.0));
+        let count = samples["cereal_commit_latency_seconds_count{kind=\"single\"}"];
+        assert_eq!(count, 2.0);
+        let all = samples["cereal_commit_latency_seconds_bucket{kind=\"single\",le=\"+Inf\"}"];
+        assert_eq!(all, count);
+        assert_eq!(samples["cereal_active_transactions"], 0.0);
+        assert_eq!(samples["cereal_locked_keys"], 0.0);
+        assert_eq!(samples["cereal_durable_write_seconds_count"], 2.0);
+    }
+}
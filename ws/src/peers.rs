@@ -0,0 +1,156 @@
+//! Long-lived connections between `RepositoryWs`s.
+//!
+//! A [`PeerManager`] keeps one [`ConnectionHandle`] per peer repository, so
+//! every accept sent to a peer goes over the same connection, opened again
+//! if it drops.
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::Duration,
+};
+
+use actix::prelude::*;
+use cereal_client::{ConnectionHandle, Credentials};
+use cereal_core::messages::CommitVote;
+use cereal_protocol::{MessageWs, ResponseBody};
+use tracing::Instrument as _;
+
+/// [actix::Message] to send a `message` to the `RepositoryWs` at `uri`.
+#[derive(Message, Debug)]
+#[rtype(result = "anyhow::Result<CommitVote>")]
+pub(crate) struct SendToPeer {
+    pub(crate) uri: String,
+    pub(crate) message: MessageWs,
+    /// The span `message` is sent in.
+    pub(crate) span: tracing::Span,
+}
+
+/// [actix::Message] to get how many times the connection to each peer
+/// failed, by uri.
+#[derive(Message, Debug)]
+#[rtype(result = "Vec<(String, u64)>")]
+pub(crate) struct GetConnectionErrors;
+
+/// [actix::Message] to check every peer answers, in time. Answered with
+/// the error of each peer, by uri.
+#[derive(Message, Debug)]
+#[rtype(result = "Vec<(String, Result<(), String>)>")]
+pub(crate) struct CheckPeers;
+
+/// How long a peer has to answer a [CheckPeers].
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Holds a [`ConnectionHandle`] per peer repository.
+#[derive(Default)]
+pub(crate) struct PeerManager {
+    peers: HashMap<String, ConnectionHandle>,
+    /// The uris of the repositories of the cluster, if it is configured.
+    /// Accepts are only sent to them.
+    members: HashSet<String>,
+    /// How this repository authenticates to its peers.
+    credentials: Credentials,
+    /// Requests to each peer that failed before it answered.
+    connection_errors: BTreeMap<String, u64>,
+}
+
+impl PeerManager {
+    pub(crate) fn new(members: impl IntoIterator<Item = String>, credentials: Credentials) -> Self {
+        PeerManager {
+            peers: HashMap::new(),
+            members: members.into_iter().collect(),
+            credentials,
+            connection_errors: BTreeMap::new(),
+        }
+    }
+}
+
+impl PeerManager {
+    /// The connection to the peer at `uri`, opened if there is none.
+    fn peer(&mut self, uri: &str) -> ConnectionHandle {
+        let credentials = &self.credentials;
+        self.peers
+            .entry(uri.to_string())
+            .or_insert_with(|| ConnectionHandle::spawn_with(uri.to_string(), credentials.clone()))
+            .clone()
+    }
+}
+
+impl Actor for PeerManager {
+    type Context = Context<Self>;
+}
+
+impl Handler<SendToPeer> for PeerManager {
+    type Result = ResponseActFuture<Self, anyhow::Result<CommitVote>>;
+
+    /// Handle for [`SendToPeer`] for [`PeerManager`].
+    /// Answers with the reply of the peer, or with an error if the connection
+    /// to it failed.
+    fn handle(&mut self, msg: SendToPeer, _ctx: &mut Self::Context) -> Self::Result {
+        let SendToPeer { uri, message, span } = msg;
+        if !self.members.is_empty() && !self.members.contains(&uri) {
+            let error = anyhow::anyhow!("peer {uri} is not a member of the cluster");
+            return Box::pin(fut::ready(Err(error)));
+        }
+        let peer = self.peer(&uri);
+
+        Box::pin(
+            async move { peer.request(message).await }
+                .instrument(span)
+                .into_actor(self)
+                .map(move |response, act, _ctx| {
+                    let body = response.map_err(|e| {
+                        *act.connection_errors.entry(uri.clone()).or_default() += 1;
+                        e.context(format!("peer {uri}"))
+                    })?;
+                    body.into_vote()
+                }),
+        )
+    }
+}
+
+impl Handler<GetConnectionErrors> for PeerManager {
+    type Result = MessageResult<GetConnectionErrors>;
+
+    /// Handle for [`GetConnectionErrors`] for [`PeerManager`].
+    fn handle(&mut self, _msg: GetConnectionErrors, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(
+            self.connection_errors
+                .iter()
+                .map(|(uri, errors)| (uri.clone(), *errors))
+                .collect(),
+        )
+    }
+}
+
+impl Handler<CheckPeers> for PeerManager {
+    type Result = ResponseFuture<Vec<(String, Result<(), String>)>>;
+
+    /// Handle for [`CheckPeers`] for [`PeerManager`].
+    /// Checks the members of the cluster if it is configured, or else the
+    /// peers connected to so far.
+    fn handle(&mut self, _msg: CheckPeers, _ctx: &mut Self::Context) -> Self::Result {
+        let mut uris: Vec<String> = if self.members.is_empty() {
+            self.peers.keys().cloned().collect()
+        } else {
+            self.members.iter().cloned().collect()
+        };
+        uris.sort();
+        let checks: Vec<_> = uris
+            .into_iter()
+            .map(|uri| {
+                let peer = self.peer(&uri);
+                async move {
+                    let answered =
+                        actix::clock::timeout(CHECK_TIMEOUT, peer.request(MessageWs::Ping));
+                    let checked = match answered.await {
+                        Ok(Ok(ResponseBody::Done)) => Ok(()),
+                        Ok(Ok(other)) => Err(format!("unexpected answer {other:?}")),
+                        Ok(Err(e)) => Err(format!("{e:#}")),
+                        Err(_) => Err(format!("no answer in {CHECK_TIMEOUT:?}")),
+                    };
+                    (uri, checked)
+                }
+            })
+            .collect();
+        Box::pin(futures_util::future::join_all(checks))
+    }
+}
@@ -2,73 +2,52 @@ use actix::prelude::*;
 use actix_web::web;
 use actix_web_actors::ws::{self, WebsocketContext};
 use cereal_core::{
-    messages::{CommitVote, GetProposedTs, GetResult, MessageAccept, MessagePrepare},
-    operations::{Arguments, Table},
+    application::TransactionKind,
+    messages::{
+        AbortTransaction, AcceptDelivered, CommitVote, DropRange, ExportRange, GetResult,
+        ImportRange, Introspect, MessageAccept, MessagePrepare, Notification, NotifyParticipants,
+        PrepareAs, ServeRange, TakeCheckpoint, Traced,
+    },
+    operations::Table,
     repository::Repository,
 };
-use futures_util::{SinkExt as _, StreamExt as _};
-use serde::{Deserialize, Serialize};
+use cereal_protocol::{Encoding, GetResultResponse, MessageWs, Request, Response, ResponseBody};
+use futures_util::future::{join_all, Either};
+use tracing::{Instrument as _, Span};
 use uuid::Uuid;
 
-use crate::GetResultResponse;
-
-/// A `network` wrap over [cereal_core::message].
-#[derive(Message, Debug, Serialize, Deserialize)]
-#[serde(tag = "type")]
-#[rtype(result = "Result<CommitVote, anyhow::Error>")]
-pub(crate) enum MessageWs {
-    Single {
-        tid: Uuid,
-        args: Arguments,
-    },
-    // tid, ops, participants.length()
-    Indep {
-        tid: Uuid,
-        args: Arguments,
-        participants_size: usize,
-    },
-    // TODO: change Vec<String> to something better?
-    IndepParticipants {
-        tid: Uuid,
-        vote: CommitVote,
-        participants: Vec<String>,
-    },
-    // TODO: change to be actors instead of u64.
-    Coord {
-        tid: Uuid,
-        args: Arguments,
-        participants_size: usize,
-    },
-    CoordParticipants {
-        tid: Uuid,
-        vote: CommitVote,
-        participants: Vec<String>,
-    },
-    AcceptIndep {
-        tid: Uuid,
-        proposed_ts: usize,
-        vote: CommitVote,
-    },
-    AcceptCoord {
-        tid: Uuid,
-        proposed_ts: usize,
-        vote: CommitVote,
-    },
-    GetResult {
-        tid: Uuid,
-    },
-}
+use crate::{
+    auth::Role,
+    peers::{PeerManager, SendToPeer},
+    telemetry,
+};
 
 /// A Ws Wrapper of `Repository`.
 #[derive(Clone)]
 pub(crate) struct RepositoryWs {
     repo_actor: web::Data<Addr<Repository>>,
+    peers: web::Data<Addr<PeerManager>>,
+    /// The [Encoding] of the responses, negotiated at the handshake.
+    encoding: Encoding,
+    /// Who is at the other end, authenticated at the handshake.
+    role: Role,
 }
 
 impl RepositoryWs {
-    /// Create a new `Repository` using a `Arc` of `Repository`.
-    pub(crate) fn new(repo_actor: web::Data<Addr<Repository>>) -> Self {
-        RepositoryWs { repo_actor }
+    /// Create a new `Repository` using a `Arc` of `Repository`, sending
+    /// messages to the other repositories through `peers`.
+    pub(crate) fn new(
+        repo_actor: web::Data<Addr<Repository>>,
+        peers: web::Data<Addr<PeerManager>>,
+        encoding: Encoding,
+        role: Role,
+    ) -> Self {
+        RepositoryWs {
+            repo_actor,
+            peers,
+            encoding,
+            role,
+        }
     }
 }
 
@@ -77,214 +56,347 @@ impl Actor for RepositoryWs {
 }
 
 impl RepositoryWs {
-    fn send_get_result(&self, tid: Uuid, ctx: &mut WebsocketContext<Self>) {
-        self.repo_actor
-            .send(GetResult(tid))
+    fn respond(&self, id: Option<u64>, body: ResponseBody, ctx: &mut WebsocketContext<Self>) {
+        log::info!("response {:?}: {:?}", id, body);
+        ctx.write_raw(self.encoding.encode(&Response { id, body }));
+    }
+
+    /// Answers a request while the next ones are handled, so many transactions
+    /// can share the connection. The response is awaited in the span of the
+    /// request.
+    fn respond_later<F>(&self, id: u64, response: F, ctx: &mut WebsocketContext<Self>)
+    where
+        F: std::future::Future<Output = ResponseBody> + 'static,
+    {
+        response
+            .instrument(Span::current())
             .into_actor(self)
-            .then(|res, _, ctx| {
-                let xaction_result: anyhow::Result<Option<Table>, _> = res.unwrap();
-                let response = match xaction_result {
-                    Ok(table) => GetResultResponse::Ok(table),
-                    Err(e) => GetResultResponse::Err(e.to_string()),
-                };
+            .map(move |body, this, ctx| this.respond(Some(id), body, ctx))
+            .spawn(ctx);
+    }
 
-                log::info!("response from tid: {:?}", response);
-                let response = serde_json::to_string(&response)
-                    .expect("Actor response is typed. So should never happend");
-                ctx.text(response);
-                fut::ready(())
-            })
-            .wait(ctx);
+    fn respond_done_later<F>(&self, id: u64, request: F, ctx: &mut WebsocketContext<Self>)
+    where
+        F: std::future::Future<Output = Result<anyhow::Result<()>, MailboxError>> + 'static,
+    {
+        self.respond_later(
+            id,
+            async move { ResponseBody::from_done(request.await.unwrap_or_else(|e| Err(e.into()))) },
+            ctx,
+        );
     }
 
-    fn send_prepare_single(&self, tid: Uuid, args: Arguments, ctx: &mut WebsocketContext<Self>) {
-        self.repo_actor
-            .send(MessagePrepare::Single(tid, args))
-            .into_actor(self)
-            .then(|res, _, ctx| {
-                let res: CommitVote = res.unwrap().unwrap();
-                log::info!("response single: {:?}", res);
-                let response = serde_json::to_string(&res)
-                    .expect("Actor response is typed. So should never happend");
-                ctx.text(response);
-                fut::ready(())
-            })
-            .wait(ctx);
+    fn send_get_result(&self, id: u64, tid: Uuid, ctx: &mut WebsocketContext<Self>) {
+        let request = self
+            .repo_actor
+            .send(Traced(Span::current(), GetResult(tid)));
+        self.respond_later(
+            id,
+            async move {
+                let xaction_result: anyhow::Result<Option<Table>> = match request.await {
+                    Ok(result) => result,
+                    Err(e) => Err(e.into()),
+                };
+                match xaction_result {
+                    Ok(table) => ResponseBody::Result(GetResultResponse::Ok(table)),
+                    Err(e) => ResponseBody::Result(GetResultResponse::Err(e.to_string())),
+                }
+            },
+            ctx,
+        );
     }
 
-    fn send_prepare_indep(
-        &self,
-        tid: Uuid,
-        args: Arguments,
-        participants_size: usize,
-        ctx: &mut WebsocketContext<Self>,
-    ) {
-        self.repo_actor
-            .send(MessagePrepare::Indep(tid, args, participants_size))
-            .into_actor(self)
-            .then(|res, _, ctx| {
-                let res: CommitVote = res.unwrap().unwrap();
-                log::info!("response indep: {:?}", res);
-                let response = serde_json::to_string(&res)
-                    .expect("Actor response is typed. So should never happend");
-                ctx.text(response);
-                fut::ready(())
-            })
-            .wait(ctx);
+    /// Prepares the transaction as the principal of the connection, if it
+    /// has one.
+    fn send_prepare(&self, id: u64, msg: MessagePrepare, ctx: &mut WebsocketContext<Self>) {
+        let span = Span::current();
+        let request = match self.role.principal() {
+            Some(principal) => {
+                let msg = PrepareAs(principal.to_string(), msg);
+                Either::Left(self.repo_actor.send(Traced(span, msg)))
+            }
+            None => Either::Right(self.repo_actor.send(Traced(span, msg))),
+        };
+        self.respond_later(
+            id,
+            async move { ResponseBody::from_vote(request.await.unwrap_or_else(|e| Err(e.into()))) },
+            ctx,
+        );
     }
 
-    fn send_prepare_coord(
-        &self,
-        tid: Uuid,
-        args: Arguments,
-        participants_size: usize,
-        ctx: &mut WebsocketContext<Self>,
-    ) {
-        self.repo_actor
-            .send(MessagePrepare::Coord(tid, args, participants_size))
-            .into_actor(self)
-            .then(|res, _, ctx| {
-                let res: CommitVote = res.unwrap().unwrap();
-                log::info!("response coord: {:?}", res);
-                let response = serde_json::to_string(&res)
-                    .expect("Actor response is typed. So should never happend");
-                ctx.text(response);
-                fut::ready(())
-            })
-            .wait(ctx);
+    fn send_accept(&self, id: u64, msg: MessageAccept, ctx: &mut WebsocketContext<Self>) {
+        let request = self.repo_actor.send(Traced(Span::current(), msg));
+        self.respond_later(
+            id,
+            async move { ResponseBody::from_vote(request.await.unwrap_or_else(|e| Err(e.into()))) },
+            ctx,
+        );
     }
 
     // TODO: Ideally this should be better because it leaks details over the protocol.
-    fn send_prepare_indep_accept(
+    /// Sends the accept of `tid` to every participant that didn't
+    /// acknowledge it yet, over the connections of the [`PeerManager`].
+    /// Answers once every participant got it, or with the first error: the
+    /// client may notify them again then.
+    fn send_accepts_to_participants(
         &self,
+        id: u64,
         tid: Uuid,
         vote: CommitVote,
         participants: Vec<String>,
+        kind: TransactionKind,
         ctx: &mut WebsocketContext<Self>,
     ) {
-        self.repo_actor
-            .send(GetProposedTs(tid))
-            .into_actor(self)
-            .then(move |res, this, ctx| {
-                let proposed_ts: usize = res.unwrap();
-                log::info!("proposed ts: {:?}", proposed_ts);
-                async move {
-                    for participant in participants.iter() {
-                        let uri = participant;
-                        let (_resp, mut connection) =
-                            awc::Client::new().ws(uri).connect().await.unwrap();
-
-                        let msg = serde_json::to_string(&MessageWs::AcceptIndep {
-                            tid,
-                            proposed_ts,
-                            vote: vote.clone(),
-                        })
-                        .expect("This can be serialized");
-                        connection
-                            .send(ws::Message::Text(msg.into()))
-                            .await
-                            .unwrap();
-
-                        let _ = connection.next().await.unwrap();
+        let peers = self.peers.clone();
+        let repo_actor = self.repo_actor.clone();
+        let principal = self.role.principal().map(str::to_string);
+        let notify = NotifyParticipants(tid, kind, vote, principal);
+        let notification = self.repo_actor.send(Traced(Span::current(), notify));
+        let accepted = async move {
+            let Notification {
+                accept,
+                delivered_to,
+            } = notification.await??;
+            let message = match accept {
+                MessageAccept::Indep(tid, proposed_ts, vote, participant) => {
+                    MessageWs::AcceptIndep {
+                        tid,
+                        proposed_ts,
+                        vote,
+                        participant,
                     }
-                    fut::ready(())
                 }
-                .into_actor(this)
-                .map(|res, _, _| println!("{:?}", res))
-                .wait(ctx);
-
-                ctx.text("Message sent to participants".to_string());
-                fut::ready(())
-            })
-            .wait(ctx);
-    }
-
-    fn send_prepare_coord_accept(
-        &self,
-        tid: Uuid,
-        vote: CommitVote,
-        participants: Vec<String>,
-        ctx: &mut WebsocketContext<Self>,
-    ) {
-        self.repo_actor
-            .send(GetProposedTs(tid))
-            .into_actor(self)
-            .then(move |res, this, ctx| {
-                let proposed_ts: usize = res.unwrap();
-                log::info!("proposed ts: {:?}", proposed_ts);
-                async move {
-                    for participant in participants.iter() {
-                        log::info!("URI: {:?}", participant);
-                        let uri = participant;
-                        let (_resp, mut connection) =
-                            awc::Client::new().ws(uri).connect().await.unwrap();
-
-                        let msg = serde_json::to_string(&MessageWs::AcceptCoord {
-                            tid,
-                            proposed_ts,
-                            vote: vote.clone(),
-                        })
-                        .expect("This can be serialized");
-                        connection
-                            .send(ws::Message::Text(msg.into()))
-                            .await
-                            .unwrap();
-
-                        log::info!("send message accept");
-                        let _ = connection.next().await.unwrap();
+                MessageAccept::Coord(tid, proposed_ts, vote, participant) => {
+                    MessageWs::AcceptCoord {
+                        tid,
+                        proposed_ts,
+                        vote,
+                        participant,
                     }
-                    fut::ready(())
                 }
-                .into_actor(this)
-                .map(|res, _, _| println!("{:?}", res))
-                .wait(ctx);
-
-                ctx.text("Message sent to participants".to_string());
-                fut::ready(())
-            })
-            .wait(ctx);
-    }
-
-    fn send_accept_indep(
-        &self,
-        tid: Uuid,
-        proposed_ts: usize,
-        vote: CommitVote,
-        ctx: &mut WebsocketContext<Self>,
-    ) {
-        self.repo_actor
-            .send(MessageAccept::Indep(tid, proposed_ts, vote))
-            .into_actor(self)
-            .then(|res, _, ctx| {
-                let res: CommitVote = res.unwrap().unwrap();
-                log::info!("response indep: {:?}", res);
-                let response = serde_json::to_string(&res)
-                    .expect("Actor response is typed. So should never happend");
-                ctx.text(response);
-                fut::ready(())
-            })
-            .wait(ctx);
+            };
+            log::info!("accept: {:?}", message);
+            let accepts = participants
+                .into_iter()
+                .filter(|uri| !delivered_to.contains(uri))
+                .map(|uri| {
+                    log::info!("URI: {:?}", uri);
+                    let sent = peers.send(SendToPeer {
+                        uri: uri.clone(),
+                        message: message.clone(),
+                        span: Span::current(),
+                    });
+                    let repo_actor = repo_actor.clone();
+                    async move {
+                        sent.await??;
+                        repo_actor.do_send(AcceptDelivered(tid, uri));
+                        anyhow::Ok(())
+                    }
+                });
+            for accepted in join_all(accepts).await {
+                accepted?;
+            }
+            anyhow::Ok(CommitVote::InProgress)
+        };
+        self.respond_later(
+            id,
+            async move { ResponseBody::from_vote(accepted.await) },
+            ctx,
+        );
     }
 
-    fn send_accept_coord(
-        &self,
-        tid: Uuid,
-        proposed_ts: usize,
-        vote: CommitVote,
-        ctx: &mut WebsocketContext<Self>,
-    ) {
-        self.repo_actor
-            .send(MessageAccept::Coord(tid, proposed_ts, vote))
-            .into_actor(self)
-            .then(|res, _, ctx| {
-                let res: CommitVote = res.unwrap().unwrap();
-                log::info!("response indep: {:?}", res);
-                let response = serde_json::to_string(&res)
-                    .expect("Actor response is typed. So should never happend");
-                ctx.text(response);
-                fut::ready(())
-            })
-            .wait(ctx);
+    /// Handles `message` in a span continuing the trace of the request.
+    fn handle_request(&self, request: Request, ctx: &mut WebsocketContext<Self>) {
+        let Request {
+            id,
+            message,
+            trace_context,
+        } = request;
+        let span = tracing::info_span!(
+            "request",
+            message = message.name(),
+            tid = message.tid().map(tracing::field::display),
+        );
+        let _span = telemetry::continue_trace(span, &trace_context).entered();
+        if let Err(e) = self.role.check(&message) {
+            log::warn!("refused a request from a {:?}: {e}", self.role);
+            self.respond(Some(id), ResponseBody::Error(e.to_string()), ctx);
+            return;
+        }
+        match message {
+            MessageWs::Single { tid, args } => {
+                log::info!("Ws deserialized single: {:?}, {}", tid, args);
+                self.send_prepare(id, MessagePrepare::Single(tid, args), ctx);
+            }
+            MessageWs::Snapshot {
+                tid,
+                args,
+                snapshot_ts,
+            } => {
+                log::info!(
+                    "Ws deserialized snapshot: {:?}, {}, {:?}",
+                    tid,
+                    args,
+                    snapshot_ts
+                );
+                self.send_prepare(id, MessagePrepare::Snapshot(tid, args, snapshot_ts), ctx);
+            }
+            MessageWs::Indep {
+                tid,
+                args,
+                participants_size,
+            } => {
+                log::info!(
+                    "Ws deserialized indep: {:?}, {}, {:?}",
+                    tid,
+                    args,
+                    participants_size
+                );
+                let msg = MessagePrepare::Indep(tid, args, participants_size);
+                self.send_prepare(id, msg, ctx);
+            }
+            MessageWs::IndepParticipants {
+                tid,
+                vote,
+                participants,
+            } => {
+                log::info!(
+                    "Ws deserialized indep participants: {:?}, {:?}, {:?}",
+                    tid,
+                    vote,
+                    participants
+                );
+                let kind = TransactionKind::Independent;
+                self.send_accepts_to_participants(id, tid, vote, participants, kind, ctx);
+            }
+            MessageWs::Coord {
+                tid,
+                args,
+                participants_size,
+            } => {
+                log::info!(
+                    "Ws deserialized coord: {:?}, {}, {:?}",
+                    tid,
+                    args,
+                    participants_size
+                );
+                let msg = MessagePrepare::Coord(tid, args, participants_size);
+                self.send_prepare(id, msg, ctx);
+            }
+            MessageWs::CoordParticipants {
+                tid,
+                vote,
+                participants,
+            } => {
+                log::info!(
+                    "Ws deserialized coord participants: {:?}, {:?}, {:?}",
+                    tid,
+                    vote,
+                    participants
+                );
+                let kind = TransactionKind::Coordinated;
+                self.send_accepts_to_participants(id, tid, vote, participants, kind, ctx);
+            }
+            MessageWs::AcceptIndep {
+                tid,
+                proposed_ts,
+                vote,
+                participant,
+            } => {
+                log::info!(
+                    "Ws deserialized accept indep: {:?}, {:?}, {:?}",
+                    tid,
+                    proposed_ts,
+                    vote
+                );
+                let accept = MessageAccept::Indep(tid, proposed_ts, vote, participant);
+                self.send_accept(id, accept, ctx);
+            }
+            MessageWs::AcceptCoord {
+                tid,
+                proposed_ts,
+                vote,
+                participant,
+            } => {
+                log::info!(
+                    "Ws deserialized accept coord: {:?}, {:?}, {:?}",
+                    tid,
+                    proposed_ts,
+                    vote
+                );
+                let accept = MessageAccept::Coord(tid, proposed_ts, vote, participant);
+                self.send_accept(id, accept, ctx);
+            }
+            MessageWs::GetResult { tid } => {
+                log::info!("Ws deserialized get result: {:?}", tid,);
+                self.send_get_result(id, tid, ctx);
+            }
+            MessageWs::ExportRange { range } => {
+                log::info!("Ws deserialized export range: {:?}", range);
+                let request = self.repo_actor.send(ExportRange(range));
+                self.respond_later(
+                    id,
+                    async move {
+                        match request.await.unwrap_or_else(|e| Err(e.into())) {
+                            Ok(entries) => ResponseBody::Entries(entries),
+                            Err(e) => ResponseBody::Error(format!("{e:#}")),
+                        }
+                    },
+                    ctx,
+                );
+            }
+            MessageWs::ImportRange { range, entries } => {
+                log::info!("Ws deserialized import range: {:?}", range);
+                let request = self.repo_actor.send(ImportRange(range, entries));
+                self.respond_done_later(id, request, ctx);
+            }
+            MessageWs::ServeRange { range } => {
+                log::info!("Ws deserialized serve range: {:?}", range);
+                let request = self.repo_actor.send(ServeRange(range));
+                self.respond_done_later(id, request, ctx);
+            }
+            MessageWs::DropRange { range } => {
+                log::info!("Ws deserialized drop range: {:?}", range);
+                let request = self.repo_actor.send(DropRange(range));
+                self.respond_done_later(id, request, ctx);
+            }
+            MessageWs::Ping => self.respond(Some(id), ResponseBody::Done, ctx),
+            MessageWs::Introspect => {
+                let request = self.repo_actor.send(Introspect);
+                self.respond_later(
+                    id,
+                    async move {
+                        match request.await {
+                            Ok(introspection) => ResponseBody::Introspection(introspection),
+                            Err(e) => ResponseBody::Error(e.to_string()),
+                        }
+                    },
+                    ctx,
+                );
+            }
+            MessageWs::Abort { tid } => {
+                log::warn!("Aborting transaction {tid} for an operator");
+                let request = self.repo_actor.send(AbortTransaction(tid));
+                self.respond_done_later(id, request, ctx);
+            }
+            MessageWs::Checkpoint => {
+                let request = self.repo_actor.send(TakeCheckpoint);
+                self.respond_later(
+                    id,
+                    async move {
+                        match request.await.unwrap_or_else(|e| Err(e.into())) {
+                            Ok(checkpoint) => ResponseBody::Checkpoint(checkpoint),
+                            Err(e) => ResponseBody::Error(format!("{e:#}")),
+                        }
+                    },
+                    ctx,
+                );
+            }
+            MessageWs::Transaction { .. } => {
+                let body = ResponseBody::Error("transactions are run by a `ws gateway`".into());
+                self.respond(Some(id), body, ctx);
+            }
+        }
     }
 }
 
@@ -293,105 +405,17 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for RepositoryWs {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         match msg {
             Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
-            Ok(ws::Message::Text(text)) => {
-                log::info!("Ws message got: {text}");
-                let message_deserialized = serde_json::from_str::<MessageWs>(&text);
-                log::info!("{:?}", message_deserialized);
-                if let Ok(message) = message_deserialized {
-                    match message {
-                        MessageWs::Single { tid, args } => {
-                            log::info!("Ws deserialized single: {:?}, {:?}", tid, args);
-                            self.send_prepare_single(tid, args, ctx);
-                        }
-                        MessageWs::Indep {
-                            tid,
-                            args,
-                            participants_size,
-                        } => {
-                            log::info!(
-                                "Ws deserialized indep: {:?}, {:?}, {:?}",
-                                tid,
-                                args,
-                                participants_size
-                            );
-                            self.send_prepare_indep(tid, args, participants_size, ctx);
-                        }
-                        MessageWs::IndepParticipants {
-                            tid,
-                            vote,
-                            participants,
-                        } => {
-                            log::info!(
-                                "Ws deserialized indep participants: {:?}, {:?}, {:?}",
-                                tid,
-                                vote,
-                                participants
-                            );
-                            self.send_prepare_indep_accept(tid, vote, participants, ctx);
-                        }
-                        MessageWs::Coord {
-                            tid,
-                            args,
-                            participants_size,
-                        } => {
-                            log::info!(
-                                "Ws deserialized coord: {:?}, {:?}, {:?}",
-                                tid,
-                                args,
-                                participants_size
-                            );
-                            self.send_prepare_coord(tid, args, participants_size, ctx);
-                        }
-                        MessageWs::CoordParticipants {
-                            tid,
-                            vote,
-                            participants,
-                        } => {
-                            log::info!(
-                                "Ws deserialized coord participants: {:?}, {:?}, {:?}",
-                                tid,
-                                vote,
-                                participants
-                            );
-                            self.send_prepare_coord_accept(tid, vote, participants, ctx);
-                        }
-                        MessageWs::AcceptIndep {
-                            tid,
-                            proposed_ts,
-                            vote,
-                        } => {
-                            log::info!(
-                                "Ws deserialized accept indep: {:?}, {:?}, {:?}",
-                                tid,
-                                proposed_ts,
-                                vote
-                            );
-                            self.send_accept_indep(tid, proposed_ts, vote, ctx);
-                        }
-                        MessageWs::AcceptCoord {
-                            tid,
-                            proposed_ts,
-                            vote,
-                        } => {
-                            log::info!(
-                                "Ws deserialized accept coord: {:?}, {:?}, {:?}",
-                                tid,
-                                proposed_ts,
-                                vote
-                            );
-                            self.send_accept_coord(tid, proposed_ts, vote, ctx);
-                        }
-                        MessageWs::GetResult { tid } => {
-                            log::info!("Ws deserialized get result: {:?}", tid,);
-                            self.send_get_result(tid, ctx);
-                        }
+            Ok(message @ (ws::Message::Text(_) | ws::Message::Binary(_))) => {
+                log::info!("Ws message got: {:?}", message);
+                match cereal_protocol::decode::<Request>(&message) {
+                    Ok(request) => self.handle_request(request, ctx),
+                    Err(e) => {
+                        log::warn!("Error deserialize ws message, {:?}", e);
+                        let body = ResponseBody::Error(format!("invalid request: {e}"));
+                        self.respond(cereal_protocol::request_id(&message), body, ctx);
                     }
-                } else {
-                    log::warn!("Error deserialize ws message, {:?}", message_deserialized);
-                    ctx.text("Error");
                 }
             }
-            Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
             _ => (),
         }
     }
@@ -0,0 +1,146 @@
+//! Periodically telling a `Repository` what it may forget.
+//!
+//! The results of finished transactions are kept for a while after they were
+//! prepared, so a client asking again over a new connection still gets them,
+//! and are forgotten afterwards. Past versions of keys are kept for a while
+//! as well, for snapshot reads and change feeds resuming from the past, until
+//! the low watermark is raised above them.
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use actix::prelude::*;
+use cereal_core::{
+    messages::{ForgetResults, Introspect, SetLowWatermark},
+    repository::Repository,
+};
+
+/// How long results are kept, unless configured otherwise.
+pub(crate) const DEFAULT_RESULT_RETENTION: Duration = Duration::from_secs(600);
+/// How long past versions are kept, unless configured otherwise.
+pub(crate) const DEFAULT_VERSION_RETENTION: Duration = Duration::from_secs(600);
+
+/// Sends [ForgetResults] and [SetLowWatermark] to a [Repository]
+/// periodically.
+pub(crate) struct Retention {
+    repo: Addr<Repository>,
+    /// How long the results of transactions are kept.
+    results: Duration,
+    /// How long versions are kept after a newer one was executed.
+    versions: Duration,
+    /// When the repository was seen at each `last_executed_ts`, oldest first.
+    executed: VecDeque<(Instant, usize)>,
+}
+
+impl Retention {
+    pub(crate) fn new(repo: Addr<Repository>, results: Duration, versions: Duration) -> Self {
+        Retention {
+            repo,
+            results,
+            versions,
+            executed: VecDeque::new(),
+        }
+    }
+
+    /// How often the repository is told to forget, a tenth of the shortest
+    /// retention.
+    fn interval(&self) -> Duration {
+        (self.results.min(self.versions) / 10)
+            .clamp(Duration::from_millis(100), Duration::from_secs(60))
+    }
+
+    /// The latest timestamp seen more than `versions` ago, forgetting the
+    /// older ones.
+    fn low_watermark(&mut self) -> Option<usize> {
+        let mut low_watermark = None;
+        while let Some((at, ts)) = self.executed.front() {
+            if at.elapsed() < self.versions {
+                break;
+            }
+            low_watermark = Some(*ts);
+            self.executed.pop_front();
+        }
+        low_watermark
+    }
+
+    fn forget(&mut self, ctx: &mut Context<Self>) {
+        self.repo.do_send(ForgetResults(self.results));
+        self.repo
+            .send(Introspect)
+            .into_actor(self)
+            .map(|introspection, retention, _ctx| match introspection {
+                Ok(introspection) => {
+                    let executed = (Instant::now(), introspection.last_executed_ts);
+                    retention.executed.push_back(executed);
+                    if let Some(low_watermark) = retention.low_watermark() {
+                        retention.repo.do_send(SetLowWatermark(low_watermark));
+                    }
+                }
+                Err(e) => log::warn!("can't introspect the repository: {e}"),
+            })
+            .spawn(ctx);
+    }
+}
+
+impl Actor for Retention {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.interval(), Self::forget);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cereal_core::{
+        messages::{GetResult, MessagePrepare},
+        operations::{Arguments, Expr, Operation, Statement, Table},
+    };
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_results_are_forgotten() {
+        let repository = Repository::new("retention".to_string()).start();
+        let forever = Duration::from_secs(3600);
+        Retention::new(repository.clone(), Duration::from_millis(50), forever).start();
+
+        let tid = Uuid::new_v4();
+        let args = Arguments {
+            timestamp: 0,
+            operations: vec![Operation::Expr(Expr::Read(1))],
+        };
+        let vote = repository.send(MessagePrepare::Single(tid, args));
+        assert!(vote.await.unwrap().is_ok());
+        let result = repository.send(GetResult(tid)).await.unwrap();
+        assert!(result.is_ok(), "{result:?}");
+
+        actix::clock::sleep(Duration::from_millis(300)).await;
+        let result = repository.send(GetResult(tid)).await.unwrap();
+        assert!(result.is_err(), "{result:?}");
+    }
+
+    #[actix_web::test]
+    async fn test_low_watermark_is_raised() {
+        let repository = Repository::new("retention-versions".to_string()).start();
+        let forever = Duration::from_secs(3600);
+        Retention::new(repository.clone(), forever, Duration::from_millis(50)).start();
+
+        let args = Arguments {
+            timestamp: 0,
+            operations: vec![Operation::Statement(Statement::Create(
+                1,
+                Box::new(Expr::Value(Table(1, 1))),
+            ))],
+        };
+        let vote = repository.send(MessagePrepare::Single(Uuid::new_v4(), args));
+        assert!(vote.await.unwrap().is_ok());
+
+        actix::clock::sleep(Duration::from_millis(400)).await;
+        let introspection = repository.send(Introspect).await.unwrap();
+        assert!(introspection.last_executed_ts > 0);
+        assert_eq!(introspection.low_watermark, introspection.last_executed_ts);
+    }
+}
@@ -0,0 +1,489 @@
+//! `ws shell`: run transactions typed in a small SQL-like language.
+//!
+//! Every statement ends with a `;`. Operations are in the syntax of
+//! [cereal_core::operations]:
+//! - `CREATE 1 = (10, 10)`, `UPDATE 1 = READ 1 + (1, 1)`, `READ 1`,
+//!   `READ 1 AT 5`, `DELETE 1`, `RELEASE 100..150` and `ACQUIRE 100..150`
+//!   are operations, each run as a single repository transaction on the
+//!   current repository, or on `name` when prefixed with `name:`;
+//! - `USE name` picks the current repository;
+//! - `BEGIN [INDEP | COORD] ON name, ...` starts a transaction with these
+//!   participants, which must be different repositories, run at `COMMIT`
+//!   (or dropped at `ROLLBACK`). Its operations are prefixed with their
+//!   participant, unless there is only one. Without a kind it is a single
+//!   repository transaction.
+//!
+//! Repositories are named by a table or a repository of the cluster config,
+//! or by their quoted uri, e.g. `'http://127.0.0.1:8080/ws/'`.
+use std::{collections::HashMap, path::PathBuf};
+
+use cereal_client::{Client, ClientBuilder, Clients, Credentials};
+use cereal_core::{
+    application::TransactionKind,
+    operations::{Operation, Table},
+    retry::RetryPolicy,
+};
+use rustyline::{error::ReadlineError, DefaultEditor};
+
+use crate::config::ClusterConfig;
+
+const HELP: &str = "\
+CREATE key = expr;         UPDATE key = expr;         expr;
+RELEASE start..end;        ACQUIRE start..end;
+expr: (a, b), READ key, READ key AT ts, DELETE key, expr + expr, expr - expr
+USE name;                  name: operation;
+BEGIN [INDEP | COORD] ON name, ...; ... COMMIT; (or ROLLBACK;)
+HELP;                      EXIT;";
+
+/// A statement of the shell.
+#[derive(Debug, Clone, PartialEq)]
+enum Command {
+    Use(String),
+    Begin {
+        kind: Option<TransactionKind>,
+        participants: Vec<String>,
+    },
+    Commit,
+    Rollback,
+    /// An operation, on the repository called `on` if it is given.
+    Run {
+        on: Option<String>,
+        operation: Operation,
+    },
+    Help,
+    Exit,
+}
+
+/// A transaction between its `BEGIN` and its `COMMIT`.
+struct Block {
+    kind: Option<TransactionKind>,
+    participants: Vec<Participant>,
+}
+
+/// A repository of a [Block], with the operations it runs.
+struct Participant {
+    /// How it was named at `BEGIN`.
+    name: String,
+    uri: String,
+    operations: Vec<Operation>,
+}
+
+pub(crate) struct Shell {
+    cluster: Option<ClusterConfig>,
+    credentials: Credentials,
+    clients: HashMap<String, Client>,
+    current: Option<String>,
+    block: Option<Block>,
+}
+
+impl Shell {
+    /// A shell using the repositories of `cluster`, starting on `current`.
+    pub(crate) fn new(
+        cluster: Option<ClusterConfig>,
+        credentials: Credentials,
+        current: Option<String>,
+    ) -> Self {
+        Shell {
+            cluster,
+            credentials,
+            clients: HashMap::new(),
+            current,
+            block: None,
+        }
+    }
+
+    /// Reads statements until `EXIT` or the end of the input, keeping the
+    /// history in `history`.
+    pub(crate) async fn run(mut self, history: Option<PathBuf>) -> anyhow::Result<()> {
+        let mut editor = DefaultEditor::new()?;
+        if let Some(history) = &history {
+            // There is no history the first time.
+            let _ = editor.load_history(history);
+        }
+        let mut buffer = String::new();
+        loop {
+            let prompt = match (&self.block, buffer.is_empty()) {
+                (_, false) => "    -> ",
+                (Some(_), true) => "cereal*> ",
+                (None, true) => "cereal> ",
+            };
+            // Reading blocks, so it doesn't hold the connections meanwhile.
+            let (returned, line) = actix_web::rt::task::spawn_blocking(move || {
+                let line = editor.readline(prompt);
+                (editor, line)
+            })
+            .await?;
+            editor = returned;
+            let line = match line {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => {
+                    buffer.clear();
+                    continue;
+                }
+                Err(ReadlineError::Eof) => break,
+                Err(e) => return Err(e.into()),
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let _ = editor.add_history_entry(line.as_str());
+            buffer.push_str(&line);
+            buffer.push('\n');
+            let (statements, rest) = split_statements(&buffer);
+            buffer = rest.to_string();
+            for statement in statements {
+                match parse(&statement) {
+                    Ok(Command::Exit) => return self.save(&mut editor, history),
+                    Ok(command) => {
+                        if let Err(e) = self.execute(command).await {
+                            println!("error: {e:#}");
+                        }
+                    }
+                    Err(e) => println!("error: {e:#}"),
+                }
+            }
+        }
+        self.save(&mut editor, history)
+    }
+
+    fn save(&self, editor: &mut DefaultEditor, history: Option<PathBuf>) -> anyhow::Result<()> {
+        if let Some(history) = history {
+            editor.save_history(&history)?;
+        }
+        Ok(())
+    }
+
+    async fn execute(&mut self, command: Command) -> anyhow::Result<()> {
+        match command {
+            Command::Use(name) => {
+                self.client(&name)?;
+                self.current = Some(name);
+            }
+            Command::Begin { kind, participants } => {
+                anyhow::ensure!(self.block.is_none(), "already in a transaction");
+                anyhow::ensure!(
+                    kind.is_some() || participants.len() == 1,
+                    "a transaction without a kind has one repository"
+                );
+                let mut block = Block {
+                    kind,
+                    participants: vec![],
+                };
+                for name in participants {
+                    let uri = self.uri(&name)?;
+                    // Tables and repositories may name the same repository.
+                    if let Some(other) = block.participants.iter().find(|p| p.uri == uri) {
+                        anyhow::bail!("{name} and {} are the same repository {uri}", other.name);
+                    }
+                    self.client(&name)?;
+                    block.participants.push(Participant {
+                        name,
+                        uri,
+                        operations: vec![],
+                    });
+                }
+                self.block = Some(block);
+            }
+            Command::Rollback => {
+                anyhow::ensure!(self.block.take().is_some(), "not in a transaction");
+            }
+            Command::Commit => {
+                let block = self
+                    .block
+                    .take()
+                    .ok_or_else(|| anyhow::anyhow!("not in a transaction"))?;
+                self.commit(block).await?;
+            }
+            Command::Run { on, operation } => match &self.block {
+                Some(block) => {
+                    let index = match (on, &block.participants[..]) {
+                        (None, [_]) => 0,
+                        (None, _) => anyhow::bail!("say which participant runs it, as `name:`"),
+                        (Some(on), participants) => {
+                            let uri = self.uri(&on)?;
+                            participants
+                                .iter()
+                                .position(|participant| participant.uri == uri)
+                                .ok_or_else(|| anyhow::anyhow!("{on} isn't a participant"))?
+                        }
+                    };
+                    let block = self.block.as_mut().expect("in a transaction");
+                    block.participants[index].operations.push(operation);
+                }
+                None => {
+                    let name = on.or_else(|| self.current.clone()).ok_or_else(|| {
+                        anyhow::anyhow!("no current repository, pick one with USE")
+                    })?;
+                    let result = self.client(&name)?.send_single(vec![operation]).await?;
+                    println!("{}", show(&result));
+                }
+            },
+            Command::Help => println!("{HELP}"),
+            Command::Exit => unreachable!("the shell stops at EXIT"),
+        }
+        Ok(())
+    }
+
+    async fn commit(&mut self, block: Block) -> anyhow::Result<()> {
+        let Some(kind) = block.kind else {
+            let [participant] = &block.participants[..] else {
+                unreachable!("BEGIN without a kind takes one repository")
+            };
+            let result = self
+                .client(&participant.name)?
+                .send_single(participant.operations.clone())
+                .await?;
+            println!("{}", show(&result));
+            return Ok(());
+        };
+        let mut participants = vec![];
+        let mut operations = vec![];
+        for participant in &block.participants {
+            participants.push(self.client(&participant.name)?);
+            operations.push(participant.operations.clone());
+        }
+        let clients = Clients {
+            participants,
+            retry_policy: RetryPolicy::default(),
+        };
+        let results = match kind {
+            TransactionKind::Independent => clients.send_indep(operations).await?,
+            TransactionKind::Coordinated => clients.send_coord(operations).await?,
+        };
+        for (participant, result) in block.participants.iter().zip(&results.results) {
+            println!("{}: {}", participant.name, show(result));
+        }
+        println!("committed after {} attempt(s)", results.attempts);
+        Ok(())
+    }
+
+    /// The client of the repository called `name`, connected the first time.
+    fn client(&mut self, name: &str) -> anyhow::Result<Client> {
+        let uri = self.uri(name)?;
+        if let Some(client) = self.clients.get(&uri) {
+            return Ok(client.clone());
+        }
+        let client = ClientBuilder::from_uri(&uri)?
+            .credentials(self.credentials.clone())
+            .build();
+        self.clients.insert(uri, client.clone());
+        Ok(client)
+    }
+
+    /// The uri of the repository called `name`.
+    fn uri(&self, name: &str) -> anyhow::Result<String> {
+        if name.contains("://") {
+            return Ok(name.to_string());
+        }
+        let cluster = self.cluster.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("no cluster config to find {name} in, quote its uri instead")
+        })?;
+        Ok(cluster
+            .repository_of(name)
+            .or_else(|_| cluster.repository(Some(name)))?
+            .uri())
+    }
+}
+
+/// A result, in the syntax of values.
+fn show(result: &Option<Table>) -> String {
+    match result {
+        Some(table) => table.to_string(),
+        None => "(none)".to_string(),
+    }
+}
+
+/// The complete statements of `input`, without their `;`, and what is left
+/// after the last one.
+fn split_statements(input: &str) -> (Vec<String>, &str) {
+    let mut statements = vec![];
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in input.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            ';' if !quoted => {
+                let statement = input[start..i].trim();
+                if !statement.is_empty() {
+                    statements.push(statement.to_string());
+                }
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    (statements, &input[start..])
+}
+
+/// Parses a statement, without its `;`. Operations are in the syntax of
+/// [cereal_core::operations].
+fn parse(statement: &str) -> anyhow::Result<Command> {
+    let (first, rest) = statement
+        .split_once(char::is_whitespace)
+        .unwrap_or((statement, ""));
+    let rest = rest.trim();
+    let command = match first.to_ascii_uppercase().as_str() {
+        "USE" => {
+            let [name] = &names(rest)?[..] else {
+                anyhow::bail!("USE takes one repository");
+            };
+            return Ok(Command::Use(name.clone()));
+        }
+        "BEGIN" => {
+            let (kind, rest) = match rest.split_once(char::is_whitespace) {
+                Some((kind, rest)) if kind.eq_ignore_ascii_case("INDEP") => {
+                    (Some(TransactionKind::Independent), rest.trim())
+                }
+                Some((kind, rest)) if kind.eq_ignore_ascii_case("COORD") => {
+                    (Some(TransactionKind::Coordinated), rest.trim())
+                }
+                _ => (None, rest),
+            };
+            let participants = match rest.split_once(char::is_whitespace) {
+                Some((on, participants)) if on.eq_ignore_ascii_case("ON") => names(participants)?,
+                _ => anyhow::bail!("expected `ON` and the participants"),
+            };
+            return Ok(Command::Begin { kind, participants });
+        }
+        "COMMIT" => Command::Commit,
+        "ROLLBACK" => Command::Rollback,
+        "HELP" => Command::Help,
+        "EXIT" | "QUIT" => Command::Exit,
+        _ => {
+            let (on, operation) = split_participant(statement)?;
+            return Ok(Command::Run {
+                on,
+                operation: operation.parse()?,
+            });
+        }
+    };
+    anyhow::ensure!(rest.is_empty(), "unexpected `{rest}` after {first}");
+    Ok(command)
+}
+
+/// The names of repositories separated by `,`, quoted or not.
+fn names(text: &str) -> anyhow::Result<Vec<String>> {
+    text.split(',').map(|name| name.trim()).map(name).collect()
+}
+
+fn name(text: &str) -> anyhow::Result<String> {
+    let name = match text.strip_prefix('\'') {
+        Some(quoted) => quoted
+            .strip_suffix('\'')
+            .ok_or_else(|| anyhow::anyhow!("missing `'` after {text}"))?,
+        None => text,
+    };
+    anyhow::ensure!(
+        !name.is_empty() && !name.contains(char::is_whitespace),
+        "expected the name of a repository, got `{text}`"
+    );
+    Ok(name.to_string())
+}
+
+/// Splits the `name:` an operation is prefixed with, if it is.
+fn split_participant(statement: &str) -> anyhow::Result<(Option<String>, &str)> {
+    // Operations have no `:`, quoted uris do.
+    let end = match statement.strip_prefix('\'') {
+        Some(quoted) => quoted.find('\'').map_or(0, |end| end + 2),
+        None => 0,
+    };
+    match statement[end..].find(':') {
+        Some(colon) => {
+            let (name_text, operation) = statement.split_at(end + colon);
+            Ok((Some(name(name_text.trim())?), &operation[1..]))
+        }
+        None => Ok((None, statement)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cereal_core::operations::Expr;
+
+    #[test]
+    fn parses_statements() {
+        let input = "USE customer; BEGIN COORD ON customer, 'http://127.0.0.1:8080/ws/';\n\
+                     'http://127.0.0.1:8080/ws/': READ 1; customer: DELETE 2; COMMIT; READ";
+        let (statements, rest) = split_statements(input);
+        assert_eq!(rest, " READ");
+        let commands: Vec<Command> = statements.iter().map(|s| parse(s).unwrap()).collect();
+        assert_eq!(
+            commands,
+            vec![
+                Command::Use("customer".to_string()),
+                Command::Begin {
+                    kind: Some(TransactionKind::Coordinated),
+                    participants: vec![
+                        "customer".to_string(),
+                        "http://127.0.0.1:8080/ws/".to_string()
+                    ],
+                },
+                Command::Run {
+                    on: Some("http://127.0.0.1:8080/ws/".to_string()),
+                    operation: Operation::Expr(Expr::Read(1)),
+                },
+                Command::Run {
+                    on: Some("customer".to_string()),
+                    operation: Operation::Expr(Expr::Delete(2)),
+                },
+                Command::Commit,
+            ]
+        );
+        assert_eq!(
+            parse("begin on customer").unwrap(),
+            Command::Begin {
+                kind: None,
+                participants: vec!["customer".to_string()],
+            }
+        );
+        assert!(parse("BEGIN COORD customer").is_err());
+        assert!(parse("COMMIT now").is_err());
+        assert!(parse("customer: UPDATE 1 = ").is_err());
+    }
+
+    #[actix_web::test]
+    async fn names_each_participant_once() {
+        let cluster: ClusterConfig = toml::from_str(
+            r#"
+            [[repositories]]
+            name = "customer"
+            listen = "127.0.0.1:8080"
+            tables = ["customer"]
+
+            [[repositories]]
+            name = "order"
+            listen = "127.0.0.1:8081"
+            tables = ["order", "product"]
+            "#,
+        )
+        .unwrap();
+        let mut shell = Shell::new(Some(cluster), Credentials::default(), None);
+        let begin = |participants: &str| parse(&format!("BEGIN COORD ON {participants}")).unwrap();
+
+        for same in [
+            "order, product",
+            "order, order",
+            "customer, 'http://127.0.0.1:8080/ws/'",
+        ] {
+            let e = shell.execute(begin(same)).await.unwrap_err();
+            assert!(format!("{e:#}").contains("the same repository"), "{e:#}");
+            assert!(shell.block.is_none());
+        }
+
+        shell.execute(begin("customer, order")).await.unwrap();
+        shell
+            .execute(parse("product: READ 1").unwrap())
+            .await
+            .unwrap();
+        let in_order = shell.execute(parse("'http://127.0.0.1:8081/ws/': READ 2").unwrap());
+        in_order.await.unwrap();
+        let block = shell.block.as_ref().unwrap();
+        assert!(block.participants[0].operations.is_empty());
+        assert_eq!(block.participants[1].operations.len(), 2);
+        assert!(shell
+            .execute(parse("stock: READ 1").unwrap())
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,119 @@
+//! Endpoints telling operators how a `ws repository` is doing.
+//!
+//! - `/health` answers `200` while the repository handles messages;
+//! - `/ready` answers `200` once the repository can write its log and every
+//!   peer answers, `503` otherwise, telling what failed. The log isn't
+//!   replayed when a repository starts, so there is nothing to recover
+//!   before it is ready;
+//! - `/debug/transactions` shows the
+//!   [cereal_core::introspection::Introspection] of the repository.
+//!
+//! `/health` and `/ready` are for probes, and aren't authenticated.
+//! `/debug/transactions` shows tids and keys, so only peers get it.
+use std::{collections::BTreeMap, time::Duration};
+
+use actix::prelude::*;
+use actix_web::{error, web, Error, HttpRequest, HttpResponse};
+use cereal_core::{
+    messages::{CheckLog, Introspect},
+    repository::Repository,
+};
+use serde_json::json;
+
+use crate::{
+    auth::{Authenticator, Role},
+    peers::{CheckPeers, PeerManager},
+};
+
+/// How long the repository has to answer.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+pub(crate) async fn health(repo: web::Data<Addr<Repository>>) -> HttpResponse {
+    match repo.send(Introspect).timeout(TIMEOUT).await {
+        Ok(_) => HttpResponse::Ok().body("ok"),
+        Err(e) => HttpResponse::ServiceUnavailable().body(format!("repository: {e}")),
+    }
+}
+
+pub(crate) async fn ready(
+    repo: web::Data<Addr<Repository>>,
+    peers: web::Data<Addr<PeerManager>>,
+) -> HttpResponse {
+    let log = match repo.send(CheckLog).timeout(TIMEOUT).await {
+        Ok(checked) => checked.map_err(|e| format!("{e:#}")),
+        Err(e) => Err(format!("repository: {e}")),
+    };
+    let status = |checked: &Result<(), String>| match checked {
+        Ok(()) => "ok".to_string(),
+        Err(e) => e.clone(),
+    };
+    let (peers_ready, peers) = match peers.send(CheckPeers).await {
+        Ok(peers) => (
+            peers.iter().all(|(_, checked)| checked.is_ok()),
+            json!(peers
+                .iter()
+                .map(|(uri, checked)| (uri.clone(), status(checked)))
+                .collect::<BTreeMap<_, _>>()),
+        ),
+        Err(e) => (false, json!(format!("peers: {e}"))),
+    };
+    let ready = log.is_ok() && peers_ready;
+    let body = json!({
+        "ready": ready,
+        "log": status(&log),
+        "peers": peers,
+    });
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+pub(crate) async fn transactions(
+    req: HttpRequest,
+    repo: web::Data<Addr<Repository>>,
+    auth: web::Data<Authenticator>,
+) -> Result<HttpResponse, Error> {
+    if auth.authenticate(&req)? != Role::Peer {
+        return Err(error::ErrorForbidden("only peers may see the transactions"));
+    }
+    let introspection = repo
+        .send(Introspect)
+        .timeout(TIMEOUT)
+        .await
+        .map_err(error::ErrorServiceUnavailable)?;
+    Ok(HttpResponse::Ok().json(introspection))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{body::to_bytes, http::StatusCode};
+
+    use super::*;
+
+    async fn check(peers: Addr<PeerManager>) -> (StatusCode, serde_json::Value) {
+        let repo = Repository::new("status".to_string()).start();
+        let response = ready(web::Data::new(repo), web::Data::new(peers)).await;
+        let status = response.status();
+        let body = to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn test_ready() {
+        let (status, body) = check(PeerManager::default().start()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["log"], "ok");
+
+        // Peers that can't be checked aren't reachable.
+        let stopped = PeerManager::create(|ctx| {
+            ctx.stop();
+            PeerManager::default()
+        });
+        let (status, body) = check(stopped).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["ready"], false);
+        assert!(body["peers"].as_str().unwrap().starts_with("peers: "));
+    }
+}
@@ -0,0 +1,236 @@
+//! Logs and traces of the `ws` commands.
+//!
+//! Logs go to stderr, filtered by `RUST_LOG` (errors only by default). With
+//! `--trace-file`, the spans of the transactions are also written to the
+//! file, as OTLP JSON lines (the OpenTelemetry file exporter format), which
+//! the OpenTelemetry Collector reads with its `otlpjsonfile` receiver.
+//!
+//! Every process sends the trace context of its spans along with its
+//! requests, so the spans of a transaction in the client, the gateway and
+//! every participant are part of one trace.
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{LineWriter, Write as _},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures_util::future::BoxFuture;
+use opentelemetry::{
+    global,
+    trace::{SpanKind, Status, TraceError, TracerProvider as _},
+    KeyValue, Value,
+};
+use opentelemetry_sdk::{
+    export::trace::{ExportResult, SpanData, SpanExporter},
+    propagation::TraceContextPropagator,
+    trace::TracerProvider,
+    Resource,
+};
+use serde_json::{json, Value as Json};
+use tracing_opentelemetry::OpenTelemetrySpanExt as _;
+use tracing_subscriber::{
+    filter::LevelFilter, layer::SubscriberExt as _, util::SubscriberInitExt as _, EnvFilter,
+    Layer as _,
+};
+
+/// Sets up the logs, and the traces of the process (called `service`) if
+/// there is a `trace_file`.
+pub(crate) fn init(service: &str, trace_file: Option<&Path>) -> anyhow::Result<()> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("error"));
+    let logs = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_filter(filter);
+    let traces = match trace_file {
+        Some(path) => {
+            let resource = Resource::new([
+                KeyValue::new("service.name", format!("ws {service}")),
+                KeyValue::new("process.pid", i64::from(std::process::id())),
+            ]);
+            let provider = TracerProvider::builder()
+                .with_simple_exporter(FileExporter::create(path)?)
+                .with_resource(resource)
+                .build();
+            let tracer = provider.tracer("ws");
+            global::set_tracer_provider(provider);
+            let layer = tracing_opentelemetry::layer()
+                .with_tracer(tracer)
+                .with_filter(LevelFilter::INFO);
+            Some(layer)
+        }
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(logs)
+        .with(traces)
+        .try_init()?;
+    Ok(())
+}
+
+/// A span continuing the trace of a request, if it came with a
+/// `trace_context`.
+pub(crate) fn continue_trace(
+    span: tracing::Span,
+    trace_context: &HashMap<String, String>,
+) -> tracing::Span {
+    if !trace_context.is_empty() {
+        let context =
+            global::get_text_map_propagator(|propagator| propagator.extract(trace_context));
+        span.set_parent(context);
+    }
+    span
+}
+
+/// Writes every span to a file, as soon as it ends, as an OTLP JSON line.
+#[derive(Debug)]
+struct FileExporter {
+    file: LineWriter<File>,
+    resource: Json,
+}
+
+impl FileExporter {
+    fn create(path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow::anyhow!("couldn't open trace file {}: {e}", path.display()))?;
+        Ok(FileExporter {
+            file: LineWriter::new(file),
+            resource: json!({ "attributes": [] }),
+        })
+    }
+}
+
+impl SpanExporter for FileExporter {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        let spans: Vec<Json> = batch.iter().map(span).collect();
+        let line = json!({
+            "resourceSpans": [{
+                "resource": self.resource,
+                "scopeSpans": [{ "scope": { "name": "ws" }, "spans": spans }],
+            }],
+        });
+        let written = writeln!(self.file, "{line}").map_err(|e| TraceError::Other(Box::new(e)));
+        Box::pin(async move { written })
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        let attributes: Vec<Json> = resource
+            .iter()
+            .map(|(key, value)| attribute(key.as_str(), value))
+            .collect();
+        self.resource = json!({ "attributes": attributes });
+    }
+}
+
+/// A span, in the OTLP JSON encoding.
+fn span(span: &SpanData) -> Json {
+    let context = &span.span_context;
+    let parent = if span.parent_span_id == opentelemetry::trace::SpanId::INVALID {
+        String::new()
+    } else {
+        span.parent_span_id.to_string()
+    };
+    let kind = match span.span_kind {
+        SpanKind::Internal => 1,
+        SpanKind::Server => 2,
+        SpanKind::Client => 3,
+        SpanKind::Producer => 4,
+        SpanKind::Consumer => 5,
+    };
+    let status = match &span.status {
+        Status::Unset => json!({}),
+        Status::Ok => json!({ "code": 1 }),
+        Status::Error { description } => json!({ "code": 2, "message": description }),
+    };
+    let events: Vec<Json> = span
+        .events
+        .iter()
+        .map(|event| {
+            json!({
+                "timeUnixNano": nanos(event.timestamp),
+                "name": event.name,
+                "attributes": attributes(&event.attributes),
+            })
+        })
+        .collect();
+    json!({
+        "traceId": context.trace_id().to_string(),
+        "spanId": context.span_id().to_string(),
+        "parentSpanId": parent,
+        "name": span.name,
+        "kind": kind,
+        "startTimeUnixNano": nanos(span.start_time),
+        "endTimeUnixNano": nanos(span.end_time),
+        "attributes": attributes(&span.attributes),
+        "events": events,
+        "status": status,
+    })
+}
+
+fn attributes(attributes: &[KeyValue]) -> Vec<Json> {
+    attributes
+        .iter()
+        .map(|kv| attribute(kv.key.as_str(), &kv.value))
+        .collect()
+}
+
+fn attribute(key: &str, value: &Value) -> Json {
+    let value = match value {
+        Value::Bool(b) => json!({ "boolValue": b }),
+        // 64 bits integers are strings in JSON.
+        Value::I64(i) => json!({ "intValue": i.to_string() }),
+        Value::F64(f) => json!({ "doubleValue": f }),
+        value => json!({ "stringValue": value.to_string() }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+/// Nanoseconds since the epoch, as a string like every 64 bits integer.
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::Registry;
+
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    #[test]
+    fn test_continue_trace() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("traces.jsonl");
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(FileExporter::create(&path).unwrap())
+            .build();
+        let subscriber = Registry::default()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("ws")));
+
+        let trace_context = HashMap::from([(
+            "traceparent".to_string(),
+            format!("00-{TRACE_ID}-{PARENT_ID}-01"),
+        )]);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request", message = "Transaction");
+            drop(continue_trace(span, &trace_context));
+        });
+
+        let traces = std::fs::read_to_string(&path).unwrap();
+        let line: Json = serde_json::from_str(traces.lines().next().unwrap()).unwrap();
+        let span = &line["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["name"], "request");
+        assert_eq!(span["traceId"], TRACE_ID);
+        assert_eq!(span["parentSpanId"], PARENT_ID);
+    }
+}
@@ -0,0 +1,112 @@
+//! Repositories served on a free local port, for the tests.
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use actix::prelude::*;
+use actix_web::{dev::ServerHandle, web, App, Error, HttpRequest, HttpResponse, HttpServer};
+use actix_web_actors::ws;
+use cereal_client::Credentials;
+use cereal_core::repository::Repository;
+
+use crate::{auth::Authenticator, peers::PeerManager};
+
+/// A `ws repository` served over plain HTTP.
+pub(crate) struct TestRepository {
+    pub(crate) address: SocketAddr,
+}
+
+impl TestRepository {
+    /// Serves `repository`, trusting every connection as a peer.
+    pub(crate) fn serve(repository: Repository) -> Self {
+        Self::serve_with(repository, Authenticator::default())
+    }
+
+    /// Serves `repository`, authenticating with `authenticator`, and to its
+    /// peers with its `peer_token`.
+    pub(crate) fn serve_with(repository: Repository, authenticator: Authenticator) -> Self {
+        Self::bind(repository, authenticator, ("127.0.0.1", 0))
+    }
+
+    /// Serves `repository` at `address`, e.g. the one of a stopped
+    /// [TestRepository].
+    pub(crate) fn serve_at(repository: Repository, address: SocketAddr) -> Self {
+        Self::bind(repository, Authenticator::default(), address)
+    }
+
+    fn bind(
+        repository: Repository,
+        authenticator: Authenticator,
+        address: impl ToSocketAddrs,
+    ) -> Self {
+        let repo_actor = web::Data::new(repository.start());
+        let mut credentials = Credentials::default();
+        if let Some(token) = &authenticator.peer_token {
+            credentials = credentials.with_token(token);
+        }
+        let peers = web::Data::new(PeerManager::new(vec![], credentials).start());
+        let authenticator = web::Data::new(authenticator);
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::clone(&repo_actor))
+                .app_data(web::Data::clone(&peers))
+                .app_data(web::Data::clone(&authenticator))
+                .configure(crate::repository_routes)
+        })
+        .workers(1)
+        .bind(address)
+        .unwrap();
+        let address = server.addrs()[0];
+        actix::spawn(server.run());
+        TestRepository { address }
+    }
+
+    /// The uri of its `RepositoryWs`.
+    pub(crate) fn uri(&self) -> String {
+        format!("http://{}/ws/", self.address)
+    }
+}
+
+/// A `RepositoryWs` taking requests, and never answering them.
+pub(crate) struct Silent {
+    pub(crate) address: SocketAddr,
+    server: ServerHandle,
+}
+
+impl Silent {
+    pub(crate) fn serve() -> Self {
+        let server = HttpServer::new(|| App::new().route("/ws/", web::get().to(silent)))
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let address = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix::spawn(server);
+        Silent {
+            address,
+            server: handle,
+        }
+    }
+
+    pub(crate) fn uri(&self) -> String {
+        format!("http://{}/ws/", self.address)
+    }
+
+    /// Stops serving, dropping every connection.
+    pub(crate) async fn stop(self) {
+        self.server.stop(false).await;
+    }
+}
+
+struct SilentWs;
+
+impl Actor for SilentWs {
+    type Context = ws::WebsocketContext<Self>;
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for SilentWs {
+    fn handle(&mut self, _: Result<ws::Message, ws::ProtocolError>, _: &mut Self::Context) {}
+}
+
+async fn silent(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
+    ws::start(SilentWs, &req, stream)
+}